@@ -0,0 +1,206 @@
+//! Keeping several accepts pending on a listener
+//!
+//! With a single `accept_overlapped` outstanding on a listener, connections
+//! arriving while the completion of the previous accept is being handled
+//! wait in the backlog until the next accept is issued, which limits how
+//! quickly a busy server can take them on. An `Acceptor` instead keeps a
+//! number of accepts pending at once, each with a socket created ahead of
+//! time to accept into.
+
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::windows::prelude::*;
+
+use iocp::CompletionStatus;
+use net::{self, AcceptAddrsBuf, SocketBuilder, TcpListenerExt, TcpStreamExt};
+use Overlapped;
+
+/// A set of accepts kept pending on a listener.
+///
+/// The listener must be associated with a completion port, to which the
+/// completion of each accept is posted. Accepts are issued with `submit`,
+/// and each completion notification for the listener is passed to
+/// `complete` to retrieve the accepted connection.
+///
+/// The notifications must be dequeued with `get_many`, as `get` returns an
+/// error for a failed accept without its status, which would leave the
+/// accept pending forever. For the same reason the listener must not skip
+/// the completion of accepts which succeed immediately, as set up by
+/// `SocketExt::set_skip_completion_port_on_success` or
+/// `ListenerBuilder::skip_completion_port_on_success`.
+pub struct Acceptor {
+    listener: TcpListener,
+    v6: bool,
+    // Boxed so the state of each accept stays in place while the acceptor
+    // is moved.
+    slots: Box<[Slot]>,
+}
+
+struct Slot {
+    overlapped: Overlapped,
+    addrs: AcceptAddrsBuf,
+    stream: Option<TcpStream>,
+}
+
+impl Acceptor {
+    /// Creates a new acceptor which keeps up to `count` accepts pending on
+    /// `listener`.
+    ///
+    /// No accepts are issued until `submit` is called.
+    pub fn new(listener: TcpListener, count: usize) -> io::Result<Acceptor> {
+        let v6 = match try!(listener.local_addr()) {
+            SocketAddr::V4(..) => false,
+            SocketAddr::V6(..) => true,
+        };
+        let slots = (0..count).map(|_| {
+            Slot {
+                overlapped: Overlapped::zero(),
+                addrs: AcceptAddrsBuf::new(),
+                stream: None,
+            }
+        }).collect::<Vec<_>>();
+        Ok(Acceptor {
+            listener: listener,
+            v6: v6,
+            slots: slots.into_boxed_slice(),
+        })
+    }
+
+    /// Returns the listener accepts are issued on.
+    pub fn listener(&self) -> &TcpListener {
+        &self.listener
+    }
+
+    /// Returns the number of accepts currently pending.
+    pub fn pending(&self) -> usize {
+        self.slots.iter().filter(|s| s.stream.is_some()).count()
+    }
+
+    /// Issues accepts until the configured number of them are pending.
+    ///
+    /// This is called once to start accepting connections, and again after
+    /// each connection is retrieved with `complete` to replace its accept.
+    /// A new socket is created for each accept issued. If an error is
+    /// returned, the accepts issued before it remain pending.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that this
+    /// acceptor is valid until the end of the accepts issued, so it must not
+    /// be dropped until the completion of each pending accept has been
+    /// passed to `complete`.
+    pub unsafe fn submit(&mut self) -> io::Result<()> {
+        for slot in self.slots.iter_mut() {
+            if slot.stream.is_some() {
+                continue
+            }
+            let socket = if self.v6 {
+                try!(SocketBuilder::new_v6())
+            } else {
+                try!(SocketBuilder::new_v4())
+            };
+            slot.overlapped = Overlapped::zero();
+            let (stream, _) = try!(self.listener.accept_overlapped(
+                &socket, &mut slot.addrs, &mut slot.overlapped));
+            slot.stream = Some(stream);
+        }
+        Ok(())
+    }
+
+    /// Retrieves the connection accepted by an accept issued through
+    /// `submit`, along with the address of its peer.
+    ///
+    /// `None` is returned if `status` isn't the completion of one of this
+    /// acceptor's accepts. Otherwise the accept is no longer pending and
+    /// either the accepted connection or the error it failed with is
+    /// returned. The connection is ready for use, but isn't associated with
+    /// any completion port.
+    ///
+    /// The status must have been dequeued with `get_many`, as described in
+    /// the documentation of `Acceptor`.
+    pub fn complete(&mut self, status: &CompletionStatus)
+                    -> Option<io::Result<(TcpStream, SocketAddr)>> {
+        let listener = &self.listener;
+        let slot = match self.slots.iter_mut().find(|s| {
+            &s.overlapped as *const Overlapped ==
+                status.overlapped() as *const Overlapped
+        }) {
+            Some(slot) => slot,
+            None => return None,
+        };
+        let stream = match slot.stream.take() {
+            Some(stream) => stream,
+            None => return None,
+        };
+        Some(Acceptor::finish(listener, slot, stream))
+    }
+
+    fn finish(listener: &TcpListener, slot: &Slot, stream: TcpStream)
+              -> io::Result<(TcpStream, SocketAddr)> {
+        unsafe {
+            try!(net::result(listener.as_raw_socket(), &slot.overlapped));
+        }
+        try!(stream.accept_complete(listener));
+        let remote = try!(slot.addrs.parse(listener)).remote();
+        let remote = try!(remote.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData,
+                           "invalid remote address")
+        }));
+        Ok((stream, remote))
+    }
+}
+
+impl fmt::Debug for Acceptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Acceptor")
+         .field("listener", &self.listener)
+         .field("count", &self.slots.len())
+         .field("pending", &self.pending())
+         .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+
+    use iocp::{CompletionPort, CompletionStatus};
+    use super::Acceptor;
+
+    #[test]
+    fn accepts() {
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let addr = t!(l.local_addr());
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &l));
+        let mut acceptor = t!(Acceptor::new(l, 4));
+        assert_eq!(acceptor.pending(), 0);
+        unsafe {
+            t!(acceptor.submit());
+        }
+        assert_eq!(acceptor.pending(), 4);
+
+        let a = t!(TcpStream::connect(addr));
+        let b = t!(TcpStream::connect(addr));
+        let mut list = [CompletionStatus::zero(); 2];
+        let mut accepted = 0;
+        while accepted < 2 {
+            for status in t!(cp.get_many(&mut list, None)).iter() {
+                let (s, remote) = t!(acceptor.complete(status).unwrap());
+                assert!(remote == t!(a.local_addr()) ||
+                        remote == t!(b.local_addr()));
+                assert_eq!(t!(s.peer_addr()), remote);
+                accepted += 1;
+            }
+        }
+        assert_eq!(acceptor.pending(), 2);
+
+        let other = CompletionStatus::new(0, 1, 0 as *mut _);
+        assert!(acceptor.complete(&other).is_none());
+        unsafe {
+            t!(acceptor.submit());
+        }
+        assert_eq!(acceptor.pending(), 4);
+    }
+}
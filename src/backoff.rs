@@ -0,0 +1,258 @@
+//! Backoff for resource exhaustion errors
+//!
+//! Each outstanding overlapped operation pins its buffer in memory and
+//! consumes non-paged pool in the kernel. A busy server issuing too many
+//! operations at once starts seeing them fail with errors such as
+//! `WSAENOBUFS` or `ERROR_WORKING_SET_QUOTA`, and retrying immediately only
+//! keeps it in an error loop. This module classifies those errors and
+//! provides a `Backoff` policy to wait before retrying, along with hooks to
+//! release memory, such as with `BufferPool::shrink`, in the meantime.
+
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use winapi::*;
+
+/// A policy for retrying operations which failed because the system ran out
+/// of resources.
+///
+/// The delay between retries starts at an initial value and doubles after
+/// each consecutive failure up to a maximum. Hooks registered with
+/// `on_exhaustion` are run each time a failure is observed, before waiting.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    retries: u32,
+    delay: Duration,
+    attempts: u32,
+    hooks: Vec<Box<FnMut() + Send>>,
+}
+
+/// Returns whether `err` indicates that the system or process ran out of
+/// the resources needed to issue an operation, in which case the operation
+/// may succeed if retried later.
+///
+/// This covers `WSAENOBUFS` and the errors reported when non-paged pool,
+/// the working set quota, or the commit limit are exhausted, as happens when
+/// too many buffers are pinned by outstanding overlapped operations.
+pub fn is_resource_exhaustion(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(code) => match code as DWORD {
+            WSAENOBUFS |
+            ERROR_NOT_ENOUGH_MEMORY |
+            ERROR_OUTOFMEMORY |
+            ERROR_NO_SYSTEM_RESOURCES |
+            ERROR_NONPAGED_SYSTEM_RESOURCES |
+            ERROR_PAGED_SYSTEM_RESOURCES |
+            ERROR_WORKING_SET_QUOTA |
+            ERROR_PAGEFILE_QUOTA |
+            ERROR_COMMITMENT_LIMIT |
+            ERROR_NOT_ENOUGH_QUOTA => true,
+            _ => false,
+        },
+        None => false,
+    }
+}
+
+impl Backoff {
+    /// Creates a new policy which waits 1ms after the first failure, at most
+    /// 1s between attempts, and gives up after 10 consecutive failures.
+    pub fn new() -> Backoff {
+        Backoff {
+            initial: Duration::from_millis(1),
+            max: Duration::from_secs(1),
+            retries: 10,
+            delay: Duration::from_millis(1),
+            attempts: 0,
+            hooks: Vec::new(),
+        }
+    }
+
+    /// Configures the delay after the first failure.
+    pub fn initial(&mut self, delay: Duration) -> &mut Backoff {
+        self.initial = delay;
+        self.delay = delay;
+        self
+    }
+
+    /// Configures the longest delay between attempts.
+    pub fn max(&mut self, delay: Duration) -> &mut Backoff {
+        self.max = delay;
+        self
+    }
+
+    /// Configures the number of consecutive failures after which the error is
+    /// returned rather than retried.
+    pub fn retries(&mut self, retries: u32) -> &mut Backoff {
+        self.retries = retries;
+        self
+    }
+
+    /// Registers a function to run each time a resource exhaustion error is
+    /// observed.
+    ///
+    /// This is intended to release memory so that the retried operation can
+    /// succeed, for example by freeing the idle buffers of a `BufferPool`
+    /// with `shrink`.
+    pub fn on_exhaustion<F>(&mut self, f: F) -> &mut Backoff
+        where F: FnMut() + Send + 'static
+    {
+        self.hooks.push(Box::new(f));
+        self
+    }
+
+    /// Records the outcome of an attempt at an operation, returning how long
+    /// to wait before retrying it.
+    ///
+    /// Returns `None` if the operation should not be retried, either because
+    /// the error is not a resource exhaustion error or because the number of
+    /// retries has been used up, in which case the policy is also reset.
+    ///
+    /// This is intended for event loops which can't block and instead
+    /// schedule the retry themselves, such as with a timer. Successful
+    /// attempts should be reported with `reset`.
+    pub fn on_error(&mut self, err: &io::Error) -> Option<Duration> {
+        if !is_resource_exhaustion(err) {
+            self.reset();
+            return None
+        }
+        for hook in self.hooks.iter_mut() {
+            hook();
+        }
+        if self.attempts >= self.retries {
+            self.reset();
+            return None
+        }
+        self.attempts += 1;
+        let delay = self.delay;
+        self.delay = cmp::min(self.delay * 2, self.max);
+        Some(delay)
+    }
+
+    /// Resets this policy after a successful attempt, so the next failure
+    /// waits for the initial delay again.
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+        self.delay = self.initial;
+    }
+
+    /// Runs `f` until it succeeds, fails with an error other than a resource
+    /// exhaustion error, or the number of retries is used up, blocking the
+    /// calling thread between attempts.
+    ///
+    /// The error of the final attempt is returned if it never succeeds.
+    pub fn retry<T, F>(&mut self, mut f: F) -> io::Result<T>
+        where F: FnMut() -> io::Result<T>
+    {
+        loop {
+            match f() {
+                Ok(t) => {
+                    self.reset();
+                    return Ok(t)
+                }
+                Err(e) => match self.on_error(&e) {
+                    Some(delay) => thread::sleep(delay),
+                    None => return Err(e),
+                },
+            }
+        }
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff::new()
+    }
+}
+
+impl fmt::Debug for Backoff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Backoff")
+         .field("initial", &self.initial)
+         .field("max", &self.max)
+         .field("retries", &self.retries)
+         .field("delay", &self.delay)
+         .field("attempts", &self.attempts)
+         .field("hooks", &self.hooks.len())
+         .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use winapi::*;
+
+    use buffer::BufferPool;
+    use super::{is_resource_exhaustion, Backoff};
+
+    fn nobufs() -> io::Error {
+        io::Error::from_raw_os_error(WSAENOBUFS as i32)
+    }
+
+    #[test]
+    fn classify() {
+        assert!(is_resource_exhaustion(&nobufs()));
+        let e = io::Error::from_raw_os_error(ERROR_WORKING_SET_QUOTA as i32);
+        assert!(is_resource_exhaustion(&e));
+        let e = io::Error::from_raw_os_error(WSAECONNRESET as i32);
+        assert!(!is_resource_exhaustion(&e));
+        let e = io::Error::new(io::ErrorKind::Other, "foo");
+        assert!(!is_resource_exhaustion(&e));
+    }
+
+    #[test]
+    fn delays() {
+        let mut b = Backoff::new();
+        b.initial(Duration::from_millis(10))
+         .max(Duration::from_millis(25))
+         .retries(3);
+        assert_eq!(b.on_error(&nobufs()), Some(Duration::from_millis(10)));
+        assert_eq!(b.on_error(&nobufs()), Some(Duration::from_millis(20)));
+        assert_eq!(b.on_error(&nobufs()), Some(Duration::from_millis(25)));
+        assert_eq!(b.on_error(&nobufs()), None);
+        assert_eq!(b.on_error(&nobufs()), Some(Duration::from_millis(10)));
+        b.reset();
+        assert_eq!(b.on_error(&nobufs()), Some(Duration::from_millis(10)));
+        let e = io::Error::new(io::ErrorKind::Other, "foo");
+        assert_eq!(b.on_error(&e), None);
+    }
+
+    #[test]
+    fn retry() {
+        let pool = Arc::new(BufferPool::new(4096, 0));
+        let bufs = (0..4).map(|_| t!(pool.get())).collect::<Vec<_>>();
+        for buf in bufs {
+            pool.put(buf);
+        }
+        assert_eq!(pool.idle(), 4);
+        let hits = Arc::new(AtomicUsize::new(0));
+        let mut b = Backoff::new();
+        b.initial(Duration::from_millis(1)).retries(5);
+        let (pool2, hits2) = (pool.clone(), hits.clone());
+        b.on_exhaustion(move || {
+            pool2.shrink(0);
+            hits2.fetch_add(1, Ordering::SeqCst);
+        });
+
+        let mut calls = 0;
+        let n = t!(b.retry(|| {
+            calls += 1;
+            if calls < 3 { Err(nobufs()) } else { Ok(calls) }
+        }));
+        assert_eq!(n, 3);
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.idle(), 0);
+
+        let e = b.retry(|| -> io::Result<()> { Err(nobufs()) }).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(WSAENOBUFS as i32));
+        assert_eq!(hits.load(Ordering::SeqCst), 2 + 6);
+    }
+}
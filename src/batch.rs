@@ -0,0 +1,302 @@
+//! Batched sends of datagrams
+//!
+//! Servers for protocols built on UDP often produce many datagrams at once,
+//! such as a burst of packets for each connection after processing the
+//! datagrams received from their peers. A `UdpSendBatch` queues such
+//! datagrams in a single buffer and issues all of their sends together,
+//! optionally combining runs of datagrams for the same peer into a single
+//! segmented send so the network stack can split them up instead.
+
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::windows::prelude::*;
+
+use winapi::SOCKET;
+
+use iocp::CompletionStatus;
+use net::{self, CMsgBuf, MsgBuf, UdpSocketExt};
+use Overlapped;
+
+// The most data combined into a single segmented send.
+const MAX_SEGMENTED: usize = 0xffff;
+
+/// A batch of datagrams sent together with overlapped sends.
+///
+/// Datagrams are queued with `push` and then sent with `submit`, after which
+/// each completion notification for the socket is passed to `complete`.
+/// Once every send of the batch has completed it is emptied, ready for the
+/// next datagrams to be queued.
+///
+/// Notifications must be dequeued with `CompletionPort::get_many`, as `get`
+/// drops the statuses of failed sends, which would then remain pending
+/// forever. For the same reason the socket must not skip completions of
+/// sends which succeed immediately (see
+/// `SocketExt::set_skip_completion_port_on_success`).
+pub struct UdpSendBatch {
+    data: Vec<u8>,
+    datagrams: Vec<(SocketAddr, usize)>,
+    segment: bool,
+    socket: RawSocket,
+    // Boxed so the state of each send stays in place while the batch is
+    // moved.
+    sends: Box<[Send]>,
+}
+
+struct Send {
+    overlapped: Overlapped,
+    msg: MsgBuf,
+    control: CMsgBuf,
+    addr: SocketAddr,
+    start: usize,
+    len: usize,
+    size: usize,
+    count: usize,
+    pending: bool,
+}
+
+impl UdpSendBatch {
+    /// Creates a new empty batch.
+    pub fn new() -> UdpSendBatch {
+        UdpSendBatch {
+            data: Vec::new(),
+            datagrams: Vec::new(),
+            segment: false,
+            socket: 0,
+            sends: Vec::new().into_boxed_slice(),
+        }
+    }
+
+    /// Configures whether runs of datagrams of the same size queued for the
+    /// same address are combined into a single send, which the network
+    /// stack splits back into datagrams (via `UDP_SEND_MSG_SIZE`).
+    ///
+    /// This reduces the number of sends issued for a batch, but isn't
+    /// supported by older versions of Windows, on which the sends fail. It
+    /// is disabled by default.
+    pub fn set_segmentation(&mut self, enable: bool) {
+        self.segment = enable;
+    }
+
+    /// Queues a datagram with the contents of `buf` to be sent to `addr`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if sends of this batch are still pending.
+    pub fn push(&mut self, buf: &[u8], addr: SocketAddr) {
+        assert!(self.pending() == 0, "sends of the batch are pending");
+        self.data.extend_from_slice(buf);
+        self.datagrams.push((addr, buf.len()));
+    }
+
+    /// Returns the number of datagrams in this batch.
+    pub fn len(&self) -> usize {
+        self.datagrams.len()
+    }
+
+    /// Returns whether this batch holds no datagrams.
+    pub fn is_empty(&self) -> bool {
+        self.datagrams.is_empty()
+    }
+
+    /// Returns the number of sends of this batch which haven't completed
+    /// yet.
+    pub fn pending(&self) -> usize {
+        self.sends.iter().filter(|s| s.pending).count()
+    }
+
+    /// Issues the sends for the datagrams queued in this batch on `socket`,
+    /// returning the number of sends issued.
+    ///
+    /// The socket must be associated with a completion port, to which the
+    /// completion of each send is posted. If an error is returned for the
+    /// first send nothing is issued and the datagrams remain queued.
+    /// Otherwise the sends issued before the error remain pending, and the
+    /// datagrams of the sends after it are discarded once those complete.
+    ///
+    /// # Panics
+    ///
+    /// Panics if sends of this batch are still pending.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that this batch
+    /// is valid until the end of the sends issued, so it must not be dropped
+    /// until the completion of each pending send has been passed to
+    /// `complete`.
+    pub unsafe fn submit(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        assert!(self.pending() == 0, "sends of the batch are pending");
+        self.socket = socket.as_raw_socket();
+        self.sends = self.plan().into_boxed_slice();
+        for i in 0..self.sends.len() {
+            let send = &mut self.sends[i];
+            let buf = &self.data[send.start..send.start + send.len];
+            let r = if send.count > 1 {
+                send.control.push_send_msg_size(send.size as u32);
+                socket.send_msg_overlapped(buf, &send.addr,
+                                           send.control.as_bytes(),
+                                           &mut send.msg, &mut send.overlapped)
+            } else {
+                socket.send_to_overlapped(buf, &send.addr,
+                                          &mut send.overlapped)
+            };
+            if let Err(e) = r {
+                if i == 0 {
+                    self.sends = Vec::new().into_boxed_slice();
+                }
+                return Err(e)
+            }
+            send.pending = true;
+        }
+        Ok(self.sends.len())
+    }
+
+    /// Records the completion of a send issued through `submit`, returning
+    /// the number of bytes sent by it.
+    ///
+    /// `None` is returned if `status` isn't the completion of one of this
+    /// batch's sends. Once the last pending send has completed the batch is
+    /// emptied.
+    ///
+    /// Every notification of the batch's sends has to be passed here,
+    /// including those of failed sends, so `status` should come from
+    /// `CompletionPort::get_many`.
+    pub fn complete(&mut self, status: &CompletionStatus)
+                    -> Option<io::Result<usize>> {
+        let socket = self.socket;
+        let ret = match self.sends.iter_mut().find(|s| {
+            s.pending && &s.overlapped as *const Overlapped ==
+                status.overlapped() as *const Overlapped
+        }) {
+            Some(send) => {
+                send.pending = false;
+                unsafe {
+                    net::result(socket as SOCKET, &send.overlapped)
+                        .map(|(n, _)| n)
+                }
+            }
+            None => return None,
+        };
+        if self.pending() == 0 {
+            self.clear();
+        }
+        Some(ret)
+    }
+
+    fn clear(&mut self) {
+        self.data.clear();
+        self.datagrams.clear();
+        self.sends = Vec::new().into_boxed_slice();
+    }
+
+    // Groups the queued datagrams into the sends issued for them.
+    fn plan(&self) -> Vec<Send> {
+        let mut sends: Vec<Send> = Vec::new();
+        let mut start = 0;
+        for &(addr, len) in self.datagrams.iter() {
+            // A datagram joins the previous send if it's for the same
+            // address, no larger than the ones before it, and the send
+            // hasn't been ended by a shorter datagram.
+            let join = match sends.last() {
+                Some(send) => {
+                    self.segment && send.addr == addr && len > 0 &&
+                        len <= send.size && send.len % send.size == 0 &&
+                        send.len + len <= MAX_SEGMENTED
+                }
+                None => false,
+            };
+            if join {
+                let send = sends.last_mut().unwrap();
+                send.len += len;
+                send.count += 1;
+            } else {
+                sends.push(Send {
+                    overlapped: Overlapped::zero(),
+                    msg: MsgBuf::new(),
+                    control: CMsgBuf::new(),
+                    addr: addr,
+                    start: start,
+                    len: len,
+                    size: len,
+                    count: 1,
+                    pending: false,
+                });
+            }
+            start += len;
+        }
+        sends
+    }
+}
+
+impl fmt::Debug for UdpSendBatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UdpSendBatch")
+         .field("datagrams", &self.datagrams.len())
+         .field("bytes", &self.data.len())
+         .field("segment", &self.segment)
+         .field("pending", &self.pending())
+         .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use iocp::{CompletionPort, CompletionStatus};
+    use net::UdpSocketExt;
+    use super::UdpSendBatch;
+
+    fn send(segment: bool) {
+        let a = t!(UdpSocket::bind("127.0.0.1:0"));
+        let b = t!(UdpSocket::bind("127.0.0.1:0"));
+        let c = t!(UdpSocket::bind("127.0.0.1:0"));
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &a));
+
+        let mut batch = UdpSendBatch::new();
+        batch.set_segmentation(segment);
+        batch.push(&[1; 100], t!(b.local_addr()));
+        batch.push(&[2; 100], t!(b.local_addr()));
+        batch.push(&[3; 50], t!(b.local_addr()));
+        batch.push(&[4; 10], t!(c.local_addr()));
+        assert_eq!(batch.len(), 4);
+        let sends = unsafe { t!(batch.submit(&a)) };
+        assert_eq!(sends, if segment { 2 } else { 4 });
+        assert_eq!(batch.pending(), sends);
+
+        let mut sent = 0;
+        let mut list = [CompletionStatus::zero(); 4];
+        while batch.pending() > 0 {
+            for status in t!(cp.get_many(&mut list, None)).iter() {
+                sent += t!(batch.complete(status).unwrap());
+            }
+        }
+        assert_eq!(sent, 260);
+        assert!(batch.is_empty());
+        assert_eq!(batch.pending(), 0);
+
+        let mut buf = [0; 200];
+        for &(n, byte) in [(100, 1), (100, 2), (50, 3)].iter() {
+            let (len, _) = t!(b.recv_from(&mut buf));
+            assert_eq!(&buf[..len], &[byte; 200][..n]);
+        }
+        let (len, _) = t!(c.recv_from(&mut buf));
+        assert_eq!(&buf[..len], &[4; 10]);
+    }
+
+    #[test]
+    fn send_to() {
+        send(false);
+    }
+
+    #[test]
+    fn segmented() {
+        // Segmentation offload isn't available on older versions of Windows.
+        let probe = t!(UdpSocket::bind("127.0.0.1:0"));
+        if probe.set_send_msg_size(100).is_err() {
+            return
+        }
+        send(true);
+    }
+}
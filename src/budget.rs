@@ -0,0 +1,189 @@
+//! Accounting of memory pinned by outstanding operations
+//!
+//! The buffers of outstanding overlapped operations are locked in memory by
+//! the kernel, which also allocates bookkeeping for each operation from the
+//! non-paged pool. A server which issues operations faster than they
+//! complete can exhaust that pool and destabilize the whole system. The
+//! `Budget` type in this module limits the total number of bytes pinned by
+//! the operations issued against it, typically one budget per completion
+//! port, and applies backpressure to submissions above that limit.
+
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// A limit on the total number of bytes pinned by outstanding operations.
+///
+/// Before issuing an operation a `Reservation` for the size of its buffer is
+/// acquired from the budget, and it is dropped once the operation's
+/// completion has been dequeued. Cloning a budget returns a handle to the
+/// same accounting.
+#[derive(Clone, Debug)]
+pub struct Budget {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    limit: usize,
+    in_flight: Mutex<usize>,
+    cond: Condvar,
+}
+
+/// Bytes reserved from a `Budget` for an outstanding operation, which are
+/// returned to the budget when this is dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    inner: Arc<Inner>,
+    bytes: usize,
+}
+
+impl Budget {
+    /// Creates a new budget allowing up to `limit` bytes to be reserved at
+    /// once.
+    pub fn new(limit: usize) -> Budget {
+        Budget {
+            inner: Arc::new(Inner {
+                limit: limit,
+                in_flight: Mutex::new(0),
+                cond: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Returns the number of bytes this budget allows to be reserved at once.
+    pub fn limit(&self) -> usize {
+        self.inner.limit
+    }
+
+    /// Returns the number of bytes currently reserved.
+    pub fn in_flight(&self) -> usize {
+        *self.inner.lock()
+    }
+
+    /// Returns whether the bytes currently reserved have reached the limit.
+    ///
+    /// This can be used as a backpressure signal, for example to stop
+    /// accepting new connections or reading new requests until outstanding
+    /// writes have drained.
+    pub fn is_exhausted(&self) -> bool {
+        self.in_flight() >= self.inner.limit
+    }
+
+    /// Reserves `bytes` if that can be done without exceeding the limit,
+    /// returning `None` otherwise.
+    ///
+    /// A reservation larger than the limit itself is granted when nothing
+    /// else is reserved, so that it can't be refused forever.
+    pub fn try_reserve(&self, bytes: usize) -> Option<Reservation> {
+        let mut in_flight = self.inner.lock();
+        if self.inner.fits(*in_flight, bytes) {
+            *in_flight += bytes;
+            Some(self.reservation(bytes))
+        } else {
+            None
+        }
+    }
+
+    /// Reserves `bytes`, blocking the calling thread until enough
+    /// reservations have been dropped for it to fit within the limit.
+    ///
+    /// If `timeout` is specified and elapses first an error of kind
+    /// `TimedOut` is returned.
+    pub fn reserve(&self, bytes: usize, timeout: Option<Duration>)
+                   -> io::Result<Reservation> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut in_flight = self.inner.lock();
+        while !self.inner.fits(*in_flight, bytes) {
+            in_flight = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                                  "in-flight limit reached"))
+                    }
+                    self.inner.cond.wait_timeout(in_flight, deadline - now)
+                        .unwrap_or_else(|e| e.into_inner()).0
+                }
+                None => {
+                    self.inner.cond.wait(in_flight)
+                        .unwrap_or_else(|e| e.into_inner())
+                }
+            };
+        }
+        *in_flight += bytes;
+        Ok(self.reservation(bytes))
+    }
+
+    fn reservation(&self, bytes: usize) -> Reservation {
+        Reservation { inner: self.inner.clone(), bytes: bytes }
+    }
+}
+
+impl Inner {
+    fn fits(&self, in_flight: usize, bytes: usize) -> bool {
+        in_flight == 0 || in_flight.saturating_add(bytes) <= self.limit
+    }
+
+    fn lock(&self) -> MutexGuard<usize> {
+        self.in_flight.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Reservation {
+    /// Returns the number of bytes reserved.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        *self.inner.lock() -= self.bytes;
+        self.inner.cond.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::Budget;
+
+    #[test]
+    fn try_reserve() {
+        let budget = Budget::new(100);
+        let a = budget.try_reserve(60).unwrap();
+        assert_eq!(budget.in_flight(), 60);
+        assert!(budget.try_reserve(50).is_none());
+        let b = budget.try_reserve(40).unwrap();
+        assert!(budget.is_exhausted());
+        drop(a);
+        drop(b);
+        assert_eq!(budget.in_flight(), 0);
+
+        let big = budget.try_reserve(1000).unwrap();
+        assert_eq!(big.bytes(), 1000);
+        assert!(budget.try_reserve(1).is_none());
+    }
+
+    #[test]
+    fn reserve_blocks() {
+        let budget = Budget::new(100);
+        let a = t!(budget.reserve(100, None));
+        let e = budget.reserve(1, Some(Duration::from_millis(10)))
+                      .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(a);
+        });
+        let b = t!(budget.reserve(50, Some(Duration::from_secs(10))));
+        assert_eq!(budget.in_flight(), 50);
+        drop(b);
+        t!(t.join());
+    }
+}
@@ -0,0 +1,367 @@
+//! Buffers for overlapped I/O operations
+//!
+//! Buffers handed to the kernel for overlapped I/O are pinned for the duration
+//! of the operation and are then typically touched by whichever thread
+//! dequeues the completion. This module provides buffers allocated in memory
+//! local to a particular NUMA node along with a pool to recycle them, and a
+//! ring buffer which overlapped reads can fill directly.
+
+use std::io;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::slice;
+use std::sync::Mutex;
+
+use winapi::*;
+use kernel32::*;
+
+/// A fixed-size buffer whose pages are allocated on a particular NUMA node.
+///
+/// The memory is allocated with `VirtualAllocExNuma` and is therefore always
+/// page aligned and zeroed when first created.
+#[derive(Debug)]
+pub struct NumaBuffer {
+    ptr: *mut u8,
+    len: usize,
+    node: u32,
+}
+
+unsafe impl Send for NumaBuffer {}
+unsafe impl Sync for NumaBuffer {}
+
+/// A pool of equally sized buffers which are all allocated on one NUMA node.
+///
+/// Buffers are handed out with `get` and returned with `put`. Buffers which
+/// are idle in the pool are reused before any new memory is allocated.
+#[derive(Debug)]
+pub struct BufferPool {
+    buffer_size: usize,
+    node: u32,
+    free: Mutex<Vec<NumaBuffer>>,
+}
+
+/// A bipartite ring buffer which overlapped reads can target directly.
+///
+/// Data is written into the contiguous region returned by `writable`, which
+/// can be handed to an overlapped read as-is, and made available with
+/// `commit` once the read completes. The committed data is exposed in order
+/// as a contiguous slice through `readable` and released with `consume`.
+///
+/// Unlike a classic ring buffer, neither region ever wraps around the end of
+/// the storage, so streaming parsers can consume received data in place
+/// rather than copying it out of per-operation buffers first. Instead the
+/// buffer keeps up to two regions of data: once the space after the first
+/// region runs out, writing continues at the start of the storage, and that
+/// second region becomes readable after the first has been consumed.
+#[derive(Debug)]
+pub struct BipBuffer {
+    buf: Box<[u8]>,
+    a_start: usize,
+    a_end: usize,
+    b_end: usize,
+    b_active: bool,
+    reserved: Option<usize>,
+}
+
+/// Returns the NUMA node of the processor the calling thread is currently
+/// running on.
+///
+/// Note that unless the thread's affinity is restricted the scheduler is free
+/// to move it to another node at any time.
+pub fn current_numa_node() -> io::Result<u32> {
+    unsafe {
+        let mut processor: PROCESSOR_NUMBER = mem::zeroed();
+        GetCurrentProcessorNumberEx(&mut processor);
+        let mut node = 0;
+        try!(::cvt(GetNumaProcessorNodeEx(&mut processor, &mut node)));
+        Ok(node as u32)
+    }
+}
+
+impl NumaBuffer {
+    /// Allocates a new zeroed buffer of `len` bytes, preferably on the NUMA
+    /// node specified.
+    ///
+    /// The node is only a preference; if the node has no memory available the
+    /// system may satisfy the allocation from another node.
+    pub fn new(len: usize, node: u32) -> io::Result<NumaBuffer> {
+        let ptr = unsafe {
+            VirtualAllocExNuma(GetCurrentProcess(), 0 as *mut _,
+                               len as SIZE_T, MEM_RESERVE | MEM_COMMIT,
+                               PAGE_READWRITE, node)
+        };
+        if ptr.is_null() {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(NumaBuffer { ptr: ptr as *mut u8, len: len, node: node })
+        }
+    }
+
+    /// Returns the NUMA node this buffer was requested to be allocated on.
+    pub fn node(&self) -> u32 {
+        self.node
+    }
+}
+
+impl Deref for NumaBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for NumaBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for NumaBuffer {
+    fn drop(&mut self) {
+        unsafe { VirtualFree(self.ptr as LPVOID, 0, MEM_RELEASE) };
+    }
+}
+
+impl BufferPool {
+    /// Creates a new empty pool of buffers of `buffer_size` bytes each, which
+    /// will be allocated on the NUMA node specified.
+    pub fn new(buffer_size: usize, node: u32) -> BufferPool {
+        BufferPool {
+            buffer_size: buffer_size,
+            node: node,
+            free: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Creates a new empty pool whose buffers will be allocated on the NUMA
+    /// node of the calling thread.
+    ///
+    /// This is intended to be called from the worker thread which will be
+    /// processing the completions for the I/O using these buffers.
+    pub fn local(buffer_size: usize) -> io::Result<BufferPool> {
+        current_numa_node().map(|node| BufferPool::new(buffer_size, node))
+    }
+
+    /// Returns the size, in bytes, of each buffer in this pool.
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Returns the NUMA node that buffers of this pool are allocated on.
+    pub fn node(&self) -> u32 {
+        self.node
+    }
+
+    /// Returns the number of buffers currently idle in this pool.
+    pub fn idle(&self) -> usize {
+        self.free.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// Acquires a buffer from this pool, allocating a new one if no idle
+    /// buffers are available.
+    ///
+    /// Buffers which are reused are not zeroed again.
+    pub fn get(&self) -> io::Result<NumaBuffer> {
+        let reused = self.free.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        match reused {
+            Some(buf) => Ok(buf),
+            None => NumaBuffer::new(self.buffer_size, self.node),
+        }
+    }
+
+    /// Returns a buffer to this pool so it can be reused.
+    ///
+    /// Buffers which were not allocated with this pool's size and node are
+    /// simply freed.
+    pub fn put(&self, buf: NumaBuffer) {
+        if buf.len == self.buffer_size && buf.node == self.node {
+            self.free.lock().unwrap_or_else(|e| e.into_inner()).push(buf);
+        }
+    }
+
+    /// Frees idle buffers until at most `keep` remain in this pool.
+    pub fn shrink(&self, keep: usize) {
+        let mut free = self.free.lock().unwrap_or_else(|e| e.into_inner());
+        free.truncate(keep);
+    }
+}
+
+impl BipBuffer {
+    /// Creates a new, empty, buffer with room for `capacity` bytes.
+    pub fn new(capacity: usize) -> BipBuffer {
+        BipBuffer {
+            buf: vec![0; capacity].into_boxed_slice(),
+            a_start: 0,
+            a_end: 0,
+            b_end: 0,
+            b_active: false,
+            reserved: None,
+        }
+    }
+
+    /// Returns the total number of bytes this buffer can hold.
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Returns the number of committed bytes which have not been consumed.
+    pub fn len(&self) -> usize {
+        (self.a_end - self.a_start) + self.b_end
+    }
+
+    /// Returns whether there are no committed bytes left to consume.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reserves the largest contiguous free region of this buffer and
+    /// returns it, to be filled by a read.
+    ///
+    /// Once the read completes, `commit` must be called with the number of
+    /// bytes it transferred. Only one region can be reserved at a time, and
+    /// calling this again replaces the previous reservation. The returned
+    /// region is empty if the buffer is full.
+    ///
+    /// Data may be consumed while a region is reserved, so a parser can keep
+    /// working through received data while the next read is pending.
+    pub fn writable(&mut self) -> &mut [u8] {
+        if self.a_start == self.a_end && !self.b_active &&
+           self.reserved.is_none() {
+            self.a_start = 0;
+            self.a_end = 0;
+        }
+        let (start, end) = if self.b_active {
+            (self.b_end, self.a_start)
+        } else if self.buf.len() - self.a_end >= self.a_start {
+            (self.a_end, self.buf.len())
+        } else {
+            (0, self.a_start)
+        };
+        self.reserved = Some(start);
+        &mut self.buf[start..end]
+    }
+
+    /// Makes the first `n` bytes of the region reserved by `writable`
+    /// available for reading, releasing the reservation.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no region is reserved or `n` is larger than the region.
+    pub fn commit(&mut self, n: usize) {
+        let start = self.reserved.take().expect("no region reserved");
+        if !self.b_active && start == self.a_end {
+            assert!(n <= self.buf.len() - self.a_end);
+            self.a_end += n;
+        } else {
+            assert!(start == self.b_end && self.b_end + n <= self.a_start);
+            self.b_end += n;
+            self.b_active = self.b_active || n > 0;
+        }
+        self.normalize();
+    }
+
+    /// Returns the committed data which can be read contiguously.
+    ///
+    /// If this buffer holds two regions of data, only the first is returned;
+    /// the second becomes readable once the first has been consumed.
+    pub fn readable(&self) -> &[u8] {
+        &self.buf[self.a_start..self.a_end]
+    }
+
+    /// Releases the first `n` bytes of the data returned by `readable`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is larger than the slice returned by `readable`.
+    pub fn consume(&mut self, n: usize) {
+        assert!(n <= self.a_end - self.a_start);
+        self.a_start += n;
+        self.normalize();
+    }
+
+    // Once the first region has been consumed, the second takes its place.
+    fn normalize(&mut self) {
+        if self.a_start != self.a_end {
+            return
+        }
+        if self.b_active {
+            self.a_start = 0;
+            self.a_end = self.b_end;
+            self.b_end = 0;
+            self.b_active = false;
+        } else if self.reserved.is_none() {
+            self.a_start = 0;
+            self.a_end = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{current_numa_node, BipBuffer, BufferPool, NumaBuffer};
+
+    #[test]
+    fn alloc() {
+        let node = t!(current_numa_node());
+        let mut buf = t!(NumaBuffer::new(4096, node));
+        assert_eq!(buf.len(), 4096);
+        assert!(buf.iter().all(|b| *b == 0));
+        buf[0] = 1;
+        assert_eq!(buf[0], 1);
+        assert_eq!(buf.node(), node);
+    }
+
+    #[test]
+    fn pool_reuse() {
+        let pool = t!(BufferPool::local(1024));
+        let a = t!(pool.get());
+        assert_eq!(a.len(), 1024);
+        assert_eq!(pool.idle(), 0);
+        pool.put(a);
+        assert_eq!(pool.idle(), 1);
+        let _a = t!(pool.get());
+        assert_eq!(pool.idle(), 0);
+
+        pool.put(t!(NumaBuffer::new(10, pool.node())));
+        assert_eq!(pool.idle(), 0);
+
+        pool.put(t!(pool.get()));
+        pool.put(t!(pool.get()));
+        pool.shrink(0);
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    fn bip_wrap() {
+        let mut bip = BipBuffer::new(8);
+        assert_eq!(bip.writable().len(), 8);
+        bip.writable()[..6].copy_from_slice(b"abcdef");
+        bip.commit(6);
+        assert_eq!(bip.readable(), b"abcdef");
+        bip.consume(4);
+
+        // Only 2 bytes are free after the data but 4 before it, so the next
+        // write goes to the start of the storage while a read is pending.
+        assert_eq!(bip.writable().len(), 4);
+        bip.consume(1);
+        bip.writable()[..3].copy_from_slice(b"ghi");
+        bip.commit(3);
+        assert_eq!(bip.len(), 4);
+        assert_eq!(bip.readable(), b"f");
+        bip.consume(1);
+        assert_eq!(bip.readable(), b"ghi");
+        bip.consume(3);
+        assert!(bip.is_empty());
+        assert_eq!(bip.writable().len(), 8);
+
+        // Consuming all of the first region while the second is being
+        // written still exposes the second region once it's committed.
+        bip.commit(7);
+        bip.consume(6);
+        assert_eq!(bip.writable().len(), 6);
+        bip.consume(1);
+        bip.commit(2);
+        assert_eq!(bip.readable().len(), 2);
+    }
+}
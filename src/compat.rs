@@ -0,0 +1,103 @@
+//! Conversions to and from the types of the `windows-sys` crate
+//!
+//! This module is only compiled when the `windows-sys` feature of this crate
+//! is enabled. The types of this crate and those of `windows-sys` have the
+//! same layout, so all conversions here are free.
+
+use std::mem;
+use std::slice;
+
+use windows_sys::Win32::System::IO::{OVERLAPPED, OVERLAPPED_ENTRY};
+use iocp::CompletionStatus;
+use Overlapped;
+
+impl Overlapped {
+    /// Returns a pointer to the underlying `OVERLAPPED` as the type defined
+    /// by `windows-sys`.
+    pub fn raw_windows_sys(&self) -> *mut OVERLAPPED {
+        self.raw() as *mut OVERLAPPED
+    }
+
+    /// Creates a reference to an `Overlapped` from a pointer to a
+    /// `windows-sys` `OVERLAPPED`.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because `ptr` must be valid for the lifetime
+    /// `'a` and must not be aliased for that lifetime.
+    pub unsafe fn from_raw_windows_sys<'a>(ptr: *mut OVERLAPPED)
+                                           -> &'a mut Overlapped {
+        &mut *(ptr as *mut Overlapped)
+    }
+}
+
+impl From<OVERLAPPED> for Overlapped {
+    fn from(overlapped: OVERLAPPED) -> Overlapped {
+        unsafe { mem::transmute(overlapped) }
+    }
+}
+
+impl From<Overlapped> for OVERLAPPED {
+    fn from(overlapped: Overlapped) -> OVERLAPPED {
+        unsafe { mem::transmute(overlapped) }
+    }
+}
+
+impl CompletionStatus {
+    /// Reinterprets a slice of `windows-sys` `OVERLAPPED_ENTRY` structures as
+    /// a slice of completion statuses.
+    pub fn from_windows_sys_slice(entries: &mut [OVERLAPPED_ENTRY])
+                                  -> &mut [CompletionStatus] {
+        unsafe {
+            slice::from_raw_parts_mut(entries.as_mut_ptr() as *mut _,
+                                      entries.len())
+        }
+    }
+
+    /// Reinterprets a slice of completion statuses as a slice of
+    /// `windows-sys` `OVERLAPPED_ENTRY` structures.
+    pub fn as_windows_sys_slice(list: &mut [CompletionStatus])
+                                -> &mut [OVERLAPPED_ENTRY] {
+        unsafe {
+            slice::from_raw_parts_mut(list.as_mut_ptr() as *mut _, list.len())
+        }
+    }
+}
+
+impl From<OVERLAPPED_ENTRY> for CompletionStatus {
+    fn from(entry: OVERLAPPED_ENTRY) -> CompletionStatus {
+        unsafe { mem::transmute(entry) }
+    }
+}
+
+impl From<CompletionStatus> for OVERLAPPED_ENTRY {
+    fn from(status: CompletionStatus) -> OVERLAPPED_ENTRY {
+        unsafe { mem::transmute(status) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use windows_sys::Win32::System::IO::{OVERLAPPED, OVERLAPPED_ENTRY};
+
+    use iocp::CompletionStatus;
+    use Overlapped;
+
+    #[test]
+    fn round_trip() {
+        let mut o = Overlapped::zero();
+        o.set_offset(1 << 33 | 4);
+        let o = Overlapped::from(OVERLAPPED::from(o));
+        assert_eq!(o.offset(), 1 << 33 | 4);
+
+        let status = CompletionStatus::new(1, 2, 3 as *mut _);
+        let entry = OVERLAPPED_ENTRY::from(status);
+        assert_eq!(entry.dwNumberOfBytesTransferred, 1);
+        assert_eq!(entry.lpCompletionKey, 2);
+        assert_eq!(entry.lpOverlapped as usize, 3);
+
+        let mut list = [entry];
+        let list = CompletionStatus::from_windows_sys_slice(&mut list);
+        assert_eq!(list[0].token(), 2);
+    }
+}
@@ -0,0 +1,273 @@
+//! Asynchronous console input
+//!
+//! Console input handles don't support overlapped I/O, so interactive
+//! programs typically dedicate a thread to blocking reads of standard input.
+//! The `ConsoleInput` type in this module instead waits for the console input
+//! handle to be signaled in the system thread pool, and posts a completion
+//! status to a port whenever input is available to be read without blocking.
+
+use std::char;
+use std::cmp;
+use std::io;
+use std::mem;
+use std::os::windows::io::*;
+
+use winapi::*;
+use kernel32::*;
+use handle::Handle;
+use iocp::{CompletionPort, CompletionStatus};
+use wait::WaitRegistration;
+
+/// The console input buffer of this process, delivering notifications of
+/// available input through a completion port.
+///
+/// Each time input becomes available a completion status is posted to the
+/// port with the token this was created with and a null `Overlapped`. The
+/// notification is not repeated until input has been read with one of the
+/// `read_*` methods, which each read all input that is available without
+/// blocking and rearm the notification.
+#[derive(Debug)]
+pub struct ConsoleInput {
+    wait: Option<WaitRegistration>,
+    port: CompletionPort,
+    token: usize,
+    handle: HANDLE,
+    mode: DWORD,
+}
+
+unsafe impl Send for ConsoleInput {}
+unsafe impl Sync for ConsoleInput {}
+
+/// An input event read from the console.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConsoleEvent {
+    /// A key was pressed or released.
+    Key {
+        /// Whether the key was pressed, as opposed to released.
+        down: bool,
+        /// The number of times the keystroke is repeated because the key is
+        /// being held down.
+        repeat: u16,
+        /// The virtual-key code of the key.
+        virtual_key: u16,
+        /// The character the keystroke translates to, if any.
+        ///
+        /// This is `None` for keys which don't produce characters, and for
+        /// each half of characters outside the basic multilingual plane.
+        ch: Option<char>,
+        /// The state of the control keys, a combination of the `*_PRESSED`
+        /// and `*_ON` flags.
+        control_keys: u32,
+    },
+    /// The console screen buffer was resized.
+    Resize {
+        /// The new number of columns.
+        columns: u16,
+        /// The new number of rows.
+        rows: u16,
+    },
+}
+
+impl ConsoleInput {
+    /// Opens the console input buffer attached to the standard input of this
+    /// process, posting notifications to `port` with `token`.
+    ///
+    /// The console is configured to report resize events. An error is
+    /// returned if standard input is not a console, for example because it
+    /// has been redirected.
+    pub fn stdin(port: &CompletionPort, token: usize)
+                 -> io::Result<ConsoleInput> {
+        let handle = unsafe { GetStdHandle(STD_INPUT_HANDLE) };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error())
+        }
+        let port = try!(Handle::duplicate(port.as_raw_handle()));
+        let mut mode = 0;
+        try!(::cvt(unsafe { GetConsoleMode(handle, &mut mode) }));
+        try!(::cvt(unsafe {
+            SetConsoleMode(handle, mode | ENABLE_WINDOW_INPUT)
+        }));
+
+        // From here on dropping the input restores the original mode, so it
+        // is also restored if arming the notification fails.
+        let mut input = ConsoleInput {
+            wait: None,
+            port: unsafe { CompletionPort::from_raw_handle(port.into_raw()) },
+            token: token,
+            handle: handle,
+            mode: mode,
+        };
+        try!(input.arm());
+        Ok(input)
+    }
+
+    /// Configures whether the console delivers input unprocessed.
+    ///
+    /// In raw mode characters are not echoed, input is available as soon as
+    /// each key is pressed rather than once a line has been entered, and
+    /// control keys such as Ctrl+C are delivered as input rather than being
+    /// handled by the system.
+    pub fn set_raw(&self, raw: bool) -> io::Result<()> {
+        let cooked = ENABLE_PROCESSED_INPUT | ENABLE_LINE_INPUT |
+                     ENABLE_ECHO_INPUT;
+        let mut mode = 0;
+        try!(::cvt(unsafe { GetConsoleMode(self.handle, &mut mode) }));
+        let mode = if raw { mode & !cooked } else { mode | cooked };
+        ::cvt(unsafe { SetConsoleMode(self.handle, mode) }).map(|_| ())
+    }
+
+    /// Reads the raw input records which are available without blocking,
+    /// returning how many were read into `records`.
+    ///
+    /// If more records are available than fit in `records`, another
+    /// notification is posted immediately.
+    pub fn read_input(&mut self, records: &mut [INPUT_RECORD])
+                      -> io::Result<usize> {
+        let mut avail = 0;
+        try!(::cvt(unsafe {
+            GetNumberOfConsoleInputEvents(self.handle, &mut avail)
+        }));
+        let len = cmp::min(avail as usize, records.len());
+        let mut read = 0;
+        if len > 0 {
+            try!(::cvt(unsafe {
+                ReadConsoleInputW(self.handle, records.as_mut_ptr(),
+                                  len as DWORD, &mut read)
+            }));
+        }
+        try!(self.arm());
+        Ok(read as usize)
+    }
+
+    /// Reads the keyboard and resize events which are available without
+    /// blocking.
+    ///
+    /// Other kinds of input, such as mouse and focus events, are discarded.
+    pub fn read_events(&mut self) -> io::Result<Vec<ConsoleEvent>> {
+        let records = try!(self.read_all());
+        Ok(records.iter().filter_map(decode).collect())
+    }
+
+    /// Reads the text typed into the console which is available without
+    /// blocking, appending it to `buf` encoded as UTF-8.
+    ///
+    /// This is the equivalent of reading bytes from standard input, and
+    /// returns the number of bytes appended. Characters which can't be
+    /// decoded are replaced with U+FFFD.
+    pub fn read_bytes(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let records = try!(self.read_all());
+        let mut units = Vec::new();
+        for record in records.iter().filter(|r| r.EventType == KEY_EVENT) {
+            let key = unsafe { record.KeyEvent() };
+            if key.bKeyDown != 0 && key.UnicodeChar != 0 {
+                for _ in 0..cmp::max(key.wRepeatCount, 1) {
+                    units.push(key.UnicodeChar);
+                }
+            }
+        }
+        let start = buf.len();
+        for ch in char::decode_utf16(units.into_iter()) {
+            let ch = ch.unwrap_or(char::REPLACEMENT_CHARACTER);
+            let mut tmp = [0; 4];
+            buf.extend_from_slice(ch.encode_utf8(&mut tmp).as_bytes());
+        }
+        Ok(buf.len() - start)
+    }
+
+    fn read_all(&mut self) -> io::Result<Vec<INPUT_RECORD>> {
+        let mut avail = 0;
+        try!(::cvt(unsafe {
+            GetNumberOfConsoleInputEvents(self.handle, &mut avail)
+        }));
+        let mut records = vec![unsafe { mem::zeroed() }; avail as usize];
+        let n = try!(self.read_input(&mut records));
+        records.truncate(n);
+        Ok(records)
+    }
+
+    fn arm(&mut self) -> io::Result<()> {
+        // Drop the previous registration first so at most one notification
+        // is outstanding at a time.
+        self.wait = None;
+        let status = CompletionStatus::new(0, self.token, 0 as *mut _);
+        self.wait = Some(try!(WaitRegistration::new(&self.port, self.handle,
+                                                    status, true)));
+        Ok(())
+    }
+}
+
+fn decode(record: &INPUT_RECORD) -> Option<ConsoleEvent> {
+    match record.EventType {
+        KEY_EVENT => {
+            let key = unsafe { record.KeyEvent() };
+            let ch = match key.UnicodeChar {
+                0 => None,
+                c => char::from_u32(c as u32),
+            };
+            Some(ConsoleEvent::Key {
+                down: key.bKeyDown != 0,
+                repeat: key.wRepeatCount,
+                virtual_key: key.wVirtualKeyCode,
+                ch: ch,
+                control_keys: key.dwControlKeyState,
+            })
+        }
+        WINDOW_BUFFER_SIZE_EVENT => {
+            let size = unsafe { record.WindowBufferSizeEvent().dwSize };
+            Some(ConsoleEvent::Resize {
+                columns: size.X as u16,
+                rows: size.Y as u16,
+            })
+        }
+        _ => None,
+    }
+}
+
+impl Drop for ConsoleInput {
+    fn drop(&mut self) {
+        self.wait = None;
+        unsafe { SetConsoleMode(self.handle, self.mode) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use winapi::*;
+
+    use super::{decode, ConsoleEvent};
+
+    #[test]
+    fn decode_records() {
+        let mut key: INPUT_RECORD = unsafe { mem::zeroed() };
+        key.EventType = KEY_EVENT;
+        unsafe {
+            let k = key.KeyEvent_mut();
+            k.bKeyDown = TRUE;
+            k.wRepeatCount = 1;
+            k.wVirtualKeyCode = 0x41;
+            k.UnicodeChar = 'a' as u16;
+        }
+        assert_eq!(decode(&key), Some(ConsoleEvent::Key {
+            down: true,
+            repeat: 1,
+            virtual_key: 0x41,
+            ch: Some('a'),
+            control_keys: 0,
+        }));
+
+        let mut resize: INPUT_RECORD = unsafe { mem::zeroed() };
+        resize.EventType = WINDOW_BUFFER_SIZE_EVENT;
+        unsafe {
+            resize.WindowBufferSizeEvent_mut().dwSize =
+                COORD { X: 80, Y: 25 };
+        }
+        assert_eq!(decode(&resize),
+                   Some(ConsoleEvent::Resize { columns: 80, rows: 25 }));
+
+        let mut focus: INPUT_RECORD = unsafe { mem::zeroed() };
+        focus.EventType = FOCUS_EVENT;
+        assert_eq!(decode(&focus), None);
+    }
+}
@@ -0,0 +1,409 @@
+//! Asynchronous DNS record queries
+//!
+//! `resolve::Resolution` only resolves host names into addresses. Service
+//! discovery also needs other kinds of records, such as `SRV`, `TXT` and
+//! `MX` records, which a `Query` looks up in the background through
+//! `DnsQueryEx`, posting a completion status to a port when it finishes.
+
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::os::windows::io::*;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use winapi::*;
+
+use handle::Handle;
+use iocp::{CompletionPort, CompletionStatus};
+
+/// The record type of an IPv4 address (`DNS_TYPE_A`).
+pub const TYPE_A: u16 = 1;
+/// The record type of an authoritative name server (`DNS_TYPE_NS`).
+pub const TYPE_NS: u16 = 2;
+/// The record type of a canonical name (`DNS_TYPE_CNAME`).
+pub const TYPE_CNAME: u16 = 5;
+/// The record type of a domain name pointer (`DNS_TYPE_PTR`).
+pub const TYPE_PTR: u16 = 12;
+/// The record type of a mail exchange (`DNS_TYPE_MX`).
+pub const TYPE_MX: u16 = 15;
+/// The record type of text strings (`DNS_TYPE_TEXT`).
+pub const TYPE_TXT: u16 = 16;
+/// The record type of an IPv6 address (`DNS_TYPE_AAAA`).
+pub const TYPE_AAAA: u16 = 28;
+/// The record type of a service location (`DNS_TYPE_SRV`).
+pub const TYPE_SRV: u16 = 33;
+
+const DNS_QUERY_REQUEST_VERSION1: ULONG = 1;
+const DNS_QUERY_STANDARD: ULONG64 = 0;
+const DNS_REQUEST_PENDING: LONG = 9506;
+const DnsFreeRecordList: c_int = 1;
+
+type DNS_QUERY_COMPLETION_ROUTINE =
+    unsafe extern "system" fn(PVOID, *mut DNS_QUERY_RESULT);
+
+#[repr(C)]
+struct DNS_QUERY_REQUEST {
+    Version: ULONG,
+    QueryName: PCWSTR,
+    QueryType: WORD,
+    QueryOptions: ULONG64,
+    pDnsServerList: PVOID,
+    InterfaceIndex: ULONG,
+    pQueryCompletionCallback: Option<DNS_QUERY_COMPLETION_ROUTINE>,
+    pQueryContext: PVOID,
+}
+
+#[repr(C)]
+struct DNS_QUERY_RESULT {
+    Version: ULONG,
+    QueryStatus: LONG,
+    QueryOptions: ULONG64,
+    pQueryRecords: *mut DNS_RECORD,
+    Reserved: PVOID,
+}
+
+#[repr(C)]
+struct DNS_QUERY_CANCEL {
+    Reserved: [u64; 4],
+}
+
+// The fixed part of a `DNS_RECORDW`, which is followed by the data of the
+// record.
+#[repr(C)]
+struct DNS_RECORD {
+    pNext: *mut DNS_RECORD,
+    pName: PWSTR,
+    wType: WORD,
+    wDataLength: WORD,
+    Flags: DWORD,
+    dwTtl: DWORD,
+    dwReserved: DWORD,
+    Data: [PVOID; 0],
+}
+
+#[repr(C)]
+struct DNS_MX_DATA {
+    pNameExchange: PWSTR,
+    wPreference: WORD,
+}
+
+#[repr(C)]
+struct DNS_TXT_DATA {
+    dwStringCount: DWORD,
+    pStringArray: [PWSTR; 1],
+}
+
+#[repr(C)]
+struct DNS_SRV_DATA {
+    pNameTarget: PWSTR,
+    wPriority: WORD,
+    wWeight: WORD,
+    wPort: WORD,
+}
+
+#[link(name = "dnsapi")]
+extern "system" {
+    fn DnsQueryEx(pQueryRequest: *mut DNS_QUERY_REQUEST,
+                  pQueryResults: *mut DNS_QUERY_RESULT,
+                  pCancelHandle: *mut DNS_QUERY_CANCEL) -> LONG;
+    fn DnsCancelQuery(pCancelHandle: *mut DNS_QUERY_CANCEL) -> LONG;
+    fn DnsFree(pData: PVOID, FreeType: c_int);
+}
+
+/// An asynchronous query for the DNS records of a name (via `DnsQueryEx`).
+///
+/// The query starts when it is created and posts the given completion status
+/// to a port once it finishes, after which the records are available through
+/// `result`. The status is posted however the query ends, even if the query
+/// has been dropped by then.
+#[derive(Debug)]
+pub struct Query {
+    inner: *mut Inner,
+}
+
+unsafe impl Send for Query {}
+unsafe impl Sync for Query {}
+
+// State shared between a `Query` and the completion routine of its lookup,
+// freed by whichever of the two releases it last.
+struct Inner {
+    result: DNS_QUERY_RESULT,
+    cancel: DNS_QUERY_CANCEL,
+    done: AtomicBool,
+    refs: AtomicUsize,
+    port: CompletionPort,
+    status: CompletionStatus,
+}
+
+/// A DNS record returned by a `Query`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    name: String,
+    ttl: u32,
+    data: RecordData,
+}
+
+/// The data of a DNS record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RecordData {
+    /// An IPv4 address (`TYPE_A`).
+    A(Ipv4Addr),
+    /// An IPv6 address (`TYPE_AAAA`).
+    Aaaa(Ipv6Addr),
+    /// A canonical name (`TYPE_CNAME`).
+    Cname(String),
+    /// An authoritative name server (`TYPE_NS`).
+    Ns(String),
+    /// A domain name pointer (`TYPE_PTR`).
+    Ptr(String),
+    /// A mail exchange (`TYPE_MX`).
+    Mx {
+        /// The preference of this exchange, where lower values are
+        /// preferred.
+        preference: u16,
+        /// The host name of the exchange.
+        exchange: String,
+    },
+    /// Text strings (`TYPE_TXT`).
+    Txt(Vec<String>),
+    /// A service location (`TYPE_SRV`).
+    Srv {
+        /// The priority of this target, where lower values are preferred.
+        priority: u16,
+        /// The relative weight of targets with the same priority.
+        weight: u16,
+        /// The port the service is found on.
+        port: u16,
+        /// The host name of the target.
+        target: String,
+    },
+    /// A record of any other type, which is given.
+    Other(u16),
+}
+
+impl Query {
+    /// Starts querying for the records of type `ty` of `name`, such as
+    /// `TYPE_SRV`, posting `status` to `port` once the query finishes.
+    pub fn new(name: &str, ty: u16, port: &CompletionPort,
+               status: CompletionStatus) -> io::Result<Query> {
+        // The query may outlive the `CompletionPort` it was started with, so
+        // hold on to our own reference to the port.
+        let port = try!(Handle::duplicate(port.as_raw_handle()));
+        let inner = Box::into_raw(Box::new(Inner {
+            result: unsafe { mem::zeroed() },
+            cancel: unsafe { mem::zeroed() },
+            done: AtomicBool::new(false),
+            refs: AtomicUsize::new(2),
+            port: unsafe { CompletionPort::from_raw_handle(port.into_raw()) },
+            status: status,
+        }));
+        let name = name.encode_utf16().chain(Some(0)).collect::<Vec<u16>>();
+        unsafe {
+            (*inner).result.Version = DNS_QUERY_REQUEST_VERSION1;
+            let mut request = DNS_QUERY_REQUEST {
+                Version: DNS_QUERY_REQUEST_VERSION1,
+                QueryName: name.as_ptr(),
+                QueryType: ty,
+                QueryOptions: DNS_QUERY_STANDARD,
+                pDnsServerList: 0 as *mut _,
+                InterfaceIndex: 0,
+                pQueryCompletionCallback: Some(callback),
+                pQueryContext: inner as PVOID,
+            };
+            let r = DnsQueryEx(&mut request, &mut (*inner).result,
+                               &mut (*inner).cancel);
+            match r {
+                DNS_REQUEST_PENDING => {}
+                // The completion routine isn't called for a query which
+                // finished immediately, such as one answered from the cache.
+                0 => complete(inner),
+                e => {
+                    drop(Box::from_raw(inner));
+                    return Err(io::Error::from_raw_os_error(e))
+                }
+            }
+        }
+        Ok(Query { inner: inner })
+    }
+
+    /// Cancels the query if it hasn't finished yet (via `DnsCancelQuery`).
+    ///
+    /// The status is still posted once the query has been cancelled, after
+    /// which `result` returns an error. Nothing happens if the query has
+    /// already finished.
+    pub fn cancel(&self) -> io::Result<()> {
+        let inner = unsafe { &*self.inner };
+        if inner.done.load(Ordering::Acquire) {
+            return Ok(())
+        }
+        let cancel = &inner.cancel as *const _ as *mut _;
+        match unsafe { DnsCancelQuery(cancel) } {
+            0 => Ok(()),
+            e => Err(io::Error::from_raw_os_error(e)),
+        }
+    }
+
+    /// Returns the records the query found.
+    ///
+    /// The records of other types which were part of the answer, such as the
+    /// `TYPE_CNAME` records leading to the name which has records of the
+    /// requested type, are included as well.
+    ///
+    /// If the query hasn't finished yet an error with the code
+    /// `ERROR_IO_INCOMPLETE` is returned, and if it failed its error is
+    /// returned, such as `DNS_ERROR_RCODE_NAME_ERROR` (9003) if the name
+    /// doesn't exist.
+    pub fn result(&self) -> io::Result<Vec<Record>> {
+        let inner = unsafe { &*self.inner };
+        if !inner.done.load(Ordering::Acquire) {
+            return Err(io::Error::from_raw_os_error(ERROR_IO_INCOMPLETE as i32))
+        }
+        if inner.result.QueryStatus != 0 {
+            return Err(io::Error::from_raw_os_error(inner.result.QueryStatus))
+        }
+        let mut records = Vec::new();
+        let mut record = inner.result.pQueryRecords;
+        while !record.is_null() {
+            unsafe {
+                records.push(Record::from_raw(&*record));
+                record = (*record).pNext;
+            }
+        }
+        Ok(records)
+    }
+}
+
+unsafe extern "system" fn callback(context: PVOID,
+                                   results: *mut DNS_QUERY_RESULT) {
+    let inner = context as *mut Inner;
+    // The results are normally delivered in the `DNS_QUERY_RESULT` given to
+    // `DnsQueryEx`, but are moved there in case they weren't.
+    if results != &mut (*inner).result as *mut _ {
+        ptr::copy(results, &mut (*inner).result, 1);
+    }
+    complete(inner)
+}
+
+unsafe fn complete(inner: *mut Inner) {
+    (*inner).done.store(true, Ordering::Release);
+    let _ = (*inner).port.post((*inner).status);
+    release(inner);
+}
+
+unsafe fn release(inner: *mut Inner) {
+    if (*inner).refs.fetch_sub(1, Ordering::AcqRel) != 1 {
+        return
+    }
+    let inner = Box::from_raw(inner);
+    if !inner.result.pQueryRecords.is_null() {
+        DnsFree(inner.result.pQueryRecords as PVOID, DnsFreeRecordList);
+    }
+}
+
+impl Drop for Query {
+    fn drop(&mut self) {
+        unsafe { release(self.inner) }
+    }
+}
+
+impl Record {
+    unsafe fn from_raw(raw: &DNS_RECORD) -> Record {
+        let data = raw.Data.as_ptr();
+        let len = raw.wDataLength as usize;
+        let data = match raw.wType {
+            TYPE_A if len >= 4 => {
+                let b = slice::from_raw_parts(data as *const u8, 4);
+                RecordData::A(Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+            }
+            TYPE_AAAA if len >= 16 => {
+                let b = slice::from_raw_parts(data as *const u8, 16);
+                let mut s = [0u16; 8];
+                for (i, segment) in s.iter_mut().enumerate() {
+                    *segment = ((b[i * 2] as u16) << 8) | b[i * 2 + 1] as u16;
+                }
+                RecordData::Aaaa(Ipv6Addr::new(s[0], s[1], s[2], s[3],
+                                               s[4], s[5], s[6], s[7]))
+            }
+            TYPE_CNAME => RecordData::Cname(wide(*(data as *const PWSTR))),
+            TYPE_NS => RecordData::Ns(wide(*(data as *const PWSTR))),
+            TYPE_PTR => RecordData::Ptr(wide(*(data as *const PWSTR))),
+            TYPE_MX => {
+                let mx = &*(data as *const DNS_MX_DATA);
+                RecordData::Mx {
+                    preference: mx.wPreference,
+                    exchange: wide(mx.pNameExchange),
+                }
+            }
+            TYPE_TXT => {
+                let txt = &*(data as *const DNS_TXT_DATA);
+                let strings = slice::from_raw_parts(txt.pStringArray.as_ptr(),
+                                                    txt.dwStringCount as usize);
+                RecordData::Txt(strings.iter().map(|s| wide(*s)).collect())
+            }
+            TYPE_SRV => {
+                let srv = &*(data as *const DNS_SRV_DATA);
+                RecordData::Srv {
+                    priority: srv.wPriority,
+                    weight: srv.wWeight,
+                    port: srv.wPort,
+                    target: wide(srv.pNameTarget),
+                }
+            }
+            ty => RecordData::Other(ty),
+        };
+        Record {
+            name: wide(raw.pName),
+            ttl: raw.dwTtl,
+            data: data,
+        }
+    }
+
+    /// Returns the name this record belongs to.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the number of seconds this record may be cached for.
+    pub fn ttl(&self) -> u32 {
+        self.ttl
+    }
+
+    /// Returns the data of this record.
+    pub fn data(&self) -> &RecordData {
+        &self.data
+    }
+}
+
+unsafe fn wide(ptr: PCWSTR) -> String {
+    if ptr.is_null() {
+        return String::new()
+    }
+    let mut len = 0;
+    while *ptr.offset(len) != 0 {
+        len += 1;
+    }
+    String::from_utf16_lossy(slice::from_raw_parts(ptr, len as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use iocp::{CompletionPort, CompletionStatus};
+    use super::{Query, RecordData, TYPE_A};
+
+    #[test]
+    fn localhost() {
+        let cp = t!(CompletionPort::new(1));
+        let status = CompletionStatus::new(0, 3, 0 as *mut _);
+        let q = t!(Query::new("localhost", TYPE_A, &cp, status));
+        assert_eq!(t!(cp.get(Some(10_000))).token(), 3);
+        let records = t!(q.result());
+        let localhost = RecordData::A(Ipv4Addr::new(127, 0, 0, 1));
+        assert!(records.iter().any(|r| *r.data() == localhost),
+                "{:?}", records);
+        t!(q.cancel());
+    }
+}
@@ -0,0 +1,270 @@
+//! Drivers for overlapped operations spanning multiple submissions
+//!
+//! Overlapped reads and writes may complete having transferred fewer bytes
+//! than requested. The types in this module track how much of a buffer has
+//! been transferred and re-issue the operation for the remainder on each
+//! completion, so that callers only observe the completion of the logical
+//! operation as a whole.
+
+use std::io;
+
+use Overlapped;
+
+/// Drives overlapped reads until a buffer has been filled completely.
+///
+/// The operation is started with `submit`, and each completion notification
+/// for it must be passed to `complete`, which issues the next read if needed.
+#[derive(Debug)]
+pub struct ReadExact<B> {
+    buf: B,
+    len: usize,
+    pos: usize,
+    offset: Option<u64>,
+}
+
+/// Drives overlapped writes until a buffer has been written completely.
+///
+/// The operation is started with `submit`, and each completion notification
+/// for it must be passed to `complete`, which issues the next write if needed.
+#[derive(Debug)]
+pub struct WriteAll<B> {
+    buf: B,
+    pos: usize,
+    offset: Option<u64>,
+}
+
+impl<B: AsMut<[u8]>> ReadExact<B> {
+    /// Creates a new driver which will fill `buf`.
+    pub fn new(mut buf: B) -> ReadExact<B> {
+        let len = buf.as_mut().len();
+        ReadExact { buf: buf, len: len, pos: 0, offset: None }
+    }
+
+    /// Configures the file offset the read starts at.
+    ///
+    /// The offset of the `Overlapped` is advanced along with the buffer on
+    /// each submission. This only has meaning for handles to seekable files.
+    pub fn offset(mut self, offset: u64) -> ReadExact<B> {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Returns the number of bytes read so far.
+    pub fn filled(&self) -> usize {
+        self.pos
+    }
+
+    /// Returns whether the buffer has been filled completely.
+    pub fn is_done(&self) -> bool {
+        self.pos >= self.len
+    }
+
+    /// Consumes this driver, returning the buffer.
+    pub fn into_inner(self) -> B {
+        self.buf
+    }
+
+    /// Issues a read for the part of the buffer that has not yet been filled.
+    ///
+    /// The function `f` is called with the unfilled part of the buffer and
+    /// must issue an overlapped read into it, such as with
+    /// `TcpStreamExt::read_overlapped`, returning its result.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that this driver
+    /// and the `overlapped` pointer are valid until the end of the I/O
+    /// operation, and neither may be moved in the meantime.
+    pub unsafe fn submit<F>(&mut self, overlapped: &mut Overlapped, f: F)
+                            -> io::Result<bool>
+        where F: FnOnce(&mut [u8], &mut Overlapped) -> io::Result<bool>
+    {
+        if let Some(offset) = self.offset {
+            overlapped.set_offset(offset + self.pos as u64);
+        }
+        f(&mut self.buf.as_mut()[self.pos..], overlapped)
+    }
+
+    /// Records the completion of a read issued through `submit`, issuing a
+    /// read for the rest of the buffer if it is not yet full.
+    ///
+    /// Returns `Ok(true)` once the buffer has been filled, in which case no
+    /// further read was issued. Returns `Ok(false)` if another read was
+    /// issued, whose completion must again be passed to this function. A
+    /// read which completes with zero bytes before the buffer is full results
+    /// in an error of kind `UnexpectedEof`.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as `submit`.
+    pub unsafe fn complete<F>(&mut self,
+                              bytes: u32,
+                              overlapped: &mut Overlapped,
+                              f: F) -> io::Result<bool>
+        where F: FnOnce(&mut [u8], &mut Overlapped) -> io::Result<bool>
+    {
+        if bytes == 0 && !self.is_done() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "failed to fill whole buffer"))
+        }
+        self.pos += bytes as usize;
+        if self.is_done() {
+            return Ok(true)
+        }
+        try!(self.submit(overlapped, f));
+        Ok(false)
+    }
+}
+
+impl<B: AsRef<[u8]>> WriteAll<B> {
+    /// Creates a new driver which will write all of `buf`.
+    pub fn new(buf: B) -> WriteAll<B> {
+        WriteAll { buf: buf, pos: 0, offset: None }
+    }
+
+    /// Configures the file offset the write starts at.
+    ///
+    /// The offset of the `Overlapped` is advanced along with the buffer on
+    /// each submission. This only has meaning for handles to seekable files.
+    pub fn offset(mut self, offset: u64) -> WriteAll<B> {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Returns the number of bytes written so far.
+    pub fn written(&self) -> usize {
+        self.pos
+    }
+
+    /// Returns whether the buffer has been written completely.
+    pub fn is_done(&self) -> bool {
+        self.pos >= self.buf.as_ref().len()
+    }
+
+    /// Consumes this driver, returning the buffer.
+    pub fn into_inner(self) -> B {
+        self.buf
+    }
+
+    /// Issues a write for the part of the buffer that has not yet been
+    /// written.
+    ///
+    /// The function `f` is called with the unwritten part of the buffer and
+    /// must issue an overlapped write of it, such as with
+    /// `TcpStreamExt::write_overlapped`, returning its result.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that this driver
+    /// and the `overlapped` pointer are valid until the end of the I/O
+    /// operation, and neither may be moved in the meantime.
+    pub unsafe fn submit<F>(&mut self, overlapped: &mut Overlapped, f: F)
+                            -> io::Result<bool>
+        where F: FnOnce(&[u8], &mut Overlapped) -> io::Result<bool>
+    {
+        if let Some(offset) = self.offset {
+            overlapped.set_offset(offset + self.pos as u64);
+        }
+        f(&self.buf.as_ref()[self.pos..], overlapped)
+    }
+
+    /// Records the completion of a write issued through `submit`, issuing a
+    /// write for the rest of the buffer if it has not all been written.
+    ///
+    /// Returns `Ok(true)` once the buffer has been written, in which case no
+    /// further write was issued. Returns `Ok(false)` if another write was
+    /// issued, whose completion must again be passed to this function. A
+    /// write which completes with zero bytes before the buffer has been
+    /// written results in an error of kind `WriteZero`.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as `submit`.
+    pub unsafe fn complete<F>(&mut self,
+                              bytes: u32,
+                              overlapped: &mut Overlapped,
+                              f: F) -> io::Result<bool>
+        where F: FnOnce(&[u8], &mut Overlapped) -> io::Result<bool>
+    {
+        if bytes == 0 && !self.is_done() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                      "failed to write whole buffer"))
+        }
+        self.pos += bytes as usize;
+        if self.is_done() {
+            return Ok(true)
+        }
+        try!(self.submit(overlapped, f));
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use iocp::CompletionPort;
+    use net::TcpStreamExt;
+    use Overlapped;
+    use super::{ReadExact, WriteAll};
+
+    #[test]
+    fn read_exact() {
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let a = t!(TcpStream::connect(t!(l.local_addr())));
+        let mut b = t!(l.accept()).0;
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &a));
+
+        let t = thread::spawn(move || {
+            for i in 0..4u8 {
+                t!(b.write_all(&[i]));
+                t!(b.flush());
+                thread::sleep(::std::time::Duration::from_millis(10));
+            }
+        });
+
+        let mut read = ReadExact::new(vec![0; 4]);
+        let mut over = Overlapped::zero();
+        unsafe {
+            t!(read.submit(&mut over, |buf, o| a.read_overlapped(buf, o)));
+            loop {
+                let status = t!(cp.get(None));
+                let done = t!(read.complete(status.bytes_transferred(),
+                                            &mut over,
+                                            |buf, o| a.read_overlapped(buf,
+                                                                       o)));
+                if done {
+                    break
+                }
+            }
+        }
+        assert_eq!(read.into_inner(), [0, 1, 2, 3]);
+        t!(t.join());
+    }
+
+    #[test]
+    fn write_all() {
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let a = t!(TcpStream::connect(t!(l.local_addr())));
+        let mut b = t!(l.accept()).0;
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &a));
+
+        let mut write = WriteAll::new(vec![1; 1024]);
+        let mut over = Overlapped::zero();
+        unsafe {
+            t!(write.submit(&mut over, |buf, o| a.write_overlapped(buf, o)));
+            let status = t!(cp.get(None));
+            assert!(t!(write.complete(status.bytes_transferred(), &mut over,
+                                      |buf, o| a.write_overlapped(buf, o))));
+        }
+        assert_eq!(write.written(), 1024);
+        drop(a);
+        let mut v = Vec::new();
+        t!(b.read_to_end(&mut v));
+        assert_eq!(v, vec![1; 1024]);
+    }
+}
@@ -0,0 +1,224 @@
+//! Fault injection for overlapped operations
+//!
+//! This module is only available when the `fault-injection` feature of this
+//! crate is enabled. It allows tests to force the overlapped operations issued
+//! through this crate down their rarely exercised paths, such as synchronous
+//! failures, operations which unexpectedly pend, short transfers, and
+//! cancellation racing with completion.
+//!
+//! Faults are queued process-wide with `inject` and each one is consumed by
+//! the next operation of the matching kind. Operations for which no fault is
+//! queued behave normally.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use winapi::*;
+use kernel32::*;
+
+/// The kind of overlapped operation a fault applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    /// Reads and receives, such as `read_overlapped` and
+    /// `recv_from_overlapped`.
+    Read,
+    /// Writes and sends, such as `write_overlapped` and `send_to_overlapped`.
+    Write,
+    /// Socket and named pipe connections.
+    Connect,
+    /// Socket accepts.
+    Accept,
+}
+
+/// A fault which can be injected into an overlapped operation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The operation is issued as usual, but is reported as pending even if
+    /// it completed synchronously.
+    ///
+    /// This is only done for operations whose completion notification is
+    /// still posted when they complete synchronously, so correct callers
+    /// must not observe any difference. Named pipe connections to which a
+    /// client had already connected, and operations on handles which skip
+    /// the completion port on success, are reported as usual instead.
+    Pending,
+    /// The operation is not issued and instead fails synchronously with the
+    /// given OS error code.
+    Error(i32),
+    /// The operation is issued with its buffer truncated to at most the given
+    /// number of bytes, resulting in a short read or write.
+    Short(usize),
+    /// The calling thread sleeps for the given duration before the operation
+    /// is issued, delaying its completion.
+    Delay(Duration),
+    /// The operation is issued and then immediately cancelled with
+    /// `CancelIoEx`, racing the cancellation with the operation's completion.
+    Cancel,
+}
+
+static FAULTS: Mutex<VecDeque<(Operation, Fault)>> =
+    Mutex::new(VecDeque::new());
+
+// Handles on which `FILE_SKIP_COMPLETION_PORT_ON_SUCCESS` has been set
+// through this crate, whose synchronous completions post no notification.
+static SKIPPING: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+/// Queues a fault to be injected into the next operation of the kind `op`.
+///
+/// Faults for the same kind of operation are consumed in the order they were
+/// injected.
+pub fn inject(op: Operation, fault: Fault) {
+    faults().push_back((op, fault));
+}
+
+/// Removes all queued faults which have not yet been consumed.
+pub fn clear() {
+    faults().clear();
+}
+
+/// Returns the number of queued faults which have not yet been consumed.
+pub fn pending() -> usize {
+    faults().len()
+}
+
+fn faults() -> ::std::sync::MutexGuard<'static, VecDeque<(Operation, Fault)>> {
+    FAULTS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Records that synchronous completions of operations on `handle` no longer
+// post a notification to its completion port.
+pub(crate) fn skip_port(handle: HANDLE) {
+    let mut skipping = SKIPPING.lock().unwrap_or_else(|e| e.into_inner());
+    if !skipping.contains(&(handle as usize)) {
+        skipping.push(handle as usize);
+    }
+}
+
+fn skips_port(handle: HANDLE) -> bool {
+    let skipping = SKIPPING.lock().unwrap_or_else(|e| e.into_inner());
+    skipping.contains(&(handle as usize))
+}
+
+fn take(op: Operation) -> Option<Fault> {
+    let mut faults = faults();
+    let pos = match faults.iter().position(|&(o, _)| o == op) {
+        Some(pos) => pos,
+        None => return None,
+    };
+    faults.remove(pos).map(|(_, fault)| fault)
+}
+
+pub(crate) unsafe fn submit<F>(op: Operation,
+                               len: usize,
+                               handle: HANDLE,
+                               overlapped: LPOVERLAPPED,
+                               f: F) -> io::Result<bool>
+    where F: FnOnce(usize) -> io::Result<bool>
+{
+    match take(op) {
+        None => f(len),
+        Some(Fault::Pending) => {
+            let ret = f(len);
+            if skips_port(handle) {
+                ret
+            } else {
+                ret.map(|_| false)
+            }
+        }
+        Some(Fault::Error(code)) => Err(io::Error::from_raw_os_error(code)),
+        Some(Fault::Short(n)) => f(cmp::min(n, len)),
+        Some(Fault::Delay(dur)) => {
+            thread::sleep(dur);
+            f(len)
+        }
+        Some(Fault::Cancel) => {
+            let ret = f(len);
+            if ret.is_ok() {
+                CancelIoEx(handle, overlapped);
+            }
+            ret
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::process;
+    use std::sync::Mutex;
+    use std::thread;
+
+    use winapi::*;
+
+    use iocp::CompletionPort;
+    use net::TcpStreamExt;
+    use pipe::NamedPipe;
+    use Overlapped;
+    use super::{inject, clear, pending, Fault, Operation};
+
+    // Faults are process-wide, so keep the tests in this module from
+    // stealing each other's faults.
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn error() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (a, _b) = ::tcp_pair();
+        inject(Operation::Read, Fault::Error(WSAECONNRESET as i32));
+        let mut buf = [0; 10];
+        let mut over = Overlapped::zero();
+        let err = unsafe {
+            a.read_overlapped(&mut buf, &mut over).unwrap_err()
+        };
+        assert_eq!(err.raw_os_error(), Some(WSAECONNRESET as i32));
+        assert_eq!(pending(), 0);
+    }
+
+    #[test]
+    fn short_write() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (a, mut b) = ::tcp_pair();
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &a));
+
+        inject(Operation::Write, Fault::Short(1));
+        inject(Operation::Write, Fault::Pending);
+        let mut over = Overlapped::zero();
+        unsafe {
+            t!(a.write_overlapped(&[1, 2, 3], &mut over));
+        }
+        assert_eq!(t!(cp.get(None)).bytes_transferred(), 1);
+        assert!(!unsafe { t!(a.write_overlapped(&[2], &mut over)) });
+        assert_eq!(t!(cp.get(None)).bytes_transferred(), 1);
+
+        let t = thread::spawn(move || {
+            let mut buf = [0; 2];
+            t!(b.read_exact(&mut buf));
+            buf
+        });
+        assert_eq!(t!(t.join()), [1, 2]);
+        clear();
+    }
+
+    #[test]
+    fn pending_pipe_connected() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let name = format!(r"\\.\pipe\miow-fault-{}", process::id());
+        let a = t!(NamedPipe::new(&name));
+        let _b = t!(File::open(&name));
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_handle(1, &a));
+
+        // No completion is posted for a client which already connected, so
+        // this mustn't be reported as pending.
+        inject(Operation::Connect, Fault::Pending);
+        let mut over = Overlapped::zero();
+        assert!(unsafe { t!(a.connect_overlapped(&mut over)) });
+        assert_eq!(pending(), 0);
+    }
+}
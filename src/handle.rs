@@ -1,7 +1,9 @@
 use std::io;
+use std::mem::MaybeUninit;
 
 use winapi::*;
 use kernel32::*;
+use fault::Operation;
 
 #[derive(Debug)]
 pub struct Handle(HANDLE);
@@ -16,6 +18,16 @@ impl Handle {
 
     pub fn raw(&self) -> HANDLE { self.0 }
 
+    pub fn duplicate(handle: HANDLE) -> io::Result<Handle> {
+        let mut ret = 0 as HANDLE;
+        try!(::cvt(unsafe {
+            let cur = GetCurrentProcess();
+            DuplicateHandle(cur, handle, cur, &mut ret, 0, FALSE,
+                            DUPLICATE_SAME_ACCESS)
+        }));
+        Ok(Handle::new(ret))
+    }
+
     pub fn into_raw(self) -> HANDLE {
         use std::mem;
 
@@ -24,11 +36,14 @@ impl Handle {
         ret
     }
 
+    // `ReadFile` and `WriteFile` take the length of their buffer as a
+    // `DWORD`, so only the first `u32::MAX` bytes of a larger buffer are
+    // transferred, which callers see as a short read or write.
     pub fn write(&self, buf: &[u8]) -> io::Result<usize> {
         let mut bytes = 0;
         try!(::cvt(unsafe {
             WriteFile(self.0, buf.as_ptr() as *const _,
-                      buf.len() as DWORD, &mut bytes, 0 as *mut _)
+                      ::clamp_len(buf.len()), &mut bytes, 0 as *mut _)
         }));
         Ok(bytes as usize)
     }
@@ -37,7 +52,7 @@ impl Handle {
         let mut bytes = 0;
         try!(::cvt(unsafe {
             ReadFile(self.0, buf.as_mut_ptr() as *mut _,
-                     buf.len() as DWORD, &mut bytes, 0 as *mut _)
+                     ::clamp_len(buf.len()), &mut bytes, 0 as *mut _)
         }));
         Ok(bytes as usize)
     }
@@ -45,9 +60,36 @@ impl Handle {
     pub unsafe fn read_overlapped(&self, buf: &mut [u8],
                                   overlapped: *mut OVERLAPPED)
                                   -> io::Result<bool> {
+        read_overlapped(self.0, buf, overlapped)
+    }
+
+    pub unsafe fn read_overlapped_uninit(&self, buf: &mut [MaybeUninit<u8>],
+                                         overlapped: *mut OVERLAPPED)
+                                         -> io::Result<bool> {
+        read_overlapped_uninit(self.0, buf, overlapped)
+    }
+
+    pub unsafe fn write_overlapped(&self, buf: &[u8],
+                                   overlapped: *mut OVERLAPPED)
+                                   -> io::Result<bool> {
+        write_overlapped(self.0, buf, overlapped)
+    }
+}
+
+pub unsafe fn read_overlapped(handle: HANDLE, buf: &mut [u8],
+                              overlapped: *mut OVERLAPPED)
+                              -> io::Result<bool> {
+    read_overlapped_uninit(handle, ::uninit(buf), overlapped)
+}
+
+pub unsafe fn read_overlapped_uninit(handle: HANDLE,
+                                     buf: &mut [MaybeUninit<u8>],
+                                     overlapped: *mut OVERLAPPED)
+                                     -> io::Result<bool> {
+    ::fault::submit(Operation::Read, buf.len(), handle, overlapped, |len| {
         let res = ::cvt({
-            ReadFile(self.0, buf.as_mut_ptr() as *mut _,
-                     buf.len() as DWORD, 0 as *mut _, overlapped)
+            ReadFile(handle, buf.as_mut_ptr() as *mut _,
+                     ::clamp_len(len), 0 as *mut _, overlapped)
         });
         match res {
             Ok(_) => Ok(true),
@@ -55,14 +97,16 @@ impl Handle {
                 => Ok(false),
             Err(e) => Err(e),
         }
-    }
+    })
+}
 
-    pub unsafe fn write_overlapped(&self, buf: &[u8],
-                                   overlapped: *mut OVERLAPPED)
-                                   -> io::Result<bool> {
+pub unsafe fn write_overlapped(handle: HANDLE, buf: &[u8],
+                               overlapped: *mut OVERLAPPED)
+                               -> io::Result<bool> {
+    ::fault::submit(Operation::Write, buf.len(), handle, overlapped, |len| {
         let res = ::cvt({
-            WriteFile(self.0, buf.as_ptr() as *const _,
-                      buf.len() as DWORD, 0 as *mut _, overlapped)
+            WriteFile(handle, buf.as_ptr() as *const _,
+                      ::clamp_len(len), 0 as *mut _, overlapped)
         });
         match res {
             Ok(_) => Ok(true),
@@ -70,7 +114,11 @@ impl Handle {
                 => Ok(false),
             Err(e) => Err(e),
         }
-    }
+    })
+}
+
+pub fn cancel(handle: HANDLE, overlapped: *mut OVERLAPPED) -> io::Result<()> {
+    ::cvt(unsafe { CancelIoEx(handle, overlapped) }).map(|_| ())
 }
 
 impl Drop for Handle {
@@ -0,0 +1,435 @@
+//! Hyper-V sockets
+//!
+//! Hyper-V sockets (`AF_HYPERV`) are stream sockets connecting a Hyper-V
+//! host with the virtual machines running on it, without going through a
+//! network. An endpoint is addressed by the GUID of a virtual machine,
+//! together with the GUID of a service registered on the host.
+
+use std::fmt;
+use std::io;
+use std::mem;
+use std::os::windows::prelude::*;
+
+use fault::Operation;
+use net::{self, WsaExtension, WSAID_ACCEPTEX, WSAID_CONNECTEX};
+use net::{SO_UPDATE_ACCEPT_CONTEXT, SO_UPDATE_CONNECT_CONTEXT};
+use winapi::*;
+use ws2_32::*;
+use {Overlapped, OverlappedIo};
+
+const AF_HYPERV: c_int = 34;
+const HV_PROTOCOL_RAW: c_int = 1;
+
+// The address of a Hyper-V socket is 36 bytes, and `AcceptEx` requires 16
+// more bytes for each of the two addresses it stores.
+const ACCEPT_ADDR_LEN: usize = 36 + 16;
+
+/// The wildcard VM ID, for binding a listening socket to accept connections
+/// from any partition.
+pub const HV_GUID_WILDCARD: GUID = GUID {
+    Data1: 0,
+    Data2: 0,
+    Data3: 0,
+    Data4: [0; 8],
+};
+
+/// The VM ID of the partition a socket is in, for connecting a socket to a
+/// listener in the same partition.
+pub const HV_GUID_LOOPBACK: GUID = GUID {
+    Data1: 0xe0e16197,
+    Data2: 0xdd56,
+    Data3: 0x4a10,
+    Data4: [0x91, 0x95, 0x5e, 0xe7, 0xa1, 0x55, 0xa8, 0x38],
+};
+
+/// The VM ID of the parent partition, for connecting a socket in a virtual
+/// machine to a listener on its host.
+pub const HV_GUID_PARENT: GUID = GUID {
+    Data1: 0xa42e7cda,
+    Data2: 0xd03f,
+    Data3: 0x480c,
+    Data4: [0x9c, 0xc2, 0xa4, 0xde, 0x20, 0xab, 0xb8, 0x78],
+};
+
+/// The VM ID of all child partitions, for binding a listening socket on a
+/// host to accept connections from its virtual machines.
+pub const HV_GUID_CHILDREN: GUID = GUID {
+    Data1: 0x90db8b89,
+    Data2: 0x0d35,
+    Data3: 0x4f79,
+    Data4: [0x8c, 0xe9, 0x49, 0xea, 0x0a, 0xc8, 0xb7, 0xcd],
+};
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SOCKADDR_HV {
+    Family: ADDRESS_FAMILY,
+    Reserved: USHORT,
+    VmId: GUID,
+    ServiceId: GUID,
+}
+
+/// The address of a Hyper-V socket.
+#[derive(Clone, Copy, Debug)]
+pub struct HvSocketAddr {
+    vm_id: GUID,
+    service_id: GUID,
+}
+
+/// A Hyper-V socket.
+///
+/// A socket is created with `new`, after which it's either bound and set
+/// listening to accept connections with `accept_overlapped`, or bound and
+/// connected with `connect_overlapped`.
+pub struct HvSocket {
+    socket: SOCKET,
+}
+
+/// A buffer in which `HvSocket::accept_overlapped` stores the addresses of
+/// an accepted connection.
+///
+/// The addresses are stored in an internal format. Once the accept has
+/// completed and `accept_complete` has been called, they can be retrieved
+/// with `local_addr` and `peer_addr` on the accepted socket.
+pub struct HvAcceptAddrsBuf {
+    buf: [u8; ACCEPT_ADDR_LEN * 2],
+}
+
+static ACCEPTEX: WsaExtension = WsaExtension::new(WSAID_ACCEPTEX);
+
+static CONNECTEX: WsaExtension = WsaExtension::new(WSAID_CONNECTEX);
+
+impl HvSocketAddr {
+    /// Creates an address from the ID of a virtual machine, or one of the
+    /// `HV_GUID_*` constants, and the ID of a service.
+    pub fn new(vm_id: GUID, service_id: GUID) -> HvSocketAddr {
+        HvSocketAddr { vm_id: vm_id, service_id: service_id }
+    }
+
+    /// Returns the ID of the virtual machine of this address.
+    pub fn vm_id(&self) -> GUID {
+        self.vm_id
+    }
+
+    /// Returns the ID of the service of this address.
+    pub fn service_id(&self) -> GUID {
+        self.service_id
+    }
+
+    fn to_raw(&self) -> SOCKADDR_HV {
+        SOCKADDR_HV {
+            Family: AF_HYPERV as ADDRESS_FAMILY,
+            Reserved: 0,
+            VmId: self.vm_id,
+            ServiceId: self.service_id,
+        }
+    }
+
+    fn from_raw(addr: &SOCKADDR_HV) -> HvSocketAddr {
+        HvSocketAddr::new(addr.VmId, addr.ServiceId)
+    }
+}
+
+impl PartialEq for HvSocketAddr {
+    fn eq(&self, other: &HvSocketAddr) -> bool {
+        guid_eq(&self.vm_id, &other.vm_id) &&
+            guid_eq(&self.service_id, &other.service_id)
+    }
+}
+
+impl Eq for HvSocketAddr {}
+
+fn guid_eq(a: &GUID, b: &GUID) -> bool {
+    a.Data1 == b.Data1 && a.Data2 == b.Data2 && a.Data3 == b.Data3 &&
+        a.Data4 == b.Data4
+}
+
+fn cvt(r: c_int) -> io::Result<()> {
+    net::cvt(r).map(|_| ())
+}
+
+impl HvSocket {
+    /// Creates a new Hyper-V socket.
+    ///
+    /// An error is returned if Hyper-V sockets aren't supported, as is the
+    /// case on systems where Hyper-V isn't available.
+    pub fn new() -> io::Result<HvSocket> {
+        let socket = try!(net::socket(AF_HYPERV, SOCK_STREAM, HV_PROTOCOL_RAW,
+                                      0));
+        Ok(HvSocket { socket: socket })
+    }
+
+    /// Binds this socket to `addr`.
+    pub fn bind(&self, addr: &HvSocketAddr) -> io::Result<()> {
+        let addr = addr.to_raw();
+        cvt(unsafe {
+            bind(self.socket, &addr as *const _ as *const _,
+                 mem::size_of_val(&addr) as c_int)
+        })
+    }
+
+    /// Starts listening for connections on this socket, which must have been
+    /// bound.
+    pub fn listen(&self, backlog: i32) -> io::Result<()> {
+        cvt(unsafe { listen(self.socket, backlog) })
+    }
+
+    /// Returns the address this socket is bound to.
+    pub fn local_addr(&self) -> io::Result<HvSocketAddr> {
+        self.addr(getsockname)
+    }
+
+    /// Returns the address this socket is connected to.
+    pub fn peer_addr(&self) -> io::Result<HvSocketAddr> {
+        self.addr(getpeername)
+    }
+
+    fn addr(&self,
+            f: unsafe extern "system" fn(SOCKET, *mut SOCKADDR, *mut c_int)
+                                         -> c_int)
+            -> io::Result<HvSocketAddr> {
+        let mut addr: SOCKADDR_HV = unsafe { mem::zeroed() };
+        let mut len = mem::size_of_val(&addr) as c_int;
+        try!(cvt(unsafe {
+            f(self.socket, &mut addr as *mut _ as *mut _, &mut len)
+        }));
+        Ok(HvSocketAddr::from_raw(&addr))
+    }
+
+    /// Execute a connect operation on this socket in an overlapped fashion
+    /// (via `ConnectEx`).
+    ///
+    /// The socket must have been bound, for example to an address with the
+    /// `HV_GUID_WILDCARD` VM and service IDs. Once the operation has
+    /// completed `connect_complete` must be called.
+    ///
+    /// If the connect succeeds immediately, `Ok(true)` is returned. If the
+    /// connect indicates that the I/O is currently pending, `Ok(false)` is
+    /// returned. Otherwise, the error associated with the operation is
+    /// returned and no overlapped operation is enqueued.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that the
+    /// `overlapped` pointer is valid until the end of the I/O operation. The
+    /// kernel also requires that `overlapped` is unique for this I/O operation
+    /// and is not in use for any other I/O.
+    pub unsafe fn connect_overlapped(&self,
+                                     addr: &HvSocketAddr,
+                                     overlapped: &mut Overlapped)
+                                     -> io::Result<bool> {
+        type ConnectEx = unsafe extern "system" fn(SOCKET, *const SOCKADDR,
+                                                   c_int, PVOID, DWORD,
+                                                   LPDWORD, LPOVERLAPPED)
+                                                   -> BOOL;
+
+        let ptr = try!(CONNECTEX.get(self.socket));
+        let connect_ex = mem::transmute::<_, ConnectEx>(ptr);
+
+        let addr = addr.to_raw();
+        let socket = self.socket;
+        net::submit(Operation::Connect, 0, socket, overlapped.raw(), |_| {
+            let r = connect_ex(socket, &addr as *const _ as *const _,
+                               mem::size_of_val(&addr) as c_int,
+                               0 as *mut _, 0, 0 as *mut _,
+                               overlapped.raw());
+            if r == TRUE {
+                Ok(true)
+            } else {
+                net::last_err()
+            }
+        })
+    }
+
+    /// Finishes setting up this socket after its `connect_overlapped` has
+    /// completed (via `SO_UPDATE_CONNECT_CONTEXT`).
+    pub fn connect_complete(&self) -> io::Result<()> {
+        cvt(unsafe {
+            setsockopt(self.socket, SOL_SOCKET, SO_UPDATE_CONNECT_CONTEXT,
+                       0 as *const _, 0)
+        })
+    }
+
+    /// Perform an accept operation on this listening socket in an overlapped
+    /// fashion (via `AcceptEx`).
+    ///
+    /// The `socket` provided must be a newly created socket, which is
+    /// connected once the operation completes. After that `accept_complete`
+    /// must be called on it.
+    ///
+    /// If the accept succeeds immediately, `Ok(true)` is returned. If the
+    /// accept indicates that the I/O is currently pending, `Ok(false)` is
+    /// returned. Otherwise, the error associated with the operation is
+    /// returned and no overlapped operation is enqueued.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that the `addrs`
+    /// and `overlapped` pointers are valid until the end of the I/O
+    /// operation. The kernel also requires that `overlapped` is unique for
+    /// this I/O operation and is not in use for any other I/O.
+    pub unsafe fn accept_overlapped(&self,
+                                    socket: &HvSocket,
+                                    addrs: &mut HvAcceptAddrsBuf,
+                                    overlapped: &mut Overlapped)
+                                    -> io::Result<bool> {
+        type AcceptEx = unsafe extern "system" fn(SOCKET, SOCKET, PVOID,
+                                                  DWORD, DWORD, DWORD,
+                                                  LPDWORD, LPOVERLAPPED)
+                                                  -> BOOL;
+
+        let ptr = try!(ACCEPTEX.get(self.socket));
+        let accept_ex = mem::transmute::<_, AcceptEx>(ptr);
+
+        let listener = self.socket;
+        let mut bytes = 0;
+        net::submit(Operation::Accept, 0, listener, overlapped.raw(), |_| {
+            let r = accept_ex(listener, socket.socket,
+                              addrs.buf.as_mut_ptr() as PVOID, 0,
+                              ACCEPT_ADDR_LEN as DWORD,
+                              ACCEPT_ADDR_LEN as DWORD,
+                              &mut bytes, overlapped.raw());
+            if r == TRUE {
+                Ok(true)
+            } else {
+                net::last_err()
+            }
+        })
+    }
+
+    /// Finishes setting up this socket after it has been accepted from
+    /// `listener` (via `SO_UPDATE_ACCEPT_CONTEXT`).
+    pub fn accept_complete(&self, listener: &HvSocket) -> io::Result<()> {
+        cvt(unsafe {
+            setsockopt(self.socket, SOL_SOCKET, SO_UPDATE_ACCEPT_CONTEXT,
+                       &listener.socket as *const _ as *const _,
+                       mem::size_of::<SOCKET>() as c_int)
+        })
+    }
+
+    /// Execute an overlapped read I/O operation on this socket (via
+    /// `WSARecv`).
+    ///
+    /// If the operation succeeds, `Ok(true)` is returned. If the operation
+    /// returns an error indicating that the I/O is currently pending,
+    /// `Ok(false)` is returned. Otherwise, the error associated with the
+    /// operation is returned and no overlapped operation is enqueued.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that the `buf` and
+    /// `overlapped` pointers are valid until the end of the I/O operation. The
+    /// kernel also requires that `overlapped` is unique for this I/O operation
+    /// and is not in use for any other I/O.
+    pub unsafe fn read_overlapped(&self,
+                                  buf: &mut [u8],
+                                  overlapped: &mut Overlapped)
+                                  -> io::Result<bool> {
+        net::recv_overlapped(self.socket, buf, &mut 0, overlapped)
+    }
+
+    /// Execute an overlapped write I/O operation on this socket (via
+    /// `WSASend`).
+    ///
+    /// If the operation succeeds, `Ok(true)` is returned. If the operation
+    /// returns an error indicating that the I/O is currently pending,
+    /// `Ok(false)` is returned. Otherwise, the error associated with the
+    /// operation is returned and no overlapped operation is enqueued.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as `read_overlapped`.
+    pub unsafe fn write_overlapped(&self,
+                                   buf: &[u8],
+                                   overlapped: &mut Overlapped)
+                                   -> io::Result<bool> {
+        net::send_overlapped(self.socket, buf, overlapped)
+    }
+}
+
+impl OverlappedIo for HvSocket {
+    unsafe fn start_read(&self, buf: &mut [u8],
+                         overlapped: &mut Overlapped)
+                         -> io::Result<bool> {
+        HvSocket::read_overlapped(self, buf, overlapped)
+    }
+
+    unsafe fn start_write(&self, buf: &[u8],
+                          overlapped: &mut Overlapped)
+                          -> io::Result<bool> {
+        HvSocket::write_overlapped(self, buf, overlapped)
+    }
+
+    fn cancel(&self, overlapped: &Overlapped) -> io::Result<()> {
+        ::handle::cancel(self.socket as HANDLE, overlapped.raw())
+    }
+}
+
+impl Drop for HvSocket {
+    fn drop(&mut self) {
+        unsafe {
+            closesocket(self.socket);
+        }
+    }
+}
+
+impl fmt::Debug for HvSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HvSocket").field("socket", &self.socket).finish()
+    }
+}
+
+impl AsRawSocket for HvSocket {
+    fn as_raw_socket(&self) -> SOCKET { self.socket }
+}
+impl FromRawSocket for HvSocket {
+    unsafe fn from_raw_socket(socket: SOCKET) -> HvSocket {
+        HvSocket { socket: socket }
+    }
+}
+impl IntoRawSocket for HvSocket {
+    fn into_raw_socket(self) -> SOCKET {
+        let socket = self.socket;
+        mem::forget(self);
+        socket
+    }
+}
+
+impl HvAcceptAddrsBuf {
+    /// Creates a new blank buffer ready to be passed to a call to
+    /// `accept_overlapped`.
+    pub fn new() -> HvAcceptAddrsBuf {
+        HvAcceptAddrsBuf { buf: [0; ACCEPT_ADDR_LEN * 2] }
+    }
+}
+
+impl fmt::Debug for HvAcceptAddrsBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HvAcceptAddrsBuf").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use winapi::GUID;
+
+    use super::{HvSocketAddr, SOCKADDR_HV, HV_GUID_LOOPBACK};
+
+    #[test]
+    fn addr() {
+        assert_eq!(mem::size_of::<SOCKADDR_HV>(), 36);
+        let service = GUID {
+            Data1: 0x12345678,
+            Data2: 0x9abc,
+            Data3: 0xdef0,
+            Data4: [1, 2, 3, 4, 5, 6, 7, 8],
+        };
+        let addr = HvSocketAddr::new(HV_GUID_LOOPBACK, service);
+        let raw = addr.to_raw();
+        assert_eq!(raw.Family, 34);
+        assert_eq!(HvSocketAddr::from_raw(&raw), addr);
+        assert!(HvSocketAddr::new(service, service) != addr);
+    }
+}
@@ -1,8 +1,10 @@
 //! Bindings to IOCP, I/O Completion Ports
 
 use std::io;
+use std::marker::PhantomData;
 use std::mem;
 use std::os::windows::io::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use handle::Handle;
 use winapi::*;
@@ -10,11 +12,66 @@ use kernel32::*;
 use Overlapped;
 
 /// A handle to an Windows I/O Completion Port.
+///
+/// The tokens of the handles associated with a port are of type `T`, which
+/// defaults to a bare `usize`. Ports created with `new_typed` use a dedicated
+/// token type instead, so the tokens of different subsystems can't be mixed
+/// up by accident.
+///
+/// Ports created elsewhere, such as by other libraries or in a parent
+/// process, can be wrapped with `FromRawHandle` or from an `OwnedHandle`.
 #[derive(Debug)]
-pub struct CompletionPort {
+#[repr(transparent)]
+pub struct CompletionPort<T = usize> {
     handle: Handle,
+    _marker: PhantomData<fn(T) -> T>,
+}
+
+/// A type used as the token of the handles associated with a completion port.
+///
+/// Completion ports carry tokens as a `usize`, which this trait converts to
+/// and from. Tokens should convert back to the same value, and statuses
+/// posted by other parts of this crate on a typed port carry the tokens they
+/// were given, so every token they use must be convertible too.
+pub trait Token: Copy {
+    /// Converts this token into the value carried by completion statuses.
+    fn into_usize(self) -> usize;
+
+    /// Converts the value carried by a completion status back into a token.
+    fn from_usize(token: usize) -> Self;
+}
+
+impl Token for usize {
+    fn into_usize(self) -> usize {
+        self
+    }
+
+    fn from_usize(token: usize) -> usize {
+        token
+    }
+}
+
+/// The association of a handle or socket with an I/O completion port.
+///
+/// A registration is returned when a handle is added to a port, and remembers
+/// the handle along with the token its completions carry. It borrows the
+/// handle for the lifetime `'a` without owning it, and the association lasts
+/// until the handle is closed regardless of whether the registration is kept
+/// around.
+#[derive(Clone, Copy, Debug)]
+pub struct Registration<'a> {
+    handle: HANDLE,
+    token: usize,
+    socket: bool,
+    _marker: PhantomData<&'a ()>,
 }
 
+unsafe impl<'a> Send for Registration<'a> {}
+unsafe impl<'a> Sync for Registration<'a> {}
+
+pub(crate) const FILE_SKIP_COMPLETION_PORT_ON_SUCCESS: UCHAR = 0x1;
+pub(crate) const FILE_SKIP_SET_EVENT_ON_HANDLE: UCHAR = 0x2;
+
 /// A status message received from an I/O completion port.
 ///
 /// These statuses can be created via the `new` or `empty` constructors and then
@@ -26,6 +83,23 @@ pub struct CompletionStatus(OVERLAPPED_ENTRY);
 unsafe impl Send for CompletionStatus {}
 unsafe impl Sync for CompletionStatus {}
 
+/// A set of I/O completion ports, typically one per processor.
+///
+/// A single completion port is protected by one lock inside the kernel, which
+/// becomes contended once enough threads are dequeuing from it. Sharding the
+/// handles of a server across several ports, each of which is serviced by its
+/// own worker thread, is the standard way to scale past that point.
+///
+/// New handles and sockets are assigned to shards in a round-robin fashion,
+/// or they can be placed on a specific shard explicitly.
+#[derive(Debug)]
+pub struct ShardedPorts {
+    ports: Vec<CompletionPort>,
+    next: AtomicUsize,
+}
+
+const ALL_PROCESSOR_GROUPS: WORD = 0xffff;
+
 impl CompletionPort {
     /// Creates a new I/O completion port with the specified concurrency value.
     ///
@@ -33,6 +107,15 @@ impl CompletionPort {
     /// allowed for threads associated with this port. Consult the Windows
     /// documentation for more information about this value.
     pub fn new(threads: u32) -> io::Result<CompletionPort> {
+        CompletionPort::new_typed(threads)
+    }
+}
+
+impl<T: Token> CompletionPort<T> {
+    /// Creates a new I/O completion port whose tokens are of type `T`.
+    ///
+    /// This is the same as `new` except for the type of the tokens.
+    pub fn new_typed(threads: u32) -> io::Result<CompletionPort<T>> {
         let ret = unsafe {
             CreateIoCompletionPort(INVALID_HANDLE_VALUE, 0 as *mut _,
                                    0, threads)
@@ -40,10 +123,29 @@ impl CompletionPort {
         if ret.is_null() {
             Err(io::Error::last_os_error())
         } else {
-            Ok(CompletionPort { handle: Handle::new(ret) })
+            Ok(CompletionPort {
+                handle: Handle::new(ret),
+                _marker: PhantomData,
+            })
         }
     }
 
+    /// Returns this port with bare `usize` tokens.
+    ///
+    /// This is intended for passing a typed port to the parts of this crate
+    /// which post their own statuses, and for migrating code to typed tokens
+    /// one subsystem at a time.
+    pub fn untyped(&self) -> &CompletionPort {
+        // Safe as the port is a transparent wrapper around its handle for
+        // every token type.
+        unsafe { &*(self as *const CompletionPort<T> as *const CompletionPort) }
+    }
+
+    /// Returns the token carried by `status` as this port's token type.
+    pub fn token(&self, status: &CompletionStatus) -> T {
+        T::from_usize(status.token())
+    }
+
     /// Associates a new `HANDLE` to this I/O completion port.
     ///
     /// This function will associate the given handle to this port with the
@@ -53,9 +155,11 @@ impl CompletionPort {
     /// Any object which is convertible to a `HANDLE` via the `AsRawHandle`
     /// trait can be provided to this function, such as `std::fs::File` and
     /// friends.
-    pub fn add_handle<T: AsRawHandle + ?Sized>(&self, token: usize,
-                                               t: &T) -> io::Result<()> {
-        self._add(token, t.as_raw_handle())
+    pub fn add_handle<'a, H>(&self, token: T, t: &'a H)
+                             -> io::Result<Registration<'a>>
+        where H: AsRawHandle + ?Sized
+    {
+        self._add(token.into_usize(), t.as_raw_handle(), false)
     }
 
     /// Associates a new `SOCKET` to this I/O completion port.
@@ -67,12 +171,15 @@ impl CompletionPort {
     /// Any object which is convertible to a `SOCKET` via the `AsRawSocket`
     /// trait can be provided to this function, such as `std::net::TcpStream`
     /// and friends.
-    pub fn add_socket<T: AsRawSocket + ?Sized>(&self, token: usize,
-                                               t: &T) -> io::Result<()> {
-        self._add(token, t.as_raw_socket() as HANDLE)
+    pub fn add_socket<'a, S>(&self, token: T, t: &'a S)
+                             -> io::Result<Registration<'a>>
+        where S: AsRawSocket + ?Sized
+    {
+        self._add(token.into_usize(), t.as_raw_socket() as HANDLE, true)
     }
 
-    fn _add(&self, token: usize, handle: HANDLE) -> io::Result<()> {
+    fn _add<'a>(&self, token: usize, handle: HANDLE, socket: bool)
+                -> io::Result<Registration<'a>> {
         let ret = unsafe {
             CreateIoCompletionPort(handle, self.handle.raw(),
                                    token as ULONG_PTR, 0)
@@ -81,7 +188,12 @@ impl CompletionPort {
             Err(io::Error::last_os_error())
         } else {
             debug_assert_eq!(ret, self.handle.raw());
-            Ok(())
+            Ok(Registration {
+                handle: handle,
+                token: token,
+                socket: socket,
+                _marker: PhantomData,
+            })
         }
     }
 
@@ -120,6 +232,22 @@ impl CompletionPort {
         })
     }
 
+    /// Dequeues a completion status from this I/O completion port without
+    /// waiting.
+    ///
+    /// This function is the same as `get` with a zero timeout, except that
+    /// `Ok(None)` is returned if no status is queued instead of a
+    /// `WAIT_TIMEOUT` error.
+    pub fn try_get(&self) -> io::Result<Option<CompletionStatus>> {
+        match self.get(Some(0)) {
+            Ok(status) => Ok(Some(status)),
+            Err(ref e) if e.raw_os_error() == Some(WAIT_TIMEOUT as i32) => {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
     /// Dequeues a number of completion statuses from this I/O completion port.
     ///
     /// This function is the same as `get` except that it may return more than
@@ -133,6 +261,80 @@ impl CompletionPort {
                         list: &'a mut [CompletionStatus],
                         timeout_ms: Option<u32>)
                         -> io::Result<&'a mut [CompletionStatus]>
+    {
+        self._get_many(list, timeout_ms, FALSE)
+    }
+
+    /// Dequeues all the completion statuses queued on this I/O completion
+    /// port without waiting, appending them to `list`.
+    ///
+    /// Statuses are dequeued in batches with `get_many` until the queue is
+    /// found empty, and the number of statuses appended is returned. Like
+    /// with `get_many`, statuses of failed operations are included, and can
+    /// be told apart with `CompletionStatus::error`.
+    ///
+    /// This is intended for shutdown paths and for processing everything
+    /// which has completed in one batch.
+    pub fn drain(&self, list: &mut Vec<CompletionStatus>)
+                 -> io::Result<usize> {
+        let mut batch = [CompletionStatus::zero(); 64];
+        let mut drained = 0;
+        loop {
+            let n = match self.get_many(&mut batch, Some(0)) {
+                Ok(statuses) => {
+                    list.extend_from_slice(statuses);
+                    statuses.len()
+                }
+                Err(ref e) if e.raw_os_error() == Some(WAIT_TIMEOUT as i32) => {
+                    0
+                }
+                Err(e) => return Err(e),
+            };
+            drained += n;
+            if n < batch.len() {
+                return Ok(drained)
+            }
+        }
+    }
+
+    /// Dequeues a completion status from this I/O completion port in an
+    /// alertable wait.
+    ///
+    /// This function is like `get` except that user-mode APCs queued to the
+    /// calling thread, such as the completion routines of `ReadFileEx` or
+    /// those queued with `QueueUserAPC`, are run while waiting. If any APC
+    /// runs before a status is dequeued an error is returned whose raw OS
+    /// error is `WAIT_IO_COMPLETION`.
+    ///
+    /// Unlike `get`, the status of an I/O operation which failed is returned
+    /// like any other, as with `get_many`, so the result of the operation has
+    /// to be checked with `CompletionStatus::error`.
+    pub fn get_alertable(&self, timeout_ms: Option<u32>)
+                         -> io::Result<CompletionStatus> {
+        let mut list = [CompletionStatus::zero()];
+        let len = try!(self._get_many(&mut list, timeout_ms, TRUE)).len();
+        debug_assert_eq!(len, 1);
+        Ok(list[0])
+    }
+
+    /// Dequeues a number of completion statuses from this I/O completion port
+    /// in an alertable wait.
+    ///
+    /// This function is the same as `get_many` except that APCs queued to the
+    /// calling thread are run while waiting, as with `get_alertable`.
+    pub fn get_many_alertable<'a>(&self,
+                                  list: &'a mut [CompletionStatus],
+                                  timeout_ms: Option<u32>)
+                                  -> io::Result<&'a mut [CompletionStatus]>
+    {
+        self._get_many(list, timeout_ms, TRUE)
+    }
+
+    fn _get_many<'a>(&self,
+                     list: &'a mut [CompletionStatus],
+                     timeout_ms: Option<u32>,
+                     alertable: BOOL)
+                     -> io::Result<&'a mut [CompletionStatus]>
     {
         debug_assert_eq!(mem::size_of::<CompletionStatus>(),
                          mem::size_of::<OVERLAPPED_ENTRY>());
@@ -144,7 +346,7 @@ impl CompletionPort {
                                         list.len() as ULONG,
                                         &mut removed,
                                         timeout,
-                                        FALSE)
+                                        alertable)
         };
         match ::cvt(ret) {
             Ok(_) => Ok(&mut list[..removed as usize]),
@@ -166,26 +368,256 @@ impl CompletionPort {
         };
         ::cvt(ret).map(|_| ())
     }
+
+    /// Posts a status carrying `token` to wake up a thread blocked in `get`
+    /// or `get_many` on this port.
+    ///
+    /// The status has zero bytes transferred and a null overlapped pointer,
+    /// which no I/O completion has, so the woken thread can tell it apart by
+    /// checking `overlapped().is_null()` and then its token. Each call wakes
+    /// up a single thread.
+    pub fn wake(&self, token: T) -> io::Result<()> {
+        self.post(CompletionStatus::new(0, token.into_usize(), 0 as *mut _))
+    }
 }
 
-impl AsRawHandle for CompletionPort {
+impl<T> AsRawHandle for CompletionPort<T> {
     fn as_raw_handle(&self) -> HANDLE {
         self.handle.raw()
     }
 }
 
-impl FromRawHandle for CompletionPort {
-    unsafe fn from_raw_handle(handle: HANDLE) -> CompletionPort {
-        CompletionPort { handle: Handle::new(handle) }
+impl<T> FromRawHandle for CompletionPort<T> {
+    unsafe fn from_raw_handle(handle: HANDLE) -> CompletionPort<T> {
+        CompletionPort { handle: Handle::new(handle), _marker: PhantomData }
     }
 }
 
-impl IntoRawHandle for CompletionPort {
+impl<T> IntoRawHandle for CompletionPort<T> {
     fn into_raw_handle(self) -> HANDLE {
         self.handle.into_raw()
     }
 }
 
+impl<T> AsHandle for CompletionPort<T> {
+    fn as_handle(&self) -> BorrowedHandle {
+        unsafe { BorrowedHandle::borrow_raw(self.handle.raw()) }
+    }
+}
+
+impl<T> From<OwnedHandle> for CompletionPort<T> {
+    fn from(handle: OwnedHandle) -> CompletionPort<T> {
+        unsafe { CompletionPort::from_raw_handle(handle.into_raw_handle()) }
+    }
+}
+
+impl<T> From<CompletionPort<T>> for OwnedHandle {
+    fn from(port: CompletionPort<T>) -> OwnedHandle {
+        unsafe { OwnedHandle::from_raw_handle(port.into_raw_handle()) }
+    }
+}
+
+impl ShardedPorts {
+    /// Creates a new set of `shards` completion ports.
+    ///
+    /// Each port is created with a concurrency value of 1 as it is intended to
+    /// be serviced by a single worker thread. An error is returned if `shards`
+    /// is zero.
+    pub fn new(shards: usize) -> io::Result<ShardedPorts> {
+        if shards == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "at least one shard is required"))
+        }
+        let mut ports = Vec::with_capacity(shards);
+        for _ in 0..shards {
+            ports.push(try!(CompletionPort::new(1)));
+        }
+        Ok(ShardedPorts { ports: ports, next: AtomicUsize::new(0) })
+    }
+
+    /// Creates a new set of completion ports with one port per active
+    /// processor in the system.
+    pub fn per_core() -> io::Result<ShardedPorts> {
+        let cores = unsafe { GetActiveProcessorCount(ALL_PROCESSOR_GROUPS) };
+        if cores == 0 {
+            return Err(io::Error::last_os_error())
+        }
+        ShardedPorts::new(cores as usize)
+    }
+
+    /// Returns the number of shards in this set.
+    pub fn len(&self) -> usize {
+        self.ports.len()
+    }
+
+    /// Returns whether this set has no shards.
+    pub fn is_empty(&self) -> bool {
+        self.ports.is_empty()
+    }
+
+    /// Returns the completion port for the specified shard, if it exists.
+    pub fn port(&self, shard: usize) -> Option<&CompletionPort> {
+        self.ports.get(shard)
+    }
+
+    /// Returns all the completion ports in this set, indexed by shard.
+    pub fn ports(&self) -> &[CompletionPort] {
+        &self.ports
+    }
+
+    /// Returns the shard that the next round-robin registration will use, and
+    /// advances the internal cursor.
+    pub fn next_shard(&self) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed) % self.ports.len()
+    }
+
+    /// Associates a new `HANDLE` with the next shard in round-robin order.
+    ///
+    /// On success the index of the shard the handle was associated with is
+    /// returned along with the registration. See `CompletionPort::add_handle`
+    /// for more information.
+    pub fn add_handle<'a, T>(&self, token: usize, t: &'a T)
+                             -> io::Result<(usize, Registration<'a>)>
+        where T: AsRawHandle + ?Sized
+    {
+        let shard = self.next_shard();
+        let registration = try!(self.add_handle_to(shard, token, t));
+        Ok((shard, registration))
+    }
+
+    /// Associates a new `SOCKET` with the next shard in round-robin order.
+    ///
+    /// On success the index of the shard the socket was associated with is
+    /// returned along with the registration. See `CompletionPort::add_socket`
+    /// for more information.
+    pub fn add_socket<'a, T>(&self, token: usize, t: &'a T)
+                             -> io::Result<(usize, Registration<'a>)>
+        where T: AsRawSocket + ?Sized
+    {
+        let shard = self.next_shard();
+        let registration = try!(self.add_socket_to(shard, token, t));
+        Ok((shard, registration))
+    }
+
+    /// Associates a new `SOCKET` with the shard matching the processor to
+    /// which its packets are delivered by receive-side scaling.
+    ///
+    /// This is intended for sets created with `per_core`, where the shard for
+    /// a processor is its index among all active processors. If the socket's
+    /// processor can't be determined, for example because the network adapter
+    /// does not support receive-side scaling, the socket is associated with
+    /// the next shard in round-robin order instead.
+    ///
+    /// On success the index of the shard the socket was associated with is
+    /// returned along with the registration.
+    pub fn add_socket_rss<'a, T>(&self, token: usize, t: &'a T)
+                                 -> io::Result<(usize, Registration<'a>)>
+        where T: AsRawSocket + ?Sized
+    {
+        let shard = match ::net::rss_processor(t.as_raw_socket()) {
+            Ok(processor) => processor.index() % self.ports.len(),
+            Err(..) => self.next_shard(),
+        };
+        let registration = try!(self.add_socket_to(shard, token, t));
+        Ok((shard, registration))
+    }
+
+    /// Associates a new `HANDLE` with the specified shard.
+    pub fn add_handle_to<'a, T>(&self, shard: usize, token: usize, t: &'a T)
+                                -> io::Result<Registration<'a>>
+        where T: AsRawHandle + ?Sized
+    {
+        try!(self.shard(shard)).add_handle(token, t)
+    }
+
+    /// Associates a new `SOCKET` with the specified shard.
+    pub fn add_socket_to<'a, T>(&self, shard: usize, token: usize, t: &'a T)
+                                -> io::Result<Registration<'a>>
+        where T: AsRawSocket + ?Sized
+    {
+        try!(self.shard(shard)).add_socket(token, t)
+    }
+
+    fn shard(&self, shard: usize) -> io::Result<&CompletionPort> {
+        self.ports.get(shard).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "shard out of range")
+        })
+    }
+}
+
+impl<'a> Registration<'a> {
+    /// Returns the handle which was associated with the port.
+    ///
+    /// Sockets are returned as their `HANDLE` value.
+    pub fn raw_handle(&self) -> HANDLE {
+        self.handle
+    }
+
+    /// Returns the token carried by the completions of this handle.
+    pub fn token(&self) -> usize {
+        self.token
+    }
+
+    /// Cancels all pending overlapped operations issued on this handle by any
+    /// thread (via `CancelIoEx`).
+    ///
+    /// The cancelled operations still post their completions to the port,
+    /// usually with the `ERROR_OPERATION_ABORTED` error.
+    pub fn cancel_pending(&self) -> io::Result<()> {
+        ::handle::cancel(self.handle, 0 as *mut _)
+    }
+
+    /// Configures how operations on this handle which complete immediately
+    /// are reported (via `SetFileCompletionNotificationModes`).
+    ///
+    /// If `skip_port` is set, operations which succeed immediately no longer
+    /// post a completion to the port, so their results must be handled when
+    /// they are issued. If `skip_event` is set, the handle itself is no longer
+    /// signaled when an operation completes. These modes can't be turned off
+    /// again once set.
+    ///
+    /// Returns whether the modes were set. For sockets `skip_port` is only
+    /// reliable with providers which return true handles, so nothing is set
+    /// and `false` is returned if it's requested for a socket of any other
+    /// provider; see `SocketExt::set_skip_completion_port_on_success`.
+    pub fn set_notification_modes(&self, skip_port: bool, skip_event: bool)
+                                  -> io::Result<bool> {
+        if skip_port && self.socket &&
+           !try!(::net::has_ifs_handles(self.handle as SOCKET)) {
+            return Ok(false)
+        }
+        let mut flags = 0;
+        if skip_port {
+            flags |= FILE_SKIP_COMPLETION_PORT_ON_SUCCESS;
+        }
+        if skip_event {
+            flags |= FILE_SKIP_SET_EVENT_ON_HANDLE;
+        }
+        try!(::cvt(unsafe {
+            SetFileCompletionNotificationModes(self.handle, flags)
+        }));
+        if skip_port {
+            ::fault::skip_port(self.handle);
+        }
+        Ok(true)
+    }
+
+    /// Checks that `status` carries the token of this registration.
+    ///
+    /// This is intended to catch completions being routed to the wrong
+    /// handler while debugging, and does nothing in release builds.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if the token of `status` isn't this
+    /// registration's token.
+    pub fn debug_validate(&self, status: &CompletionStatus) {
+        debug_assert!(status.token() == self.token,
+                      "completion with token {} dequeued for registration \
+                       with token {}", status.token(), self.token);
+    }
+}
+
 impl CompletionStatus {
     /// Creates a new completion status with the provided parameters.
     ///
@@ -230,19 +662,65 @@ impl CompletionStatus {
     pub fn overlapped(&self) -> *mut Overlapped {
         self.0.lpOverlapped as *mut _
     }
+
+    /// Returns the error the I/O operation associated with this completion
+    /// status failed with, if any.
+    ///
+    /// Statuses dequeued with `get_many` are returned for failed operations
+    /// as well as successful ones. The error is decoded from the status code
+    /// the system stores in the operation's `Overlapped`, and `None` is
+    /// returned if the operation succeeded or the status has no
+    /// `Overlapped`.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the `Overlapped` of this status is
+    /// read, so it must still be valid. Statuses posted with arbitrary
+    /// `Overlapped` pointers must not be passed here.
+    pub unsafe fn error(&self) -> Option<io::Error> {
+        let overlapped = self.overlapped();
+        if overlapped.is_null() {
+            None
+        } else {
+            (*overlapped).error()
+        }
+    }
+
+    /// Returns the result of the I/O operation associated with this
+    /// completion status, which is the number of bytes transferred on
+    /// success.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as `error`.
+    pub unsafe fn result(&self) -> io::Result<u32> {
+        match self.error() {
+            Some(e) => Err(e),
+            None => Ok(self.bytes_transferred()),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::mem;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::windows::prelude::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
     use winapi::*;
+    use kernel32::{GetCurrentThread, QueueUserAPC};
 
-    use iocp::{CompletionPort, CompletionStatus};
+    use iocp::{CompletionPort, CompletionStatus, ShardedPorts, Token};
+    use net::TcpStreamExt;
+    use Overlapped;
 
     #[test]
     fn is_send_sync() {
         fn is_send_sync<T: Send + Sync>() {}
         is_send_sync::<CompletionPort>();
+        is_send_sync::<ShardedPorts>();
     }
 
     #[test]
@@ -267,6 +745,63 @@ mod tests {
         assert_eq!(s.overlapped(), 3 as *mut _);
     }
 
+    #[test]
+    fn wake() {
+        let c = Arc::new(t!(CompletionPort::new(1)));
+        let c2 = c.clone();
+        let t = thread::spawn(move || t!(c2.wake(9)));
+        let s = t!(c.get(None));
+        assert_eq!(s.token(), 9);
+        assert_eq!(s.bytes_transferred(), 0);
+        assert!(s.overlapped().is_null());
+        t!(t.join());
+    }
+
+    #[test]
+    fn try_get() {
+        let c = t!(CompletionPort::new(1));
+        assert!(t!(c.try_get()).is_none());
+        t!(c.post(CompletionStatus::new(1, 2, 3 as *mut _)));
+        let s = t!(c.try_get()).unwrap();
+        assert_eq!(s.token(), 2);
+        assert!(t!(c.try_get()).is_none());
+    }
+
+    #[test]
+    fn drain() {
+        let c = t!(CompletionPort::new(1));
+        let mut list = Vec::new();
+        assert_eq!(t!(c.drain(&mut list)), 0);
+        for i in 0..100 {
+            t!(c.post(CompletionStatus::new(0, i, 0 as *mut _)));
+        }
+        list.push(CompletionStatus::zero());
+        assert_eq!(t!(c.drain(&mut list)), 100);
+        assert_eq!(list.len(), 101);
+        for (i, s) in list[1..].iter().enumerate() {
+            assert_eq!(s.token(), i);
+        }
+        assert!(t!(c.try_get()).is_none());
+    }
+
+    #[test]
+    fn handle_conversions() {
+        let c = t!(CompletionPort::new(1));
+        let raw = c.as_raw_handle();
+        assert_eq!(c.as_handle().as_raw_handle(), raw);
+
+        let owned = OwnedHandle::from(c);
+        assert_eq!(owned.as_raw_handle(), raw);
+        let c = CompletionPort::<usize>::from(owned);
+        t!(c.post(CompletionStatus::new(1, 2, 3 as *mut _)));
+        assert_eq!(t!(c.get(None)).token(), 2);
+
+        let c = unsafe {
+            CompletionPort::<usize>::from_raw_handle(c.into_raw_handle())
+        };
+        assert_eq!(c.as_raw_handle(), raw);
+    }
+
     #[test]
     fn get_many() {
         let c = CompletionPort::new(1).unwrap();
@@ -289,4 +824,145 @@ mod tests {
         assert_eq!(s[2].token(), 0);
         assert_eq!(s[2].overlapped(), 0 as *mut _);
     }
+
+    #[test]
+    fn get_alertable() {
+        static RAN: AtomicUsize = AtomicUsize::new(0);
+
+        unsafe extern "system" fn apc(data: ULONG_PTR) {
+            RAN.store(data as usize, Ordering::SeqCst);
+        }
+
+        let c = CompletionPort::new(1).unwrap();
+        let ret = unsafe { QueueUserAPC(Some(apc), GetCurrentThread(), 7) };
+        assert!(ret != 0);
+        let err = c.get_alertable(Some(1000)).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(WAIT_IO_COMPLETION as i32));
+        assert_eq!(RAN.load(Ordering::SeqCst), 7);
+
+        c.post(CompletionStatus::new(1, 2, 3 as *mut _)).unwrap();
+        let s = c.get_alertable(None).unwrap();
+        assert_eq!(s.token(), 2);
+
+        let mut list = vec![CompletionStatus::zero(); 4];
+        let err = c.get_many_alertable(&mut list, Some(1)).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(WAIT_TIMEOUT as i32));
+    }
+
+    #[test]
+    fn registration() {
+        let c = t!(CompletionPort::new(1));
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let a = t!(TcpStream::connect(t!(l.local_addr())));
+        let r = t!(c.add_socket(7, &a));
+        assert_eq!(r.token(), 7);
+        assert_eq!(r.raw_handle(), a.as_raw_socket() as HANDLE);
+        assert!(t!(r.set_notification_modes(false, true)));
+        let ifs = t!(::net::has_ifs_handles(a.as_raw_socket()));
+        assert_eq!(t!(r.set_notification_modes(true, false)), ifs);
+
+        let mut buf = [0; 10];
+        let mut overlapped = Overlapped::zero();
+        unsafe {
+            assert!(!t!(a.read_overlapped(&mut buf, &mut overlapped)));
+        }
+        t!(r.cancel_pending());
+        let err = c.get(None).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(ERROR_OPERATION_ABORTED as i32));
+        r.debug_validate(&CompletionStatus::new(0, 7, 0 as *mut _));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn registration_wrong_token() {
+        let c = t!(CompletionPort::new(1));
+        let d = t!(CompletionPort::new(1));
+        let r = t!(c.add_handle(1, &d));
+        r.debug_validate(&CompletionStatus::new(0, 2, 0 as *mut _));
+    }
+
+    #[test]
+    fn typed_tokens() {
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        struct Conn(u32);
+
+        impl Token for Conn {
+            fn into_usize(self) -> usize {
+                self.0 as usize
+            }
+
+            fn from_usize(token: usize) -> Conn {
+                Conn(token as u32)
+            }
+        }
+
+        let c = t!(CompletionPort::<Conn>::new_typed(1));
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let a = t!(TcpStream::connect(t!(l.local_addr())));
+        let r = t!(c.add_socket(Conn(3), &a));
+        assert_eq!(r.token(), 3);
+
+        t!(c.untyped().post(CompletionStatus::new(0, 4, 0 as *mut _)));
+        let s = t!(c.get(None));
+        assert_eq!(c.token(&s), Conn(4));
+    }
+
+    #[test]
+    fn status_error() {
+        let c = t!(CompletionPort::new(1));
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let a = t!(TcpStream::connect(t!(l.local_addr())));
+        let r = t!(c.add_socket(1, &a));
+
+        let mut buf = [0; 10];
+        let mut overlapped = Overlapped::zero();
+        unsafe {
+            assert!(!t!(a.read_overlapped(&mut buf, &mut overlapped)));
+        }
+        t!(r.cancel_pending());
+        let mut list = vec![CompletionStatus::zero(); 4];
+        {
+            let s = t!(c.get_many(&mut list, None));
+            assert_eq!(s.len(), 1);
+            assert_eq!(s[0].overlapped(), &mut overlapped as *mut _);
+            let err = unsafe { s[0].result().unwrap_err() };
+            assert_eq!(err.raw_os_error(),
+                       Some(ERROR_OPERATION_ABORTED as i32));
+        }
+
+        let mut overlapped = Overlapped::zero();
+        t!(c.post(CompletionStatus::new(5, 1, &mut overlapped)));
+        let s = t!(c.get(None));
+        unsafe {
+            assert!(s.error().is_none());
+            assert_eq!(t!(s.result()), 5);
+            assert!(CompletionStatus::zero().error().is_none());
+        }
+    }
+
+    #[test]
+    fn sharded_round_robin() {
+        let ports = t!(ShardedPorts::new(2));
+        assert!(ShardedPorts::new(0).is_err());
+        assert!(ports.port(2).is_none());
+
+        let a = t!(CompletionPort::new(1));
+        let b = t!(CompletionPort::new(1));
+        let c = t!(CompletionPort::new(1));
+        assert_eq!(t!(ports.add_handle(1, &a)).0, 0);
+        let (shard, r) = t!(ports.add_handle(2, &b));
+        assert_eq!(shard, 1);
+        assert_eq!(r.token(), 2);
+        assert_eq!(t!(ports.add_handle(3, &c)).0, 0);
+        assert!(ports.add_handle_to(2, 4, &c).is_err());
+    }
+
+    #[test]
+    fn sharded_rss() {
+        let ports = t!(ShardedPorts::per_core());
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let a = t!(TcpStream::connect(t!(l.local_addr())));
+        assert!(t!(ports.add_socket_rss(1, &a)).0 < ports.len());
+    }
 }
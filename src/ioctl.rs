@@ -0,0 +1,404 @@
+//! Device I/O control codes and overlapped `DeviceIoControl`
+//!
+//! This module provides a `ControlCode` type for constructing and inspecting
+//! I/O control codes the way the `CTL_CODE` macro of the Windows SDK does, an
+//! overlapped wrapper around `DeviceIoControl`, and typed wrappers for a few
+//! commonly used codes.
+
+use std::io;
+use std::mem;
+use std::os::windows::io::*;
+use std::str;
+
+use winapi::*;
+use kernel32::*;
+use Overlapped;
+
+/// An I/O control code, as passed to `DeviceIoControl`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ControlCode(u32);
+
+/// The method used to transfer the buffers of an I/O control operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    /// Both buffers are copied through a system buffer (`METHOD_BUFFERED`).
+    Buffered,
+    /// The output buffer is mapped for direct input (`METHOD_IN_DIRECT`).
+    InDirect,
+    /// The output buffer is mapped for direct output (`METHOD_OUT_DIRECT`).
+    OutDirect,
+    /// The driver accesses the caller's buffers directly
+    /// (`METHOD_NEITHER`).
+    Neither,
+}
+
+/// The access to the handle required to issue an I/O control operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// Any access (`FILE_ANY_ACCESS`).
+    Any,
+    /// Read access (`FILE_READ_ACCESS`).
+    Read,
+    /// Write access (`FILE_WRITE_ACCESS`).
+    Write,
+    /// Both read and write access.
+    ReadWrite,
+}
+
+/// Locks a volume, preventing other handles from accessing it.
+pub const FSCTL_LOCK_VOLUME: ControlCode = ControlCode(0x00090018);
+/// Unlocks a volume previously locked with `FSCTL_LOCK_VOLUME`.
+pub const FSCTL_UNLOCK_VOLUME: ControlCode = ControlCode(0x0009001c);
+/// Dismounts a volume, invalidating all other open handles to it.
+pub const FSCTL_DISMOUNT_VOLUME: ControlCode = ControlCode(0x00090020);
+/// Queries a property of a storage device or adapter.
+pub const IOCTL_STORAGE_QUERY_PROPERTY: ControlCode = ControlCode(0x002d1400);
+/// Queries the physical location of a volume on one or more disks.
+pub const IOCTL_VOLUME_GET_VOLUME_DISK_EXTENTS: ControlCode =
+    ControlCode(0x00560000);
+/// Queries the length of a disk, partition, or volume.
+pub const IOCTL_DISK_GET_LENGTH_INFO: ControlCode = ControlCode(0x0007405c);
+
+impl ControlCode {
+    /// Constructs a control code from its components, like the `CTL_CODE`
+    /// macro.
+    ///
+    /// Only the low 12 bits of `function` are used. Functions below `0x800`
+    /// are reserved for Microsoft.
+    pub fn new(device_type: u16, function: u16, method: Method,
+               access: Access) -> ControlCode {
+        let method = match method {
+            Method::Buffered => 0,
+            Method::InDirect => 1,
+            Method::OutDirect => 2,
+            Method::Neither => 3,
+        };
+        let access = match access {
+            Access::Any => 0,
+            Access::Read => 1,
+            Access::Write => 2,
+            Access::ReadWrite => 3,
+        };
+        ControlCode(((device_type as u32) << 16) | (access << 14) |
+                    (((function as u32) & 0xfff) << 2) | method)
+    }
+
+    /// Creates a control code from its raw value.
+    pub fn from_raw(code: u32) -> ControlCode {
+        ControlCode(code)
+    }
+
+    /// Returns the raw value of this control code.
+    pub fn raw(&self) -> u32 {
+        self.0
+    }
+
+    /// Returns the device type of this control code.
+    pub fn device_type(&self) -> u16 {
+        (self.0 >> 16) as u16
+    }
+
+    /// Returns the function of this control code.
+    pub fn function(&self) -> u16 {
+        ((self.0 >> 2) & 0xfff) as u16
+    }
+
+    /// Returns the buffer transfer method of this control code.
+    pub fn method(&self) -> Method {
+        match self.0 & 3 {
+            0 => Method::Buffered,
+            1 => Method::InDirect,
+            2 => Method::OutDirect,
+            _ => Method::Neither,
+        }
+    }
+
+    /// Returns the access required by this control code.
+    pub fn access(&self) -> Access {
+        match (self.0 >> 14) & 3 {
+            0 => Access::Any,
+            1 => Access::Read,
+            2 => Access::Write,
+            _ => Access::ReadWrite,
+        }
+    }
+}
+
+/// Issues an overlapped I/O control operation on a handle.
+///
+/// The handle must have been opened for overlapped I/O. If the operation
+/// succeeds immediately, `Ok(true)` is returned. If an asynchronous operation
+/// is enqueued, then `Ok(false)` is returned. Otherwise if an error occurred
+/// it is returned.
+///
+/// The number of bytes written to `output` will be returned as part of the
+/// completion notification when the I/O finishes.
+///
+/// # Unsafety
+///
+/// This function is unsafe because the kernel requires that the `output` and
+/// `overlapped` pointers are valid until the end of the I/O operation, as is
+/// `input` unless `code` uses `Method::Buffered`. The kernel also requires
+/// that `overlapped` is unique for this I/O operation and is not in use for
+/// any other I/O.
+///
+/// To safely use this function callers must ensure that these pointers are
+/// valid until the I/O operation is completed, typically via completion ports
+/// and waiting to receive the completion notification on the port.
+pub unsafe fn device_io_control_overlapped<T>(t: &T,
+                                              code: ControlCode,
+                                              input: &[u8],
+                                              output: &mut [u8],
+                                              overlapped: &mut Overlapped)
+                                              -> io::Result<bool>
+    where T: AsRawHandle + ?Sized
+{
+    let in_len = try!(::checked_len(input.len()));
+    let r = DeviceIoControl(t.as_raw_handle(), code.raw(),
+                            input.as_ptr() as LPVOID, in_len,
+                            output.as_mut_ptr() as LPVOID,
+                            ::clamp_len(output.len()),
+                            0 as *mut _, overlapped.raw());
+    match ::cvt(r) {
+        Ok(_) => Ok(true),
+        Err(ref e) if e.raw_os_error() == Some(ERROR_IO_PENDING as i32)
+            => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Issues an overlapped `FSCTL_LOCK_VOLUME` on a volume handle.
+///
+/// See `device_io_control_overlapped` for more information.
+pub unsafe fn lock_volume_overlapped<T>(t: &T, overlapped: &mut Overlapped)
+                                        -> io::Result<bool>
+    where T: AsRawHandle + ?Sized
+{
+    device_io_control_overlapped(t, FSCTL_LOCK_VOLUME, &[], &mut [],
+                                 overlapped)
+}
+
+/// Issues an overlapped `FSCTL_UNLOCK_VOLUME` on a volume handle.
+///
+/// See `device_io_control_overlapped` for more information.
+pub unsafe fn unlock_volume_overlapped<T>(t: &T, overlapped: &mut Overlapped)
+                                          -> io::Result<bool>
+    where T: AsRawHandle + ?Sized
+{
+    device_io_control_overlapped(t, FSCTL_UNLOCK_VOLUME, &[], &mut [],
+                                 overlapped)
+}
+
+/// Issues an overlapped `FSCTL_DISMOUNT_VOLUME` on a volume handle.
+///
+/// See `device_io_control_overlapped` for more information.
+pub unsafe fn dismount_volume_overlapped<T>(t: &T,
+                                            overlapped: &mut Overlapped)
+                                            -> io::Result<bool>
+    where T: AsRawHandle + ?Sized
+{
+    device_io_control_overlapped(t, FSCTL_DISMOUNT_VOLUME, &[], &mut [],
+                                 overlapped)
+}
+
+/// Issues an overlapped query for the descriptor of a storage device
+/// (`IOCTL_STORAGE_QUERY_PROPERTY` with `StorageDeviceProperty`).
+///
+/// Once the operation completes, the descriptor can be parsed from `output`
+/// with `StorageDeviceDescriptor::parse`. A buffer of 1024 bytes is
+/// sufficient for most devices.
+///
+/// See `device_io_control_overlapped` for more information.
+pub unsafe fn storage_device_descriptor_overlapped<T>(
+        t: &T,
+        output: &mut [u8],
+        overlapped: &mut Overlapped) -> io::Result<bool>
+    where T: AsRawHandle + ?Sized
+{
+    // STORAGE_PROPERTY_QUERY with PropertyId = StorageDeviceProperty and
+    // QueryType = PropertyStandardQuery. The query is buffered, so it only
+    // needs to live until the call returns.
+    let query = [0u8; 12];
+    device_io_control_overlapped(t, IOCTL_STORAGE_QUERY_PROPERTY, &query,
+                                 output, overlapped)
+}
+
+/// Issues an overlapped `IOCTL_VOLUME_GET_VOLUME_DISK_EXTENTS` on a volume
+/// handle.
+///
+/// Once the operation completes, the extents can be parsed from `output`
+/// with `DiskExtent::parse`.
+///
+/// See `device_io_control_overlapped` for more information.
+pub unsafe fn volume_disk_extents_overlapped<T>(t: &T,
+                                                output: &mut [u8],
+                                                overlapped: &mut Overlapped)
+                                                -> io::Result<bool>
+    where T: AsRawHandle + ?Sized
+{
+    device_io_control_overlapped(t, IOCTL_VOLUME_GET_VOLUME_DISK_EXTENTS,
+                                 &[], output, overlapped)
+}
+
+/// The descriptor of a storage device, parsed from the output of
+/// `storage_device_descriptor_overlapped`.
+#[derive(Clone, Copy, Debug)]
+pub struct StorageDeviceDescriptor<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> StorageDeviceDescriptor<'a> {
+    /// Parses a descriptor from the bytes written by a completed query.
+    ///
+    /// Returns `None` if `buf` is too short to contain the fixed part of a
+    /// descriptor.
+    pub fn parse(buf: &'a [u8]) -> Option<StorageDeviceDescriptor<'a>> {
+        if buf.len() < 36 {
+            None
+        } else {
+            Some(StorageDeviceDescriptor { buf: buf })
+        }
+    }
+
+    /// Returns the size of the complete descriptor.
+    ///
+    /// If this is larger than the buffer the descriptor was parsed from, some
+    /// of its strings may be missing and the query should be repeated with a
+    /// buffer of at least this size.
+    pub fn size(&self) -> usize {
+        u32_at(self.buf, 4) as usize
+    }
+
+    /// Returns whether the device has removable media.
+    pub fn removable(&self) -> bool {
+        self.buf[10] != 0
+    }
+
+    /// Returns the bus type of the device, one of the `STORAGE_BUS_TYPE`
+    /// values.
+    pub fn bus_type(&self) -> u32 {
+        u32_at(self.buf, 28)
+    }
+
+    /// Returns the vendor identifier of the device, if it has one.
+    pub fn vendor_id(&self) -> Option<&'a str> {
+        self.string(12)
+    }
+
+    /// Returns the product identifier of the device, if it has one.
+    pub fn product_id(&self) -> Option<&'a str> {
+        self.string(16)
+    }
+
+    /// Returns the product revision of the device, if it has one.
+    pub fn product_revision(&self) -> Option<&'a str> {
+        self.string(20)
+    }
+
+    /// Returns the serial number of the device, if it has one.
+    pub fn serial_number(&self) -> Option<&'a str> {
+        self.string(24)
+    }
+
+    fn string(&self, field: usize) -> Option<&'a str> {
+        let offset = u32_at(self.buf, field) as usize;
+        if offset == 0 || offset >= self.buf.len() {
+            return None
+        }
+        let bytes = &self.buf[offset..];
+        let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        str::from_utf8(&bytes[..len]).ok().map(|s| s.trim())
+    }
+}
+
+/// A contiguous range of a disk occupied by a volume.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DiskExtent {
+    /// The number of the disk, as in `\\.\PhysicalDriveN`.
+    pub disk_number: u32,
+    /// The offset of the extent from the start of the disk, in bytes.
+    pub offset: u64,
+    /// The length of the extent, in bytes.
+    pub length: u64,
+}
+
+impl DiskExtent {
+    /// Parses the extents from the bytes written by a completed
+    /// `volume_disk_extents_overlapped`.
+    ///
+    /// Extents which don't fit in `buf` are omitted.
+    pub fn parse(buf: &[u8]) -> Vec<DiskExtent> {
+        if buf.len() < 8 {
+            return Vec::new()
+        }
+        let count = u32_at(buf, 0) as usize;
+        buf[8..].chunks(24).take(count).filter(|c| c.len() == 24).map(|c| {
+            DiskExtent {
+                disk_number: u32_at(c, 0),
+                offset: u64_at(c, 8),
+                length: u64_at(c, 16),
+            }
+        }).collect()
+    }
+}
+
+fn u32_at(buf: &[u8], i: usize) -> u32 {
+    let mut b = [0; 4];
+    b.copy_from_slice(&buf[i..i + mem::size_of::<u32>()]);
+    u32::from_le_bytes(b)
+}
+
+fn u64_at(buf: &[u8], i: usize) -> u64 {
+    let mut b = [0; 8];
+    b.copy_from_slice(&buf[i..i + mem::size_of::<u64>()]);
+    u64::from_le_bytes(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_codes() {
+        let code = ControlCode::new(9, 6, Method::Buffered, Access::Any);
+        assert_eq!(code, FSCTL_LOCK_VOLUME);
+        let code = ControlCode::new(0x2d, 0x500, Method::Buffered, Access::Any);
+        assert_eq!(code, IOCTL_STORAGE_QUERY_PROPERTY);
+        let code = IOCTL_DISK_GET_LENGTH_INFO;
+        assert_eq!(code.device_type(), 7);
+        assert_eq!(code.function(), 0x17);
+        assert_eq!(code.method(), Method::Buffered);
+        assert_eq!(code.access(), Access::Read);
+    }
+
+    #[test]
+    fn parse_descriptor() {
+        let mut buf = vec![0u8; 48];
+        buf[4] = 48;
+        buf[10] = 1;
+        buf[12] = 36;
+        buf[28] = 7;
+        buf[36..40].copy_from_slice(b"ACME");
+        let desc = StorageDeviceDescriptor::parse(&buf).unwrap();
+        assert_eq!(desc.size(), 48);
+        assert!(desc.removable());
+        assert_eq!(desc.bus_type(), 7);
+        assert_eq!(desc.vendor_id(), Some("ACME"));
+        assert_eq!(desc.serial_number(), None);
+        assert!(StorageDeviceDescriptor::parse(&buf[..20]).is_none());
+    }
+
+    #[test]
+    fn parse_extents() {
+        let mut buf = vec![0u8; 32];
+        buf[0] = 1;
+        buf[8] = 2;
+        buf[16] = 0x10;
+        buf[24] = 0x20;
+        assert_eq!(DiskExtent::parse(&buf), vec![DiskExtent {
+            disk_number: 2,
+            offset: 0x10,
+            length: 0x20,
+        }]);
+    }
+}
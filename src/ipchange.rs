@@ -0,0 +1,153 @@
+//! Network address and route change notifications
+//!
+//! Daemons which bind to specific interfaces or cache routing decisions need
+//! to learn when the addresses or routes of the machine change. This module
+//! requests those notifications in overlapped mode (via `NotifyAddrChange`
+//! and `NotifyRouteChange`) and delivers them through an I/O completion port,
+//! so they can be handled along with the daemon's sockets.
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use winapi::*;
+use kernel32::*;
+use handle::Handle;
+use iocp::{CompletionPort, CompletionStatus};
+use wait::WaitRegistration;
+use Overlapped;
+
+#[link(name = "iphlpapi")]
+extern "system" {
+    fn NotifyAddrChange(Handle: PHANDLE, overlapped: LPOVERLAPPED) -> DWORD;
+    fn NotifyRouteChange(Handle: PHANDLE, overlapped: LPOVERLAPPED) -> DWORD;
+    fn CancelIPChangeNotify(notifyOverlapped: LPOVERLAPPED) -> BOOL;
+}
+
+/// A watcher for changes to the IPv4 addresses or routes of the machine.
+///
+/// Each time a change is detected a completion status is posted to the
+/// completion port the watcher was created with. The status carries the
+/// token provided at creation, zero bytes transferred, and a null overlapped
+/// pointer.
+///
+/// A watcher only reports the first change after it has been armed. After
+/// receiving a notification, call `rearm` to be notified of the next change.
+#[derive(Debug)]
+pub struct ChangeWatcher {
+    // Taken when the watcher is dropped so the wait is unregistered before
+    // the request is cancelled, as the cancellation signals the event.
+    wait: Option<WaitRegistration>,
+    // Boxed so the request's `OVERLAPPED` stays in place while the watcher
+    // is moved.
+    overlapped: Box<Overlapped>,
+    // The handle the request was issued on, which is owned by the system
+    // and mustn't be closed.
+    handle: AtomicUsize,
+    _event: Handle,
+    routes: bool,
+}
+
+unsafe impl Send for ChangeWatcher {}
+unsafe impl Sync for ChangeWatcher {}
+
+impl ChangeWatcher {
+    /// Starts watching for changes to the IPv4 addresses of the machine (via
+    /// `NotifyAddrChange`).
+    ///
+    /// Notifications will be posted to `port` with the specified `token`.
+    pub fn addresses(port: &CompletionPort, token: usize)
+                     -> io::Result<ChangeWatcher> {
+        ChangeWatcher::new(port, token, false)
+    }
+
+    /// Starts watching for changes to the IPv4 routing table of the machine
+    /// (via `NotifyRouteChange`).
+    ///
+    /// Notifications will be posted to `port` with the specified `token`.
+    pub fn routes(port: &CompletionPort, token: usize)
+                  -> io::Result<ChangeWatcher> {
+        ChangeWatcher::new(port, token, true)
+    }
+
+    fn new(port: &CompletionPort, token: usize, routes: bool)
+           -> io::Result<ChangeWatcher> {
+        let event = unsafe {
+            CreateEventW(0 as *mut _, FALSE, FALSE, 0 as *const _)
+        };
+        if event.is_null() {
+            return Err(io::Error::last_os_error())
+        }
+        let event = Handle::new(event);
+        let mut overlapped = Box::new(Overlapped::zero());
+        overlapped.set_event(event.raw());
+
+        let status = CompletionStatus::new(0, token, 0 as *mut _);
+        let wait = try!(WaitRegistration::new(port, event.raw(), status,
+                                              false));
+        let watcher = ChangeWatcher {
+            wait: Some(wait),
+            overlapped: overlapped,
+            handle: AtomicUsize::new(0),
+            _event: event,
+            routes: routes,
+        };
+        try!(watcher.rearm());
+        Ok(watcher)
+    }
+
+    /// Requests notification of the next change.
+    ///
+    /// This must be called after each notification is received in order to
+    /// continue receiving notifications.
+    pub fn rearm(&self) -> io::Result<()> {
+        let mut handle = 0 as HANDLE;
+        let r = unsafe {
+            if self.routes {
+                NotifyRouteChange(&mut handle, self.overlapped.raw())
+            } else {
+                NotifyAddrChange(&mut handle, self.overlapped.raw())
+            }
+        };
+        match r {
+            ERROR_IO_PENDING => {
+                self.handle.store(handle as usize, Ordering::SeqCst);
+                Ok(())
+            }
+            e => Err(io::Error::from_raw_os_error(e as i32)),
+        }
+    }
+}
+
+impl Drop for ChangeWatcher {
+    fn drop(&mut self) {
+        drop(self.wait.take());
+        let handle = self.handle.load(Ordering::SeqCst) as HANDLE;
+        let mut bytes = 0;
+        unsafe {
+            // Wait for the cancelled request to finish so its `OVERLAPPED`
+            // can be freed.
+            if CancelIPChangeNotify(self.overlapped.raw()) != 0 {
+                GetOverlappedResult(handle, self.overlapped.raw(), &mut bytes,
+                                    TRUE);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iocp::CompletionPort;
+    use super::ChangeWatcher;
+
+    #[test]
+    fn watch() {
+        let cp = t!(CompletionPort::new(1));
+        let addrs = t!(ChangeWatcher::addresses(&cp, 1));
+        let routes = t!(ChangeWatcher::routes(&cp, 2));
+        drop(addrs);
+        drop(routes);
+
+        // Cancelling the requests doesn't post a notification.
+        assert!(cp.get(Some(50)).is_err());
+    }
+}
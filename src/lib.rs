@@ -5,14 +5,20 @@
 #![allow(bad_style)]
 #![doc(html_root_url = "http://alexcrichton.com/miow")]
 
+extern crate advapi32;
 extern crate kernel32;
 extern crate net2;
 extern crate winapi;
 extern crate ws2_32;
 
+#[cfg(feature = "windows-sys")] extern crate windows_sys;
 #[cfg(test)] extern crate rand;
 
+use std::cmp;
 use std::io;
+use std::mem::MaybeUninit;
+use std::slice;
+use std::time::Duration;
 use winapi::*;
 
 macro_rules! t {
@@ -22,14 +28,69 @@ macro_rules! t {
     })
 }
 
+// A pair of TCP streams connected to each other over loopback, for tests.
+#[cfg(test)]
+fn tcp_pair() -> (std::net::TcpStream, std::net::TcpStream) {
+    let l = t!(std::net::TcpListener::bind("127.0.0.1:0"));
+    let a = t!(std::net::TcpStream::connect(t!(l.local_addr())));
+    let b = t!(l.accept()).0;
+    (a, b)
+}
+
+#[cfg(feature = "windows-sys")]
+mod compat;
 mod handle;
 mod overlapped;
 
+#[cfg(feature = "fault-injection")]
+pub mod fault;
+#[cfg(not(feature = "fault-injection"))]
+mod fault {
+    use std::io;
+    use winapi::*;
+
+    #[derive(Clone, Copy)]
+    pub enum Operation { Read, Write, Connect, Accept }
+
+    pub unsafe fn submit<F>(_op: Operation, len: usize, _handle: HANDLE,
+                            _overlapped: LPOVERLAPPED, f: F)
+                            -> io::Result<bool>
+        where F: FnOnce(usize) -> io::Result<bool>
+    {
+        f(len)
+    }
+
+    pub fn skip_port(_handle: HANDLE) {}
+}
+
+pub mod accept;
+pub mod backoff;
+pub mod batch;
+pub mod budget;
+pub mod buffer;
+pub mod console;
+pub mod dns;
+pub mod drive;
+pub mod hvsocket;
 pub mod iocp;
+pub mod ioctl;
+pub mod ipchange;
+pub mod mailslot;
 pub mod net;
 pub mod pipe;
+pub mod registry;
+pub mod relay;
+pub mod resolve;
+pub mod rio;
+pub mod serial;
+pub mod shutdown;
+pub mod stats;
+pub mod timer;
+pub mod trace;
+pub mod wait;
+pub mod watch;
 
-pub use overlapped::Overlapped;
+pub use overlapped::{Overlapped, OverlappedIo};
 
 fn cvt(i: BOOL) -> io::Result<BOOL> {
     if i == 0 {
@@ -38,3 +99,35 @@ fn cvt(i: BOOL) -> io::Result<BOOL> {
         Ok(i)
     }
 }
+
+// Clamps the length of a buffer to the most a single operation transfers,
+// for buffers which an operation may fill or write in part.
+fn clamp_len(len: usize) -> DWORD {
+    cmp::min(len, DWORD::max_value() as usize) as DWORD
+}
+
+// Converts the length of a buffer which an operation must take whole,
+// failing if it's longer than the operation can be passed.
+fn checked_len(len: usize) -> io::Result<DWORD> {
+    if len > DWORD::max_value() as usize {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer too large"))
+    } else {
+        Ok(len as DWORD)
+    }
+}
+
+// Converts a duration to milliseconds, rounding up so that timeouts never
+// end early.
+fn dur2ms(dur: Duration) -> u64 {
+    let ms = dur.as_secs().saturating_mul(1_000);
+    ms.saturating_add((dur.subsec_nanos() as u64 + 999_999) / 1_000_000)
+}
+
+// Views an initialized buffer as one which may be uninitialized, so reads
+// into either kind of buffer can share an implementation. The buffer is only
+// ever written to through the returned slice.
+fn uninit(buf: &mut [u8]) -> &mut [MaybeUninit<u8>] {
+    unsafe {
+        slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut _, buf.len())
+    }
+}
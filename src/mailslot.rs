@@ -0,0 +1,219 @@
+//! Mailslots
+
+use std::ffi::OsStr;
+use std::fs::{OpenOptions, File};
+use std::io::prelude::*;
+use std::io;
+use std::os::windows::ffi::*;
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::*;
+
+use winapi::*;
+use kernel32::*;
+use handle::Handle;
+use Overlapped;
+
+/// The server end of a mailslot, which receives messages written by clients.
+#[derive(Debug)]
+pub struct Mailslot(Handle);
+
+/// Information about the state of a mailslot, as returned by
+/// `Mailslot::info`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MailslotInfo {
+    max_message_size: u32,
+    next_size: Option<u32>,
+    message_count: u32,
+    read_timeout: Option<u32>,
+}
+
+/// A convenience function to open the client end of a mailslot for writing.
+///
+/// The `addr` is either the name of a local mailslot, such as
+/// `\\.\mailslot\foo`, or the name of mailslots on other computers.
+pub fn connect<A: AsRef<OsStr>>(addr: A) -> io::Result<File> {
+    OpenOptions::new().write(true)
+                      .share_mode(FILE_SHARE_READ)
+                      .open(addr.as_ref())
+}
+
+impl Mailslot {
+    /// Creates a new mailslot with the specified name.
+    ///
+    /// The `max_message_size` is the largest message which can be written to
+    /// the mailslot, or 0 to allow messages of any size. The `read_timeout`
+    /// is the number of milliseconds a blocking read waits for a message to
+    /// arrive, where `None` waits forever.
+    pub fn new<A: AsRef<OsStr>>(addr: A,
+                                max_message_size: u32,
+                                read_timeout: Option<u32>)
+                                -> io::Result<Mailslot> {
+        let addr = addr.as_ref().encode_wide().chain(Some(0))
+                       .collect::<Vec<_>>();
+        let h = unsafe {
+            CreateMailslotW(addr.as_ptr(), max_message_size,
+                            read_timeout.unwrap_or(MAILSLOT_WAIT_FOREVER),
+                            0 as *mut _)
+        };
+        if h == INVALID_HANDLE_VALUE {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(Mailslot(Handle::new(h)))
+        }
+    }
+
+    /// Queries the current state of this mailslot.
+    ///
+    /// This can be used to poll for messages without blocking, and to size
+    /// the buffer for the next read exactly.
+    pub fn info(&self) -> io::Result<MailslotInfo> {
+        let mut max_message_size = 0;
+        let mut next_size = 0;
+        let mut message_count = 0;
+        let mut read_timeout = 0;
+        try!(::cvt(unsafe {
+            GetMailslotInfo(self.0.raw(), &mut max_message_size,
+                            &mut next_size, &mut message_count,
+                            &mut read_timeout)
+        }));
+        Ok(MailslotInfo {
+            max_message_size: max_message_size,
+            next_size: if next_size == MAILSLOT_NO_MESSAGE {
+                None
+            } else {
+                Some(next_size)
+            },
+            message_count: message_count,
+            read_timeout: if read_timeout == MAILSLOT_WAIT_FOREVER {
+                None
+            } else {
+                Some(read_timeout)
+            },
+        })
+    }
+
+    /// Returns the size of the next message in this mailslot, or `None` if
+    /// there are no messages.
+    pub fn next_message_size(&self) -> io::Result<Option<u32>> {
+        self.info().map(|info| info.next_size())
+    }
+
+    /// Sets the number of milliseconds a blocking read waits for a message to
+    /// arrive, where `None` waits forever.
+    ///
+    /// A timeout of 0 makes reads return immediately if there is no message.
+    pub fn set_read_timeout(&self, timeout: Option<u32>) -> io::Result<()> {
+        let timeout = timeout.unwrap_or(MAILSLOT_WAIT_FOREVER);
+        ::cvt(unsafe {
+            SetMailslotInfo(self.0.raw(), timeout)
+        }).map(|_| ())
+    }
+
+    /// Issues an overlapped read of the next message in this mailslot.
+    ///
+    /// The `buf` must be large enough to hold the entire message, which can
+    /// be determined with `next_message_size`.
+    ///
+    /// If the operation succeeds immediately, `Ok(true)` is returned. If an
+    /// asynchronous operation is enqueued, then `Ok(false)` is returned.
+    /// Otherwise if an error occurred it is returned.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that the `buf` and
+    /// `overlapped` pointers to be valid until the end of the I/O operation.
+    /// The kernel also requires that `overlapped` is unique for this I/O
+    /// operation and is not in use for any other I/O.
+    ///
+    /// To safely use this function callers must ensure that the pointers are
+    /// valid until the I/O operation is completed, typically via completion
+    /// ports and waiting to receive the completion notification on the port.
+    pub unsafe fn read_overlapped(&self, buf: &mut [u8],
+                                  overlapped: &mut Overlapped)
+                                  -> io::Result<bool> {
+        self.0.read_overlapped(buf, overlapped.raw())
+    }
+}
+
+impl MailslotInfo {
+    /// Returns the largest message which can be written to the mailslot, or
+    /// 0 if messages can be of any size.
+    pub fn max_message_size(&self) -> u32 {
+        self.max_message_size
+    }
+
+    /// Returns the size of the next message, or `None` if there are no
+    /// messages.
+    pub fn next_size(&self) -> Option<u32> {
+        self.next_size
+    }
+
+    /// Returns the number of messages waiting to be read.
+    pub fn message_count(&self) -> u32 {
+        self.message_count
+    }
+
+    /// Returns the number of milliseconds a blocking read waits for a
+    /// message, where `None` waits forever.
+    pub fn read_timeout(&self) -> Option<u32> {
+        self.read_timeout
+    }
+}
+
+impl Read for Mailslot {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.0.read(buf) }
+}
+impl<'a> Read for &'a Mailslot {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.0.read(buf) }
+}
+
+impl AsRawHandle for Mailslot {
+    fn as_raw_handle(&self) -> HANDLE { self.0.raw() }
+}
+impl FromRawHandle for Mailslot {
+    unsafe fn from_raw_handle(handle: HANDLE) -> Mailslot {
+        Mailslot(Handle::new(handle))
+    }
+}
+impl IntoRawHandle for Mailslot {
+    fn into_raw_handle(self) -> HANDLE { self.0.into_raw() }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    use rand::{thread_rng, Rng};
+
+    use super::{connect, Mailslot};
+
+    fn name() -> String {
+        let name = thread_rng().gen_ascii_chars().take(30).collect::<String>();
+        format!(r"\\.\mailslot\{}", name)
+    }
+
+    #[test]
+    fn info() {
+        let name = name();
+        let mut slot = t!(Mailslot::new(&name, 100, Some(0)));
+        let info = t!(slot.info());
+        assert_eq!(info.max_message_size(), 100);
+        assert_eq!(info.next_size(), None);
+        assert_eq!(info.message_count(), 0);
+        assert_eq!(info.read_timeout(), Some(0));
+
+        let mut client = t!(connect(&name));
+        t!(client.write_all(b"hello"));
+        t!(client.write_all(b"hi"));
+        let info = t!(slot.info());
+        assert_eq!(info.next_size(), Some(5));
+        assert_eq!(info.message_count(), 2);
+
+        let mut buf = [0; 100];
+        assert_eq!(t!(slot.read(&mut buf)), 5);
+        assert_eq!(t!(slot.next_message_size()), Some(2));
+
+        t!(slot.set_read_timeout(None));
+        assert_eq!(t!(slot.info()).read_timeout(), None);
+    }
+}
@@ -3,29 +3,187 @@
 //! This module contains a number of extension traits for the types in
 //! `std::net` for Windows-specific functionality.
 
-use std::io;
-use std::mem;
+use std::cell::Cell;
+use std::cmp;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, IoSlice, IoSliceMut};
+use std::marker;
+use std::mem::{self, MaybeUninit};
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::ptr;
+use std::slice;
+use std::str;
+use std::sync::{Once, RwLock};
 use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::Duration;
 use std::net::{TcpStream, UdpSocket, SocketAddr, TcpListener};
-use std::net::{SocketAddrV4, Ipv4Addr, SocketAddrV6, Ipv6Addr};
+use std::net::{SocketAddrV4, Ipv4Addr, SocketAddrV6, Ipv6Addr, IpAddr};
 use std::os::windows::prelude::*;
 
+use kernel32::{GetHandleInformation, SetFileCompletionNotificationModes,
+               SetHandleInformation};
 use net2::TcpBuilder;
+use fault::Operation;
+use handle::Handle;
+use iocp::{FILE_SKIP_COMPLETION_PORT_ON_SUCCESS, FILE_SKIP_SET_EVENT_ON_HANDLE};
 use winapi::*;
 use ws2_32::*;
-use Overlapped;
+use {Overlapped, OverlappedIo};
 
 /// A type to represent a buffer in which a socket address will be stored.
 ///
 /// This type is used with the `recv_from_overlapped` function on the
 /// `UdpSocketExt` trait to provide space for the overlapped I/O operation to
 /// fill in the address upon completion.
+///
+/// A completed operation overwrites the length of the buffer with the length
+/// of the address it stored, so a buffer must be `reset` before it's passed
+/// to another operation.
 #[derive(Clone, Copy)]
 pub struct SocketAddrBuf {
-    buf: SOCKADDR_STORAGE,
+    addr: RawSocketAddr,
+    unmap_v4: bool,
+}
+
+/// The address of a Unix domain (`AF_UNIX`) socket.
+///
+/// The address is either a path in the file system, an abstract name
+/// beginning with a null byte, or unnamed, as for a socket which hasn't been
+/// bound.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct UnixSocketAddr {
+    // The bytes of `sun_path`, without the terminating null of a path.
+    path: Vec<u8>,
+}
+
+/// A socket address in the form taken by Winsock functions, a `SOCKADDR`
+/// along with its length.
+///
+/// This converts between the address types of the standard library and
+/// this crate and the raw structures, for callers issuing Winsock calls of
+/// their own. A `RawSocketAddr` can hold an address of any family.
+#[derive(Clone, Copy)]
+pub struct RawSocketAddr {
+    storage: SOCKADDR_STORAGE,
     len: c_int,
 }
 
+/// A TCP socket which hasn't been connected or started listening yet,
+/// created by this crate (via `WSASocketW`).
+///
+/// This can be used in place of the `TcpBuilder` type of the `net2` library
+/// for `connect_overlapped` through `TcpBuilderExt`, and as the socket to
+/// accept a connection into with `accept_overlapped`. Like `TcpBuilder`, the
+/// socket is consumed once it has been turned into a stream or listener,
+/// after which the builder can no longer be used.
+///
+/// The socket is created for overlapped I/O and isn't inherited by child
+/// processes.
+#[derive(Debug)]
+pub struct SocketBuilder {
+    socket: Cell<SOCKET>,
+}
+
+/// A builder for TCP listeners set up for `accept_overlapped`.
+///
+/// This configures the address, the address reuse options, the backlog and
+/// the overlapped behavior of a listener in one place. The listener is
+/// created for overlapped I/O and by default isn't inherited by child
+/// processes.
+#[derive(Clone, Debug)]
+pub struct ListenerBuilder {
+    addr: SocketAddr,
+    backlog: i32,
+    reuse_address: bool,
+    exclusive_address_use: bool,
+    only_v6: Option<bool>,
+    loopback_fast_path: bool,
+    skip_completion_port_on_success: bool,
+    inheritable: bool,
+}
+
+/// The message header of an overlapped `WSARecvMsg` or `WSASendMsg`
+/// operation, along with space for the address of the peer.
+///
+/// This type is used with the `recv_msg_overlapped` and `send_msg_overlapped`
+/// functions on the `UdpSocketExt` trait. For receives the kernel updates the
+/// header when the operation completes, after which the address, the length
+/// of the control data, and the flags of the message can be retrieved from
+/// it.
+pub struct MsgBuf {
+    msg: WSAMSG,
+    addr: SOCKADDR_STORAGE,
+    data: WSABUF,
+}
+
+/// A buffer of control messages to be sent with `send_msg_overlapped`.
+///
+/// Each message is laid out with a `WSACMSGHDR` header following the
+/// alignment rules of Winsock, so the contents of this buffer can be passed
+/// as the `control` argument directly.
+#[derive(Clone, Debug, Default)]
+pub struct CMsgBuf {
+    // Stored as words so the start of the buffer is suitably aligned for
+    // the headers.
+    buf: Vec<usize>,
+    len: usize,
+}
+
+/// An iterator over the control messages received by `recv_msg_overlapped`.
+#[derive(Clone, Debug)]
+pub struct CMsgIter<'a> {
+    buf: &'a [u8],
+}
+
+/// A control message parsed by `CMsgIter`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CMsg<'a> {
+    /// The destination address and arriving interface index of an IPv4
+    /// datagram (`IP_PKTINFO`).
+    PktInfoV4(Ipv4Addr, u32),
+    /// The destination address and arriving interface index of an IPv6
+    /// datagram (`IPV6_PKTINFO`).
+    PktInfoV6(Ipv6Addr, u32),
+    /// The time to live of an IPv4 datagram (`IP_TTL`).
+    Ttl(u32),
+    /// The hop limit of an IPv6 datagram (`IPV6_HOPLIMIT`).
+    HopLimit(u32),
+    /// The ECN codepoint of a datagram (`IP_ECN` or `IPV6_ECN`).
+    Ecn(u8),
+    /// The size of the segments coalesced into a single receive
+    /// (`UDP_COALESCED_INFO`).
+    ///
+    /// The received data is made up of datagrams of this size, apart from
+    /// the last which may be shorter, so it can be split back into datagrams
+    /// with `chunks`.
+    CoalescedSize(u32),
+    /// The time at which a datagram was received (`SO_TIMESTAMP`), as a
+    /// `QueryPerformanceCounter` value.
+    ///
+    /// This is delivered once receive timestamps are enabled with
+    /// `UdpSocketExt::set_timestamping`.
+    Timestamp(u64),
+    /// A message of any other kind.
+    Other {
+        /// The protocol level of the message, such as `IPPROTO_IP`.
+        level: i32,
+        /// The type of the message within its level.
+        kind: i32,
+        /// The data of the message.
+        data: &'a [u8],
+    },
+}
+
+#[repr(C)]
+struct CMSGHDR {
+    cmsg_len: SIZE_T,
+    cmsg_level: INT,
+    cmsg_type: INT,
+}
+
 /// A type to represent a buffer in which an accepted socket's address will be
 /// stored.
 ///
@@ -43,18 +201,136 @@ pub struct AcceptAddrsBuf {
     _pad2: [u8; 16],
 }
 
+/// A compact variant of `AcceptAddrsBuf` only large enough for IPv4
+/// addresses.
+///
+/// This can be used in place of `AcceptAddrsBuf` for accepts on IPv4
+/// listeners to reduce the memory kept for each pending accept. Using it
+/// with an IPv6 listener causes the accept to fail.
+#[repr(C)]
+pub struct AcceptAddrsBufV4 {
+    local: SOCKADDR_IN,
+    _pad1: [u8; 16],
+    remote: SOCKADDR_IN,
+    _pad2: [u8; 16],
+}
+
+/// A compact variant of `AcceptAddrsBuf` only large enough for IPv6
+/// addresses.
+///
+/// This can be used in place of `AcceptAddrsBuf` for accepts on IPv6
+/// listeners to reduce the memory kept for each pending accept.
+#[repr(C)]
+pub struct AcceptAddrsBufV6 {
+    local: sockaddr_in6,
+    _pad1: [u8; 16],
+    remote: sockaddr_in6,
+    _pad2: [u8; 16],
+}
+
+/// A buffer which `accept_overlapped` can fill in with the local and remote
+/// addresses of the accepted connection.
+///
+/// This is implemented by `AcceptAddrsBuf`, which can hold addresses of any
+/// family, and the compact `AcceptAddrsBufV4` and `AcceptAddrsBufV6`.
+pub trait AcceptAddrsStorage: sealed::Sealed {
+    #[doc(hidden)]
+    fn args(&self) -> (PVOID, DWORD, DWORD, DWORD);
+}
+
+/// A socket which `accept_overlapped` can accept a connection into.
+///
+/// This is implemented by `SocketBuilder` and by the `TcpBuilder` type of the
+/// `net2` library.
+pub trait AcceptSocket: sealed::Sealed {
+    #[doc(hidden)]
+    fn accept_socket(&self) -> io::Result<SOCKET>;
+    #[doc(hidden)]
+    fn to_stream(&self) -> io::Result<TcpStream>;
+}
+
+// Traits which hand raw buffers or sockets to Winsock are only implemented
+// within this crate.
+mod sealed {
+    pub trait Sealed {}
+}
+
 /// The parsed return value of `AcceptAddrsBuf`.
 pub struct AcceptAddrs<'a> {
     local: LPSOCKADDR,
     local_len: c_int,
     remote: LPSOCKADDR,
     remote_len: c_int,
-    _data: &'a AcceptAddrsBuf,
+    unmap_v4: bool,
+    _data: marker::PhantomData<&'a [u8]>,
+}
+
+/// A buffer of data to be sent, layout compatible with the Winsock `WSABUF`
+/// structure.
+///
+/// Slices of this type can be passed directly to Winsock functions taking an
+/// array of `WSABUF`s for vectored I/O.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct WsaBuf<'a> {
+    raw: WSABUF,
+    _marker: marker::PhantomData<&'a [u8]>,
+}
+
+/// A buffer for data to be received into, layout compatible with the Winsock
+/// `WSABUF` structure.
+///
+/// Slices of this type can be passed directly to Winsock functions taking an
+/// array of `WSABUF`s for vectored I/O.
+#[repr(C)]
+pub struct WsaBufMut<'a> {
+    raw: WSABUF,
+    _marker: marker::PhantomData<&'a mut [u8]>,
+}
+
+unsafe impl<'a> Send for WsaBuf<'a> {}
+unsafe impl<'a> Sync for WsaBuf<'a> {}
+unsafe impl<'a> Send for WsaBufMut<'a> {}
+unsafe impl<'a> Sync for WsaBufMut<'a> {}
+
+/// The processor to which receive-side scaling delivers the packets of a
+/// socket, as returned by `TcpStreamExt::rss_processor`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RssProcessor {
+    group: u16,
+    number: u8,
+    numa_node: u16,
+}
+
+/// Statistics of a TCP connection, as returned by `TcpStreamExt::tcp_info`.
+#[derive(Clone, Copy, Debug)]
+pub struct TcpInfo(TCP_INFO_v0);
+
+/// A manual-reset event signaled when network events selected with
+/// `SocketExt::event_select` occur (via `WSACreateEvent`).
+#[derive(Debug)]
+pub struct SocketEvent {
+    handle: Handle,
 }
 
-struct WsaExtension {
+/// The network events which occurred on a socket, as returned by
+/// `SocketExt::network_events`.
+#[derive(Clone, Copy)]
+pub struct NetworkEvents(WSANETWORKEVENTS);
+
+// A Winsock extension function, whose pointer is provided by the service
+// provider of a socket, as different providers may implement the function
+// differently.
+//
+// On most systems every installed provider is a base provider implemented by
+// the same DLL, so the pointer is the same for every socket. It's then looked
+// up once and returned without inspecting each socket. Otherwise, for
+// example once a layered provider is installed, the pointer is looked up
+// once for each provider, identified by the catalog entry of its sockets.
+pub(crate) struct WsaExtension {
     guid: GUID,
-    val: AtomicUsize,
+    shared: AtomicUsize,
+    cache: RwLock<Vec<(DWORD, usize)>>,
 }
 
 /// Additional methods for the `TcpStream` type in the standard library.
@@ -72,7 +348,8 @@ pub trait TcpStreamExt {
     /// operation is returned and no overlapped operation is enqueued.
     ///
     /// The number of bytes read will be returned as part of the completion
-    /// notification when the I/O finishes.
+    /// notification when the I/O finishes. At most `u32::MAX` bytes are read
+    /// by a single operation, however large `buf` is.
     ///
     /// # Unsafety
     ///
@@ -89,6 +366,122 @@ pub trait TcpStreamExt {
                               buf: &mut [u8],
                               overlapped: &mut Overlapped) -> io::Result<bool>;
 
+    /// Execute an overlapped read I/O operation on this TCP stream into a
+    /// buffer which may be uninitialized.
+    ///
+    /// This is like `read_overlapped`, but saves zeroing a large receive
+    /// buffer beforehand. Once the operation has completed, the number of
+    /// bytes it reports at the start of `buf` have been initialized.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as `read_overlapped`.
+    unsafe fn read_overlapped_uninit(&self,
+                                     buf: &mut [MaybeUninit<u8>],
+                                     overlapped: &mut Overlapped)
+                                     -> io::Result<bool>;
+
+    /// Execute an overlapped read I/O operation on this TCP stream with the
+    /// specified flags (via `WSARecv`).
+    ///
+    /// This is like `read_overlapped`, except that `flags` is passed to the
+    /// receive, for example `MSG_PEEK` to copy data into `buf` without
+    /// removing it from the socket's receive buffer. If the operation
+    /// completes immediately `flags` is updated with the flags of the
+    /// received data, such as `MSG_PARTIAL`. Otherwise those flags are only
+    /// available through `result` once the operation completes, and `flags`
+    /// isn't accessed after this function returns.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as `read_overlapped`.
+    unsafe fn read_overlapped_with_flags(&self,
+                                         buf: &mut [u8],
+                                         flags: &mut u32,
+                                         overlapped: &mut Overlapped)
+                                         -> io::Result<bool>;
+
+    /// Execute an overlapped read I/O operation on this TCP stream which
+    /// only completes once `buf` has been filled.
+    ///
+    /// This passes `MSG_WAITALL` to `WSARecv`, so a fixed-length frame is
+    /// received with a single completion notification rather than a loop of
+    /// partial reads. The operation may still complete with fewer bytes if
+    /// the connection is closed or reset, or if the operation is canceled.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as `read_overlapped`.
+    unsafe fn read_exact_hint_overlapped(&self, buf: &mut [u8],
+                                         overlapped: &mut Overlapped)
+                                         -> io::Result<bool>;
+
+    /// Execute an overlapped zero-length read on this TCP stream, which
+    /// completes once data is available to read.
+    ///
+    /// This issues a `WSARecv` with an empty buffer, so no memory is pinned
+    /// while the operation is pending. Once it completes, with 0 bytes
+    /// transferred, the data that arrived can be read with a non-blocking or
+    /// overlapped read, making this a cheap readiness notification for
+    /// servers with many idle connections.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that the
+    /// `overlapped` pointer is valid until the end of the I/O operation. The
+    /// kernel also requires that `overlapped` is unique for this I/O
+    /// operation and is not in use for any other I/O.
+    unsafe fn read_ready_overlapped(&self, overlapped: &mut Overlapped)
+                                    -> io::Result<bool>;
+
+    /// Execute an overlapped read I/O operation on this TCP stream into
+    /// several buffers at once.
+    ///
+    /// This function will issue a single overlapped I/O read (via `WSARecv`)
+    /// which fills in `bufs` in order, each one being filled completely
+    /// before data is placed in the next. This avoids copying out of an
+    /// intermediate buffer when reading into segmented storage, such as the
+    /// two free regions of a ring buffer.
+    ///
+    /// The return value and the completion notification are the same as for
+    /// `read_overlapped`, with the number of bytes read being the total
+    /// across all buffers.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that the storage
+    /// referenced by `bufs` and the `overlapped` pointer are valid until the
+    /// end of the I/O operation. The kernel also requires that `overlapped`
+    /// is unique for this I/O operation and is not in use for any other I/O.
+    /// The `bufs` slice itself only needs to live until this function
+    /// returns.
+    ///
+    /// To safely use this function callers must ensure that these pointers
+    /// are valid until the I/O operation is completed, typically via
+    /// completion ports and waiting to receive the completion notification on
+    /// the port.
+    unsafe fn read_overlapped_vectored(&self,
+                                       bufs: &mut [WsaBufMut],
+                                       overlapped: &mut Overlapped)
+                                       -> io::Result<bool>;
+
+    /// Execute an overlapped vectored read I/O operation on this TCP stream
+    /// into `IoSliceMut`s.
+    ///
+    /// This is the same as `read_overlapped_vectored`, for buffers coming
+    /// from code written against the standard library's I/O traits. No
+    /// conversion takes place, as `IoSliceMut` has the same layout as
+    /// `WSABUF`.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as
+    /// `read_overlapped_vectored`.
+    unsafe fn read_overlapped_io_slices(&self,
+                                       bufs: &mut [IoSliceMut],
+                                       overlapped: &mut Overlapped)
+                                       -> io::Result<bool>;
+
     /// Execute an overlapped write I/O operation on this TCP stream.
     ///
     /// This function will issue an overlapped I/O write (via `WSASend`) on this
@@ -102,7 +495,9 @@ pub trait TcpStreamExt {
     /// operation is returned and no overlapped operation is enqueued.
     ///
     /// The number of bytes written will be returned as part of the completion
-    /// notification when the I/O finishes.
+    /// notification when the I/O finishes. At most `u32::MAX` bytes are
+    /// written by a single operation, so if `buf` is larger than that the
+    /// rest has to be written by another operation.
     ///
     /// # Unsafety
     ///
@@ -119,6 +514,52 @@ pub trait TcpStreamExt {
                                buf: &[u8],
                                overlapped: &mut Overlapped) -> io::Result<bool>;
 
+    /// Execute an overlapped write I/O operation on this TCP stream from
+    /// several buffers at once.
+    ///
+    /// This function will issue a single overlapped I/O write (via
+    /// `WSASend`) of the contents of `bufs` in order, so that for example a
+    /// protocol header and body can be sent without first being coalesced
+    /// into a temporary buffer.
+    ///
+    /// The return value and the completion notification are the same as for
+    /// `write_overlapped`, with the number of bytes written being the total
+    /// across all buffers.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that the data
+    /// referenced by `bufs` and the `overlapped` pointer are valid until the
+    /// end of the I/O operation. The kernel also requires that `overlapped`
+    /// is unique for this I/O operation and is not in use for any other I/O.
+    /// The `bufs` slice itself only needs to live until this function
+    /// returns.
+    ///
+    /// To safely use this function callers must ensure that these pointers
+    /// are valid until the I/O operation is completed, typically via
+    /// completion ports and waiting to receive the completion notification on
+    /// the port.
+    unsafe fn write_overlapped_vectored(&self,
+                                        bufs: &[WsaBuf],
+                                        overlapped: &mut Overlapped)
+                                        -> io::Result<bool>;
+
+    /// Execute an overlapped vectored write I/O operation on this TCP stream
+    /// from `IoSlice`s.
+    ///
+    /// This is the same as `write_overlapped_vectored`, for buffers coming
+    /// from code written against the standard library's I/O traits. No
+    /// conversion takes place, as `IoSlice` has the same layout as `WSABUF`.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as
+    /// `write_overlapped_vectored`.
+    unsafe fn write_overlapped_io_slices(&self,
+                                        bufs: &[IoSlice],
+                                        overlapped: &mut Overlapped)
+                                        -> io::Result<bool>;
+
     /// Execute a connection operation for this socket.
     ///
     /// For more information about this method, see the
@@ -128,6 +569,198 @@ pub trait TcpStreamExt {
     unsafe fn connect_overlapped(&self, addr: &SocketAddr,
                                  overlapped: &mut Overlapped)
                                  -> io::Result<bool>;
+
+    /// Queries the processor to which the network adapter delivers the
+    /// packets of this connection (via `SIO_QUERY_RSS_PROCESSOR_INFO`).
+    ///
+    /// Handling the completions of a connection on the same processor that
+    /// receives its packets avoids bouncing data between processor caches.
+    /// See `ShardedPorts::add_socket_rss` for placing a connection on the
+    /// matching shard.
+    ///
+    /// An error is returned if the adapter the connection runs over does not
+    /// support receive-side scaling, as is the case for loopback.
+    fn rss_processor(&self) -> io::Result<RssProcessor>;
+
+    /// Queries statistics of this connection, such as its round trip time,
+    /// congestion window and retransmissions (via `SIO_TCP_INFO`).
+    ///
+    /// This is cheap enough to be sampled periodically for telemetry on
+    /// long-lived connections, and is supported from Windows 10 version 1703
+    /// onwards.
+    fn tcp_info(&self) -> io::Result<TcpInfo>;
+
+    /// Queries the ideal send backlog of this connection (via
+    /// `SIO_IDEAL_SEND_BACKLOG_QUERY`).
+    ///
+    /// This is the number of bytes which should be outstanding in overlapped
+    /// writes at any time to keep the connection saturated without queueing
+    /// more data than needed, based on its current bandwidth and round trip
+    /// time.
+    fn ideal_send_backlog(&self) -> io::Result<u32>;
+
+    /// Requests a notification when the ideal send backlog of this
+    /// connection changes (via `SIO_IDEAL_SEND_BACKLOG_CHANGE`).
+    ///
+    /// The operation completes through the given `Overlapped` instance once
+    /// the value returned by `ideal_send_backlog` has changed, after which it
+    /// should be queried again and a new notification requested.
+    ///
+    /// If the operation succeeds, `Ok(true)` is returned. If the operation
+    /// returns an error indicating that the I/O is currently pending,
+    /// `Ok(false)` is returned. Otherwise, the error associated with the
+    /// operation is returned and no overlapped operation is enqueued.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that the
+    /// `overlapped` pointer is valid until the end of the I/O operation. The
+    /// kernel also requires that `overlapped` is unique for this I/O
+    /// operation and is not in use for any other I/O.
+    unsafe fn ideal_send_backlog_change_overlapped(&self,
+                                                   overlapped: &mut Overlapped)
+                                                   -> io::Result<bool>;
+
+    /// Configures TCP keepalive for this connection (via
+    /// `SIO_KEEPALIVE_VALS`).
+    ///
+    /// When `enable` is true, a keepalive probe is sent after the connection
+    /// has been idle for `time`, and repeated every `interval` until it's
+    /// acknowledged or the connection is considered dead, at which point
+    /// outstanding operations fail. Both durations are rounded up to
+    /// milliseconds.
+    ///
+    /// Unlike `SO_KEEPALIVE` this allows the timeouts to be tuned for this
+    /// connection alone, rather than taking the system defaults of two hours
+    /// and one second respectively.
+    fn set_keepalive_vals(&self,
+                          enable: bool,
+                          time: Duration,
+                          interval: Duration)
+                          -> io::Result<()>;
+
+    /// Gracefully closes this connection in an overlapped fashion (via
+    /// `DisconnectEx`).
+    ///
+    /// If `reuse` is `true` the socket is prepared for reuse once the
+    /// operation completes (`TF_REUSE_SOCKET`), after which it can be passed
+    /// to `accept_overlapped` or `connect_overlapped` again instead of being
+    /// closed and a new one created. High connection rate servers recycle
+    /// sockets this way to avoid the cost of socket creation.
+    ///
+    /// If the operation succeeds, `Ok(true)` is returned. If the operation
+    /// returns an error indicating that the I/O is currently pending,
+    /// `Ok(false)` is returned. Otherwise, the error associated with the
+    /// operation is returned and no overlapped operation is enqueued.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that the
+    /// `overlapped` pointer is valid until the end of the I/O operation. The
+    /// kernel also requires that `overlapped` is unique for this I/O operation
+    /// and is not in use for any other I/O.
+    ///
+    /// To safely use this function callers must ensure that this pointer is
+    /// valid until the I/O operation is completed, typically via completion
+    /// ports and waiting to receive the completion notification on the port.
+    unsafe fn disconnect_overlapped(&self, reuse: bool,
+                                    overlapped: &mut Overlapped)
+                                    -> io::Result<bool>;
+
+    /// Sends the contents of a file over this connection in an overlapped
+    /// fashion (via `TransmitFile`).
+    ///
+    /// The file data is sent by the kernel directly from the file system
+    /// cache without being copied through user space, preceded by `head` and
+    /// followed by `tail`, either of which may be empty. The file is sent
+    /// from the offset configured in `overlapped`, such as with
+    /// `Overlapped::set_offset`, to its end, and it must be open for
+    /// reading.
+    /// An `InvalidInput` error is returned if `head` or `tail` is larger than
+    /// 4 GiB, as they can't be sent in part.
+    ///
+    /// The `flags` are a combination of the `TF_*` constants in this module,
+    /// such as `TF_DISCONNECT` and `TF_REUSE_SOCKET` to close and recycle the
+    /// socket after the transfer, or 0.
+    ///
+    /// If the operation succeeds, `Ok(true)` is returned. If the operation
+    /// returns an error indicating that the I/O is currently pending,
+    /// `Ok(false)` is returned. Otherwise, the error associated with the
+    /// operation is returned and no overlapped operation is enqueued.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that the `head`,
+    /// `tail`, and `overlapped` pointers are valid until the end of the I/O
+    /// operation. The kernel also requires that `overlapped` is unique for
+    /// this I/O operation and is not in use for any other I/O.
+    ///
+    /// To safely use this function callers must ensure that these pointers
+    /// are valid until the I/O operation is completed, typically via
+    /// completion ports and waiting to receive the completion notification on
+    /// the port.
+    unsafe fn transmit_file_overlapped(&self,
+                                       file: &File,
+                                       head: &[u8],
+                                       tail: &[u8],
+                                       flags: u32,
+                                       overlapped: &mut Overlapped)
+                                       -> io::Result<bool>;
+
+    /// Finishes setting up a stream returned by `accept_overlapped` on
+    /// `listener`, once the accept has completed (via
+    /// `SO_UPDATE_ACCEPT_CONTEXT`).
+    ///
+    /// Until this is called the accepted socket doesn't inherit the
+    /// properties of the listening socket, and functions such as `peer_addr`,
+    /// `local_addr`, and `shutdown` fail on it.
+    fn accept_complete(&self, listener: &TcpListener) -> io::Result<()>;
+
+    /// Finishes setting up a stream whose `connect_overlapped` has completed
+    /// (via `SO_UPDATE_CONNECT_CONTEXT`).
+    ///
+    /// Until this is called after the connect completion has been dequeued,
+    /// functions such as `peer_addr`, `local_addr`, and `shutdown` don't
+    /// behave correctly on the stream.
+    fn connect_complete(&self) -> io::Result<()>;
+
+    /// Returns the reason a completed `connect_overlapped` tracked by
+    /// `overlapped` failed, or `None` if it succeeded.
+    ///
+    /// The completion of a failed connect only carries a generic error
+    /// translated from the status of the I/O, such as
+    /// `ERROR_CONNECTION_REFUSED`. This returns the Winsock error instead,
+    /// for example `WSAECONNREFUSED`, `WSAENETUNREACH`, or `WSAETIMEDOUT`,
+    /// which maps to the expected `io::ErrorKind`. It's a thin wrapper over
+    /// `result`, so an error with the code `WSA_IO_INCOMPLETE` is returned if
+    /// the connect hasn't completed yet.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as `result`, and
+    /// `overlapped` must have been used for a connect.
+    unsafe fn connect_error(&self, overlapped: &Overlapped)
+                            -> io::Result<Option<io::Error>>;
+
+    /// Fetches the result of an overlapped operation issued on this stream
+    /// (via `WSAGetOverlappedResult`), without waiting for it to complete.
+    ///
+    /// On success the number of bytes transferred and the flags of the
+    /// operation, such as `MSG_PARTIAL` for a receive, are returned. If the
+    /// operation hasn't completed yet an error with the code
+    /// `WSA_IO_INCOMPLETE` is returned.
+    ///
+    /// This is intended for callers that wait for operations to complete
+    /// without a completion port, for example through the event of the
+    /// `Overlapped`.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because `overlapped` must have been used for
+    /// an operation issued on this stream, which must not have been issued
+    /// again since.
+    unsafe fn result(&self, overlapped: &Overlapped)
+                     -> io::Result<(usize, u32)>;
 }
 
 /// Additional methods for the `UdpSocket` type in the standard library.
@@ -165,6 +798,29 @@ pub trait UdpSocketExt {
                                    overlapped: &mut Overlapped)
                                    -> io::Result<bool>;
 
+    /// Execute an overlapped receive I/O operation on this UDP socket with
+    /// the specified flags (via `WSARecvFrom`).
+    ///
+    /// This is like `recv_from_overlapped`, except that `flags` is passed to
+    /// the receive and reports whether the datagram was truncated. If the
+    /// operation completes immediately `flags` is updated with the flags of
+    /// the received data, and a datagram larger than `buf` has `MSG_PARTIAL`
+    /// set rather than failing with `WSAEMSGSIZE`; in both cases a completion
+    /// is still queued. Otherwise those flags are only available through
+    /// `recv_result` once the operation completes, and `flags` isn't accessed
+    /// after this function returns.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as
+    /// `recv_from_overlapped`.
+    unsafe fn recv_from_overlapped_with_flags(&self,
+                                              buf: &mut [u8],
+                                              addr: &mut SocketAddrBuf,
+                                              flags: &mut u32,
+                                              overlapped: &mut Overlapped)
+                                              -> io::Result<bool>;
+
     /// Execute an overlapped send I/O operation on this UDP socket.
     ///
     /// This function will issue an overlapped I/O write (via `WSASendTo`) on
@@ -196,24 +852,121 @@ pub trait UdpSocketExt {
                                  addr: &SocketAddr,
                                  overlapped: &mut Overlapped)
                                  -> io::Result<bool>;
-}
 
-/// Additional methods for the `TcpBuilder` type in the `net2` library.
-pub trait TcpBuilderExt {
-    /// Attempt to consume the internal socket in this builder by executing an
-    /// overlapped connect operation.
+    /// Execute an overlapped receive I/O operation on this connected UDP
+    /// socket.
     ///
-    /// This function will issue a connect operation to the address specified on
-    /// the underlying socket, flagging it as an overlapped operation which will
-    /// complete asynchronously. If successful this function will return the
-    /// corresponding TCP stream.
+    /// This function will issue an overlapped I/O read (via `WSARecv`) on
+    /// this socket, which must have been connected with `connect`. Unlike
+    /// `recv_from_overlapped` the address of the sender isn't returned, as
+    /// it's always the address the socket is connected to.
     ///
-    /// This function will also return whether the connect immediately
-    /// succeeded or not. If `false` is returned then the I/O operation is still
-    /// pending and will complete at a later date.
+    /// If the operation succeeds, `Ok(true)` is returned. If the operation
+    /// returns an error indicating that the I/O is currently pending,
+    /// `Ok(false)` is returned. Otherwise, the error associated with the
+    /// operation is returned and no overlapped operation is enqueued.
     ///
-    /// Note that to succeed this requires that the underlying socket has
-    /// previously been bound via a call to `bind` to a local address.
+    /// The number of bytes read will be returned as part of the completion
+    /// notification when the I/O finishes.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as
+    /// `recv_from_overlapped`.
+    unsafe fn recv_overlapped(&self,
+                              buf: &mut [u8],
+                              overlapped: &mut Overlapped)
+                              -> io::Result<bool>;
+
+    /// Execute an overlapped receive I/O operation on this UDP socket into a
+    /// buffer which may be uninitialized.
+    ///
+    /// This is like `recv_from_overlapped`, but saves zeroing a large
+    /// receive buffer beforehand. Once the operation has completed, the
+    /// number of bytes it reports at the start of `buf` have been
+    /// initialized.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as
+    /// `recv_from_overlapped`.
+    unsafe fn recv_from_overlapped_uninit(&self,
+                                          buf: &mut [MaybeUninit<u8>],
+                                          addr: &mut SocketAddrBuf,
+                                          overlapped: &mut Overlapped)
+                                          -> io::Result<bool>;
+
+    /// Execute an overlapped receive I/O operation on this connected UDP
+    /// socket into a buffer which may be uninitialized.
+    ///
+    /// This is like `recv_overlapped`, with the same caveats as
+    /// `recv_from_overlapped_uninit`.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as
+    /// `recv_from_overlapped`.
+    unsafe fn recv_overlapped_uninit(&self,
+                                     buf: &mut [MaybeUninit<u8>],
+                                     overlapped: &mut Overlapped)
+                                     -> io::Result<bool>;
+
+    /// Execute an overlapped send I/O operation on this connected UDP
+    /// socket.
+    ///
+    /// This function will issue an overlapped I/O write (via `WSASend`) on
+    /// this socket to the address it has been connected to with `connect`.
+    ///
+    /// If the operation succeeds, `Ok(true)` is returned. If the operation
+    /// returns an error indicating that the I/O is currently pending,
+    /// `Ok(false)` is returned. Otherwise, the error associated with the
+    /// operation is returned and no overlapped operation is enqueued.
+    ///
+    /// The number of bytes written will be returned as part of the completion
+    /// notification when the I/O finishes.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as `send_to_overlapped`.
+    unsafe fn send_overlapped(&self,
+                              buf: &[u8],
+                              overlapped: &mut Overlapped)
+                              -> io::Result<bool>;
+
+    /// Configures whether ICMP "port unreachable" messages are reported on
+    /// this socket (via `SIO_UDP_CONNRESET`).
+    ///
+    /// By default when a datagram sent from this socket elicits a "port
+    /// unreachable" response, the next receive on this socket fails with
+    /// `WSAECONNRESET`. For a server receiving from many peers this error is
+    /// rarely meaningful and only breaks the receive loop, so it is commonly
+    /// disabled by passing `false`.
+    fn set_connreset(&self, enable: bool) -> io::Result<()>;
+
+    /// Configures whether ICMP "TTL expired" messages are reported on this
+    /// socket (via `SIO_UDP_NETRESET`).
+    ///
+    /// Like `set_connreset`, when enabled these messages cause the next
+    /// receive on this socket to fail, in this case with `WSAENETRESET`.
+    fn set_netreset(&self, enable: bool) -> io::Result<()>;
+
+    /// Queries the local address of the interface which traffic to `addr`
+    /// would currently be sent over (via `SIO_ROUTING_INTERFACE_QUERY`).
+    fn routing_interface(&self, addr: &SocketAddr) -> io::Result<SocketAddr>;
+
+    /// Requests a notification when the interface used to reach `addr`
+    /// changes (via `SIO_ROUTING_INTERFACE_CHANGE`).
+    ///
+    /// The notification is delivered as the completion of this overlapped
+    /// operation, after which `routing_interface` can be used to determine
+    /// the new interface and rebind the socket if needed. Passing an
+    /// unspecified address requests a notification when any interface of
+    /// this socket's address family changes.
+    ///
+    /// If the operation succeeds, `Ok(true)` is returned. If the operation
+    /// returns an error indicating that the I/O is currently pending,
+    /// `Ok(false)` is returned. Otherwise, the error associated with the
+    /// operation is returned and no overlapped operation is enqueued.
     ///
     /// # Unsafety
     ///
@@ -225,12 +978,411 @@ pub trait TcpBuilderExt {
     /// To safely use this function callers must ensure that this pointer is
     /// valid until the I/O operation is completed, typically via completion
     /// ports and waiting to receive the completion notification on the port.
-    unsafe fn connect_overlapped(&self, addr: &SocketAddr,
-                                 overlapped: &mut Overlapped)
-                                 -> io::Result<(TcpStream, bool)>;
-}
+    unsafe fn routing_interface_change_overlapped(&self,
+                                                  addr: &SocketAddr,
+                                                  overlapped: &mut Overlapped)
+                                                  -> io::Result<bool>;
 
-/// Additional methods for the `TcpListener` type in the standard library.
+    /// Restricts outgoing IPv4 traffic of this socket to the interface with
+    /// the specified index (via `IP_UNICAST_IF`), regardless of the routing
+    /// table.
+    ///
+    /// This lets multi-homed hosts and VPN-aware applications force traffic
+    /// out a particular interface without binding to one of its addresses.
+    /// An index of 0 restores the default of following the routing table.
+    fn set_unicast_interface_v4(&self, index: u32) -> io::Result<()>;
+
+    /// Restricts outgoing IPv6 traffic of this socket to the interface with
+    /// the specified index (via `IPV6_UNICAST_IF`).
+    ///
+    /// See `set_unicast_interface_v4` for more details.
+    fn set_unicast_interface_v6(&self, index: u32) -> io::Result<()>;
+
+    /// Returns the index of the interface outgoing IPv4 traffic of this
+    /// socket is restricted to, or 0 if it follows the routing table.
+    fn unicast_interface_v4(&self) -> io::Result<u32>;
+
+    /// Returns the index of the interface outgoing IPv6 traffic of this
+    /// socket is restricted to, or 0 if it follows the routing table.
+    fn unicast_interface_v6(&self) -> io::Result<u32>;
+
+    /// Joins the multicast group `group` on the interface with the specified
+    /// index (via `MCAST_JOIN_GROUP`), so datagrams sent to the group are
+    /// received by this socket.
+    ///
+    /// An interface index of 0 lets the system choose the interface. Both
+    /// IPv4 and IPv6 groups are supported, matching the address family of
+    /// this socket.
+    fn join_multicast_group(&self, group: &IpAddr, interface: u32)
+                            -> io::Result<()>;
+
+    /// Leaves a multicast group previously joined with
+    /// `join_multicast_group` (via `MCAST_LEAVE_GROUP`).
+    fn leave_multicast_group(&self, group: &IpAddr, interface: u32)
+                             -> io::Result<()>;
+
+    /// Joins the multicast group `group` on the interface with the specified
+    /// index, only receiving datagrams sent to it from `source` (via
+    /// `MCAST_JOIN_SOURCE_GROUP`).
+    ///
+    /// This is source-specific multicast, and may be called several times
+    /// for the same group to receive from multiple sources. The `group` and
+    /// `source` must be of the same address family.
+    fn join_source_group(&self, group: &IpAddr, source: &IpAddr,
+                         interface: u32) -> io::Result<()>;
+
+    /// Stops receiving datagrams from `source` sent to a group previously
+    /// joined with `join_source_group` (via `MCAST_LEAVE_SOURCE_GROUP`).
+    fn leave_source_group(&self, group: &IpAddr, source: &IpAddr,
+                          interface: u32) -> io::Result<()>;
+
+    /// Configures the interface with the specified index as the one that
+    /// IPv4 multicast datagrams sent from this socket go out of (via
+    /// `IP_MULTICAST_IF`).
+    ///
+    /// An index of 0 restores the system default interface.
+    fn set_multicast_interface_v4(&self, index: u32) -> io::Result<()>;
+
+    /// Configures the interface with the specified index as the one that
+    /// IPv6 multicast datagrams sent from this socket go out of (via
+    /// `IPV6_MULTICAST_IF`).
+    ///
+    /// An index of 0 restores the system default interface.
+    fn set_multicast_interface_v6(&self, index: u32) -> io::Result<()>;
+
+    /// Execute an overlapped receive I/O operation on this UDP socket which
+    /// also receives control data (via `WSARecvMsg`).
+    ///
+    /// This is like `recv_from_overlapped`, except that ancillary data about
+    /// the datagram is written to `control` when the operation completes.
+    /// Which data is delivered is configured with socket options, such as
+    /// `set_recv_pktinfo_v4` for the destination address and interface of the
+    /// datagram, or `set_recv_ecn_v4` for its ECN codepoint. The source
+    /// address, the length of the control data, and the message flags are
+    /// written to `msg`.
+    ///
+    /// If the operation succeeds, `Ok(true)` is returned. If the operation
+    /// returns an error indicating that the I/O is currently pending,
+    /// `Ok(false)` is returned. Otherwise, the error associated with the
+    /// operation is returned and no overlapped operation is enqueued.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that the `buf`,
+    /// `control`, `msg`, and `overlapped` pointers are valid until the end of
+    /// the I/O operation. The kernel also requires that `overlapped` is
+    /// unique for this I/O operation and is not in use for any other I/O.
+    ///
+    /// To safely use this function callers must ensure that these pointers
+    /// are valid until the I/O operation is completed, typically via
+    /// completion ports and waiting to receive the completion notification on
+    /// the port.
+    unsafe fn recv_msg_overlapped(&self,
+                                  buf: &mut [u8],
+                                  control: &mut [u8],
+                                  msg: &mut MsgBuf,
+                                  overlapped: &mut Overlapped)
+                                  -> io::Result<bool>;
+
+    /// Execute an overlapped send I/O operation on this UDP socket which
+    /// also sends control data (via `WSASendMsg`).
+    ///
+    /// This is like `send_to_overlapped`, except that the ancillary data in
+    /// `control` is attached to the datagram. For example an `IP_PKTINFO`
+    /// control message selects the source address and outgoing interface of
+    /// the datagram. The `msg` holds the message header for the duration of
+    /// the operation.
+    ///
+    /// If the operation succeeds, `Ok(true)` is returned. If the operation
+    /// returns an error indicating that the I/O is currently pending,
+    /// `Ok(false)` is returned. Otherwise, the error associated with the
+    /// operation is returned and no overlapped operation is enqueued.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that the `buf`,
+    /// `control`, `msg`, and `overlapped` pointers are valid until the end of
+    /// the I/O operation. The kernel also requires that `overlapped` is
+    /// unique for this I/O operation and is not in use for any other I/O.
+    ///
+    /// To safely use this function callers must ensure that these pointers
+    /// are valid until the I/O operation is completed, typically via
+    /// completion ports and waiting to receive the completion notification on
+    /// the port.
+    unsafe fn send_msg_overlapped(&self,
+                                  buf: &[u8],
+                                  addr: &SocketAddr,
+                                  control: &[u8],
+                                  msg: &mut MsgBuf,
+                                  overlapped: &mut Overlapped)
+                                  -> io::Result<bool>;
+
+    /// Configures whether the destination address and arriving interface of
+    /// IPv4 datagrams are delivered as `IP_PKTINFO` control data to
+    /// `recv_msg_overlapped`.
+    fn set_recv_pktinfo_v4(&self, enable: bool) -> io::Result<()>;
+
+    /// Configures whether the destination address and arriving interface of
+    /// IPv6 datagrams are delivered as `IPV6_PKTINFO` control data to
+    /// `recv_msg_overlapped`.
+    fn set_recv_pktinfo_v6(&self, enable: bool) -> io::Result<()>;
+
+    /// Configures whether the ECN codepoint of IPv4 datagrams is delivered as
+    /// `IP_ECN` control data to `recv_msg_overlapped`.
+    fn set_recv_ecn_v4(&self, enable: bool) -> io::Result<()>;
+
+    /// Configures whether the ECN codepoint of IPv6 datagrams is delivered as
+    /// `IPV6_ECN` control data to `recv_msg_overlapped`.
+    fn set_recv_ecn_v6(&self, enable: bool) -> io::Result<()>;
+
+    /// Sets the segment size used to split each send on this socket into
+    /// multiple datagrams (`UDP_SEND_MSG_SIZE`).
+    ///
+    /// With a non-zero size each buffer sent is split into datagrams of
+    /// that size, with the last datagram holding the remainder, which lets
+    /// the network stack or adapter segment them instead of each being sent
+    /// separately. A size of 0 disables segmentation. The size can also be
+    /// set for a single `send_msg_overlapped` through
+    /// `CMsgBuf::push_send_msg_size`.
+    fn set_send_msg_size(&self, size: u32) -> io::Result<()>;
+
+    /// Gets the value of the `UDP_SEND_MSG_SIZE` option on this socket.
+    ///
+    /// For more information about this option, see `set_send_msg_size`.
+    fn send_msg_size(&self) -> io::Result<u32>;
+
+    /// Sets the maximum size of the data received on this socket which may
+    /// be coalesced from multiple datagrams (`UDP_RECV_MAX_COALESCED_SIZE`).
+    ///
+    /// With a non-zero size datagrams of the same size from the same sender
+    /// may be delivered together by a single `recv_msg_overlapped`, with the
+    /// size of the datagrams delivered as `CMsg::CoalescedSize` control data.
+    /// A size of 0 disables coalescing.
+    fn set_recv_max_coalesced_size(&self, size: u32) -> io::Result<()>;
+
+    /// Gets the value of the `UDP_RECV_MAX_COALESCED_SIZE` option on this
+    /// socket.
+    ///
+    /// For more information about this option, see
+    /// `set_recv_max_coalesced_size`.
+    fn recv_max_coalesced_size(&self) -> io::Result<u32>;
+
+    /// Configures timestamping of the datagrams sent and received on this
+    /// socket (via `SIO_TIMESTAMPING`).
+    ///
+    /// `flags` is a combination of `TIMESTAMPING_FLAG_RX`, which delivers the
+    /// time each datagram was received as `CMsg::Timestamp` control data to
+    /// `recv_msg_overlapped`, and `TIMESTAMPING_FLAG_TX`, which records the
+    /// time each datagram is sent for retrieval with `tx_timestamp`. Up to
+    /// `tx_buffered` send timestamps are kept until they're retrieved, after
+    /// which further ones are dropped.
+    ///
+    /// Timestamps are `QueryPerformanceCounter` values. This isn't supported
+    /// by older versions of Windows, on which an error is returned.
+    fn set_timestamping(&self, flags: u32, tx_buffered: u16)
+                        -> io::Result<()>;
+
+    /// Returns the identifier of the send timestamp which will be recorded
+    /// for the next datagram sent on this socket (`SO_TIMESTAMP_ID`).
+    ///
+    /// This is read before issuing a send so its timestamp can later be
+    /// retrieved with `tx_timestamp`.
+    fn timestamp_id(&self) -> io::Result<u32>;
+
+    /// Retrieves the time at which the datagram with the send timestamp `id`
+    /// was sent (via `SIO_GET_TX_TIMESTAMP`), as a `QueryPerformanceCounter`
+    /// value.
+    ///
+    /// Send timestamps must have been enabled with `set_timestamping`. If the
+    /// timestamp isn't available yet an error of the kind `WouldBlock` is
+    /// returned, and each timestamp can only be retrieved once.
+    fn tx_timestamp(&self, id: u32) -> io::Result<u64>;
+
+    /// Fetches the result of an overlapped operation issued on this socket
+    /// (via `WSAGetOverlappedResult`), without waiting for it to complete.
+    ///
+    /// On success the number of bytes transferred and the flags of the
+    /// operation, such as `MSG_PARTIAL` for a receive, are returned. If the
+    /// operation hasn't completed yet an error with the code
+    /// `WSA_IO_INCOMPLETE` is returned.
+    ///
+    /// This is intended for callers that wait for operations to complete
+    /// without a completion port, for example through the event of the
+    /// `Overlapped`, or to recover the flags of a completion dequeued from a
+    /// port.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because `overlapped` must have been used for
+    /// an operation issued on this socket, which must not have been issued
+    /// again since.
+    unsafe fn result(&self, overlapped: &Overlapped)
+                     -> io::Result<(usize, u32)>;
+
+    /// Fetches the result of an overlapped receive issued on this socket,
+    /// like `result`.
+    ///
+    /// A receive of a datagram larger than its buffer is reported as a
+    /// success with `MSG_PARTIAL` set, along with the number of bytes which
+    /// fit in the buffer, even though the completion itself carries the
+    /// error `WSAEMSGSIZE`.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as `result`, and
+    /// `overlapped` must have been used for a receive.
+    unsafe fn recv_result(&self, overlapped: &Overlapped)
+                          -> io::Result<(usize, u32)>;
+}
+
+/// Additional methods for the `TcpBuilder` type in the `net2` library, also
+/// implemented by `SocketBuilder`.
+pub trait TcpBuilderExt {
+    /// Attempt to consume the internal socket in this builder by executing an
+    /// overlapped connect operation.
+    ///
+    /// This function will issue a connect operation to the address specified on
+    /// the underlying socket, flagging it as an overlapped operation which will
+    /// complete asynchronously. If successful this function will return the
+    /// corresponding TCP stream.
+    ///
+    /// This function will also return whether the connect immediately
+    /// succeeded or not. If `false` is returned then the I/O operation is still
+    /// pending and will complete at a later date.
+    ///
+    /// Note that to succeed this requires that the underlying socket has
+    /// previously been bound via a call to `bind` to a local address, which
+    /// `connect_overlapped_auto_bind` takes care of.
+    ///
+    /// The socket is only taken out of the builder once the operation has
+    /// been issued. If an error is returned instead, the builder still owns
+    /// the socket, so the connect can be retried with the same builder, for
+    /// example after binding it.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that the
+    /// `overlapped` pointer is valid until the end of the I/O operation. The
+    /// kernel also requires that `overlapped` is unique for this I/O operation
+    /// and is not in use for any other I/O.
+    ///
+    /// To safely use this function callers must ensure that this pointer is
+    /// valid until the I/O operation is completed, typically via completion
+    /// ports and waiting to receive the completion notification on the port.
+    unsafe fn connect_overlapped(&self, addr: &SocketAddr,
+                                 overlapped: &mut Overlapped)
+                                 -> io::Result<(TcpStream, bool)>;
+
+    /// Attempt to consume the internal socket in this builder by executing an
+    /// overlapped connect operation which also sends the first data of the
+    /// connection.
+    ///
+    /// This is like `connect_overlapped`, except that `buf` is sent once the
+    /// connection has been established as part of the same operation (via
+    /// `ConnectEx`), saving a separate write for protocols whose client
+    /// speaks first. With TCP Fast Open enabled the data may even be carried
+    /// in the SYN.
+    ///
+    /// If the connect succeeds immediately the number of bytes sent is
+    /// returned as `Some`. If the I/O is pending `None` is returned and the
+    /// number of bytes sent is instead reported by the completion
+    /// notification.
+    ///
+    /// Note that to succeed this requires that the underlying socket has
+    /// previously been bound via a call to `bind` to a local address. As with
+    /// `connect_overlapped`, the builder keeps its socket if an error is
+    /// returned.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that the `buf` and
+    /// `overlapped` pointers are valid until the end of the I/O operation.
+    /// The kernel also requires that `overlapped` is unique for this I/O
+    /// operation and is not in use for any other I/O.
+    ///
+    /// To safely use this function callers must ensure that these pointers
+    /// are valid until the I/O operation is completed, typically via
+    /// completion ports and waiting to receive the completion notification on
+    /// the port.
+    unsafe fn connect_overlapped_with_data(&self, addr: &SocketAddr,
+                                           buf: &[u8],
+                                           overlapped: &mut Overlapped)
+                                           -> io::Result<(TcpStream,
+                                                          Option<usize>)>;
+
+    /// Attempt to consume the internal socket in this builder by executing an
+    /// overlapped connect operation, binding the socket first if needed.
+    ///
+    /// This is like `connect_overlapped`, except that a socket which hasn't
+    /// been bound yet is first bound to the unspecified address of the same
+    /// family as `addr` with port 0, as `ConnectEx` requires. A socket which
+    /// is already bound is left as it is.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as `connect_overlapped`.
+    unsafe fn connect_overlapped_auto_bind(&self, addr: &SocketAddr,
+                                           overlapped: &mut Overlapped)
+                                           -> io::Result<(TcpStream, bool)>;
+
+    /// Configures the initial retransmission timeout and the number of SYN
+    /// retransmissions used when connecting this socket (via
+    /// `SIO_TCP_INITIAL_RTO`).
+    ///
+    /// The `rtt` is the initial round trip time estimate from which the
+    /// retransmission timeout is derived, and `max_syn_retransmissions` is
+    /// the number of times the SYN is retransmitted before the connection
+    /// attempt fails. Values of `None` leave the respective setting
+    /// unchanged. Lowering these lets latency-sensitive clients on lossy
+    /// links detect a lost SYN much sooner than the system default of
+    /// several seconds.
+    ///
+    /// This must be called before the socket is connected.
+    fn set_initial_rto(&self,
+                       rtt: Option<Duration>,
+                       max_syn_retransmissions: Option<u8>)
+                       -> io::Result<()>;
+
+    /// Restricts outgoing IPv4 traffic of this socket to the interface with
+    /// the specified index (via `IP_UNICAST_IF`), regardless of the routing
+    /// table.
+    ///
+    /// This must be called before the socket is connected, such as with
+    /// `connect_overlapped`, to affect the route of the connection.
+    ///
+    /// This lets multi-homed hosts and VPN-aware applications force traffic
+    /// out a particular interface without binding to one of its addresses.
+    /// An index of 0 restores the default of following the routing table.
+    fn set_unicast_interface_v4(&self, index: u32) -> io::Result<()>;
+
+    /// Restricts outgoing IPv6 traffic of this socket to the interface with
+    /// the specified index (via `IPV6_UNICAST_IF`).
+    ///
+    /// See `set_unicast_interface_v4` for more details.
+    fn set_unicast_interface_v6(&self, index: u32) -> io::Result<()>;
+
+    /// Returns the index of the interface outgoing IPv4 traffic of this
+    /// socket is restricted to, or 0 if it follows the routing table.
+    fn unicast_interface_v4(&self) -> io::Result<u32>;
+
+    /// Returns the index of the interface outgoing IPv6 traffic of this
+    /// socket is restricted to, or 0 if it follows the routing table.
+    fn unicast_interface_v6(&self) -> io::Result<u32>;
+
+    /// Configures whether connections of this socket take the loopback fast
+    /// path (via `SIO_LOOPBACK_FAST_PATH`).
+    ///
+    /// When both ends of a loopback connection enable the fast path, data is
+    /// passed between them without going through most of the TCP/IP stack,
+    /// which significantly lowers the latency and raises the throughput of
+    /// local IPC. It has no effect on connections to other hosts.
+    ///
+    /// This must be called before the socket is connected or starts
+    /// listening.
+    fn set_loopback_fast_path(&self, enable: bool) -> io::Result<()>;
+}
+
+/// Additional methods for the `TcpListener` type in the standard library.
 pub trait TcpListenerExt {
     /// Perform an accept operation on this listener, accepting a connection in
     /// an overlapped fashion.
@@ -248,6 +1400,11 @@ pub trait TcpListenerExt {
     /// false)` is returned. Otherwise, the error associated with the operation
     /// is returned and no overlapped operation is enqueued.
     ///
+    /// The builder's socket is only consumed once the operation has been
+    /// issued, so if an error is returned it is left in the builder and can be
+    /// passed to another call. An error is also returned, rather than a panic,
+    /// if the builder's socket has already been consumed.
+    ///
     /// # Unsafety
     ///
     /// This function is unsafe because the kernel requires that the
@@ -258,70 +1415,828 @@ pub trait TcpListenerExt {
     /// To safely use this function callers must ensure that the pointers are
     /// valid until the I/O operation is completed, typically via completion
     /// ports and waiting to receive the completion notification on the port.
-    unsafe fn accept_overlapped(&self,
-                                socket: &TcpBuilder,
-                                addrs: &mut AcceptAddrsBuf,
-                                overlapped: &mut Overlapped)
-                                -> io::Result<(TcpStream, bool)>;
-}
+    unsafe fn accept_overlapped<S, A>(&self,
+                                      socket: &S,
+                                      addrs: &mut A,
+                                      overlapped: &mut Overlapped)
+                                      -> io::Result<(TcpStream, bool)>
+        where S: AcceptSocket + ?Sized, A: AcceptAddrsStorage;
 
-#[doc(hidden)]
-trait NetInt {
-    fn from_be(i: Self) -> Self;
-    fn to_be(&self) -> Self;
-}
-macro_rules! doit {
-    ($($t:ident)*) => ($(impl NetInt for $t {
-        fn from_be(i: Self) -> Self { <$t>::from_be(i) }
-        fn to_be(&self) -> Self { <$t>::to_be(*self) }
-    })*)
+    /// Perform an accept operation on this listener into a socket owned by
+    /// the caller, accepting a connection in an overlapped fashion.
+    ///
+    /// This is the same as `accept_overlapped`, except that `socket` is any
+    /// configured but not bound or connected socket, such as one created by
+    /// another library, and it isn't consumed. The caller remains responsible
+    /// for closing it, and once the accept has completed successfully it
+    /// should be updated with `TcpStreamExt::accept_complete` before use.
+    ///
+    /// If the accept succeeds immediately, `Ok(true)` is returned. If the
+    /// I/O is currently pending, `Ok(false)` is returned. Otherwise, the error
+    /// associated with the operation is returned and no overlapped operation
+    /// is enqueued.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that the
+    /// `addrs` and `overlapped` pointers are valid until the end of the I/O
+    /// operation, and that `socket` isn't closed before then. The kernel also
+    /// requires that `overlapped` is unique for this I/O operation and is not
+    /// in use for any other I/O.
+    unsafe fn accept_overlapped_raw<A>(&self,
+                                       socket: RawSocket,
+                                       addrs: &mut A,
+                                       overlapped: &mut Overlapped)
+                                       -> io::Result<bool>
+        where A: AcceptAddrsStorage;
+
+    /// Configures whether accepted connections take the loopback fast path
+    /// (via `SIO_LOOPBACK_FAST_PATH`).
+    ///
+    /// See `TcpBuilderExt::set_loopback_fast_path` for more details. This
+    /// must be called before any connections are accepted.
+    fn set_loopback_fast_path(&self, enable: bool) -> io::Result<()>;
 }
-doit! { i8 i16 i32 i64 isize u8 u16 u32 u64 usize }
 
-// fn hton<I: NetInt>(i: I) -> I { i.to_be() }
-fn ntoh<I: NetInt>(i: I) -> I { I::from_be(i) }
+/// Additional methods for any type which wraps a socket.
+///
+/// This provides overlapped reads and writes on sockets which aren't one of
+/// the types in `std::net`, such as raw or ICMP sockets, or sockets owned by
+/// other libraries. For a `TcpStream` they behave like `read_overlapped`
+/// and `write_overlapped` of `TcpStreamExt`.
+pub trait SocketExt {
+    /// Execute an overlapped read I/O operation on this socket.
+    ///
+    /// This function will issue an overlapped I/O read (via `WSARecv`) on
+    /// this socket. The provided buffer will be filled in when the operation
+    /// completes and the given `Overlapped` instance is used to track the
+    /// overlapped operation.
+    ///
+    /// If the operation succeeds, `Ok(true)` is returned. If the operation
+    /// returns an error indicating that the I/O is currently pending,
+    /// `Ok(false)` is returned. Otherwise, the error associated with the
+    /// operation is returned and no overlapped operation is enqueued.
+    ///
+    /// The number of bytes read will be returned as part of the completion
+    /// notification when the I/O finishes. At most `u32::MAX` bytes are read
+    /// by a single operation, however large `buf` is.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that the `buf` and
+    /// `overlapped` pointers are valid until the end of the I/O operation. The
+    /// kernel also requires that `overlapped` is unique for this I/O operation
+    /// and is not in use for any other I/O.
+    ///
+    /// To safely use this function callers must ensure that these two input
+    /// pointers are valid until the I/O operation is completed, typically via
+    /// completion ports and waiting to receive the completion notification on
+    /// the port.
+    unsafe fn recv_overlapped_raw(&self,
+                                  buf: &mut [u8],
+                                  overlapped: &mut Overlapped)
+                                  -> io::Result<bool>;
 
-fn last_err() -> io::Result<bool> {
-    let err = unsafe { WSAGetLastError() };
-    if err == WSA_IO_PENDING as i32 {
-        Ok(false)
-    } else {
-        Err(io::Error::from_raw_os_error(err))
-    }
+    /// Execute an overlapped write I/O operation on this socket.
+    ///
+    /// This function will issue an overlapped I/O write (via `WSASend`) on
+    /// this socket. The provided buffer will be written when the operation
+    /// completes and the given `Overlapped` instance is used to track the
+    /// overlapped operation.
+    ///
+    /// If the operation succeeds, `Ok(true)` is returned. If the operation
+    /// returns an error indicating that the I/O is currently pending,
+    /// `Ok(false)` is returned. Otherwise, the error associated with the
+    /// operation is returned and no overlapped operation is enqueued.
+    ///
+    /// The number of bytes written will be returned as part of the completion
+    /// notification when the I/O finishes. At most `u32::MAX` bytes are
+    /// written by a single operation, so if `buf` is larger than that the
+    /// rest has to be written by another operation.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as
+    /// `recv_overlapped_raw`.
+    unsafe fn send_overlapped_raw(&self,
+                                  buf: &[u8],
+                                  overlapped: &mut Overlapped)
+                                  -> io::Result<bool>;
+
+    /// Returns the base service provider socket underlying this socket (via
+    /// `SIO_BASE_HANDLE`).
+    ///
+    /// Layered service providers installed on the system may wrap the sockets
+    /// returned by Winsock, and often don't deliver completions to the ports
+    /// such a socket is associated with. Associating the base socket returned
+    /// here with a completion port instead avoids this. When no layered
+    /// provider is installed this is the socket itself.
+    fn base_socket(&self) -> io::Result<RawSocket>;
+
+    /// Prepares this socket to be shared with the process `pid` (via
+    /// `WSADuplicateSocketW`).
+    ///
+    /// The returned structure is sent to the target process, through any
+    /// means of interprocess communication, which then creates its own
+    /// handle to the socket with `from_protocol_info`. Both processes can use
+    /// the socket independently afterwards, including for overlapped I/O on
+    /// their own completion ports, and it is closed once every process has
+    /// closed its handle.
+    fn duplicate_socket(&self, pid: u32) -> io::Result<WSAPROTOCOL_INFOW>;
+
+    /// Requests that `event` be signaled when any of the network `events`
+    /// occur on this socket (via `WSAEventSelect`).
+    ///
+    /// The events are a combination of the `FD_*` constants in this module,
+    /// such as `FD_ACCEPT | FD_CLOSE`, and replace any events previously
+    /// selected for this socket. Selecting no events cancels the request.
+    ///
+    /// Overlapped operations can still be issued on the socket while events
+    /// are selected, which allows readiness of a listener or the closing of
+    /// a connection to be noticed without an operation outstanding. Note
+    /// that this also switches the socket to nonblocking mode, which stays in
+    /// effect until no events are selected and the socket is switched back.
+    fn event_select(&self, event: &SocketEvent, events: u32)
+                    -> io::Result<()>;
+
+    /// Returns the network events which occurred on this socket since the
+    /// last call (via `WSAEnumNetworkEvents`).
+    ///
+    /// The events are cleared once returned, and if `event` is specified it
+    /// is reset as well.
+    fn network_events(&self, event: Option<&SocketEvent>)
+                      -> io::Result<NetworkEvents>;
+
+    /// Configures this socket to not post a completion to its port for
+    /// overlapped operations which complete immediately (via
+    /// `SetFileCompletionNotificationModes`).
+    ///
+    /// Once this is set, an operation returning `Ok(true)` is already
+    /// complete, so the caller must handle its completion right away as none
+    /// is dequeued from the port for it, which saves a round trip through the
+    /// port on hot paths. Operations returning `Ok(false)` still complete
+    /// through the port. The event of the socket isn't signaled by operations
+    /// either. This can't be turned off again for the socket.
+    ///
+    /// Sockets of layered service providers which don't hand out real file
+    /// system handles may then lose completions of operations which pend,
+    /// so for those nothing is changed and `Ok(false)` is returned. Otherwise
+    /// `Ok(true)` is returned once the mode is set.
+    fn set_skip_completion_port_on_success(&self) -> io::Result<bool>;
+
+    /// Configures whether this socket is inherited by child processes (via
+    /// `SetHandleInformation`).
+    ///
+    /// Sockets created by this crate aren't inheritable, but those created
+    /// elsewhere may be, leaking them into every process spawned with handle
+    /// inheritance enabled.
+    fn set_inheritable(&self, inheritable: bool) -> io::Result<()>;
+
+    /// Returns whether this socket is inherited by child processes.
+    ///
+    /// For more information about this option, see `set_inheritable`.
+    fn inheritable(&self) -> io::Result<bool>;
+
+    /// Execute an overlapped I/O control operation on this socket (via
+    /// `WSAIoctl`).
+    ///
+    /// This issues the control code `code` with `input` as its input buffer
+    /// and `output` as its output buffer, for codes which this crate doesn't
+    /// wrap, such as vendor specific `SIO_*` codes. The `Overlapped`
+    /// instance is used to track the operation.
+    ///
+    /// If the operation succeeds, `Ok(true)` is returned. If the operation
+    /// returns an error indicating that the I/O is currently pending,
+    /// `Ok(false)` is returned. Otherwise, the error associated with the
+    /// operation is returned and no overlapped operation is enqueued.
+    ///
+    /// The number of bytes written to `output` will be returned as part of
+    /// the completion notification when the I/O finishes.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that the `input`,
+    /// `output` and `overlapped` pointers are valid until the end of the I/O
+    /// operation. The kernel also requires that `overlapped` is unique for
+    /// this I/O operation and is not in use for any other I/O. Callers must
+    /// also ensure that `input` and `output` are what `code` expects.
+    ///
+    /// To safely use this function callers must ensure that these pointers
+    /// are valid until the I/O operation is completed, typically via
+    /// completion ports and waiting to receive the completion notification on
+    /// the port.
+    unsafe fn ioctl_overlapped(&self,
+                               code: u32,
+                               input: &[u8],
+                               output: &mut [u8],
+                               overlapped: &mut Overlapped)
+                               -> io::Result<bool>;
 }
 
-fn cvt(i: c_int) -> io::Result<bool> {
-    if i == SOCKET_ERROR {
-        last_err()
+/// Creates a socket from a structure returned by `duplicate_socket` in
+/// another process (via `WSASocketW`).
+///
+/// The socket is created for overlapped I/O and isn't inherited by child
+/// processes. Each structure may only be used to create a socket once.
+pub fn from_protocol_info<T>(info: &WSAPROTOCOL_INFOW) -> io::Result<T>
+    where T: FromRawSocket
+{
+    init();
+    let socket = unsafe {
+        WSASocketW(FROM_PROTOCOL_INFO, FROM_PROTOCOL_INFO, FROM_PROTOCOL_INFO,
+                   info as *const _ as *mut _, 0,
+                   WSA_FLAG_OVERLAPPED | WSA_FLAG_NO_HANDLE_INHERIT)
+    };
+    if socket == INVALID_SOCKET {
+        Err(io::Error::from_raw_os_error(unsafe { WSAGetLastError() }))
     } else {
-        Ok(true)
+        Ok(unsafe { T::from_raw_socket(socket) })
     }
 }
 
-fn socket_addr_to_ptrs(addr: &SocketAddr) -> (*const SOCKADDR, c_int) {
-    match *addr {
-        SocketAddr::V4(ref a) => {
-            (a as *const _ as *const _, mem::size_of::<SOCKADDR_IN>() as c_int)
-        }
-        SocketAddr::V6(ref a) => {
-            (a as *const _ as *const _, mem::size_of::<sockaddr_in6>() as c_int)
+/// Connects a new TCP stream to `host` on the port `service` (via
+/// `WSAConnectByNameW`).
+///
+/// The host name is resolved and each of its addresses is tried in turn,
+/// over both IPv4 and IPv6, until a connection succeeds, so callers don't
+/// have to resolve the name and issue `connect_overlapped` themselves. The
+/// whole attempt fails with a timeout error once `timeout` has elapsed.
+///
+/// This blocks the calling thread until the connection is established.
+/// The stream returned is set up for overlapped I/O, so it can be used
+/// with `TcpStreamExt` and associated with a completion port afterwards.
+pub fn connect_by_name(host: &str, service: u16, timeout: Option<Duration>)
+                       -> io::Result<TcpStream> {
+    let socket = try!(socket(AF_INET6, SOCK_STREAM, IPPROTO_TCP.0 as c_int,
+                             0));
+    let stream = unsafe { TcpStream::from_raw_socket(socket) };
+    // A dual stack socket is needed for IPv4 addresses to be tried too.
+    try!(setsockopt(socket, IPPROTO_IPV6.0 as c_int, IPV6_V6ONLY,
+                    0 as DWORD));
+    let mut host = host.encode_utf16().chain(Some(0)).collect::<Vec<u16>>();
+    let mut service = service.to_string().encode_utf16().chain(Some(0))
+                             .collect::<Vec<u16>>();
+    let timeout = timeout.map(|dur| {
+        timeval {
+            tv_sec: cmp::min(dur.as_secs(), c_long::max_value() as u64)
+                as c_long,
+            tv_usec: dur.subsec_micros() as c_long,
         }
+    });
+    let r = unsafe {
+        WSAConnectByNameW(socket, host.as_mut_ptr(), service.as_mut_ptr(),
+                          0 as *mut _, 0 as *mut _, 0 as *mut _, 0 as *mut _,
+                          timeout.as_ref().map_or(0 as *const _, |t| t),
+                          0 as *mut _)
+    };
+    if r == FALSE {
+        return Err(io::Error::from_raw_os_error(unsafe { WSAGetLastError() }))
     }
+    try!(stream.connect_complete());
+    Ok(stream)
 }
 
-unsafe fn ptrs_to_socket_addr(ptr: *const SOCKADDR,
-                              len: c_int) -> Option<SocketAddr> {
-    if (len as usize) < mem::size_of::<c_int>() {
-        return None
-    }
-    match (*ptr).sa_family as i32 {
-        AF_INET if len as usize >= mem::size_of::<SOCKADDR_IN>() => {
-            let b = &*(ptr as *const SOCKADDR_IN);
-            let ip = ntoh(b.sin_addr.S_un);
-            let ip = Ipv4Addr::new((ip >> 24) as u8,
-                                   (ip >> 16) as u8,
-                                   (ip >>  8) as u8,
-                                   (ip >>  0) as u8);
+/// Network event for `event_select` signaled when data can be read.
+pub const FD_READ: u32 = 0x01;
+/// Network event for `event_select` signaled when data can be written.
+pub const FD_WRITE: u32 = 0x02;
+/// Network event for `event_select` signaled when out-of-band data can be
+/// read.
+pub const FD_OOB: u32 = 0x04;
+/// Network event for `event_select` signaled when a connection can be
+/// accepted.
+pub const FD_ACCEPT: u32 = 0x08;
+/// Network event for `event_select` signaled when a connection attempt
+/// completes.
+pub const FD_CONNECT: u32 = 0x10;
+/// Network event for `event_select` signaled when the connection is closed.
+pub const FD_CLOSE: u32 = 0x20;
+
+/// Flag for `transmit_file_overlapped` to gracefully close the connection
+/// once the data has been sent.
+pub const TF_DISCONNECT: u32 = 0x01;
+/// Flag for `transmit_file_overlapped` to prepare the socket for reuse once
+/// the data has been sent, which must be combined with `TF_DISCONNECT`.
+pub const TF_REUSE_SOCKET: u32 = 0x02;
+/// Flag for `transmit_file_overlapped` to complete the operation once the
+/// data has been buffered by the system, without waiting for it to be
+/// acknowledged.
+pub const TF_WRITE_BEHIND: u32 = 0x04;
+
+/// Flag for `read_overlapped_with_flags` to copy received data without
+/// removing it from the receive buffer.
+pub const MSG_PEEK: u32 = 0x02;
+/// Flag for `read_overlapped_with_flags` to complete the operation only once
+/// the buffer has been filled, as used by `read_exact_hint_overlapped`.
+pub const MSG_WAITALL: u32 = 0x08;
+/// Flag reported by `read_overlapped_with_flags` when only part of a message
+/// was received.
+pub const MSG_PARTIAL: u32 = 0x8000;
+
+/// Flag for `set_timestamping` to deliver the time each datagram was
+/// received.
+pub const TIMESTAMPING_FLAG_RX: u32 = 0x1;
+/// Flag for `set_timestamping` to record the time each datagram is sent.
+pub const TIMESTAMPING_FLAG_TX: u32 = 0x2;
+
+static STARTUPS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+const SIO_BASE_HANDLE: DWORD = IOC_OUT | IOC_WS2 | 34;
+const SIO_KEEPALIVE_VALS: DWORD = IOC_IN | IOC_VENDOR | 4;
+const SIO_UDP_CONNRESET: DWORD = IOC_IN | IOC_VENDOR | 12;
+const SIO_UDP_NETRESET: DWORD = IOC_IN | IOC_VENDOR | 15;
+const SIO_LOOPBACK_FAST_PATH: DWORD = IOC_IN | IOC_VENDOR | 16;
+const SIO_TCP_INITIAL_RTO: DWORD = IOC_IN | IOC_VENDOR | 17;
+const SIO_TCP_INFO: DWORD = IOC_INOUT | IOC_VENDOR | 39;
+const SIO_IDEAL_SEND_BACKLOG_QUERY: DWORD = 0x4004747b;
+const SIO_IDEAL_SEND_BACKLOG_CHANGE: DWORD = 0x2000747a;
+const SIO_GET_TX_TIMESTAMP: DWORD = IOC_IN | IOC_VENDOR | 234;
+const SIO_TIMESTAMPING: DWORD = IOC_IN | IOC_VENDOR | 235;
+
+const SO_PROTOCOL_INFOW: c_int = 0x2005;
+const XP1_IFS_HANDLES: DWORD = 0x00020000;
+const BASE_PROTOCOL: c_int = 1;
+
+const HANDLE_FLAG_INHERIT: DWORD = 0x1;
+pub(crate) const SO_UPDATE_ACCEPT_CONTEXT: c_int = 0x700b;
+pub(crate) const SO_UPDATE_CONNECT_CONTEXT: c_int = 0x7010;
+
+pub(crate) const WSAID_ACCEPTEX: GUID = GUID {
+    Data1: 0xb5367df1,
+    Data2: 0xcbac,
+    Data3: 0x11cf,
+    Data4: [0x95, 0xca, 0x00, 0x80, 0x5f, 0x48, 0xa1, 0x92],
+};
+pub(crate) const WSAID_CONNECTEX: GUID = GUID {
+    Data1: 0x25a207b9,
+    Data2: 0xddf3,
+    Data3: 0x4660,
+    Data4: [0x8e, 0xe9, 0x76, 0xe5, 0x8c, 0x74, 0x06, 0x3e],
+};
+
+const WSA_FLAG_OVERLAPPED: DWORD = 0x01;
+const WSA_FLAG_NO_HANDLE_INHERIT: DWORD = 0x80;
+const FROM_PROTOCOL_INFO: c_int = -1;
+
+const IP_UNICAST_IF: c_int = 31;
+const MCAST_JOIN_GROUP: c_int = 41;
+const MCAST_LEAVE_GROUP: c_int = 42;
+const MCAST_JOIN_SOURCE_GROUP: c_int = 45;
+const MCAST_LEAVE_SOURCE_GROUP: c_int = 46;
+const IP_RECVECN: c_int = 50;
+const IPV6_RECVECN: c_int = 50;
+
+const MSG_TRUNC: ULONG = 0x0100;
+const MSG_CTRUNC: ULONG = 0x0200;
+
+const UNIX_PATH_MAX: usize = 108;
+
+const IPV6_HOPLIMIT: c_int = 21;
+const IP_ECN: c_int = 50;
+const IPV6_ECN: c_int = 50;
+const UDP_SEND_MSG_SIZE: c_int = 2;
+const UDP_RECV_MAX_COALESCED_SIZE: c_int = 3;
+const UDP_COALESCED_INFO: c_int = 3;
+const SO_TIMESTAMP: c_int = 0x300a;
+const SO_TIMESTAMP_ID: c_int = 0x300b;
+
+const TCP_INITIAL_RTO_UNSPECIFIED_RTT: u16 = 0xffff;
+const TCP_INITIAL_RTO_UNSPECIFIED_MAX_SYN_RETRANSMISSIONS: u8 = 0xff;
+
+#[repr(C)]
+struct TCP_INITIAL_RTO_PARAMETERS {
+    Rtt: USHORT,
+    MaxSynRetransmissions: UCHAR,
+}
+
+#[repr(C)]
+struct TIMESTAMPING_CONFIG {
+    Flags: ULONG,
+    TxTimestampsBuffered: USHORT,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TCP_INFO_v0 {
+    State: c_int,
+    Mss: ULONG,
+    ConnectionTimeMs: ULONG64,
+    TimestampsEnabled: BOOLEAN,
+    RttUs: ULONG,
+    MinRttUs: ULONG,
+    BytesInFlight: ULONG,
+    Cwnd: ULONG,
+    SndWnd: ULONG,
+    RcvWnd: ULONG,
+    RcvBuf: ULONG,
+    BytesOut: ULONG64,
+    BytesIn: ULONG64,
+    BytesReordered: ULONG,
+    BytesRetrans: ULONG,
+    FastRetrans: ULONG,
+    DupAcksIn: ULONG,
+    TimeoutEpisodes: ULONG,
+    SynRetrans: UCHAR,
+}
+
+#[repr(C)]
+struct tcp_keepalive {
+    onoff: u_long,
+    keepalivetime: u_long,
+    keepaliveinterval: u_long,
+}
+
+#[repr(C)]
+struct TRANSMIT_FILE_BUFFERS {
+    Head: PVOID,
+    HeadLength: DWORD,
+    Tail: PVOID,
+    TailLength: DWORD,
+}
+type LPTRANSMIT_FILE_BUFFERS = *mut TRANSMIT_FILE_BUFFERS;
+
+#[repr(C)]
+struct GROUP_REQ {
+    gr_interface: ULONG,
+    gr_group: SOCKADDR_STORAGE,
+}
+
+#[repr(C)]
+struct GROUP_SOURCE_REQ {
+    gsr_interface: ULONG,
+    gsr_group: SOCKADDR_STORAGE,
+    gsr_source: SOCKADDR_STORAGE,
+}
+
+/// Initializes Winsock for use by this process, requesting the specified
+/// `(major, minor)` version of the Winsock specification.
+///
+/// The standard library lazily initializes Winsock the first time a socket
+/// is created through `std::net`, but programs which only create sockets
+/// through other means, such as raw `WSASocketW` calls, must initialize it
+/// themselves. On success the version of Winsock which will be used is
+/// returned; the highest version currently available is 2.2.
+///
+/// Each successful call to this function must be balanced by a call to
+/// `cleanup`. Calls to this function are reference counted by Winsock, so
+/// it's safe to call it even if Winsock was already initialized elsewhere.
+pub fn startup(version: (u8, u8)) -> io::Result<(u8, u8)> {
+    let version = try!(wsa_startup(version));
+    STARTUPS.fetch_add(1, Ordering::SeqCst);
+    Ok(version)
+}
+
+// Initializes Winsock, as the standard library would have done before
+// creating its own sockets. The reference is never released, just as the
+// standard library never releases its own, so it isn't counted for
+// `cleanup`.
+pub(crate) fn init() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        drop(wsa_startup((2, 2)));
+    });
+}
+
+// Acquires a reference to Winsock (via `WSAStartup`), returning the version
+// which will be used.
+fn wsa_startup(version: (u8, u8)) -> io::Result<(u8, u8)> {
+    let requested = (version.0 as WORD) | ((version.1 as WORD) << 8);
+    let mut data: WSADATA = unsafe { mem::zeroed() };
+    let r = unsafe { WSAStartup(requested, &mut data) };
+    if r != 0 {
+        return Err(io::Error::from_raw_os_error(r))
+    }
+    Ok((data.wVersion as u8, (data.wVersion >> 8) as u8))
+}
+
+/// Releases a reference to Winsock previously acquired with `startup`.
+///
+/// This only calls `WSACleanup` for references acquired through `startup`,
+/// so an unbalanced call cannot tear down the initialization performed by
+/// the standard library; in that case an error is returned instead.
+pub fn cleanup() -> io::Result<()> {
+    let mut cur = STARTUPS.load(Ordering::SeqCst);
+    loop {
+        if cur == 0 {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      "cleanup without matching startup"))
+        }
+        match STARTUPS.compare_exchange(cur, cur - 1, Ordering::SeqCst,
+                                        Ordering::SeqCst) {
+            Ok(_) => break,
+            Err(n) => cur = n,
+        }
+    }
+    if unsafe { WSACleanup() } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(unsafe { WSAGetLastError() }))
+    }
+}
+
+#[doc(hidden)]
+trait NetInt {
+    fn from_be(i: Self) -> Self;
+    fn to_be(&self) -> Self;
+}
+macro_rules! doit {
+    ($($t:ident)*) => ($(impl NetInt for $t {
+        fn from_be(i: Self) -> Self { <$t>::from_be(i) }
+        fn to_be(&self) -> Self { <$t>::to_be(*self) }
+    })*)
+}
+doit! { i8 i16 i32 i64 isize u8 u16 u32 u64 usize }
+
+fn hton<I: NetInt>(i: I) -> I { i.to_be() }
+fn ntoh<I: NetInt>(i: I) -> I { I::from_be(i) }
+
+pub(crate) fn last_err() -> io::Result<bool> {
+    let err = unsafe { WSAGetLastError() };
+    if err == WSA_IO_PENDING as i32 {
+        Ok(false)
+    } else {
+        Err(io::Error::from_raw_os_error(err))
+    }
+}
+
+pub(crate) fn cvt(i: c_int) -> io::Result<bool> {
+    if i == SOCKET_ERROR {
+        last_err()
+    } else {
+        Ok(true)
+    }
+}
+
+pub(crate) unsafe fn result(socket: SOCKET, overlapped: &Overlapped)
+                                 -> io::Result<(usize, u32)> {
+    overlapped_result(socket, overlapped, false)
+}
+
+unsafe fn recv_result(socket: SOCKET, overlapped: &Overlapped)
+                      -> io::Result<(usize, u32)> {
+    overlapped_result(socket, overlapped, true)
+}
+
+unsafe fn overlapped_result(socket: SOCKET, overlapped: &Overlapped,
+                            recv: bool) -> io::Result<(usize, u32)> {
+    let mut transferred = 0;
+    let mut flags = 0;
+    let r = WSAGetOverlappedResult(socket, overlapped.raw(), &mut transferred,
+                                   FALSE, &mut flags);
+    if r == TRUE {
+        Ok((transferred as usize, flags))
+    } else {
+        match WSAGetLastError() {
+            // A datagram larger than the buffer fails the receive, but the
+            // buffer is still filled with as much of it as fits.
+            e if recv && e == WSAEMSGSIZE as i32 => {
+                Ok((transferred as usize, flags | MSG_PARTIAL))
+            }
+            e => Err(io::Error::from_raw_os_error(e)),
+        }
+    }
+}
+
+// Creates an overlapped, non-inheritable socket with the additional `flags`
+// passed to `WSASocketW`, for the kinds of sockets the standard library and
+// `net2` can't create.
+pub(crate) fn socket(family: c_int, ty: c_int, protocol: c_int, flags: DWORD)
+                     -> io::Result<SOCKET> {
+    init();
+    let socket = unsafe {
+        WSASocketW(family, ty, protocol, 0 as *mut _, 0,
+                   WSA_FLAG_OVERLAPPED | WSA_FLAG_NO_HANDLE_INHERIT | flags)
+    };
+    if socket == INVALID_SOCKET {
+        Err(io::Error::from_raw_os_error(unsafe { WSAGetLastError() }))
+    } else {
+        Ok(socket)
+    }
+}
+
+// Returns whether the provider of `socket` returns true handles, whose
+// completions can be skipped reliably.
+pub(crate) fn has_ifs_handles(socket: SOCKET) -> io::Result<bool> {
+    let info: WSAPROTOCOL_INFOW = try!(getsockopt(socket, SOL_SOCKET,
+                                                  SO_PROTOCOL_INFOW));
+    Ok(info.dwServiceFlags1 & XP1_IFS_HANDLES != 0)
+}
+
+// Issues an overlapped socket operation through the fault injection layer.
+pub(crate) unsafe fn submit<F>(op: Operation, len: usize, socket: SOCKET,
+                               overlapped: LPOVERLAPPED, f: F)
+                               -> io::Result<bool>
+    where F: FnOnce(usize) -> io::Result<bool>
+{
+    ::fault::submit(op, len, socket as HANDLE, overlapped, f)
+}
+
+// Issues a synchronous `WSAIoctl` which takes an input value and produces no
+// output.
+fn ioctl_in<T>(socket: SOCKET, code: DWORD, input: &T) -> io::Result<()> {
+    let mut bytes = 0;
+    let r = unsafe {
+        WSAIoctl(socket, code, input as *const T as *mut _,
+                 mem::size_of::<T>() as DWORD, 0 as *mut _, 0, &mut bytes,
+                 0 as *mut _, None)
+    };
+    cvt(r).map(|_| ())
+}
+
+// Issues a synchronous `WSAIoctl` which takes no input and produces an output
+// value.
+fn ioctl_out<T>(socket: SOCKET, code: DWORD) -> io::Result<T> {
+    let mut ret: T = unsafe { mem::zeroed() };
+    let mut bytes = 0;
+    let r = unsafe {
+        WSAIoctl(socket, code, 0 as *mut _, 0,
+                 &mut ret as *mut T as *mut _, mem::size_of::<T>() as DWORD,
+                 &mut bytes, 0 as *mut _, None)
+    };
+    try!(cvt(r));
+    Ok(ret)
+}
+
+// Issues a synchronous `WSAIoctl` which takes an input value and produces an
+// output value.
+fn ioctl_in_out<T, U>(socket: SOCKET, code: DWORD, input: &T)
+                      -> io::Result<U> {
+    let mut ret: U = unsafe { mem::zeroed() };
+    let mut bytes = 0;
+    let r = unsafe {
+        WSAIoctl(socket, code, input as *const T as *mut _,
+                 mem::size_of::<T>() as DWORD,
+                 &mut ret as *mut U as *mut _, mem::size_of::<U>() as DWORD,
+                 &mut bytes, 0 as *mut _, None)
+    };
+    try!(cvt(r));
+    Ok(ret)
+}
+
+fn setsockopt<T>(socket: SOCKET, level: c_int, name: c_int, value: T)
+                 -> io::Result<()> {
+    let r = unsafe {
+        ::ws2_32::setsockopt(socket, level, name,
+                             &value as *const T as *const _,
+                             mem::size_of::<T>() as c_int)
+    };
+    cvt(r).map(|_| ())
+}
+
+fn getsockopt<T>(socket: SOCKET, level: c_int, name: c_int)
+                 -> io::Result<T> {
+    let mut value: T = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<T>() as c_int;
+    let r = unsafe {
+        ::ws2_32::getsockopt(socket, level, name,
+                             &mut value as *mut T as *mut _, &mut len)
+    };
+    try!(cvt(r));
+    Ok(value)
+}
+
+fn set_loopback_fast_path(socket: SOCKET, enable: bool) -> io::Result<()> {
+    let enable = enable as c_int;
+    ioctl_in(socket, SIO_LOOPBACK_FAST_PATH, &enable)
+}
+
+fn set_initial_rto(socket: SOCKET,
+                   rtt: Option<Duration>,
+                   max_syn_retransmissions: Option<u8>) -> io::Result<()> {
+    let rtt = match rtt {
+        Some(rtt) => {
+            let max = TCP_INITIAL_RTO_UNSPECIFIED_RTT as u64 - 1;
+            cmp::min(::dur2ms(rtt), max) as u16
+        }
+        None => TCP_INITIAL_RTO_UNSPECIFIED_RTT,
+    };
+    let params = TCP_INITIAL_RTO_PARAMETERS {
+        Rtt: rtt,
+        MaxSynRetransmissions: max_syn_retransmissions.unwrap_or(
+            TCP_INITIAL_RTO_UNSPECIFIED_MAX_SYN_RETRANSMISSIONS),
+    };
+    ioctl_in(socket, SIO_TCP_INITIAL_RTO, &params)
+}
+
+// Binds `socket` to the unspecified address of the family of `addr` unless
+// it's already bound, which `getsockname` reports with `WSAEINVAL`.
+fn bind_unbound(socket: SOCKET, addr: &SocketAddr) -> io::Result<()> {
+    let mut local = RawSocketAddr::new();
+    let mut len = mem::size_of::<SOCKADDR_STORAGE>() as c_int;
+    let r = unsafe { getsockname(socket, local.as_mut_ptr(), &mut len) };
+    match cvt(r) {
+        Ok(_) => return Ok(()),
+        Err(ref e) if e.raw_os_error() == Some(WSAEINVAL as i32) => {}
+        Err(e) => return Err(e),
+    }
+    let unspecified = match *addr {
+        SocketAddr::V4(..) => SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(),
+                                              0),
+        SocketAddr::V6(..) => SocketAddr::new(Ipv6Addr::from([0; 16]).into(),
+                                              0),
+    };
+    let unspecified = RawSocketAddr::from(unspecified);
+    let r = unsafe {
+        ::ws2_32::bind(socket, unspecified.as_ptr(), unspecified.len())
+    };
+    cvt(r).map(|_| ())
+}
+
+// `AsRawSocket for TcpBuilder` panics once the builder's socket has been
+// turned into a stream or listener, but `local_addr` reports that as an error
+// without an OS error code, so check it before handing out the socket.
+fn builder_socket(builder: &TcpBuilder) -> io::Result<SOCKET> {
+    if let Err(e) = builder.local_addr() {
+        if e.raw_os_error().is_none() {
+            return Err(e)
+        }
+    }
+    Ok(builder.as_raw_socket())
+}
+
+// `IP_UNICAST_IF` takes the interface index in network byte order, while
+// `IPV6_UNICAST_IF` takes it in host byte order.
+fn set_unicast_interface(socket: SOCKET, v6: bool, index: u32)
+                         -> io::Result<()> {
+    if v6 {
+        setsockopt(socket, IPPROTO_IPV6.0 as c_int, IPV6_UNICAST_IF,
+                   index as DWORD)
+    } else {
+        setsockopt(socket, IPPROTO_IP, IP_UNICAST_IF, hton(index) as DWORD)
+    }
+}
+
+fn unicast_interface(socket: SOCKET, v6: bool) -> io::Result<u32> {
+    if v6 {
+        getsockopt::<DWORD>(socket, IPPROTO_IPV6.0 as c_int, IPV6_UNICAST_IF)
+    } else {
+        getsockopt::<DWORD>(socket, IPPROTO_IP, IP_UNICAST_IF).map(ntoh)
+    }
+}
+
+fn ip_level(addr: &IpAddr) -> c_int {
+    match *addr {
+        IpAddr::V4(..) => IPPROTO_IP,
+        IpAddr::V6(..) => IPPROTO_IPV6.0 as c_int,
+    }
+}
+
+fn ip_storage(addr: &IpAddr) -> SOCKADDR_STORAGE {
+    let addr = match *addr {
+        IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, 0)),
+        IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, 0, 0, 0)),
+    };
+    RawSocketAddr::from(addr).storage
+}
+
+fn multicast_group(socket: SOCKET, name: c_int, group: &IpAddr,
+                   interface: u32) -> io::Result<()> {
+    let req = GROUP_REQ {
+        gr_interface: interface,
+        gr_group: ip_storage(group),
+    };
+    setsockopt(socket, ip_level(group), name, req)
+}
+
+fn multicast_source_group(socket: SOCKET, name: c_int, group: &IpAddr,
+                          source: &IpAddr, interface: u32)
+                          -> io::Result<()> {
+    if group.is_ipv4() != source.is_ipv4() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "group and source address families differ"))
+    }
+    let req = GROUP_SOURCE_REQ {
+        gsr_interface: interface,
+        gsr_group: ip_storage(group),
+        gsr_source: ip_storage(source),
+    };
+    setsockopt(socket, ip_level(group), name, req)
+}
+
+pub(crate) fn rss_processor(socket: SOCKET) -> io::Result<RssProcessor> {
+    let info: SOCKET_PROCESSOR_AFFINITY =
+        try!(ioctl_out(socket, SIO_QUERY_RSS_PROCESSOR_INFO));
+    Ok(RssProcessor {
+        group: info.Processor.Group,
+        number: info.Processor.Number,
+        numa_node: info.NumaNodeId,
+    })
+}
+
+pub(crate) unsafe fn ptrs_to_socket_addr(ptr: *const SOCKADDR,
+                                         len: c_int)
+                                         -> Option<SocketAddr> {
+    if (len as usize) < mem::size_of::<c_int>() {
+        return None
+    }
+    match (*ptr).sa_family as i32 {
+        AF_INET if len as usize >= mem::size_of::<SOCKADDR_IN>() => {
+            let b = &*(ptr as *const SOCKADDR_IN);
+            let ip = ntoh(b.sin_addr.S_un);
+            let ip = Ipv4Addr::new((ip >> 24) as u8,
+                                   (ip >> 16) as u8,
+                                   (ip >>  8) as u8,
+                                   (ip >>  0) as u8);
             Some(SocketAddr::V4(SocketAddrV4::new(ip, ntoh(b.sin_port))))
         }
         AF_INET6 if len as usize >= mem::size_of::<sockaddr_in6>() => {
@@ -337,36 +2252,136 @@ unsafe fn ptrs_to_socket_addr(ptr: *const SOCKADDR,
                 ((arr[12] as u16) << 8) | (arr[13] as u16),
                 ((arr[14] as u16) << 8) | (arr[15] as u16));
             let addr = SocketAddrV6::new(ip, ntoh(b.sin6_port),
-                                         ntoh(b.sin6_flowinfo),
-                                         ntoh(b.sin6_scope_id));
+                                         b.sin6_flowinfo, b.sin6_scope_id);
             Some(SocketAddr::V6(addr))
         }
         _ => None
     }
 }
 
+// Converts an IPv4-mapped IPv6 address into the IPv4 address it maps.
+fn unmap_v4(addr: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(ref a) = addr {
+        let s = a.ip().segments();
+        if s[..6] == [0, 0, 0, 0, 0, 0xffff] {
+            let ip = Ipv4Addr::new((s[6] >> 8) as u8, s[6] as u8,
+                                   (s[7] >> 8) as u8, s[7] as u8);
+            return SocketAddr::V4(SocketAddrV4::new(ip, a.port()))
+        }
+    }
+    addr
+}
+
+pub(crate) unsafe fn ptrs_to_unix_addr(ptr: *const SOCKADDR, len: c_int)
+                                       -> Option<UnixSocketAddr> {
+    let offset = mem::size_of::<ADDRESS_FAMILY>();
+    if (len as usize) < offset || (*ptr).sa_family as i32 != AF_UNIX {
+        return None
+    }
+    let len = cmp::min(len as usize - offset, UNIX_PATH_MAX);
+    let path = slice::from_raw_parts((ptr as *const u8).offset(offset as isize),
+                                     len);
+    // A path ends at its terminating null, if the length counts it, while an
+    // abstract name starts with a null and takes up the whole length. Some
+    // providers return an unnamed address as a path of only nulls.
+    let path = match path.iter().position(|&b| b == 0) {
+        Some(0) if path.iter().any(|&b| b != 0) => path,
+        Some(0) => &[],
+        Some(n) => &path[..n],
+        None => path,
+    };
+    Some(UnixSocketAddr { path: path.to_vec() })
+}
+
 impl TcpStreamExt for TcpStream {
     unsafe fn read_overlapped(&self, buf: &mut [u8],
                               overlapped: &mut Overlapped) -> io::Result<bool> {
-        let mut buf = WSABUF {
-            len: buf.len() as u_long,
-            buf: buf.as_mut_ptr() as *mut _,
-        };
-        let mut flags = 0;
-        let r = WSARecv(self.as_raw_socket(), &mut buf, 1,
-                        0 as *mut _, &mut flags, overlapped.raw(), None);
-        cvt(r)
+        self.read_overlapped_with_flags(buf, &mut 0, overlapped)
+    }
+
+    unsafe fn read_overlapped_uninit(&self,
+                                     buf: &mut [MaybeUninit<u8>],
+                                     overlapped: &mut Overlapped)
+                                     -> io::Result<bool> {
+        recv_overlapped_uninit(self.as_raw_socket(), buf, &mut 0, overlapped)
+    }
+
+    unsafe fn read_overlapped_with_flags(&self,
+                                         buf: &mut [u8],
+                                         flags: &mut u32,
+                                         overlapped: &mut Overlapped)
+                                         -> io::Result<bool> {
+        recv_overlapped(self.as_raw_socket(), buf, flags, overlapped)
+    }
+
+    unsafe fn read_exact_hint_overlapped(&self, buf: &mut [u8],
+                                         overlapped: &mut Overlapped)
+                                         -> io::Result<bool> {
+        let mut flags = MSG_WAITALL;
+        self.read_overlapped_with_flags(buf, &mut flags, overlapped)
+    }
+
+    unsafe fn read_ready_overlapped(&self, overlapped: &mut Overlapped)
+                                    -> io::Result<bool> {
+        recv_overlapped(self.as_raw_socket(), &mut [], &mut 0, overlapped)
+    }
+
+    unsafe fn read_overlapped_vectored(&self,
+                                       bufs: &mut [WsaBufMut],
+                                       overlapped: &mut Overlapped)
+                                       -> io::Result<bool> {
+        let socket = self.as_raw_socket();
+        let bufs = &*WsaBufMut::as_raw_slice(bufs);
+        submit(Operation::Read, total_len(bufs), socket, overlapped.raw(),
+               |len| {
+            with_len(bufs, len, |bufs| {
+                let mut flags = 0;
+                let r = WSARecv(socket, bufs.as_ptr() as *mut _,
+                                bufs.len() as DWORD, 0 as *mut _, &mut flags,
+                                overlapped.raw(), None);
+                cvt(r)
+            })
+        })
+    }
+
+    unsafe fn read_overlapped_io_slices(&self,
+                                       bufs: &mut [IoSliceMut],
+                                       overlapped: &mut Overlapped)
+                                       -> io::Result<bool> {
+        self.read_overlapped_vectored(WsaBufMut::from_io_slices(bufs),
+                                      overlapped)
     }
 
     unsafe fn write_overlapped(&self, buf: &[u8],
                                overlapped: &mut Overlapped) -> io::Result<bool> {
-        let mut buf = WSABUF {
-            len: buf.len() as u_long,
-            buf: buf.as_ptr() as *mut _,
-        };
-        let r = WSASend(self.as_raw_socket(), &mut buf, 1,
-                        0 as *mut _, 0, overlapped.raw(), None);
-        cvt(r)
+        send_overlapped(self.as_raw_socket(), buf, overlapped)
+    }
+
+    unsafe fn write_overlapped_vectored(&self,
+                                        bufs: &[WsaBuf],
+                                        overlapped: &mut Overlapped)
+                                        -> io::Result<bool> {
+        let socket = self.as_raw_socket();
+        let bufs = WsaBuf::as_raw_slice(bufs);
+        submit(Operation::Write, total_len(bufs), socket, overlapped.raw(),
+               |len| {
+            with_len(bufs, len, |bufs| {
+                // `WSASend` takes a mutable pointer but doesn't write
+                // through it.
+                let r = WSASend(socket, bufs.as_ptr() as *mut _,
+                                bufs.len() as DWORD, 0 as *mut _, 0,
+                                overlapped.raw(), None);
+                cvt(r)
+            })
+        })
+    }
+
+    unsafe fn write_overlapped_io_slices(&self,
+                                        bufs: &[IoSlice],
+                                        overlapped: &mut Overlapped)
+                                        -> io::Result<bool> {
+        self.write_overlapped_vectored(WsaBuf::from_io_slices(bufs),
+                                       overlapped)
     }
 
     unsafe fn connect_overlapped(&self, addr: &SocketAddr,
@@ -374,375 +2389,3579 @@ impl TcpStreamExt for TcpStream {
                                  -> io::Result<bool> {
         connect_overlapped(self.as_raw_socket(), addr, overlapped)
     }
-}
 
-unsafe fn connect_overlapped(socket: SOCKET, addr: &SocketAddr,
-                             overlapped: &mut Overlapped) -> io::Result<bool> {
-    static CONNECTEX: WsaExtension = WsaExtension {
-        guid: GUID {
-            Data1: 0x25a207b9,
-            Data2: 0xddf3,
-            Data3: 0x4660,
-            Data4: [0x8e, 0xe9, 0x76, 0xe5, 0x8c, 0x74, 0x06, 0x3e],
-        },
-        val: ATOMIC_USIZE_INIT,
-    };
-    type ConnectEx = unsafe extern "system" fn(SOCKET, *const SOCKADDR,
-                                               c_int, PVOID, DWORD, LPDWORD,
-                                               LPOVERLAPPED) -> BOOL;
+    fn rss_processor(&self) -> io::Result<RssProcessor> {
+        rss_processor(self.as_raw_socket())
+    }
 
-    let ptr = try!(CONNECTEX.get(socket));
-    assert!(ptr != 0);
-    let connect_ex = mem::transmute::<_, ConnectEx>(ptr);
+    fn tcp_info(&self) -> io::Result<TcpInfo> {
+        let version: DWORD = 0;
+        ioctl_in_out(self.as_raw_socket(), SIO_TCP_INFO, &version).map(TcpInfo)
+    }
 
-    let (addr_buf, addr_len) = socket_addr_to_ptrs(addr);
-    let r = connect_ex(socket, addr_buf, addr_len,
-                       0 as *mut _, 0, 0 as *mut _, overlapped.raw());
-    if r == TRUE {
-        Ok(true)
-    } else {
-        last_err()
+    fn ideal_send_backlog(&self) -> io::Result<u32> {
+        ioctl_out::<ULONG>(self.as_raw_socket(), SIO_IDEAL_SEND_BACKLOG_QUERY)
     }
-}
 
-impl UdpSocketExt for UdpSocket {
-    unsafe fn recv_from_overlapped(&self,
-                                   buf: &mut [u8],
-                                   addr: &mut SocketAddrBuf,
-                                   overlapped: &mut Overlapped)
-                                   -> io::Result<bool> {
-        let mut buf = WSABUF {
-            len: buf.len() as u_long,
-            buf: buf.as_mut_ptr() as *mut _,
-        };
-        let mut flags = 0;
-        let r = WSARecvFrom(self.as_raw_socket(), &mut buf, 1,
-                            0 as *mut _, &mut flags,
-                            &mut addr.buf as *mut _ as *mut _,
-                            &mut addr.len,
-                            overlapped.raw(), None);
+    unsafe fn ideal_send_backlog_change_overlapped(&self,
+                                                   overlapped: &mut Overlapped)
+                                                   -> io::Result<bool> {
+        let mut bytes = 0;
+        let r = WSAIoctl(self.as_raw_socket(), SIO_IDEAL_SEND_BACKLOG_CHANGE,
+                         0 as *mut _, 0, 0 as *mut _, 0, &mut bytes,
+                         overlapped.raw(), None);
         cvt(r)
     }
 
-    unsafe fn send_to_overlapped(&self,
-                                 buf: &[u8],
-                                 addr: &SocketAddr,
-                                 overlapped: &mut Overlapped)
-                                 -> io::Result<bool> {
-        let (addr_buf, addr_len) = socket_addr_to_ptrs(addr);
-        let mut buf = WSABUF {
-            len: buf.len() as u_long,
-            buf: buf.as_ptr() as *mut _,
+    fn set_keepalive_vals(&self,
+                          enable: bool,
+                          time: Duration,
+                          interval: Duration)
+                          -> io::Result<()> {
+        let max = u_long::max_value() as u64;
+        let vals = tcp_keepalive {
+            onoff: enable as u_long,
+            keepalivetime: cmp::min(::dur2ms(time), max) as u_long,
+            keepaliveinterval: cmp::min(::dur2ms(interval), max) as u_long,
         };
-        let r = WSASendTo(self.as_raw_socket(), &mut buf, 1,
-                          0 as *mut _, 0,
-                          addr_buf as *const _, addr_len,
-                          overlapped.raw(), None);
-        cvt(r)
+        ioctl_in(self.as_raw_socket(), SIO_KEEPALIVE_VALS, &vals)
     }
-}
 
-impl TcpBuilderExt for TcpBuilder {
-    unsafe fn connect_overlapped(&self, addr: &SocketAddr,
-                                 overlapped: &mut Overlapped)
-                                 -> io::Result<(TcpStream, bool)> {
-        connect_overlapped(self.as_raw_socket(), addr, overlapped).map(|s| {
-            (self.to_tcp_stream().unwrap(), s)
-        })
+    unsafe fn disconnect_overlapped(&self, reuse: bool,
+                                    overlapped: &mut Overlapped)
+                                    -> io::Result<bool> {
+        static DISCONNECTEX: WsaExtension = WsaExtension::new(GUID {
+            Data1: 0x7fda2e11,
+            Data2: 0x8630,
+            Data3: 0x436f,
+            Data4: [0xa0, 0x31, 0xf5, 0x36, 0xa6, 0xee, 0xc1, 0x57],
+        });
+        type DisconnectEx = unsafe extern "system" fn(SOCKET, LPOVERLAPPED,
+                                                      DWORD, DWORD) -> BOOL;
+
+        let socket = self.as_raw_socket();
+        let ptr = try!(DISCONNECTEX.get(socket));
+        let disconnect_ex = mem::transmute::<_, DisconnectEx>(ptr);
+        let flags = if reuse { TF_REUSE_SOCKET } else { 0 };
+        let r = disconnect_ex(socket, overlapped.raw(), flags, 0);
+        if r == TRUE {
+            Ok(true)
+        } else {
+            last_err()
+        }
     }
-}
 
-impl TcpListenerExt for TcpListener {
-    unsafe fn accept_overlapped(&self,
-                                socket: &TcpBuilder,
-                                addrs: &mut AcceptAddrsBuf,
-                                overlapped: &mut Overlapped)
-                                -> io::Result<(TcpStream, bool)> {
-        static ACCEPTEX: WsaExtension = WsaExtension {
-            guid: GUID {
-                Data1: 0xb5367df1,
-                Data2: 0xcbac,
-                Data3: 0x11cf,
-                Data4: [0x95, 0xca, 0x00, 0x80, 0x5f, 0x48, 0xa1, 0x92],
-            },
-            val: ATOMIC_USIZE_INIT,
+    unsafe fn transmit_file_overlapped(&self,
+                                       file: &File,
+                                       head: &[u8],
+                                       tail: &[u8],
+                                       flags: u32,
+                                       overlapped: &mut Overlapped)
+                                       -> io::Result<bool> {
+        static TRANSMITFILE: WsaExtension = WsaExtension::new(GUID {
+            Data1: 0xb5367df0,
+            Data2: 0xcbac,
+            Data3: 0x11cf,
+            Data4: [0x95, 0xca, 0x00, 0x80, 0x5f, 0x48, 0xa1, 0x92],
+        });
+        type TransmitFile = unsafe extern "system" fn(SOCKET, HANDLE, DWORD,
+                                                      DWORD, LPOVERLAPPED,
+                                                      LPTRANSMIT_FILE_BUFFERS,
+                                                      DWORD) -> BOOL;
+
+        let socket = self.as_raw_socket();
+        let ptr = try!(TRANSMITFILE.get(socket));
+        let transmit_file = mem::transmute::<_, TransmitFile>(ptr);
+        let mut buffers = TRANSMIT_FILE_BUFFERS {
+            Head: head.as_ptr() as *mut _,
+            HeadLength: try!(::checked_len(head.len())),
+            Tail: tail.as_ptr() as *mut _,
+            TailLength: try!(::checked_len(tail.len())),
         };
-        type AcceptEx = unsafe extern "system" fn(SOCKET, SOCKET, PVOID,
-                                                  DWORD, DWORD, DWORD, LPDWORD,
-                                                  LPOVERLAPPED) -> BOOL;
+        let r = transmit_file(socket, file.as_raw_handle(), 0, 0,
+                              overlapped.raw(), &mut buffers, flags);
+        if r == TRUE {
+            Ok(true)
+        } else {
+            last_err()
+        }
+    }
 
-        let ptr = try!(ACCEPTEX.get(self.as_raw_socket()));
-        assert!(ptr != 0);
-        let accept_ex = mem::transmute::<_, AcceptEx>(ptr);
+    fn accept_complete(&self, listener: &TcpListener) -> io::Result<()> {
+        setsockopt(self.as_raw_socket(), SOL_SOCKET, SO_UPDATE_ACCEPT_CONTEXT,
+                   listener.as_raw_socket())
+    }
 
-        let mut bytes = 0;
-        let (a, b, c, d) = addrs.args();
-        let r = accept_ex(self.as_raw_socket(), socket.as_raw_socket(),
-                          a, b, c, d, &mut bytes, overlapped.raw());
-        let succeeded = if r == TRUE {
-            true
+    fn connect_complete(&self) -> io::Result<()> {
+        let r = unsafe {
+            ::ws2_32::setsockopt(self.as_raw_socket(), SOL_SOCKET,
+                                 SO_UPDATE_CONNECT_CONTEXT, 0 as *const _, 0)
+        };
+        cvt(r).map(|_| ())
+    }
+
+    unsafe fn connect_error(&self, overlapped: &Overlapped)
+                            -> io::Result<Option<io::Error>> {
+        match TcpStreamExt::result(self, overlapped) {
+            Ok(..) => Ok(None),
+            Err(e) => {
+                if e.raw_os_error() == Some(WSA_IO_INCOMPLETE as i32) {
+                    Err(e)
+                } else {
+                    Ok(Some(e))
+                }
+            }
+        }
+    }
+
+    unsafe fn result(&self, overlapped: &Overlapped)
+                     -> io::Result<(usize, u32)> {
+        result(self.as_raw_socket(), overlapped)
+    }
+}
+
+pub(crate) unsafe fn recv_overlapped(socket: SOCKET,
+                                     buf: &mut [u8],
+                                     flags: &mut u32,
+                                     overlapped: &mut Overlapped)
+                                     -> io::Result<bool> {
+    recv_overlapped_uninit(socket, ::uninit(buf), flags, overlapped)
+}
+
+unsafe fn recv_overlapped_uninit(socket: SOCKET,
+                                 buf: &mut [MaybeUninit<u8>],
+                                 flags: &mut u32,
+                                 overlapped: &mut Overlapped)
+                                 -> io::Result<bool> {
+    submit(Operation::Read, buf.len(), socket, overlapped.raw(), |len| {
+        let mut buf = WSABUF {
+            len: ::clamp_len(len),
+            buf: buf.as_mut_ptr() as *mut _,
+        };
+        let r = WSARecv(socket, &mut buf, 1,
+                        0 as *mut _, flags, overlapped.raw(), None);
+        cvt(r)
+    })
+}
+
+pub(crate) unsafe fn send_overlapped(socket: SOCKET,
+                                     buf: &[u8],
+                                     overlapped: &mut Overlapped)
+                                     -> io::Result<bool> {
+    submit(Operation::Write, buf.len(), socket, overlapped.raw(), |len| {
+        let mut buf = WSABUF {
+            len: ::clamp_len(len),
+            buf: buf.as_ptr() as *mut _,
+        };
+        let r = WSASend(socket, &mut buf, 1,
+                        0 as *mut _, 0, overlapped.raw(), None);
+        cvt(r)
+    })
+}
+
+unsafe fn connect_overlapped(socket: SOCKET, addr: &SocketAddr,
+                             overlapped: &mut Overlapped) -> io::Result<bool> {
+    connect_overlapped_with_data(socket, addr, &[], &mut 0, overlapped)
+}
+
+unsafe fn connect_overlapped_with_data(socket: SOCKET, addr: &SocketAddr,
+                                       buf: &[u8], sent: &mut DWORD,
+                                       overlapped: &mut Overlapped)
+                                       -> io::Result<bool> {
+    static CONNECTEX: WsaExtension = WsaExtension::new(WSAID_CONNECTEX);
+    type ConnectEx = unsafe extern "system" fn(SOCKET, *const SOCKADDR,
+                                               c_int, PVOID, DWORD, LPDWORD,
+                                               LPOVERLAPPED) -> BOOL;
+
+    let ptr = try!(CONNECTEX.get(socket));
+    let connect_ex = mem::transmute::<_, ConnectEx>(ptr);
+
+    let addr = RawSocketAddr::from(*addr);
+    let (addr_buf, addr_len) = (addr.as_ptr(), addr.len());
+    submit(Operation::Connect, buf.len(), socket, overlapped.raw(), |len| {
+        let r = connect_ex(socket, addr_buf, addr_len,
+                           buf.as_ptr() as *mut _, ::clamp_len(len), sent,
+                           overlapped.raw());
+        if r == TRUE {
+            Ok(true)
         } else {
-            try!(last_err())
+            last_err()
+        }
+    })
+}
+
+impl OverlappedIo for TcpStream {
+    unsafe fn start_read(&self, buf: &mut [u8],
+                         overlapped: &mut Overlapped)
+                         -> io::Result<bool> {
+        TcpStreamExt::read_overlapped(self, buf, overlapped)
+    }
+
+    unsafe fn start_write(&self, buf: &[u8],
+                          overlapped: &mut Overlapped)
+                          -> io::Result<bool> {
+        TcpStreamExt::write_overlapped(self, buf, overlapped)
+    }
+
+    fn cancel(&self, overlapped: &Overlapped) -> io::Result<()> {
+        ::handle::cancel(self.as_raw_socket() as HANDLE, overlapped.raw())
+    }
+}
+
+impl OverlappedIo for UdpSocket {
+    unsafe fn start_read(&self, buf: &mut [u8],
+                         overlapped: &mut Overlapped)
+                         -> io::Result<bool> {
+        UdpSocketExt::recv_overlapped(self, buf, overlapped)
+    }
+
+    unsafe fn start_write(&self, buf: &[u8],
+                          overlapped: &mut Overlapped)
+                          -> io::Result<bool> {
+        UdpSocketExt::send_overlapped(self, buf, overlapped)
+    }
+
+    fn cancel(&self, overlapped: &Overlapped) -> io::Result<()> {
+        ::handle::cancel(self.as_raw_socket() as HANDLE, overlapped.raw())
+    }
+}
+
+impl UdpSocketExt for UdpSocket {
+    unsafe fn recv_from_overlapped(&self,
+                                   buf: &mut [u8],
+                                   addr: &mut SocketAddrBuf,
+                                   overlapped: &mut Overlapped)
+                                   -> io::Result<bool> {
+        self.recv_from_overlapped_uninit(::uninit(buf), addr, overlapped)
+    }
+
+    unsafe fn recv_from_overlapped_uninit(&self,
+                                          buf: &mut [MaybeUninit<u8>],
+                                          addr: &mut SocketAddrBuf,
+                                          overlapped: &mut Overlapped)
+                                          -> io::Result<bool> {
+        let socket = self.as_raw_socket();
+        submit(Operation::Read, buf.len(), socket, overlapped.raw(),
+               |len| {
+            let mut buf = WSABUF {
+                len: ::clamp_len(len),
+                buf: buf.as_mut_ptr() as *mut _,
+            };
+            let mut flags = 0;
+            let r = WSARecvFrom(socket, &mut buf, 1,
+                                0 as *mut _, &mut flags,
+                                addr.addr.as_mut_ptr(), &mut addr.addr.len,
+                                overlapped.raw(), None);
+            cvt(r)
+        })
+    }
+
+    unsafe fn recv_from_overlapped_with_flags(&self,
+                                              buf: &mut [u8],
+                                              addr: &mut SocketAddrBuf,
+                                              flags: &mut u32,
+                                              overlapped: &mut Overlapped)
+                                              -> io::Result<bool> {
+        let socket = self.as_raw_socket();
+        submit(Operation::Read, buf.len(), socket, overlapped.raw(),
+               |len| {
+            let mut buf = WSABUF {
+                len: ::clamp_len(len),
+                buf: buf.as_mut_ptr() as *mut _,
+            };
+            let r = WSARecvFrom(socket, &mut buf, 1,
+                                0 as *mut _, flags,
+                                addr.addr.as_mut_ptr(), &mut addr.addr.len,
+                                overlapped.raw(), None);
+            match cvt(r) {
+                Err(ref e) if e.raw_os_error() == Some(WSAEMSGSIZE as i32) => {
+                    *flags |= MSG_PARTIAL;
+                    Ok(true)
+                }
+                r => r,
+            }
+        })
+    }
+
+    unsafe fn send_to_overlapped(&self,
+                                 buf: &[u8],
+                                 addr: &SocketAddr,
+                                 overlapped: &mut Overlapped)
+                                 -> io::Result<bool> {
+        let addr = RawSocketAddr::from(*addr);
+        let (addr_buf, addr_len) = (addr.as_ptr(), addr.len());
+        let socket = self.as_raw_socket();
+        submit(Operation::Write, buf.len(), socket, overlapped.raw(),
+               |len| {
+            let mut buf = WSABUF {
+                len: ::clamp_len(len),
+                buf: buf.as_ptr() as *mut _,
+            };
+            let r = WSASendTo(socket, &mut buf, 1,
+                              0 as *mut _, 0,
+                              addr_buf as *const _, addr_len,
+                              overlapped.raw(), None);
+            cvt(r)
+        })
+    }
+
+    unsafe fn recv_overlapped(&self,
+                              buf: &mut [u8],
+                              overlapped: &mut Overlapped)
+                              -> io::Result<bool> {
+        recv_overlapped(self.as_raw_socket(), buf, &mut 0, overlapped)
+    }
+
+    unsafe fn recv_overlapped_uninit(&self,
+                                     buf: &mut [MaybeUninit<u8>],
+                                     overlapped: &mut Overlapped)
+                                     -> io::Result<bool> {
+        recv_overlapped_uninit(self.as_raw_socket(), buf, &mut 0, overlapped)
+    }
+
+    unsafe fn send_overlapped(&self,
+                              buf: &[u8],
+                              overlapped: &mut Overlapped)
+                              -> io::Result<bool> {
+        send_overlapped(self.as_raw_socket(), buf, overlapped)
+    }
+
+    fn set_connreset(&self, enable: bool) -> io::Result<()> {
+        let enable = enable as BOOL;
+        ioctl_in(self.as_raw_socket(), SIO_UDP_CONNRESET, &enable)
+    }
+
+    fn set_netreset(&self, enable: bool) -> io::Result<()> {
+        let enable = enable as BOOL;
+        ioctl_in(self.as_raw_socket(), SIO_UDP_NETRESET, &enable)
+    }
+
+    fn routing_interface(&self, addr: &SocketAddr) -> io::Result<SocketAddr> {
+        let addr = RawSocketAddr::from(*addr);
+        let (addr_buf, addr_len) = (addr.as_ptr(), addr.len());
+        let mut ret: SOCKADDR_STORAGE = unsafe { mem::zeroed() };
+        let mut bytes = 0;
+        let r = unsafe {
+            WSAIoctl(self.as_raw_socket(), SIO_ROUTING_INTERFACE_QUERY,
+                     addr_buf as *mut _, addr_len as DWORD,
+                     &mut ret as *mut _ as *mut _,
+                     mem::size_of_val(&ret) as DWORD, &mut bytes,
+                     0 as *mut _, None)
+        };
+        try!(cvt(r));
+        unsafe {
+            ptrs_to_socket_addr(&ret as *const _ as *const _, bytes as c_int)
+        }.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Other,
+                           "unrecognized routing interface address")
+        })
+    }
+
+    unsafe fn routing_interface_change_overlapped(&self,
+                                                  addr: &SocketAddr,
+                                                  overlapped: &mut Overlapped)
+                                                  -> io::Result<bool> {
+        let addr = RawSocketAddr::from(*addr);
+        let (addr_buf, addr_len) = (addr.as_ptr(), addr.len());
+        let mut bytes = 0;
+        let r = WSAIoctl(self.as_raw_socket(), SIO_ROUTING_INTERFACE_CHANGE,
+                         addr_buf as *mut _, addr_len as DWORD,
+                         0 as *mut _, 0, &mut bytes, overlapped.raw(), None);
+        cvt(r)
+    }
+
+    fn set_unicast_interface_v4(&self, index: u32) -> io::Result<()> {
+        set_unicast_interface(self.as_raw_socket(), false, index)
+    }
+
+    fn set_unicast_interface_v6(&self, index: u32) -> io::Result<()> {
+        set_unicast_interface(self.as_raw_socket(), true, index)
+    }
+
+    fn unicast_interface_v4(&self) -> io::Result<u32> {
+        unicast_interface(self.as_raw_socket(), false)
+    }
+
+    fn unicast_interface_v6(&self) -> io::Result<u32> {
+        unicast_interface(self.as_raw_socket(), true)
+    }
+
+    fn join_multicast_group(&self, group: &IpAddr, interface: u32)
+                            -> io::Result<()> {
+        multicast_group(self.as_raw_socket(), MCAST_JOIN_GROUP, group,
+                        interface)
+    }
+
+    fn leave_multicast_group(&self, group: &IpAddr, interface: u32)
+                             -> io::Result<()> {
+        multicast_group(self.as_raw_socket(), MCAST_LEAVE_GROUP, group,
+                        interface)
+    }
+
+    fn join_source_group(&self, group: &IpAddr, source: &IpAddr,
+                         interface: u32) -> io::Result<()> {
+        multicast_source_group(self.as_raw_socket(), MCAST_JOIN_SOURCE_GROUP,
+                               group, source, interface)
+    }
+
+    fn leave_source_group(&self, group: &IpAddr, source: &IpAddr,
+                          interface: u32) -> io::Result<()> {
+        multicast_source_group(self.as_raw_socket(), MCAST_LEAVE_SOURCE_GROUP,
+                               group, source, interface)
+    }
+
+    // Like `IP_UNICAST_IF`, `IP_MULTICAST_IF` interprets addresses in the
+    // 0.0.0.0/8 range as an interface index in network byte order.
+    fn set_multicast_interface_v4(&self, index: u32) -> io::Result<()> {
+        setsockopt(self.as_raw_socket(), IPPROTO_IP, IP_MULTICAST_IF,
+                   hton(index) as DWORD)
+    }
+
+    fn set_multicast_interface_v6(&self, index: u32) -> io::Result<()> {
+        setsockopt(self.as_raw_socket(), IPPROTO_IPV6.0 as c_int,
+                   IPV6_MULTICAST_IF, index as DWORD)
+    }
+
+    unsafe fn recv_msg_overlapped(&self,
+                                  buf: &mut [u8],
+                                  control: &mut [u8],
+                                  msg: &mut MsgBuf,
+                                  overlapped: &mut Overlapped)
+                                  -> io::Result<bool> {
+        let socket = self.as_raw_socket();
+        let ptr = try!(WSARECVMSG.get(socket));
+        let recv_msg = mem::transmute::<_, WSARecvMsg>(ptr);
+        submit(Operation::Read, buf.len(), socket, overlapped.raw(),
+               |len| {
+            msg.data = WSABUF {
+                len: ::clamp_len(len),
+                buf: buf.as_mut_ptr() as *mut _,
+            };
+            msg.msg = WSAMSG {
+                name: &mut msg.addr as *mut _ as *mut _,
+                namelen: mem::size_of::<SOCKADDR_STORAGE>() as INT,
+                lpBuffers: &mut msg.data,
+                dwBufferCount: 1,
+                Control: WSABUF {
+                    len: ::clamp_len(control.len()),
+                    buf: control.as_mut_ptr() as *mut _,
+                },
+                dwFlags: 0,
+            };
+            let r = recv_msg(socket, &mut msg.msg, 0 as *mut _,
+                             overlapped.raw(), None);
+            cvt(r)
+        })
+    }
+
+    unsafe fn send_msg_overlapped(&self,
+                                  buf: &[u8],
+                                  addr: &SocketAddr,
+                                  control: &[u8],
+                                  msg: &mut MsgBuf,
+                                  overlapped: &mut Overlapped)
+                                  -> io::Result<bool> {
+        let socket = self.as_raw_socket();
+        let addr = RawSocketAddr::from(*addr);
+        let (addr_buf, addr_len) = (addr.as_ptr(), addr.len());
+        ptr::copy_nonoverlapping(addr_buf as *const u8,
+                                 &mut msg.addr as *mut _ as *mut u8,
+                                 addr_len as usize);
+        submit(Operation::Write, buf.len(), socket, overlapped.raw(),
+               |len| {
+            msg.data = WSABUF {
+                len: ::clamp_len(len),
+                buf: buf.as_ptr() as *mut _,
+            };
+            msg.msg = WSAMSG {
+                name: &mut msg.addr as *mut _ as *mut _,
+                namelen: addr_len,
+                lpBuffers: &mut msg.data,
+                dwBufferCount: 1,
+                Control: WSABUF {
+                    len: ::clamp_len(control.len()),
+                    buf: control.as_ptr() as *mut _,
+                },
+                dwFlags: 0,
+            };
+            let r = WSASendMsg(socket, &mut msg.msg, 0, 0 as *mut _,
+                               overlapped.raw(), None);
+            cvt(r)
+        })
+    }
+
+    fn set_recv_pktinfo_v4(&self, enable: bool) -> io::Result<()> {
+        setsockopt(self.as_raw_socket(), IPPROTO_IP, IP_PKTINFO,
+                   enable as DWORD)
+    }
+
+    fn set_recv_pktinfo_v6(&self, enable: bool) -> io::Result<()> {
+        setsockopt(self.as_raw_socket(), IPPROTO_IPV6.0 as c_int,
+                   IPV6_PKTINFO, enable as DWORD)
+    }
+
+    fn set_recv_ecn_v4(&self, enable: bool) -> io::Result<()> {
+        setsockopt(self.as_raw_socket(), IPPROTO_IP, IP_RECVECN,
+                   enable as DWORD)
+    }
+
+    fn set_recv_ecn_v6(&self, enable: bool) -> io::Result<()> {
+        setsockopt(self.as_raw_socket(), IPPROTO_IPV6.0 as c_int,
+                   IPV6_RECVECN, enable as DWORD)
+    }
+
+    fn set_send_msg_size(&self, size: u32) -> io::Result<()> {
+        setsockopt(self.as_raw_socket(), IPPROTO_UDP.0 as c_int,
+                   UDP_SEND_MSG_SIZE, size as DWORD)
+    }
+
+    fn send_msg_size(&self) -> io::Result<u32> {
+        getsockopt::<DWORD>(self.as_raw_socket(), IPPROTO_UDP.0 as c_int,
+                            UDP_SEND_MSG_SIZE)
+    }
+
+    fn set_recv_max_coalesced_size(&self, size: u32) -> io::Result<()> {
+        setsockopt(self.as_raw_socket(), IPPROTO_UDP.0 as c_int,
+                   UDP_RECV_MAX_COALESCED_SIZE, size as DWORD)
+    }
+
+    fn recv_max_coalesced_size(&self) -> io::Result<u32> {
+        getsockopt::<DWORD>(self.as_raw_socket(), IPPROTO_UDP.0 as c_int,
+                            UDP_RECV_MAX_COALESCED_SIZE)
+    }
+
+    fn set_timestamping(&self, flags: u32, tx_buffered: u16)
+                        -> io::Result<()> {
+        let config = TIMESTAMPING_CONFIG {
+            Flags: flags,
+            TxTimestampsBuffered: tx_buffered,
         };
-        // NB: this unwrap() should be guaranteed to succeed, and this is an
-        // assert that it does indeed succeed.
-        Ok((socket.to_tcp_stream().unwrap(), succeeded))
+        ioctl_in(self.as_raw_socket(), SIO_TIMESTAMPING, &config)
+    }
+
+    fn timestamp_id(&self) -> io::Result<u32> {
+        getsockopt::<DWORD>(self.as_raw_socket(), SOL_SOCKET, SO_TIMESTAMP_ID)
+    }
+
+    fn tx_timestamp(&self, id: u32) -> io::Result<u64> {
+        ioctl_in_out::<DWORD, u64>(self.as_raw_socket(), SIO_GET_TX_TIMESTAMP,
+                                   &id)
+    }
+
+    unsafe fn result(&self, overlapped: &Overlapped)
+                     -> io::Result<(usize, u32)> {
+        result(self.as_raw_socket(), overlapped)
+    }
+
+    unsafe fn recv_result(&self, overlapped: &Overlapped)
+                          -> io::Result<(usize, u32)> {
+        recv_result(self.as_raw_socket(), overlapped)
     }
 }
 
-impl SocketAddrBuf {
-    /// Creates a new blank socket address buffer.
+impl TcpBuilderExt for TcpBuilder {
+    unsafe fn connect_overlapped(&self, addr: &SocketAddr,
+                                 overlapped: &mut Overlapped)
+                                 -> io::Result<(TcpStream, bool)> {
+        let done = try!(connect_overlapped(try!(builder_socket(self)), addr,
+                                           overlapped));
+        Ok((try!(self.to_tcp_stream()), done))
+    }
+
+    unsafe fn connect_overlapped_with_data(&self, addr: &SocketAddr,
+                                           buf: &[u8],
+                                           overlapped: &mut Overlapped)
+                                           -> io::Result<(TcpStream,
+                                                          Option<usize>)> {
+        let mut sent = 0;
+        let socket = try!(builder_socket(self));
+        let done = try!(connect_overlapped_with_data(socket,
+                                                     addr, buf, &mut sent,
+                                                     overlapped));
+        let sent = if done { Some(sent as usize) } else { None };
+        Ok((try!(self.to_tcp_stream()), sent))
+    }
+
+    unsafe fn connect_overlapped_auto_bind(&self, addr: &SocketAddr,
+                                           overlapped: &mut Overlapped)
+                                           -> io::Result<(TcpStream, bool)> {
+        try!(bind_unbound(try!(builder_socket(self)), addr));
+        TcpBuilderExt::connect_overlapped(self, addr, overlapped)
+    }
+
+    fn set_initial_rto(&self,
+                       rtt: Option<Duration>,
+                       max_syn_retransmissions: Option<u8>)
+                       -> io::Result<()> {
+        set_initial_rto(try!(builder_socket(self)), rtt,
+                        max_syn_retransmissions)
+    }
+
+    fn set_unicast_interface_v4(&self, index: u32) -> io::Result<()> {
+        set_unicast_interface(try!(builder_socket(self)), false, index)
+    }
+
+    fn set_unicast_interface_v6(&self, index: u32) -> io::Result<()> {
+        set_unicast_interface(try!(builder_socket(self)), true, index)
+    }
+
+    fn unicast_interface_v4(&self) -> io::Result<u32> {
+        unicast_interface(try!(builder_socket(self)), false)
+    }
+
+    fn unicast_interface_v6(&self) -> io::Result<u32> {
+        unicast_interface(try!(builder_socket(self)), true)
+    }
+
+    fn set_loopback_fast_path(&self, enable: bool) -> io::Result<()> {
+        set_loopback_fast_path(try!(builder_socket(self)), enable)
+    }
+}
+
+impl TcpBuilderExt for SocketBuilder {
+    unsafe fn connect_overlapped(&self, addr: &SocketAddr,
+                                 overlapped: &mut Overlapped)
+                                 -> io::Result<(TcpStream, bool)> {
+        let done = try!(connect_overlapped(try!(self.socket()), addr,
+                                           overlapped));
+        Ok((try!(self.to_tcp_stream()), done))
+    }
+
+    unsafe fn connect_overlapped_with_data(&self, addr: &SocketAddr,
+                                           buf: &[u8],
+                                           overlapped: &mut Overlapped)
+                                           -> io::Result<(TcpStream,
+                                                          Option<usize>)> {
+        let mut sent = 0;
+        let done = try!(connect_overlapped_with_data(try!(self.socket()),
+                                                     addr, buf, &mut sent,
+                                                     overlapped));
+        let sent = if done { Some(sent as usize) } else { None };
+        Ok((try!(self.to_tcp_stream()), sent))
+    }
+
+    unsafe fn connect_overlapped_auto_bind(&self, addr: &SocketAddr,
+                                           overlapped: &mut Overlapped)
+                                           -> io::Result<(TcpStream, bool)> {
+        try!(bind_unbound(try!(self.socket()), addr));
+        TcpBuilderExt::connect_overlapped(self, addr, overlapped)
+    }
+
+    fn set_initial_rto(&self,
+                       rtt: Option<Duration>,
+                       max_syn_retransmissions: Option<u8>)
+                       -> io::Result<()> {
+        set_initial_rto(try!(self.socket()), rtt, max_syn_retransmissions)
+    }
+
+    fn set_unicast_interface_v4(&self, index: u32) -> io::Result<()> {
+        set_unicast_interface(try!(self.socket()), false, index)
+    }
+
+    fn set_unicast_interface_v6(&self, index: u32) -> io::Result<()> {
+        set_unicast_interface(try!(self.socket()), true, index)
+    }
+
+    fn unicast_interface_v4(&self) -> io::Result<u32> {
+        unicast_interface(try!(self.socket()), false)
+    }
+
+    fn unicast_interface_v6(&self) -> io::Result<u32> {
+        unicast_interface(try!(self.socket()), true)
+    }
+
+    fn set_loopback_fast_path(&self, enable: bool) -> io::Result<()> {
+        set_loopback_fast_path(try!(self.socket()), enable)
+    }
+}
+
+impl SocketBuilder {
+    /// Creates a new IPv4 TCP socket.
+    pub fn new_v4() -> io::Result<SocketBuilder> {
+        SocketBuilder::new(AF_INET)
+    }
+
+    /// Creates a new IPv6 TCP socket.
+    pub fn new_v6() -> io::Result<SocketBuilder> {
+        SocketBuilder::new(AF_INET6)
+    }
+
+    fn new(family: c_int) -> io::Result<SocketBuilder> {
+        let socket = try!(socket(family, SOCK_STREAM,
+                                 IPPROTO_TCP.0 as c_int, 0));
+        Ok(SocketBuilder { socket: Cell::new(socket) })
+    }
+
+    /// Binds this socket to the specified address.
     ///
-    /// This should be used before a call to `recv_from_overlapped` overlapped
-    /// to create an instance to pass down.
-    pub fn new() -> SocketAddrBuf {
-        SocketAddrBuf {
-            buf: unsafe { mem::zeroed() },
-            len: mem::size_of::<SOCKADDR_STORAGE>() as c_int,
+    /// A socket has to be bound before it's passed to `connect_overlapped`,
+    /// typically to an unspecified address with port 0, but not before it's
+    /// passed to `accept_overlapped`.
+    pub fn bind(&self, addr: &SocketAddr) -> io::Result<&SocketBuilder> {
+        let addr = RawSocketAddr::from(*addr);
+        let r = unsafe {
+            ::ws2_32::bind(try!(self.socket()), addr.as_ptr(), addr.len())
+        };
+        cvt(r).map(|_| self)
+    }
+
+    /// Returns the address this socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        let socket = try!(self.socket());
+        unsafe {
+            let mut storage: SOCKADDR_STORAGE = mem::zeroed();
+            let mut len = mem::size_of_val(&storage) as c_int;
+            try!(cvt(getsockname(socket, &mut storage as *mut _ as *mut _,
+                                 &mut len)));
+            ptrs_to_socket_addr(&storage as *const _ as *const _, len)
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput,
+                                   "invalid address family")
+                })
         }
     }
 
-    /// Parses this buffer to return a standard socket address.
-    ///
-    /// This function should be called after the buffer has been filled in with
-    /// a call to `recv_from_overlapped` being completed. It will interpret the
-    /// address filled in and return the standard socket address type.
-    ///
-    /// If an error is encountered then `None` is returned.
-    pub fn to_socket_addr(&self) -> Option<SocketAddr> {
+    /// Starts listening for connections on this socket, consuming it to
+    /// return a TCP listener.
+    pub fn listen(&self, backlog: i32) -> io::Result<TcpListener> {
+        let r = unsafe { ::ws2_32::listen(try!(self.socket()), backlog) };
+        try!(cvt(r));
+        self.to_tcp_listener()
+    }
+
+    /// Consumes the socket of this builder to return a TCP stream, without
+    /// connecting it.
+    pub fn to_tcp_stream(&self) -> io::Result<TcpStream> {
+        let socket = try!(self.socket());
+        self.socket.set(INVALID_SOCKET);
+        Ok(unsafe { TcpStream::from_raw_socket(socket) })
+    }
+
+    /// Consumes the socket of this builder to return a TCP listener, without
+    /// starting to listen on it.
+    pub fn to_tcp_listener(&self) -> io::Result<TcpListener> {
+        let socket = try!(self.socket());
+        self.socket.set(INVALID_SOCKET);
+        Ok(unsafe { TcpListener::from_raw_socket(socket) })
+    }
+
+    fn socket(&self) -> io::Result<SOCKET> {
+        match self.socket.get() {
+            INVALID_SOCKET => {
+                Err(io::Error::new(io::ErrorKind::Other,
+                                   "builder has already finished its socket"))
+            }
+            socket => Ok(socket),
+        }
+    }
+}
+
+impl AsRawSocket for SocketBuilder {
+    /// Returns the socket of this builder, which is `INVALID_SOCKET` once it
+    /// has been consumed.
+    fn as_raw_socket(&self) -> RawSocket {
+        self.socket.get()
+    }
+}
+
+impl IntoRawSocket for SocketBuilder {
+    fn into_raw_socket(self) -> RawSocket {
+        let socket = self.socket.get();
+        mem::forget(self);
+        socket
+    }
+}
+
+impl Drop for SocketBuilder {
+    fn drop(&mut self) {
+        if self.socket.get() != INVALID_SOCKET {
+            unsafe { closesocket(self.socket.get()) };
+        }
+    }
+}
+
+impl sealed::Sealed for SocketBuilder {}
+
+impl AcceptSocket for SocketBuilder {
+    fn accept_socket(&self) -> io::Result<SOCKET> {
+        self.socket()
+    }
+
+    fn to_stream(&self) -> io::Result<TcpStream> {
+        self.to_tcp_stream()
+    }
+}
+
+impl sealed::Sealed for TcpBuilder {}
+
+impl AcceptSocket for TcpBuilder {
+    fn accept_socket(&self) -> io::Result<SOCKET> {
+        builder_socket(self)
+    }
+
+    fn to_stream(&self) -> io::Result<TcpStream> {
+        self.to_tcp_stream()
+    }
+}
+
+impl ListenerBuilder {
+    /// Creates a new builder for a listener bound to `addr`, with the default
+    /// settings.
+    pub fn new(addr: SocketAddr) -> ListenerBuilder {
+        ListenerBuilder {
+            addr: addr,
+            backlog: 128,
+            reuse_address: false,
+            exclusive_address_use: false,
+            only_v6: None,
+            loopback_fast_path: false,
+            skip_completion_port_on_success: false,
+            inheritable: false,
+        }
+    }
+
+    /// Sets the maximum number of connections waiting to be accepted.
+    ///
+    /// This defaults to 128.
+    pub fn backlog(&mut self, backlog: i32) -> &mut Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Indicates whether the listener may bind to an address which is
+    /// already in use (`SO_REUSEADDR`).
+    ///
+    /// This defaults to `false`.
+    pub fn reuse_address(&mut self, reuse: bool) -> &mut Self {
+        self.reuse_address = reuse;
+        self
+    }
+
+    /// Indicates whether other sockets are prevented from binding to the
+    /// listener's address, even with `SO_REUSEADDR` (`SO_EXCLUSIVEADDRUSE`).
+    ///
+    /// This defaults to `false`, and can't be combined with
+    /// `reuse_address`.
+    pub fn exclusive_address_use(&mut self, exclusive: bool) -> &mut Self {
+        self.exclusive_address_use = exclusive;
+        self
+    }
+
+    /// Indicates whether an IPv6 listener only accepts IPv6 connections
+    /// (`IPV6_V6ONLY`), rather than IPv4 connections too.
+    ///
+    /// This is left at the system default unless set, and is ignored for
+    /// IPv4 addresses.
+    pub fn only_v6(&mut self, only_v6: bool) -> &mut Self {
+        self.only_v6 = Some(only_v6);
+        self
+    }
+
+    /// Indicates whether accepted connections take the loopback fast path
+    /// (via `SIO_LOOPBACK_FAST_PATH`).
+    ///
+    /// This defaults to `false`. See `TcpBuilderExt::set_loopback_fast_path`
+    /// for more details.
+    pub fn loopback_fast_path(&mut self, enable: bool) -> &mut Self {
+        self.loopback_fast_path = enable;
+        self
+    }
+
+    /// Indicates whether accepts which complete immediately don't post a
+    /// completion to the listener's port.
+    ///
+    /// This defaults to `false`. See
+    /// `SocketExt::set_skip_completion_port_on_success` for more details,
+    /// including the providers for which this isn't enabled. A listener
+    /// skipping these completions can't be used with `accept::Acceptor`.
+    pub fn skip_completion_port_on_success(&mut self, skip: bool)
+                                           -> &mut Self {
+        self.skip_completion_port_on_success = skip;
+        self
+    }
+
+    /// Indicates whether the listener is inherited by child processes.
+    ///
+    /// This defaults to `false`, so the listener doesn't leak into processes
+    /// spawned by the server.
+    pub fn inheritable(&mut self, inheritable: bool) -> &mut Self {
+        self.inheritable = inheritable;
+        self
+    }
+
+    /// Using the options in this builder, creates a socket, binds it and
+    /// starts listening for connections on it.
+    ///
+    /// The `AcceptEx` and `GetAcceptExSockaddrs` extensions are looked up
+    /// for the listener ahead of time, so the first `accept_overlapped` on
+    /// it doesn't have to.
+    pub fn listen(&self) -> io::Result<TcpListener> {
+        let builder = match self.addr {
+            SocketAddr::V4(..) => try!(SocketBuilder::new_v4()),
+            SocketAddr::V6(..) => try!(SocketBuilder::new_v6()),
+        };
+        let socket = try!(builder.socket());
+        if self.reuse_address {
+            try!(setsockopt(socket, SOL_SOCKET, SO_REUSEADDR, 1 as c_int));
+        }
+        if self.exclusive_address_use {
+            try!(setsockopt(socket, SOL_SOCKET, SO_EXCLUSIVEADDRUSE,
+                            1 as c_int));
+        }
+        if let (SocketAddr::V6(..), Some(only_v6)) = (self.addr, self.only_v6) {
+            try!(setsockopt(socket, IPPROTO_IPV6.0 as c_int, IPV6_V6ONLY,
+                            only_v6 as DWORD));
+        }
+        if self.loopback_fast_path {
+            try!(set_loopback_fast_path(socket, true));
+        }
+        try!(builder.bind(&self.addr));
+        let listener = try!(builder.listen(self.backlog));
+        try!(ACCEPTEX.get(socket));
+        try!(GETACCEPTEXSOCKADDRS.get(socket));
+        if self.skip_completion_port_on_success {
+            try!(listener.set_skip_completion_port_on_success());
+        }
+        if self.inheritable {
+            try!(listener.set_inheritable(true));
+        }
+        Ok(listener)
+    }
+}
+
+impl TcpListenerExt for TcpListener {
+    unsafe fn accept_overlapped<S, A>(&self,
+                                      socket: &S,
+                                      addrs: &mut A,
+                                      overlapped: &mut Overlapped)
+                                      -> io::Result<(TcpStream, bool)>
+        where S: AcceptSocket + ?Sized, A: AcceptAddrsStorage
+    {
+        let accepted = try!(socket.accept_socket());
+        let succeeded = try!(self.accept_overlapped_raw(accepted, addrs,
+                                                        overlapped));
+        Ok((try!(socket.to_stream()), succeeded))
+    }
+
+    unsafe fn accept_overlapped_raw<A>(&self,
+                                       socket: RawSocket,
+                                       addrs: &mut A,
+                                       overlapped: &mut Overlapped)
+                                       -> io::Result<bool>
+        where A: AcceptAddrsStorage
+    {
+        type AcceptEx = unsafe extern "system" fn(SOCKET, SOCKET, PVOID,
+                                                  DWORD, DWORD, DWORD, LPDWORD,
+                                                  LPOVERLAPPED) -> BOOL;
+
+        let ptr = try!(ACCEPTEX.get(self.as_raw_socket()));
+        let accept_ex = mem::transmute::<_, AcceptEx>(ptr);
+        // Looked up now so the buffer can be parsed later with `addrs`.
+        try!(GETACCEPTEXSOCKADDRS.get(self.as_raw_socket()));
+
+        let mut bytes = 0;
+        let (a, b, c, d) = addrs.args();
+        let listener = self.as_raw_socket();
+        submit(Operation::Accept, 0, listener, overlapped.raw(), |_| {
+            let r = accept_ex(listener, socket as SOCKET,
+                              a, b, c, d, &mut bytes, overlapped.raw());
+            if r == TRUE {
+                Ok(true)
+            } else {
+                last_err()
+            }
+        })
+    }
+
+    fn set_loopback_fast_path(&self, enable: bool) -> io::Result<()> {
+        set_loopback_fast_path(self.as_raw_socket(), enable)
+    }
+}
+
+impl<T: AsRawSocket + ?Sized> SocketExt for T {
+    unsafe fn recv_overlapped_raw(&self,
+                                  buf: &mut [u8],
+                                  overlapped: &mut Overlapped)
+                                  -> io::Result<bool> {
+        recv_overlapped(self.as_raw_socket(), buf, &mut 0, overlapped)
+    }
+
+    unsafe fn send_overlapped_raw(&self,
+                                  buf: &[u8],
+                                  overlapped: &mut Overlapped)
+                                  -> io::Result<bool> {
+        send_overlapped(self.as_raw_socket(), buf, overlapped)
+    }
+
+    fn base_socket(&self) -> io::Result<RawSocket> {
+        ioctl_out(self.as_raw_socket(), SIO_BASE_HANDLE)
+    }
+
+    fn duplicate_socket(&self, pid: u32) -> io::Result<WSAPROTOCOL_INFOW> {
+        unsafe {
+            let mut info = mem::zeroed();
+            let r = WSADuplicateSocketW(self.as_raw_socket(), pid, &mut info);
+            cvt(r).map(|_| info)
+        }
+    }
+
+    fn event_select(&self, event: &SocketEvent, events: u32)
+                    -> io::Result<()> {
+        let r = unsafe {
+            WSAEventSelect(self.as_raw_socket(), event.handle.raw(),
+                           events as c_long)
+        };
+        cvt(r).map(|_| ())
+    }
+
+    fn set_skip_completion_port_on_success(&self) -> io::Result<bool> {
+        let socket = self.as_raw_socket();
+        if !try!(has_ifs_handles(socket)) {
+            return Ok(false)
+        }
+        try!(::cvt(unsafe {
+            SetFileCompletionNotificationModes(
+                socket as HANDLE,
+                FILE_SKIP_COMPLETION_PORT_ON_SUCCESS |
+                    FILE_SKIP_SET_EVENT_ON_HANDLE)
+        }));
+        ::fault::skip_port(socket as HANDLE);
+        Ok(true)
+    }
+
+    fn set_inheritable(&self, inheritable: bool) -> io::Result<()> {
+        let flags = if inheritable { HANDLE_FLAG_INHERIT } else { 0 };
+        ::cvt(unsafe {
+            SetHandleInformation(self.as_raw_socket() as HANDLE,
+                                 HANDLE_FLAG_INHERIT, flags)
+        }).map(|_| ())
+    }
+
+    fn inheritable(&self) -> io::Result<bool> {
+        let mut flags = 0;
+        try!(::cvt(unsafe {
+            GetHandleInformation(self.as_raw_socket() as HANDLE, &mut flags)
+        }));
+        Ok(flags & HANDLE_FLAG_INHERIT != 0)
+    }
+
+    fn network_events(&self, event: Option<&SocketEvent>)
+                      -> io::Result<NetworkEvents> {
+        let event = event.map_or(0 as HANDLE, |e| e.handle.raw());
+        unsafe {
+            let mut events = mem::zeroed();
+            let r = WSAEnumNetworkEvents(self.as_raw_socket(), event,
+                                         &mut events);
+            cvt(r).map(|_| NetworkEvents(events))
+        }
+    }
+
+    unsafe fn ioctl_overlapped(&self,
+                               code: u32,
+                               input: &[u8],
+                               output: &mut [u8],
+                               overlapped: &mut Overlapped)
+                               -> io::Result<bool> {
+        let in_len = try!(::checked_len(input.len()));
+        let mut bytes = 0;
+        let r = WSAIoctl(self.as_raw_socket(), code,
+                         input.as_ptr() as *mut _, in_len,
+                         output.as_mut_ptr() as *mut _,
+                         ::clamp_len(output.len()),
+                         &mut bytes, overlapped.raw(), None);
+        cvt(r)
+    }
+}
+
+fn total_len(bufs: &[WSABUF]) -> usize {
+    bufs.iter().fold(0, |n, b| n + b.len as usize)
+}
+
+// Calls `f` with the prefix of `bufs` covering their first `len` bytes, which
+// is all of them unless fault injection shortened the operation.
+unsafe fn with_len<F>(bufs: &[WSABUF], len: usize, f: F)
+                      -> io::Result<bool>
+    where F: FnOnce(&[WSABUF]) -> io::Result<bool>
+{
+    if len == total_len(bufs) {
+        return f(bufs)
+    }
+    let mut short = Vec::new();
+    let mut left = len;
+    for buf in bufs.iter() {
+        if left == 0 {
+            break
+        }
+        let n = cmp::min(buf.len as usize, left);
+        short.push(WSABUF { len: n as u_long, buf: buf.buf });
+        left -= n;
+    }
+    f(&short)
+}
+
+impl<'a> WsaBuf<'a> {
+    /// Creates a new buffer referencing the data in `buf`.
+    ///
+    /// `WSABUF` can only describe buffers of up to `u32::MAX` bytes, so if
+    /// `buf` is larger than that only its first `u32::MAX` bytes are
+    /// referenced.
+    pub fn new(buf: &'a [u8]) -> WsaBuf<'a> {
+        WsaBuf {
+            raw: WSABUF {
+                len: ::clamp_len(buf.len()),
+                buf: buf.as_ptr() as *mut _,
+            },
+            _marker: marker::PhantomData,
+        }
+    }
+
+    /// Returns the data referenced by this buffer.
+    pub fn as_slice(&self) -> &'a [u8] {
+        unsafe {
+            slice::from_raw_parts(self.raw.buf as *const u8,
+                                  self.raw.len as usize)
+        }
+    }
+
+    /// Returns a reference to the underlying `WSABUF`.
+    pub fn as_raw(&self) -> &WSABUF {
+        &self.raw
+    }
+
+    /// Views a slice of these buffers as a slice of `WSABUF`s.
+    pub fn as_raw_slice<'b>(bufs: &'b [WsaBuf<'a>]) -> &'b [WSABUF] {
+        unsafe { slice::from_raw_parts(bufs.as_ptr() as *const _, bufs.len()) }
+    }
+
+    /// Views a slice of `IoSlice`s as a slice of these buffers.
+    ///
+    /// The standard library guarantees that `IoSlice` has the same layout as
+    /// `WSABUF`, so no conversion takes place.
+    pub fn from_io_slices<'b>(bufs: &'b [IoSlice<'a>]) -> &'b [WsaBuf<'a>] {
+        unsafe { slice::from_raw_parts(bufs.as_ptr() as *const _, bufs.len()) }
+    }
+}
+
+impl<'a> Deref for WsaBuf<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl<'a> From<&'a [u8]> for WsaBuf<'a> {
+    fn from(buf: &'a [u8]) -> WsaBuf<'a> {
+        WsaBuf::new(buf)
+    }
+}
+
+impl<'a> From<&'a mut [u8]> for WsaBuf<'a> {
+    fn from(buf: &'a mut [u8]) -> WsaBuf<'a> {
+        WsaBuf::new(buf)
+    }
+}
+
+impl<'a> WsaBufMut<'a> {
+    /// Creates a new buffer referencing the storage in `buf`.
+    ///
+    /// `WSABUF` can only describe buffers of up to `u32::MAX` bytes, so if
+    /// `buf` is larger than that only its first `u32::MAX` bytes are
+    /// referenced.
+    pub fn new(buf: &'a mut [u8]) -> WsaBufMut<'a> {
+        WsaBufMut {
+            raw: WSABUF {
+                len: ::clamp_len(buf.len()),
+                buf: buf.as_mut_ptr() as *mut _,
+            },
+            _marker: marker::PhantomData,
+        }
+    }
+
+    /// Consumes this buffer, returning the storage it references.
+    pub fn into_slice(self) -> &'a mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self.raw.buf as *mut u8,
+                                      self.raw.len as usize)
+        }
+    }
+
+    /// Returns a reference to the underlying `WSABUF`.
+    pub fn as_raw(&self) -> &WSABUF {
+        &self.raw
+    }
+
+    /// Views a slice of these buffers as a slice of `WSABUF`s.
+    pub fn as_raw_slice<'b>(bufs: &'b mut [WsaBufMut<'a>])
+                            -> &'b mut [WSABUF] {
+        unsafe {
+            slice::from_raw_parts_mut(bufs.as_mut_ptr() as *mut _, bufs.len())
+        }
+    }
+
+    /// Views a slice of `IoSliceMut`s as a slice of these buffers.
+    ///
+    /// The standard library guarantees that `IoSliceMut` has the same layout
+    /// as `WSABUF`, so no conversion takes place.
+    pub fn from_io_slices<'b>(bufs: &'b mut [IoSliceMut<'a>])
+                              -> &'b mut [WsaBufMut<'a>] {
+        unsafe {
+            slice::from_raw_parts_mut(bufs.as_mut_ptr() as *mut _, bufs.len())
+        }
+    }
+}
+
+impl<'a> Deref for WsaBufMut<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self.raw.buf as *const u8,
+                                  self.raw.len as usize)
+        }
+    }
+}
+
+impl<'a> DerefMut for WsaBufMut<'a> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self.raw.buf as *mut u8,
+                                      self.raw.len as usize)
+        }
+    }
+}
+
+impl<'a> From<&'a mut [u8]> for WsaBufMut<'a> {
+    fn from(buf: &'a mut [u8]) -> WsaBufMut<'a> {
+        WsaBufMut::new(buf)
+    }
+}
+
+impl SocketAddrBuf {
+    /// Creates a new blank socket address buffer.
+    ///
+    /// This should be used before a call to `recv_from_overlapped` overlapped
+    /// to create an instance to pass down.
+    pub fn new() -> SocketAddrBuf {
+        SocketAddrBuf {
+            addr: RawSocketAddr::new(),
+            unmap_v4: false,
+        }
+    }
+
+    /// Configures whether IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) are
+    /// returned by `to_socket_addr` as the IPv4 addresses they map.
+    ///
+    /// Dual-stack sockets receive from IPv4 peers at such addresses, which
+    /// most code expects to see as plain IPv4 addresses. This is disabled by
+    /// default, and is kept by `reset`.
+    pub fn set_unmap_v4(&mut self, unmap: bool) {
+        self.unmap_v4 = unmap;
+    }
+
+    /// Parses this buffer to return a standard socket address.
+    ///
+    /// This function should be called after the buffer has been filled in with
+    /// a call to `recv_from_overlapped` being completed. It will interpret the
+    /// address filled in and return the standard socket address type.
+    ///
+    /// If an error is encountered then `None` is returned.
+    pub fn to_socket_addr(&self) -> Option<SocketAddr> {
+        let addr = self.addr.to_socket_addr();
+        if self.unmap_v4 {
+            addr.map(unmap_v4)
+        } else {
+            addr
+        }
+    }
+
+    /// Parses this buffer to return the address of a Unix domain socket.
+    ///
+    /// This is like `to_socket_addr`, but for buffers filled in with an
+    /// `AF_UNIX` address. If the buffer holds an address of another family
+    /// `None` is returned.
+    pub fn to_unix_addr(&self) -> Option<UnixSocketAddr> {
+        self.addr.to_unix_addr()
+    }
+
+    /// Resets this buffer to the state returned by `new`, so it can be
+    /// passed to another `recv_from_overlapped` operation.
+    pub fn reset(&mut self) {
+        let unmap = self.unmap_v4;
+        *self = SocketAddrBuf::new();
+        self.unmap_v4 = unmap;
+    }
+
+    /// Returns the address family stored in this buffer, such as `AF_INET`.
+    pub fn family(&self) -> u16 {
+        self.addr.family()
+    }
+
+    /// Returns the length of the address stored in this buffer.
+    ///
+    /// Before an operation has filled in the buffer this is the size of the
+    /// whole storage.
+    pub fn len(&self) -> usize {
+        self.addr.len() as usize
+    }
+
+    /// Returns whether the address stored in this buffer is empty, as it is
+    /// after an operation which didn't store an address.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the raw `SOCKADDR_STORAGE` the address is stored in.
+    pub fn storage(&self) -> &SOCKADDR_STORAGE {
+        &self.addr.storage
+    }
+
+    /// Returns the raw address stored in this buffer.
+    pub fn as_raw(&self) -> &RawSocketAddr {
+        &self.addr
+    }
+
+    /// Returns the bytes of the address stored in this buffer, up to its
+    /// length.
+    ///
+    /// This can be used to decode addresses of families which
+    /// `to_socket_addr` doesn't know about.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self.addr.as_ptr() as *const u8, self.len())
+        }
+    }
+}
+
+impl UnixSocketAddr {
+    /// Creates the address of the Unix domain socket at `path`.
+    ///
+    /// An error is returned if the path isn't valid Unicode, contains a null
+    /// byte, or is too long to fit in a `sockaddr_un`.
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<UnixSocketAddr> {
+        let path = try!(path.as_ref().to_str().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput,
+                           "path is not valid unicode")
+        }));
+        if path.as_bytes().contains(&0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "path contains a null byte"))
+        }
+        UnixSocketAddr::from_bytes(path.as_bytes())
+    }
+
+    /// Creates an abstract address with the specified name, which isn't
+    /// associated with the file system.
+    ///
+    /// An error is returned if the name is too long to fit in a
+    /// `sockaddr_un`.
+    pub fn new_abstract(name: &[u8]) -> io::Result<UnixSocketAddr> {
+        let mut path = vec![0];
+        path.extend_from_slice(name);
+        UnixSocketAddr::from_bytes(&path)
+    }
+
+    /// Returns an unnamed address.
+    pub fn unnamed() -> UnixSocketAddr {
+        UnixSocketAddr { path: Vec::new() }
+    }
+
+    fn from_bytes(path: &[u8]) -> io::Result<UnixSocketAddr> {
+        // The path of a `sockaddr_un` has to leave room for the terminating
+        // null.
+        if path.len() >= UNIX_PATH_MAX {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "path is too long"))
+        }
+        Ok(UnixSocketAddr { path: path.to_vec() })
+    }
+
+    /// Returns the path of this address, if it's a path in the file system.
+    pub fn as_pathname(&self) -> Option<&Path> {
+        match self.path.first() {
+            Some(&0) | None => None,
+            Some(_) => str::from_utf8(&self.path).ok().map(Path::new),
+        }
+    }
+
+    /// Returns the name of this address, if it's an abstract address.
+    pub fn as_abstract_name(&self) -> Option<&[u8]> {
+        match self.path.first() {
+            Some(&0) => Some(&self.path[1..]),
+            _ => None,
+        }
+    }
+
+    /// Returns whether this address is unnamed.
+    pub fn is_unnamed(&self) -> bool {
+        self.path.is_empty()
+    }
+}
+
+impl RawSocketAddr {
+    /// Creates an empty address with room for an address of any family, to
+    /// be filled in by a Winsock function such as `getsockname`.
+    ///
+    /// The length is that of the whole storage until it's updated with
+    /// `set_len`.
+    pub fn new() -> RawSocketAddr {
+        RawSocketAddr {
+            storage: unsafe { mem::zeroed() },
+            len: mem::size_of::<SOCKADDR_STORAGE>() as c_int,
+        }
+    }
+
+    /// Creates an address by copying `len` bytes from `ptr`.
+    ///
+    /// At most the size of a `SOCKADDR_STORAGE` is copied.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because `ptr` must point to at least `len`
+    /// readable bytes.
+    pub unsafe fn from_raw_parts(ptr: *const SOCKADDR, len: c_int)
+                                 -> RawSocketAddr {
+        let mut ret = RawSocketAddr::new();
+        ret.len = cmp::max(0, cmp::min(len, ret.len));
+        ptr::copy_nonoverlapping(ptr as *const u8,
+                                 &mut ret.storage as *mut _ as *mut u8,
+                                 ret.len as usize);
+        ret
+    }
+
+    /// Returns a pointer to the address, for passing to Winsock.
+    pub fn as_ptr(&self) -> *const SOCKADDR {
+        &self.storage as *const _ as *const _
+    }
+
+    /// Returns a mutable pointer to the address, for Winsock to fill in.
+    pub fn as_mut_ptr(&mut self) -> *mut SOCKADDR {
+        &mut self.storage as *mut _ as *mut _
+    }
+
+    /// Returns the length of the address.
+    pub fn len(&self) -> c_int {
+        self.len
+    }
+
+    /// Returns whether the address is empty, with a length of zero.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Sets the length of the address, as returned by the Winsock function
+    /// which filled it in.
+    ///
+    /// The length is limited to the size of a `SOCKADDR_STORAGE`.
+    pub fn set_len(&mut self, len: c_int) {
+        let max = mem::size_of::<SOCKADDR_STORAGE>() as c_int;
+        self.len = cmp::max(0, cmp::min(len, max));
+    }
+
+    /// Returns the address family, such as `AF_INET`.
+    pub fn family(&self) -> u16 {
+        self.storage.ss_family as u16
+    }
+
+    /// Returns the IP socket address held, if it's an `AF_INET` or
+    /// `AF_INET6` address.
+    pub fn to_socket_addr(&self) -> Option<SocketAddr> {
+        unsafe { ptrs_to_socket_addr(self.as_ptr(), self.len) }
+    }
+
+    /// Returns the Unix domain socket address held, if it's an `AF_UNIX`
+    /// address.
+    pub fn to_unix_addr(&self) -> Option<UnixSocketAddr> {
+        unsafe { ptrs_to_unix_addr(self.as_ptr(), self.len) }
+    }
+}
+
+impl From<SocketAddr> for RawSocketAddr {
+    fn from(addr: SocketAddr) -> RawSocketAddr {
+        let mut ret = RawSocketAddr::new();
+        match addr {
+            SocketAddr::V4(ref a) => {
+                let mut b: SOCKADDR_IN = unsafe { mem::zeroed() };
+                b.sin_family = AF_INET as ADDRESS_FAMILY;
+                b.sin_port = hton(a.port());
+                b.sin_addr.S_un = hton(u32::from(*a.ip()));
+                unsafe {
+                    ptr::write(ret.as_mut_ptr() as *mut SOCKADDR_IN, b);
+                }
+                ret.len = mem::size_of::<SOCKADDR_IN>() as c_int;
+            }
+            SocketAddr::V6(ref a) => {
+                let mut b: sockaddr_in6 = unsafe { mem::zeroed() };
+                b.sin6_family = AF_INET6 as c_short;
+                b.sin6_port = hton(a.port());
+                b.sin6_flowinfo = a.flowinfo();
+                b.sin6_addr.s6_addr = a.ip().octets();
+                b.sin6_scope_id = a.scope_id();
+                unsafe {
+                    ptr::write(ret.as_mut_ptr() as *mut sockaddr_in6, b);
+                }
+                ret.len = mem::size_of::<sockaddr_in6>() as c_int;
+            }
+        }
+        ret
+    }
+}
+
+impl<'a> From<&'a UnixSocketAddr> for RawSocketAddr {
+    fn from(addr: &'a UnixSocketAddr) -> RawSocketAddr {
+        let mut ret = RawSocketAddr::new();
+        let family = mem::size_of::<ADDRESS_FAMILY>();
+        unsafe {
+            let base = ret.as_mut_ptr() as *mut u8;
+            *(base as *mut ADDRESS_FAMILY) = AF_UNIX as ADDRESS_FAMILY;
+            ptr::copy_nonoverlapping(addr.path.as_ptr(),
+                                     base.offset(family as isize),
+                                     addr.path.len());
+        }
+        // A path is followed by its terminating null, which the storage
+        // was zeroed with.
+        let nul = match addr.path.first() {
+            Some(&0) | None => 0,
+            Some(_) => 1,
+        };
+        ret.len = (family + addr.path.len() + nul) as c_int;
+        ret
+    }
+}
+
+impl From<UnixSocketAddr> for RawSocketAddr {
+    fn from(addr: UnixSocketAddr) -> RawSocketAddr {
+        RawSocketAddr::from(&addr)
+    }
+}
+
+impl TryFrom<RawSocketAddr> for SocketAddr {
+    type Error = io::Error;
+
+    fn try_from(addr: RawSocketAddr) -> io::Result<SocketAddr> {
+        addr.to_socket_addr().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput,
+                           "invalid address family")
+        })
+    }
+}
+
+impl TryFrom<RawSocketAddr> for UnixSocketAddr {
+    type Error = io::Error;
+
+    fn try_from(addr: RawSocketAddr) -> io::Result<UnixSocketAddr> {
+        addr.to_unix_addr().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput,
+                           "invalid address family")
+        })
+    }
+}
+
+impl fmt::Debug for RawSocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RawSocketAddr")
+         .field("family", &self.family())
+         .field("len", &self.len)
+         .finish()
+    }
+}
+
+impl MsgBuf {
+    /// Creates a new blank message header.
+    ///
+    /// This should be used before a call to `recv_msg_overlapped` or
+    /// `send_msg_overlapped` to create an instance to pass down.
+    pub fn new() -> MsgBuf {
+        unsafe { mem::zeroed() }
+    }
+
+    /// Returns the address the message was received from.
+    ///
+    /// If the operation hasn't completed or the address couldn't be parsed
+    /// then `None` is returned.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        unsafe {
+            ptrs_to_socket_addr(&self.addr as *const _ as *const _,
+                                self.msg.namelen)
+        }
+    }
+
+    /// Returns the number of bytes of control data which were received.
+    pub fn control_len(&self) -> usize {
+        self.msg.Control.len as usize
+    }
+
+    /// Returns the flags of the received message, such as `MSG_TRUNC`.
+    pub fn flags(&self) -> u32 {
+        self.msg.dwFlags
+    }
+
+    /// Returns whether the datagram was larger than the data buffer and the
+    /// excess was discarded.
+    pub fn is_truncated(&self) -> bool {
+        self.msg.dwFlags & MSG_TRUNC != 0
+    }
+
+    /// Returns whether the control data was larger than the control buffer
+    /// and the excess was discarded.
+    pub fn is_control_truncated(&self) -> bool {
+        self.msg.dwFlags & MSG_CTRUNC != 0
+    }
+}
+
+unsafe impl Send for MsgBuf {}
+unsafe impl Sync for MsgBuf {}
+
+// The `WSA_CMSGHDR_ALIGN` and `WSA_CMSGDATA_ALIGN` macros, which both align to
+// the size of a pointer.
+fn cmsg_align(len: usize) -> usize {
+    let align = mem::size_of::<usize>();
+    (len + align - 1) & !(align - 1)
+}
+
+fn cmsg_data_offset() -> usize {
+    cmsg_align(mem::size_of::<CMSGHDR>())
+}
+
+impl CMsgBuf {
+    /// Creates a new empty buffer.
+    pub fn new() -> CMsgBuf {
+        CMsgBuf { buf: Vec::new(), len: 0 }
+    }
+
+    /// Returns the number of bytes of buffer space taken by a control
+    /// message with `len` bytes of data (`WSA_CMSG_SPACE`).
+    ///
+    /// This can be used to size the control buffer passed to
+    /// `recv_msg_overlapped` for the messages expected.
+    pub fn space(len: usize) -> usize {
+        cmsg_data_offset() + cmsg_align(len)
+    }
+
+    /// Appends a control message with the specified level, type, and data.
+    pub fn push(&mut self, level: i32, kind: i32, data: &[u8])
+                -> &mut CMsgBuf {
+        let start = self.len;
+        let end = start + CMsgBuf::space(data.len());
+        let words = (end + mem::size_of::<usize>() - 1) /
+                    mem::size_of::<usize>();
+        self.buf.resize(words, 0);
+        self.len = end;
+        let hdr = CMSGHDR {
+            cmsg_len: (cmsg_data_offset() + data.len()) as SIZE_T,
+            cmsg_level: level,
+            cmsg_type: kind,
+        };
+        unsafe {
+            let base = self.buf.as_mut_ptr() as *mut u8;
+            ptr::write_unaligned(base.offset(start as isize) as *mut CMSGHDR,
+                                 hdr);
+            ptr::copy_nonoverlapping(data.as_ptr(),
+                                     base.offset((start + cmsg_data_offset())
+                                                 as isize),
+                                     data.len());
+        }
+        self
+    }
+
+    /// Appends an `IP_PKTINFO` message, which sends an IPv4 datagram from
+    /// the source address `addr` out of the interface with the specified
+    /// index.
+    ///
+    /// Either may be left unspecified, by passing the unspecified address or
+    /// an index of 0 respectively.
+    pub fn push_pktinfo_v4(&mut self, addr: Ipv4Addr, interface: u32)
+                           -> &mut CMsgBuf {
+        let mut data = [0; 8];
+        data[..4].copy_from_slice(&addr.octets());
+        data[4..].copy_from_slice(&interface.to_ne_bytes());
+        self.push(IPPROTO_IP, IP_PKTINFO, &data)
+    }
+
+    /// Appends an `IPV6_PKTINFO` message, which sends an IPv6 datagram from
+    /// the source address `addr` out of the interface with the specified
+    /// index.
+    pub fn push_pktinfo_v6(&mut self, addr: Ipv6Addr, interface: u32)
+                           -> &mut CMsgBuf {
+        let mut data = [0; 20];
+        data[..16].copy_from_slice(&addr.octets());
+        data[16..].copy_from_slice(&interface.to_ne_bytes());
+        self.push(IPPROTO_IPV6.0 as c_int, IPV6_PKTINFO, &data)
+    }
+
+    /// Appends an `IP_ECN` message, which sends an IPv4 datagram with the
+    /// specified ECN codepoint.
+    pub fn push_ecn_v4(&mut self, ecn: u8) -> &mut CMsgBuf {
+        self.push(IPPROTO_IP, IP_ECN, &(ecn as u32).to_ne_bytes())
+    }
+
+    /// Appends an `IPV6_ECN` message, which sends an IPv6 datagram with the
+    /// specified ECN codepoint.
+    pub fn push_ecn_v6(&mut self, ecn: u8) -> &mut CMsgBuf {
+        let data = (ecn as u32).to_ne_bytes();
+        self.push(IPPROTO_IPV6.0 as c_int, IPV6_ECN, &data)
+    }
+
+    /// Appends a `UDP_SEND_MSG_SIZE` message, which splits the data of a
+    /// single send into datagrams of the specified size.
+    ///
+    /// For more information, see `UdpSocketExt::set_send_msg_size`.
+    pub fn push_send_msg_size(&mut self, size: u32) -> &mut CMsgBuf {
+        self.push(IPPROTO_UDP.0 as c_int, UDP_SEND_MSG_SIZE,
+                  &size.to_ne_bytes())
+    }
+
+    /// Returns the encoded control messages.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self.buf.as_ptr() as *const u8, self.len)
+        }
+    }
+
+    /// Removes all messages from this buffer.
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.len = 0;
+    }
+
+    /// Returns an iterator over the messages in this buffer.
+    pub fn iter(&self) -> CMsgIter {
+        CMsgIter::new(self.as_bytes())
+    }
+}
+
+fn bytes_u32(data: &[u8]) -> Option<u32> {
+    if data.len() < 4 {
+        return None
+    }
+    let mut n = [0; 4];
+    n.copy_from_slice(&data[..4]);
+    Some(u32::from_ne_bytes(n))
+}
+
+fn bytes_u64(data: &[u8]) -> Option<u64> {
+    if data.len() < 8 {
+        return None
+    }
+    let mut n = [0; 8];
+    n.copy_from_slice(&data[..8]);
+    Some(u64::from_ne_bytes(n))
+}
+
+impl<'a> CMsgIter<'a> {
+    /// Creates an iterator over the control messages in `buf`.
+    ///
+    /// After a `recv_msg_overlapped` operation completes this should be the
+    /// control buffer truncated to `MsgBuf::control_len`.
+    pub fn new(buf: &'a [u8]) -> CMsgIter<'a> {
+        CMsgIter { buf: buf }
+    }
+}
+
+impl<'a> Iterator for CMsgIter<'a> {
+    type Item = CMsg<'a>;
+
+    fn next(&mut self) -> Option<CMsg<'a>> {
+        if self.buf.len() < mem::size_of::<CMSGHDR>() {
+            return None
+        }
+        let hdr = unsafe {
+            ptr::read_unaligned(self.buf.as_ptr() as *const CMSGHDR)
+        };
+        let len = hdr.cmsg_len as usize;
+        if len < cmsg_data_offset() || len > self.buf.len() {
+            self.buf = &[];
+            return None
+        }
+        let data = &self.buf[cmsg_data_offset()..len];
+        let next = cmp::min(cmsg_align(len), self.buf.len());
+        self.buf = &self.buf[next..];
+
+        let level = hdr.cmsg_level;
+        let kind = hdr.cmsg_type;
+        let v6 = IPPROTO_IPV6.0 as c_int;
+        let udp = IPPROTO_UDP.0 as c_int;
+        let msg = match (level, kind) {
+            (IPPROTO_IP, IP_PKTINFO) if data.len() >= 8 => {
+                let mut addr = [0; 4];
+                addr.copy_from_slice(&data[..4]);
+                bytes_u32(&data[4..]).map(|i| CMsg::PktInfoV4(addr.into(), i))
+            }
+            (l, IPV6_PKTINFO) if l == v6 && data.len() >= 20 => {
+                let mut addr = [0; 16];
+                addr.copy_from_slice(&data[..16]);
+                bytes_u32(&data[16..]).map(|i| CMsg::PktInfoV6(addr.into(), i))
+            }
+            (IPPROTO_IP, IP_TTL) => bytes_u32(data).map(CMsg::Ttl),
+            (l, IPV6_HOPLIMIT) if l == v6 => {
+                bytes_u32(data).map(CMsg::HopLimit)
+            }
+            (IPPROTO_IP, IP_ECN) => bytes_u32(data).map(|n| CMsg::Ecn(n as u8)),
+            (l, IPV6_ECN) if l == v6 => {
+                bytes_u32(data).map(|n| CMsg::Ecn(n as u8))
+            }
+            (l, UDP_COALESCED_INFO) if l == udp => {
+                bytes_u32(data).map(CMsg::CoalescedSize)
+            }
+            (SOL_SOCKET, SO_TIMESTAMP) => bytes_u64(data).map(CMsg::Timestamp),
+            _ => None,
+        };
+        Some(msg.unwrap_or(CMsg::Other {
+            level: level,
+            kind: kind,
+            data: data,
+        }))
+    }
+}
+
+static WSARECVMSG: WsaExtension = WsaExtension::new(GUID {
+    Data1: 0xf689d7c8,
+    Data2: 0x6f1f,
+    Data3: 0x436b,
+    Data4: [0x8a, 0x53, 0xe5, 0x4f, 0xe3, 0x51, 0xc3, 0x22],
+});
+type WSARecvMsg = unsafe extern "system" fn(SOCKET, LPWSAMSG, LPDWORD,
+                                            LPWSAOVERLAPPED,
+                                            LPWSAOVERLAPPED_COMPLETION_ROUTINE)
+                                            -> c_int;
+
+static ACCEPTEX: WsaExtension = WsaExtension::new(WSAID_ACCEPTEX);
+
+static GETACCEPTEXSOCKADDRS: WsaExtension = WsaExtension::new(GUID {
+    Data1: 0xb5367df2,
+    Data2: 0xcbac,
+    Data3: 0x11cf,
+    Data4: [0x95, 0xca, 0x00, 0x80, 0x5f, 0x48, 0xa1, 0x92],
+});
+type GetAcceptExSockaddrs = unsafe extern "system" fn(PVOID, DWORD, DWORD, DWORD,
+                                                      *mut LPSOCKADDR, LPINT,
+                                                      *mut LPSOCKADDR, LPINT);
+
+impl AcceptAddrsBuf {
+    /// Creates a new blank buffer ready to be passed to a call to
+    /// `accept_overlapped`.
+    pub fn new() -> AcceptAddrsBuf {
+        unsafe { mem::zeroed() }
+    }
+
+    /// Parses the data contained in this address buffer, returning the parsed
+    /// result if successful.
+    ///
+    /// This function can be called after a call to `accept_overlapped` has
+    /// succeeded to parse out the data that was written in. The `socket` is
+    /// only used to look up `GetAcceptExSockaddrs`, so it can be any socket
+    /// of the same provider, such as the accepted stream.
+    pub fn parse<S>(&self, socket: &S) -> io::Result<AcceptAddrs>
+        where S: AsRawSocket
+    {
+        parse_accept_addrs(self, socket.as_raw_socket())
+    }
+}
+
+impl sealed::Sealed for AcceptAddrsBuf {}
+
+impl AcceptAddrsStorage for AcceptAddrsBuf {
+    fn args(&self) -> (PVOID, DWORD, DWORD, DWORD) {
+        accept_args(self, mem::size_of::<SOCKADDR_STORAGE>() + 16)
+    }
+}
+
+impl AcceptAddrsBufV4 {
+    /// Creates a new blank buffer ready to be passed to a call to
+    /// `accept_overlapped` on an IPv4 listener.
+    pub fn new() -> AcceptAddrsBufV4 {
+        unsafe { mem::zeroed() }
+    }
+
+    /// Parses the data contained in this address buffer, returning the parsed
+    /// result if successful.
+    ///
+    /// See `AcceptAddrsBuf::parse` for more information.
+    pub fn parse<S>(&self, socket: &S) -> io::Result<AcceptAddrs>
+        where S: AsRawSocket
+    {
+        parse_accept_addrs(self, socket.as_raw_socket())
+    }
+}
+
+impl sealed::Sealed for AcceptAddrsBufV4 {}
+
+impl AcceptAddrsStorage for AcceptAddrsBufV4 {
+    fn args(&self) -> (PVOID, DWORD, DWORD, DWORD) {
+        accept_args(self, mem::size_of::<SOCKADDR_IN>() + 16)
+    }
+}
+
+impl AcceptAddrsBufV6 {
+    /// Creates a new blank buffer ready to be passed to a call to
+    /// `accept_overlapped` on an IPv6 listener.
+    pub fn new() -> AcceptAddrsBufV6 {
+        unsafe { mem::zeroed() }
+    }
+
+    /// Parses the data contained in this address buffer, returning the parsed
+    /// result if successful.
+    ///
+    /// See `AcceptAddrsBuf::parse` for more information.
+    pub fn parse<S>(&self, socket: &S) -> io::Result<AcceptAddrs>
+        where S: AsRawSocket
+    {
+        parse_accept_addrs(self, socket.as_raw_socket())
+    }
+}
+
+impl sealed::Sealed for AcceptAddrsBufV6 {}
+
+impl AcceptAddrsStorage for AcceptAddrsBufV6 {
+    fn args(&self) -> (PVOID, DWORD, DWORD, DWORD) {
+        accept_args(self, mem::size_of::<sockaddr_in6>() + 16)
+    }
+}
+
+// The arguments describing an accept buffer to `AcceptEx` and
+// `GetAcceptExSockaddrs`: no received data, the local address at the start,
+// and the remote address at `remote_offset` up to the end of the buffer. The
+// remote address follows the local one and the 16 bytes of padding which
+// `AcceptEx` requires after each address.
+fn accept_args<T>(buf: &T, remote_offset: usize)
+                  -> (PVOID, DWORD, DWORD, DWORD) {
+    (buf as *const _ as *mut _, 0, remote_offset as DWORD,
+     (mem::size_of::<T>() - remote_offset) as DWORD)
+}
+
+fn parse_accept_addrs<'a, A>(buf: &'a A, socket: SOCKET)
+                             -> io::Result<AcceptAddrs<'a>>
+    where A: AcceptAddrsStorage
+{
+    let mut ret = AcceptAddrs {
+        local: 0 as *mut _, local_len: 0,
+        remote: 0 as *mut _, remote_len: 0,
+        unmap_v4: false,
+        _data: marker::PhantomData,
+    };
+    let ptr = try!(GETACCEPTEXSOCKADDRS.get(socket));
+    unsafe {
+        let get_sockaddrs = mem::transmute::<_, GetAcceptExSockaddrs>(ptr);
+        let (a, b, c, d) = buf.args();
+        get_sockaddrs(a, b, c, d,
+                      &mut ret.local, &mut ret.local_len,
+                      &mut ret.remote, &mut ret.remote_len);
+        Ok(ret)
+    }
+}
+
+impl RssProcessor {
+    /// Returns the processor group of this processor.
+    pub fn group(&self) -> u16 {
+        self.group
+    }
+
+    /// Returns the number of this processor within its group.
+    pub fn number(&self) -> u8 {
+        self.number
+    }
+
+    /// Returns the NUMA node this processor belongs to.
+    pub fn numa_node(&self) -> u16 {
+        self.numa_node
+    }
+
+    /// Returns the index of this processor among all active processors in
+    /// the system, counting through the processor groups in order.
+    ///
+    /// This matches the numbering of the shards created by
+    /// `ShardedPorts::per_core`.
+    pub fn index(&self) -> usize {
+        let before = (0..self.group).map(|group| unsafe {
+            ::kernel32::GetActiveProcessorCount(group) as usize
+        }).sum::<usize>();
+        before + self.number as usize
+    }
+}
+
+impl TcpInfo {
+    /// Returns the maximum segment size of the connection.
+    pub fn mss(&self) -> u32 {
+        self.0.Mss
+    }
+
+    /// Returns how long the connection has been established for.
+    pub fn connection_time(&self) -> Duration {
+        Duration::from_millis(self.0.ConnectionTimeMs)
+    }
+
+    /// Returns whether TCP timestamps are in use on the connection.
+    pub fn timestamps_enabled(&self) -> bool {
+        self.0.TimestampsEnabled != 0
+    }
+
+    /// Returns the smoothed round trip time of the connection.
+    pub fn rtt(&self) -> Duration {
+        Duration::from_micros(self.0.RttUs as u64)
+    }
+
+    /// Returns the lowest round trip time observed on the connection.
+    pub fn min_rtt(&self) -> Duration {
+        Duration::from_micros(self.0.MinRttUs as u64)
+    }
+
+    /// Returns the number of bytes sent which haven't been acknowledged yet.
+    pub fn bytes_in_flight(&self) -> u32 {
+        self.0.BytesInFlight
+    }
+
+    /// Returns the congestion window of the connection, in bytes.
+    pub fn congestion_window(&self) -> u32 {
+        self.0.Cwnd
+    }
+
+    /// Returns the window advertised by the peer, in bytes.
+    pub fn send_window(&self) -> u32 {
+        self.0.SndWnd
+    }
+
+    /// Returns the window advertised to the peer, in bytes.
+    pub fn receive_window(&self) -> u32 {
+        self.0.RcvWnd
+    }
+
+    /// Returns the size of the receive buffer of the connection.
+    pub fn receive_buffer(&self) -> u32 {
+        self.0.RcvBuf
+    }
+
+    /// Returns the number of bytes sent on the connection.
+    pub fn bytes_out(&self) -> u64 {
+        self.0.BytesOut
+    }
+
+    /// Returns the number of bytes received on the connection.
+    pub fn bytes_in(&self) -> u64 {
+        self.0.BytesIn
+    }
+
+    /// Returns the number of bytes received out of order.
+    pub fn bytes_reordered(&self) -> u32 {
+        self.0.BytesReordered
+    }
+
+    /// Returns the number of bytes retransmitted.
+    pub fn bytes_retransmitted(&self) -> u32 {
+        self.0.BytesRetrans
+    }
+
+    /// Returns the number of fast retransmissions.
+    pub fn fast_retransmits(&self) -> u32 {
+        self.0.FastRetrans
+    }
+
+    /// Returns the number of duplicate acknowledgements received.
+    pub fn duplicate_acks_in(&self) -> u32 {
+        self.0.DupAcksIn
+    }
+
+    /// Returns the number of times the retransmission timer expired.
+    pub fn timeout_episodes(&self) -> u32 {
+        self.0.TimeoutEpisodes
+    }
+
+    /// Returns the number of times the initial SYN was retransmitted.
+    pub fn syn_retransmits(&self) -> u8 {
+        self.0.SynRetrans
+    }
+}
+
+impl SocketEvent {
+    /// Creates a new event, which is initially not signaled.
+    pub fn new() -> io::Result<SocketEvent> {
+        init();
+        let event = unsafe { WSACreateEvent() };
+        if event.is_null() {
+            return Err(io::Error::from_raw_os_error(unsafe {
+                WSAGetLastError()
+            }))
+        }
+        Ok(SocketEvent { handle: Handle::new(event) })
+    }
+
+    /// Resets this event to not signaled (via `WSAResetEvent`).
+    ///
+    /// This usually isn't needed, as `network_events` resets the event it's
+    /// given.
+    pub fn reset(&self) -> io::Result<()> {
+        let r = unsafe { WSAResetEvent(self.handle.raw()) };
+        if r == FALSE {
+            Err(io::Error::from_raw_os_error(unsafe { WSAGetLastError() }))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl AsRawHandle for SocketEvent {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.handle.raw() as RawHandle
+    }
+}
+
+impl FromRawHandle for SocketEvent {
+    unsafe fn from_raw_handle(handle: RawHandle) -> SocketEvent {
+        SocketEvent { handle: Handle::new(handle as HANDLE) }
+    }
+}
+
+impl IntoRawHandle for SocketEvent {
+    fn into_raw_handle(self) -> RawHandle {
+        self.handle.into_raw() as RawHandle
+    }
+}
+
+impl NetworkEvents {
+    /// Returns the `FD_*` events which occurred.
+    pub fn events(&self) -> u32 {
+        self.0.lNetworkEvents as u32
+    }
+
+    /// Returns whether all of the `FD_*` events specified occurred.
+    pub fn contains(&self, events: u32) -> bool {
+        self.events() & events == events
+    }
+
+    /// Returns the error reported with the single `FD_*` event specified,
+    /// such as the error of a failed connection attempt for `FD_CONNECT`.
+    ///
+    /// `None` is returned if the event didn't occur or occurred without an
+    /// error.
+    pub fn error(&self, event: u32) -> Option<io::Error> {
+        if !self.contains(event) || !event.is_power_of_two() {
+            return None
+        }
+        let bit = event.trailing_zeros() as usize;
+        match self.0.iErrorCode.get(bit) {
+            Some(&0) | None => None,
+            Some(&e) => Some(io::Error::from_raw_os_error(e)),
+        }
+    }
+}
+
+impl fmt::Debug for NetworkEvents {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NetworkEvents")
+         .field("events", &self.events())
+         .finish()
+    }
+}
+
+impl<'a> AcceptAddrs<'a> {
+    /// Configures whether IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) are
+    /// returned by `local` and `remote` as the IPv4 addresses they map.
+    ///
+    /// Dual-stack listeners accept connections from IPv4 peers at such
+    /// addresses, which most code expects to see as plain IPv4 addresses.
+    /// This is disabled by default.
+    pub fn set_unmap_v4(&mut self, unmap: bool) {
+        self.unmap_v4 = unmap;
+    }
+
+    /// Returns the local socket address contained in this buffer.
+    pub fn local(&self) -> Option<SocketAddr> {
+        self.addr(self.local, self.local_len)
+    }
+
+    /// Returns the remote socket address contained in this buffer.
+    pub fn remote(&self) -> Option<SocketAddr> {
+        self.addr(self.remote, self.remote_len)
+    }
+
+    fn addr(&self, ptr: LPSOCKADDR, len: c_int) -> Option<SocketAddr> {
+        let addr = unsafe { ptrs_to_socket_addr(ptr, len) };
+        if self.unmap_v4 {
+            addr.map(unmap_v4)
+        } else {
+            addr
+        }
+    }
+}
+
+impl WsaExtension {
+    pub(crate) const fn new(guid: GUID) -> WsaExtension {
+        WsaExtension {
+            guid: guid,
+            shared: AtomicUsize::new(0),
+            cache: RwLock::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn get(&self, socket: SOCKET) -> io::Result<usize> {
+        if single_provider_dll() {
+            let ptr = self.shared.load(Ordering::Acquire);
+            if ptr != 0 {
+                return Ok(ptr)
+            }
+            let ptr = try!(self.lookup(socket));
+            self.shared.store(ptr, Ordering::Release);
+            return Ok(ptr)
+        }
+        let info: WSAPROTOCOL_INFOW = try!(getsockopt(socket, SOL_SOCKET,
+                                                      SO_PROTOCOL_INFOW));
+        let key = info.dwCatalogEntryId;
+        {
+            let cache = self.cache.read().unwrap_or_else(|e| e.into_inner());
+            if let Some(&(_, ptr)) = cache.iter().find(|e| e.0 == key) {
+                return Ok(ptr)
+            }
+        }
+        let ret = try!(self.lookup(socket));
+        let mut cache = self.cache.write().unwrap_or_else(|e| e.into_inner());
+        if !cache.iter().any(|e| e.0 == key) {
+            cache.push((key, ret));
+        }
+        Ok(ret)
+    }
+
+    fn lookup(&self, socket: SOCKET) -> io::Result<usize> {
+        let mut ret = 0 as usize;
+        let mut bytes = 0;
+        let r = unsafe {
+            WSAIoctl(socket, SIO_GET_EXTENSION_FUNCTION_POINTER,
+                     &self.guid as *const _ as *mut _,
+                     mem::size_of_val(&self.guid) as DWORD,
+                     &mut ret as *mut _ as *mut _,
+                     mem::size_of_val(&ret) as DWORD,
+                     &mut bytes,
+                     0 as *mut _, None)
+        };
+        try!(cvt(r));
+        if bytes as usize != mem::size_of_val(&ret) || ret == 0 {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      "failed to load winsock extension"))
+        }
+        Ok(ret)
+    }
+}
+
+// Returns whether all the installed service providers are base providers
+// implemented by the same DLL, in which case their extension functions are
+// the same. The catalog is only inspected once, as providers are rarely
+// installed while a program is running.
+fn single_provider_dll() -> bool {
+    const UNKNOWN: usize = 0;
+    const SINGLE: usize = 1;
+    const MANY: usize = 2;
+    static STATE: AtomicUsize = AtomicUsize::new(UNKNOWN);
+
+    match STATE.load(Ordering::Relaxed) {
+        SINGLE => return true,
+        MANY => return false,
+        _ => {}
+    }
+    let single = unsafe { catalog_has_single_dll() };
+    STATE.store(if single { SINGLE } else { MANY }, Ordering::Relaxed);
+    single
+}
+
+unsafe fn catalog_has_single_dll() -> bool {
+    let mut len = 0;
+    let r = WSAEnumProtocolsW(0 as *mut _, 0 as *mut _, &mut len);
+    if r != SOCKET_ERROR || WSAGetLastError() != WSAENOBUFS as c_int {
+        return false
+    }
+    let cap = len as usize / mem::size_of::<WSAPROTOCOL_INFOW>() + 1;
+    let mut infos = Vec::<WSAPROTOCOL_INFOW>::with_capacity(cap);
+    let r = WSAEnumProtocolsW(0 as *mut _, infos.as_mut_ptr(), &mut len);
+    if r == SOCKET_ERROR {
+        return false
+    }
+    infos.set_len(r as usize);
+
+    let mut dll = None;
+    for info in infos.iter_mut() {
+        // Layered providers and protocol chains wrap the functions of the
+        // providers below them.
+        if info.ProtocolChain.ChainLen != BASE_PROTOCOL {
+            return false
+        }
+        let mut path = [0u16; MAX_PATH];
+        let mut path_len = path.len() as c_int;
+        let mut err = 0;
+        if WSCGetProviderPath(&mut info.ProviderId, path.as_mut_ptr(),
+                              &mut path_len, &mut err) == SOCKET_ERROR {
+            return false
+        }
+        let path = &path[..path.iter().position(|&c| c == 0)
+                               .unwrap_or(path.len())];
+        match dll {
+            None => dll = Some(path.to_vec()),
+            Some(ref dll) if &dll[..] == path => {}
+            Some(_) => return false,
+        }
+    }
+    dll.is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, UdpSocket, TcpStream, SocketAddr, Ipv4Addr};
+    use std::thread;
+    use std::time::Duration;
+    use std::io::prelude::*;
+
+    use Overlapped;
+    use iocp::{CompletionPort, CompletionStatus};
+    use net::{TcpStreamExt, UdpSocketExt, SocketAddrBuf, MsgBuf};
+    use net::{CMsg, CMsgBuf, CMsgIter, TF_DISCONNECT, MSG_PEEK,
+              MSG_PARTIAL};
+    use net::{TcpBuilderExt, TcpListenerExt, AcceptAddrsBuf};
+    use net::{AcceptAddrsBufV4, AcceptAddrsBufV6};
+    use net::{WsaBuf, WsaBufMut};
+    use net2::TcpBuilder;
+    use winapi::{INVALID_SOCKET, WSA_IO_INCOMPLETE};
+
+    fn each_ip(f: &mut FnMut(SocketAddr)) {
+        f(t!("127.0.0.1:0".parse()));
+        f(t!("[::1]:0".parse()));
+    }
+
+    // Connects a pair of streams over loopback, with the connecting one
+    // associated with `cp` under token 1.
+    fn connected_pair(cp: &CompletionPort) -> (TcpStream, TcpStream) {
+        let (s, b) = ::tcp_pair();
+        t!(cp.add_socket(1, &s));
+        (s, b)
+    }
+
+    #[test]
+    fn tcp_read() {
+        each_ip(&mut |addr| {
+            let l = t!(TcpListener::bind(addr));
+            let addr = t!(l.local_addr());
+            let t = thread::spawn(move || {
+                let mut a = t!(l.accept()).0;
+                t!(a.write_all(&[1, 2, 3]));
+            });
+
+            let cp = t!(CompletionPort::new(1));
+            let s = t!(TcpStream::connect(addr));
+            t!(cp.add_socket(1, &s));
+
+            let mut b = [0; 10];
+            let mut a = Overlapped::zero();
+            unsafe {
+                t!(s.read_overlapped(&mut b, &mut a));
+            }
+            let status = t!(cp.get(None));
+            assert_eq!(status.bytes_transferred(), 3);
+            assert_eq!(status.token(), 1);
+            assert_eq!(status.overlapped(), &mut a as *mut _);
+            assert_eq!(&b[0..3], &[1, 2, 3]);
+
+            t!(t.join());
+        })
+    }
+
+    #[test]
+    fn tcp_read_vectored() {
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let addr = t!(l.local_addr());
+        let t = thread::spawn(move || {
+            let mut a = t!(l.accept()).0;
+            t!(a.write_all(&[1, 2, 3, 4, 5]));
+        });
+
+        let cp = t!(CompletionPort::new(1));
+        let s = t!(TcpStream::connect(addr));
+        t!(cp.add_socket(1, &s));
+        t!(t.join());
+
+        let mut b1 = [0; 2];
+        let mut b2 = [0; 10];
+        let mut a = Overlapped::zero();
+        {
+            let mut bufs = [WsaBufMut::new(&mut b1), WsaBufMut::new(&mut b2)];
+            unsafe {
+                t!(s.read_overlapped_vectored(&mut bufs, &mut a));
+            }
+        }
+        let status = t!(cp.get(None));
+        assert_eq!(status.bytes_transferred(), 5);
+        assert_eq!(b1, [1, 2]);
+        assert_eq!(&b2[..3], &[3, 4, 5]);
+    }
+
+    #[test]
+    fn tcp_io_slices() {
+        use std::io::{IoSlice, IoSliceMut};
+
+        let cp = t!(CompletionPort::new(1));
+        let (s, b) = connected_pair(&cp);
+        t!(cp.add_socket(2, &b));
+
+        let (header, body) = ([1, 2], [3, 4, 5]);
+        let mut b1 = [0; 1];
+        let mut b2 = [0; 10];
+        let mut r = Overlapped::zero();
+        let mut w = Overlapped::zero();
+        {
+            let mut bufs = [IoSliceMut::new(&mut b1), IoSliceMut::new(&mut b2)];
+            let slices = [IoSlice::new(&header), IoSlice::new(&body)];
+            unsafe {
+                t!(b.read_overlapped_io_slices(&mut bufs, &mut r));
+                t!(s.write_overlapped_io_slices(&slices, &mut w));
+            }
+        }
+        for _ in 0..2 {
+            assert_eq!(t!(cp.get(None)).bytes_transferred(), 5);
+        }
+        assert_eq!(b1, [1]);
+        assert_eq!(&b2[..4], &[2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn tcp_read_peek() {
+        let cp = t!(CompletionPort::new(1));
+        let (s, mut b) = connected_pair(&cp);
+        t!(b.write_all(&[1, 2, 3]));
+
+        let mut buf = [0; 10];
+        let mut a = Overlapped::zero();
+        for _ in 0..2 {
+            let mut flags = MSG_PEEK;
+            unsafe {
+                t!(s.read_overlapped_with_flags(&mut buf, &mut flags, &mut a));
+            }
+            let status = t!(cp.get(None));
+            assert_eq!(status.bytes_transferred(), 3);
+            assert_eq!(&buf[..3], &[1, 2, 3]);
+            buf = [0; 10];
+        }
+        let mut rest = [0; 3];
+        t!((&s).read_exact(&mut rest));
+    }
+
+    #[test]
+    fn tcp_read_exact_hint() {
+        let cp = t!(CompletionPort::new(1));
+        let (s, mut b) = connected_pair(&cp);
+
+        let mut buf = [0; 6];
+        let mut a = Overlapped::zero();
+        unsafe {
+            t!(s.read_exact_hint_overlapped(&mut buf, &mut a));
+        }
+        t!(b.write_all(&[1, 2, 3]));
+        t!(b.flush());
+        thread::sleep(Duration::from_millis(20));
+        t!(b.write_all(&[4, 5, 6]));
+        let status = t!(cp.get(None));
+        assert_eq!(status.bytes_transferred(), 6);
+        assert_eq!(buf, [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn overlapped_result() {
+        let cp = t!(CompletionPort::new(1));
+        let (s, mut b) = connected_pair(&cp);
+
+        let mut buf = [0; 10];
+        let mut a = Overlapped::zero();
+        unsafe {
+            t!(s.read_overlapped(&mut buf, &mut a));
+        }
+        t!(b.write_all(&[1, 2, 3]));
+        t!(cp.get(None));
+        assert_eq!(t!(unsafe { s.result(&a) }), (3, 0));
+
+        let u = t!(UdpSocket::bind("127.0.0.1:0"));
+        let v = t!(UdpSocket::bind("127.0.0.1:0"));
+        t!(cp.add_socket(2, &u));
+        let mut addr = SocketAddrBuf::new();
+        unsafe {
+            t!(u.recv_from_overlapped(&mut buf, &mut addr, &mut a));
+            let e = u.result(&a).unwrap_err();
+            assert_eq!(e.raw_os_error(), Some(WSA_IO_INCOMPLETE as i32));
+        }
+        t!(v.send_to(&[4, 5], t!(u.local_addr())));
+        t!(cp.get(None));
+        assert_eq!(t!(unsafe { u.result(&a) }), (2, 0));
+    }
+
+    #[test]
+    fn socket_ext() {
+        use net::SocketExt;
+
+        let cp = t!(CompletionPort::new(1));
+        let (s, mut b) = connected_pair(&cp);
+
+        let mut buf = [0; 10];
+        let mut a = Overlapped::zero();
+        unsafe {
+            t!(s.send_overlapped_raw(&[1, 2, 3], &mut a));
+        }
+        assert_eq!(t!(cp.get(None)).bytes_transferred(), 3);
+        t!(b.read_exact(&mut buf[..3]));
+        assert_eq!(&buf[..3], &[1, 2, 3]);
+
+        t!(b.write_all(&[4, 5]));
+        unsafe {
+            t!(s.recv_overlapped_raw(&mut buf, &mut a));
+        }
+        assert_eq!(t!(cp.get(None)).bytes_transferred(), 2);
+        assert_eq!(&buf[..2], &[4, 5]);
+
+        assert!(t!(s.base_socket()) != INVALID_SOCKET);
+    }
+
+    #[test]
+    fn connect_by_name() {
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let port = t!(l.local_addr()).port();
+        let t = thread::spawn(move || {
+            let mut a = t!(l.accept()).0;
+            t!(a.write_all(&[1, 2, 3]));
+        });
+
+        let s = t!(super::connect_by_name("localhost", port,
+                                          Some(Duration::from_secs(10))));
+        assert_eq!(t!(s.peer_addr()).port(), port);
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &s));
+        let mut b = [0; 10];
+        let mut a = Overlapped::zero();
+        unsafe {
+            t!(s.read_overlapped(&mut b, &mut a));
+        }
+        let status = t!(cp.get(None));
+        assert_eq!(status.bytes_transferred(), 3);
+        assert_eq!(&b[..3], &[1, 2, 3]);
+        t!(t.join());
+    }
+
+    #[test]
+    fn duplicate_socket() {
+        use std::process;
+        use net::{from_protocol_info, SocketExt};
+
+        let (s, mut b) = ::tcp_pair();
+
+        let info = t!(s.duplicate_socket(process::id()));
+        let dup = t!(from_protocol_info::<TcpStream>(&info));
+        assert_eq!(t!(dup.local_addr()), t!(s.local_addr()));
+        drop(s);
+
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &dup));
+        let mut buf = [0; 10];
+        let mut a = Overlapped::zero();
+        unsafe {
+            t!(dup.recv_overlapped_raw(&mut buf, &mut a));
+        }
+        t!(b.write_all(&[1, 2]));
+        assert_eq!(t!(cp.get(None)).bytes_transferred(), 2);
+    }
+
+    #[test]
+    fn event_select() {
+        use std::os::windows::io::AsRawHandle;
+        use kernel32::WaitForSingleObject;
+        use winapi::{HANDLE, WAIT_OBJECT_0};
+        use net::{SocketExt, SocketEvent, FD_ACCEPT, FD_CLOSE, FD_READ};
+
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let event = t!(SocketEvent::new());
+        t!(l.event_select(&event, FD_ACCEPT | FD_CLOSE));
+        let s = t!(TcpStream::connect(t!(l.local_addr())));
+        unsafe {
+            assert_eq!(WaitForSingleObject(event.as_raw_handle() as HANDLE,
+                                           10_000),
+                       WAIT_OBJECT_0);
+        }
+        let events = t!(l.network_events(Some(&event)));
+        assert!(events.contains(FD_ACCEPT));
+        assert!(!events.contains(FD_READ));
+        assert!(events.error(FD_ACCEPT).is_none());
+        t!(l.event_select(&event, 0));
+        drop(s);
+
+        // Overlapped reads can still be issued while events are selected.
+        let cp = t!(CompletionPort::new(1));
+        let (a, b) = connected_pair(&cp);
+        t!(a.event_select(&event, FD_CLOSE));
+        let mut buf = [0; 10];
+        let mut over = Overlapped::zero();
+        unsafe {
+            t!(a.recv_overlapped_raw(&mut buf, &mut over));
+        }
+        drop(b);
+        assert_eq!(t!(cp.get(None)).bytes_transferred(), 0);
+        unsafe {
+            assert_eq!(WaitForSingleObject(event.as_raw_handle() as HANDLE,
+                                           10_000),
+                       WAIT_OBJECT_0);
+        }
+        assert!(t!(a.network_events(Some(&event))).contains(FD_CLOSE));
+        t!(event.reset());
+    }
+
+    #[test]
+    fn skip_completion_port_on_success() {
+        use net::SocketExt;
+
+        let cp = t!(CompletionPort::new(1));
+        let (s, mut b) = connected_pair(&cp);
+        if !t!(s.set_skip_completion_port_on_success()) {
+            return
+        }
+
+        // Wait for the data to arrive so the read completes immediately.
+        t!(b.write_all(&[1, 2, 3]));
+        let mut buf = [0; 10];
+        assert_eq!(t!(s.peek(&mut buf)), 3);
+        let mut a = Overlapped::zero();
+        unsafe {
+            assert!(t!(s.recv_overlapped_raw(&mut buf, &mut a)));
+        }
+        assert!(cp.get(Some(50)).is_err());
+
+        // Reads which pend still complete through the port.
+        unsafe {
+            assert!(!t!(s.recv_overlapped_raw(&mut buf, &mut a)));
+        }
+        t!(b.write_all(&[4]));
+        assert_eq!(t!(cp.get(None)).bytes_transferred(), 1);
+    }
+
+    #[test]
+    fn inheritable() {
+        use net::{ListenerBuilder, SocketBuilder, SocketExt};
+
+        let s = t!(SocketBuilder::new_v4());
+        assert!(!t!(s.inheritable()));
+        t!(s.set_inheritable(true));
+        assert!(t!(s.inheritable()));
+        t!(s.set_inheritable(false));
+        assert!(!t!(s.inheritable()));
+
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let l = t!(ListenerBuilder::new(addr).inheritable(true).listen());
+        assert!(t!(l.inheritable()));
+    }
+
+    #[test]
+    fn cancel_overlapped() {
+        use winapi::{ERROR_NOT_FOUND, ERROR_OPERATION_ABORTED};
+
+        use OverlappedIo;
+
+        let a = t!(UdpSocket::bind("127.0.0.1:0"));
+        let cp = t!(CompletionPort::new(1));
+        let r = t!(cp.add_socket(1, &a));
+        let aborted = Some(ERROR_OPERATION_ABORTED as i32);
+
+        let mut buf1 = [0; 10];
+        let mut buf2 = [0; 10];
+        let mut over1 = Overlapped::zero();
+        let mut over2 = Overlapped::zero();
+        unsafe {
+            assert!(!t!(a.start_read(&mut buf1, &mut over1)));
+            assert!(!t!(a.start_read(&mut buf2, &mut over2)));
+        }
+        t!(a.cancel(&over1));
+        assert_eq!(cp.get(None).unwrap_err().raw_os_error(), aborted);
+
+        t!(r.cancel_pending());
+        assert_eq!(cp.get(None).unwrap_err().raw_os_error(), aborted);
+        let err = r.cancel_pending().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(ERROR_NOT_FOUND as i32));
+    }
+
+    #[test]
+    fn ioctl_overlapped() {
+        use net::{SocketExt, SIO_KEEPALIVE_VALS};
+
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let s = t!(TcpStream::connect(t!(l.local_addr())));
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &s));
+
+        // `struct tcp_keepalive` enabling keepalive after 10s every 1s.
+        let mut input = [0u8; 12];
+        input[0] = 1;
+        input[4..6].copy_from_slice(&[0x10, 0x27]);
+        input[8..10].copy_from_slice(&[0xe8, 0x03]);
+        let mut a = Overlapped::zero();
+        unsafe {
+            t!(s.ioctl_overlapped(SIO_KEEPALIVE_VALS, &input, &mut [],
+                                  &mut a));
+        }
+        let status = t!(cp.get(Some(1000)));
+        assert_eq!(status.overlapped(), &mut a as *mut _);
+    }
+
+    #[test]
+    fn tcp_read_ready() {
+        let cp = t!(CompletionPort::new(1));
+        let (s, mut b) = connected_pair(&cp);
+
+        let mut a = Overlapped::zero();
+        unsafe {
+            t!(s.read_ready_overlapped(&mut a));
+        }
+        t!(b.write_all(&[1, 2, 3]));
+        let status = t!(cp.get(None));
+        assert_eq!(status.bytes_transferred(), 0);
+        assert_eq!(status.overlapped(), &mut a as *mut _);
+        let mut buf = [0; 3];
+        t!((&s).read_exact(&mut buf));
+        assert_eq!(buf, [1, 2, 3]);
+    }
+
+    #[test]
+    fn tcp_write() {
+        each_ip(&mut |addr| {
+            let l = t!(TcpListener::bind(addr));
+            let addr = t!(l.local_addr());
+            let t = thread::spawn(move || {
+                let mut a = t!(l.accept()).0;
+                let mut b = [0; 10];
+                let n = t!(a.read(&mut b));
+                assert_eq!(n, 3);
+                assert_eq!(&b[0..3], &[1, 2, 3]);
+            });
+
+            let cp = t!(CompletionPort::new(1));
+            let s = t!(TcpStream::connect(addr));
+            t!(cp.add_socket(1, &s));
+
+            let b = [1, 2, 3];
+            let mut a = Overlapped::zero();
+            unsafe {
+                t!(s.write_overlapped(&b, &mut a));
+            }
+            let status = t!(cp.get(None));
+            assert_eq!(status.bytes_transferred(), 3);
+            assert_eq!(status.token(), 1);
+            assert_eq!(status.overlapped(), &mut a as *mut _);
+
+            t!(t.join());
+        })
+    }
+
+    #[test]
+    fn tcp_write_vectored() {
+        let cp = t!(CompletionPort::new(1));
+        let (s, mut b) = connected_pair(&cp);
+
+        let header = [1, 2];
+        let body = [3, 4, 5];
+        let bufs = [WsaBuf::new(&header), WsaBuf::new(&body)];
+        let mut a = Overlapped::zero();
+        unsafe {
+            t!(s.write_overlapped_vectored(&bufs, &mut a));
+        }
+        let status = t!(cp.get(None));
+        assert_eq!(status.bytes_transferred(), 5);
+        drop(s);
+
+        let mut v = Vec::new();
+        t!(b.read_to_end(&mut v));
+        assert_eq!(v, [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn tcp_disconnect() {
+        let cp = t!(CompletionPort::new(1));
+        let (s, mut b) = connected_pair(&cp);
+
+        let mut a = Overlapped::zero();
+        unsafe {
+            t!(s.disconnect_overlapped(true, &mut a));
+        }
+        let status = t!(cp.get(None));
+        assert_eq!(status.overlapped(), &mut a as *mut _);
+        assert_eq!(t!(b.read(&mut [0; 10])), 0);
+    }
+
+    #[test]
+    fn tcp_transmit_file() {
+        use std::env;
+        use std::fs::{self, File, OpenOptions};
+        use rand::{thread_rng, Rng};
+
+        let name = thread_rng().gen_ascii_chars().take(10).collect::<String>();
+        let path = env::temp_dir().join(format!("miow-{}", name));
+        t!(t!(File::create(&path)).write_all(b"file"));
+        let file = t!(OpenOptions::new().read(true).open(&path));
+
+        let cp = t!(CompletionPort::new(1));
+        let (s, mut b) = connected_pair(&cp);
+
+        let mut a = Overlapped::zero();
+        unsafe {
+            t!(s.transmit_file_overlapped(&file, b"head ", b" tail",
+                                          TF_DISCONNECT, &mut a));
+        }
+        let status = t!(cp.get(None));
+        assert_eq!(status.bytes_transferred(), 14);
+        let mut v = Vec::new();
+        t!(b.read_to_end(&mut v));
+        assert_eq!(v, b"head file tail");
+
+        drop(file);
+        t!(fs::remove_file(&path));
+    }
+
+    #[test]
+    fn tcp_connect() {
+        each_ip(&mut |addr_template| {
+            let l = t!(TcpListener::bind(addr_template));
+            let addr = t!(l.local_addr());
+            let t = thread::spawn(move || {
+                t!(l.accept());
+            });
+
+            let cp = t!(CompletionPort::new(1));
+            let builder = match addr {
+                SocketAddr::V4(..) => t!(TcpBuilder::new_v4()),
+                SocketAddr::V6(..) => t!(TcpBuilder::new_v6()),
+            };
+            t!(cp.add_socket(1, &builder));
+
+            // Connecting an unbound socket fails, leaving it in the builder
+            // to be retried once bound.
+            let mut a = Overlapped::zero();
+            unsafe {
+                assert!(builder.connect_overlapped(&addr, &mut a).is_err());
+            }
+            t!(builder.bind(addr_template));
+            let (s, _) = unsafe {
+                t!(builder.connect_overlapped(&addr, &mut a))
+            };
+            let status = t!(cp.get(None));
+            assert_eq!(status.bytes_transferred(), 0);
+            assert_eq!(status.token(), 1);
+            assert_eq!(status.overlapped(), &mut a as *mut _);
+            assert!(unsafe { t!(s.connect_error(&a)) }.is_none());
+
+            t!(s.connect_complete());
+            assert_eq!(t!(s.peer_addr()), addr);
+
+            t!(t.join());
+        })
+    }
+
+    #[test]
+    fn tcp_connect_auto_bind() {
+        use net::SocketBuilder;
+
+        each_ip(&mut |addr_template| {
+            let l = t!(TcpListener::bind(addr_template));
+            let addr = t!(l.local_addr());
+            let cp = t!(CompletionPort::new(1));
+            let builder = match addr {
+                SocketAddr::V4(..) => t!(SocketBuilder::new_v4()),
+                SocketAddr::V6(..) => t!(SocketBuilder::new_v6()),
+            };
+            t!(cp.add_socket(1, &builder));
+
+            let mut a = Overlapped::zero();
+            let (s, _) = unsafe {
+                t!(builder.connect_overlapped_auto_bind(&addr, &mut a))
+            };
+            t!(cp.get(None));
+            t!(s.connect_complete());
+            assert_eq!(t!(s.peer_addr()), addr);
+            t!(l.accept());
+        });
+
+        // A socket which is already bound keeps its address.
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let cp = t!(CompletionPort::new(1));
+        let builder = t!(TcpBuilder::new_v4());
+        t!(builder.bind("127.0.0.1:0"));
+        let local = t!(builder.local_addr());
+        t!(cp.add_socket(1, &builder));
+        let mut a = Overlapped::zero();
+        let (s, _) = unsafe {
+            t!(builder.connect_overlapped_auto_bind(&t!(l.local_addr()),
+                                                    &mut a))
+        };
+        t!(cp.get(None));
+        assert_eq!(t!(s.local_addr()), local);
+    }
+
+    #[test]
+    fn tcp_connect_error() {
+        use std::io;
+        use winapi::WSAECONNREFUSED;
+
+        // Find a port nothing is listening on.
+        let addr = t!(t!(TcpListener::bind("127.0.0.1:0")).local_addr());
+        let cp = t!(CompletionPort::new(1));
+        let builder = t!(TcpBuilder::new_v4());
+        t!(builder.bind("127.0.0.1:0"));
+        t!(cp.add_socket(1, &builder));
+
+        let mut a = Overlapped::zero();
+        let (s, _) = unsafe {
+            t!(builder.connect_overlapped(&addr, &mut a))
+        };
+        assert!(cp.get(None).is_err());
+        let e = unsafe { t!(s.connect_error(&a)).unwrap() };
+        assert_eq!(e.raw_os_error(), Some(WSAECONNREFUSED as i32));
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn tcp_connect_with_data() {
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let addr = t!(l.local_addr());
+        let cp = t!(CompletionPort::new(1));
+        let builder = t!(TcpBuilder::new_v4());
+        t!(builder.bind("127.0.0.1:0"));
+        t!(cp.add_socket(1, &builder));
+
+        let mut a = Overlapped::zero();
+        let (_s, sent) = unsafe {
+            t!(builder.connect_overlapped_with_data(&addr, b"hello", &mut a))
+        };
+        let status = t!(cp.get(None));
+        assert_eq!(status.overlapped(), &mut a as *mut _);
+        assert_eq!(sent.unwrap_or(status.bytes_transferred() as usize), 5);
+
+        let mut b = t!(l.accept()).0;
+        let mut buf = [0; 5];
+        t!(b.read_exact(&mut buf));
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn tcp_initial_rto() {
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let addr = t!(l.local_addr());
+        let builder = t!(TcpBuilder::new_v4());
+        t!(builder.set_initial_rto(Some(Duration::from_millis(500)), Some(2)));
+        t!(builder.set_initial_rto(None, None));
+        t!(builder.connect(addr));
+        t!(l.accept());
+    }
+
+    #[test]
+    fn keepalive_vals() {
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let s = t!(TcpStream::connect(t!(l.local_addr())));
+        t!(s.set_keepalive_vals(true, Duration::from_secs(10),
+                                Duration::from_millis(500)));
+        t!(s.set_keepalive_vals(false, Duration::from_secs(0),
+                                Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn tcp_info() {
+        let (mut s, mut b) = ::tcp_pair();
+        t!(s.write_all(&[1, 2, 3]));
+        let mut buf = [0; 3];
+        t!(b.read_exact(&mut buf));
+
+        let info = t!(s.tcp_info());
+        assert_eq!(info.bytes_out(), 3);
+        assert!(info.mss() > 0);
+        assert_eq!(info.bytes_retransmitted(), 0);
+    }
+
+    #[test]
+    fn ideal_send_backlog() {
+        let cp = t!(CompletionPort::new(1));
+        let (s, _b) = connected_pair(&cp);
+        assert!(t!(s.ideal_send_backlog()) > 0);
+
+        let mut a = Overlapped::zero();
+        unsafe {
+            t!(s.ideal_send_backlog_change_overlapped(&mut a));
+        }
+        // Closing the socket aborts the notification, which still completes
+        // through the port before `a` goes out of scope.
+        drop(s);
+        let _ = cp.get(Some(1000));
+    }
+
+    #[test]
+    fn loopback_fast_path() {
+        let l = t!(TcpBuilder::new_v4());
+        t!(l.set_loopback_fast_path(true));
+        t!(l.bind("127.0.0.1:0"));
+        let l = t!(l.listen(1));
+        let c = t!(TcpBuilder::new_v4());
+        t!(c.set_loopback_fast_path(true));
+        let mut c = t!(c.connect(t!(l.local_addr())));
+        let mut s = t!(l.accept()).0;
+        t!(c.write_all(&[1, 2, 3]));
+        let mut buf = [0; 3];
+        t!(s.read_exact(&mut buf));
+        assert_eq!(buf, [1, 2, 3]);
+
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        t!(l.set_loopback_fast_path(false));
+    }
+
+    #[test]
+    fn unicast_interface() {
+        let builder = t!(TcpBuilder::new_v4());
+        assert_eq!(t!(builder.unicast_interface_v4()), 0);
+        t!(builder.set_unicast_interface_v4(1));
+        assert_eq!(t!(builder.unicast_interface_v4()), 1);
+        t!(builder.set_unicast_interface_v4(0));
+
+        let a = t!(UdpSocket::bind("[::1]:0"));
+        t!(a.set_unicast_interface_v6(1));
+        assert_eq!(t!(a.unicast_interface_v6()), 1);
+    }
+
+    #[test]
+    fn udp_recv_from() {
+        each_ip(&mut |addr| {
+            let a = t!(UdpSocket::bind(addr));
+            let b = t!(UdpSocket::bind(addr));
+            let a_addr = t!(a.local_addr());
+            let b_addr = t!(b.local_addr());
+            let t = thread::spawn(move || {
+                t!(a.send_to(&[1, 2, 3], b_addr));
+            });
+
+            let cp = t!(CompletionPort::new(1));
+            t!(cp.add_socket(1, &b));
+
+            let mut buf = [0; 10];
+            let mut a = Overlapped::zero();
+            let mut addr = SocketAddrBuf::new();
+            unsafe {
+                t!(b.recv_from_overlapped(&mut buf, &mut addr, &mut a));
+            }
+            let status = t!(cp.get(None));
+            assert_eq!(status.bytes_transferred(), 3);
+            assert_eq!(status.token(), 1);
+            assert_eq!(status.overlapped(), &mut a as *mut _);
+            assert_eq!(&buf[..3], &[1, 2, 3]);
+            assert_eq!(addr.to_socket_addr(), Some(a_addr));
+
+            t!(t.join());
+        })
+    }
+
+    #[test]
+    fn unix_addr() {
+        use std::env;
+        use std::fs;
+        use std::os::windows::prelude::*;
+        use std::path::Path;
+        use std::process;
+        use std::ptr;
+        use winapi::{c_int, AF_UNIX, SOCK_STREAM};
+        use ws2_32::getsockname;
+        use net::{socket, UnixSocketAddr};
+
+        // Write an address into a buffer the way Winsock does.
+        fn fill(addr: &mut SocketAddrBuf, path: &[u8], len: usize) {
+            addr.reset();
+            unsafe {
+                let buf = addr.addr.as_mut_ptr() as *mut u8;
+                *(buf as *mut u16) = AF_UNIX as u16;
+                ptr::copy_nonoverlapping(path.as_ptr(), buf.offset(2),
+                                         path.len());
+            }
+            addr.addr.set_len(len as c_int);
+        }
+
+        let mut addr = SocketAddrBuf::new();
+        fill(&mut addr, b"/tmp/a\0", 9);
+        let unix = addr.to_unix_addr().unwrap();
+        assert_eq!(unix, t!(UnixSocketAddr::new("/tmp/a")));
+        assert_eq!(unix.as_pathname(), Some(Path::new("/tmp/a")));
+        assert!(addr.to_socket_addr().is_none());
+        fill(&mut addr, b"\0name", 7);
+        let unix = addr.to_unix_addr().unwrap();
+        assert_eq!(unix.as_abstract_name(), Some(&b"name"[..]));
+        assert_eq!(unix, t!(UnixSocketAddr::new_abstract(b"name")));
+        fill(&mut addr, b"", 2);
+        assert!(addr.to_unix_addr().unwrap().is_unnamed());
+        assert!(UnixSocketAddr::new(vec!["a"; 200].concat()).is_err());
+
+        // Unix domain sockets aren't available on older versions of
+        // Windows.
+        let s = match socket(AF_UNIX, SOCK_STREAM, 0, 0) {
+            Ok(s) => unsafe { TcpStream::from_raw_socket(s) },
+            Err(..) => return,
+        };
+        let path = env::temp_dir().join(format!("miow-unix-{}",
+                                                process::id()));
+        let _ = fs::remove_file(&path);
+        let mut name = path.to_str().unwrap().as_bytes().to_vec();
+        name.push(0);
+        fill(&mut addr, &name, 2 + name.len());
+        unsafe {
+            let r = ::ws2_32::bind(s.as_raw_socket(), addr.addr.as_ptr(),
+                                   addr.addr.len());
+            assert_eq!(r, 0);
+            addr.reset();
+            let r = getsockname(s.as_raw_socket(), addr.addr.as_mut_ptr(),
+                                &mut addr.addr.len);
+            assert_eq!(r, 0);
+        }
+        let unix = addr.to_unix_addr().unwrap();
+        assert_eq!(unix.as_pathname(), Some(&*path));
+        drop(s);
+        t!(fs::remove_file(&path));
+    }
+
+    #[test]
+    fn raw_socket_addr() {
+        use std::convert::TryFrom;
+        use std::mem;
+        use std::net::SocketAddrV6;
+        use std::os::windows::prelude::*;
+        use winapi::{AF_INET, AF_INET6, SOCKADDR_IN};
+        use ws2_32::getsockname;
+        use net::{RawSocketAddr, UnixSocketAddr};
+
+        let v4 = "10.1.2.3:80".parse::<SocketAddr>().unwrap();
+        let raw = RawSocketAddr::from(v4);
+        assert_eq!(raw.family(), AF_INET as u16);
+        assert_eq!(raw.len() as usize, mem::size_of::<SOCKADDR_IN>());
+        unsafe {
+            let b = &*(raw.as_ptr() as *const SOCKADDR_IN);
+            assert_eq!(b.sin_port, 80u16.to_be());
+            assert_eq!(b.sin_addr.S_un, 0x0a010203u32.to_be());
+        }
+        assert_eq!(t!(SocketAddr::try_from(raw)), v4);
+        assert!(raw.to_unix_addr().is_none());
+
+        let v6 = SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(),
+                                                  443, 7, 12));
+        let raw = RawSocketAddr::from(v6);
+        assert_eq!(raw.family(), AF_INET6 as u16);
+        assert_eq!(raw.to_socket_addr(), Some(v6));
+
+        let unix = t!(UnixSocketAddr::new("/tmp/b"));
+        let raw = RawSocketAddr::from(&unix);
+        assert_eq!(raw.len(), 2 + 7);
+        assert_eq!(t!(UnixSocketAddr::try_from(raw)), unix);
+        assert!(SocketAddr::try_from(raw).is_err());
+
+        let s = t!(UdpSocket::bind("127.0.0.1:0"));
+        let mut raw = RawSocketAddr::new();
+        let mut len = raw.len();
+        unsafe {
+            assert_eq!(getsockname(s.as_raw_socket(), raw.as_mut_ptr(),
+                                   &mut len), 0);
+        }
+        raw.set_len(len);
+        assert_eq!(raw.to_socket_addr(), Some(t!(s.local_addr())));
+        let copy = unsafe { RawSocketAddr::from_raw_parts(raw.as_ptr(), len) };
+        assert_eq!(copy.to_socket_addr(), Some(t!(s.local_addr())));
+    }
+
+    #[test]
+    fn raw_socket_addr_scope_id() {
+        use std::mem;
+        use std::os::windows::prelude::*;
+        use winapi::{SIO_ADDRESS_LIST_QUERY, SOCKET_ADDRESS,
+                     SOCKET_ADDRESS_LIST};
+        use ws2_32::{getsockname, WSAIoctl};
+        use net::RawSocketAddr;
+
+        // Link-local addresses carry the scope id of their interface, so look
+        // for one among the local addresses.
+        let s = t!(UdpSocket::bind("[::1]:0"));
+        let mut list = [0u64; 512];
+        let mut bytes = 0;
+        let r = unsafe {
+            WSAIoctl(s.as_raw_socket(), SIO_ADDRESS_LIST_QUERY,
+                     0 as *mut _, 0, list.as_mut_ptr() as *mut _,
+                     mem::size_of_val(&list) as u32, &mut bytes,
+                     0 as *mut _, None)
+        };
+        assert_eq!(r, 0);
+        let addrs = unsafe {
+            let list = &*(list.as_ptr() as *const SOCKET_ADDRESS_LIST);
+            let first = list.Address.as_ptr() as *const SOCKET_ADDRESS;
+            (0..list.iAddressCount as usize).map(|i| {
+                let a = &*first.offset(i as isize);
+                RawSocketAddr::from_raw_parts(a.lpSockaddr, a.iSockaddrLength)
+            }).filter_map(|a| a.to_socket_addr()).collect::<Vec<_>>()
+        };
+        let addr = match addrs.iter().find(|a| match **a {
+            SocketAddr::V6(ref a) => a.scope_id() != 0,
+            SocketAddr::V4(..) => false,
+        }) {
+            Some(addr) => *addr,
+            None => return,
+        };
+
+        // Binding fails unless the scope id was decoded in host order.
+        let s = t!(UdpSocket::bind(addr));
+        let mut raw = RawSocketAddr::new();
+        let mut len = raw.len();
+        unsafe {
+            assert_eq!(getsockname(s.as_raw_socket(), raw.as_mut_ptr(),
+                                   &mut len), 0);
+        }
+        raw.set_len(len);
+        assert_eq!(raw.to_socket_addr(), Some(t!(s.local_addr())));
+    }
+
+    #[test]
+    fn udp_recv_from_reuse() {
+        use winapi::AF_INET;
+
+        let a = t!(UdpSocket::bind("127.0.0.1:0"));
+        let b = t!(UdpSocket::bind("127.0.0.1:0"));
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &b));
+
+        let mut buf = [0; 10];
+        let mut addr = SocketAddrBuf::new();
+        let full = addr.len();
+        for _ in 0..2 {
+            let mut over = Overlapped::zero();
+            addr.reset();
+            assert_eq!(addr.len(), full);
+            unsafe {
+                t!(b.recv_from_overlapped(&mut buf, &mut addr, &mut over));
+            }
+            t!(a.send_to(&[1], t!(b.local_addr())));
+            assert_eq!(t!(cp.get(None)).bytes_transferred(), 1);
+            assert_eq!(addr.to_socket_addr(), Some(t!(a.local_addr())));
+            assert_eq!(addr.family(), AF_INET as u16);
+            assert!(addr.len() < full);
+            assert_eq!(addr.as_bytes().len(), addr.len());
+            assert_eq!(addr.storage().ss_family, AF_INET as u16);
+        }
+    }
+
+    #[test]
+    fn udp_connected() {
+        let a = t!(UdpSocket::bind("127.0.0.1:0"));
+        let b = t!(UdpSocket::bind("127.0.0.1:0"));
+        t!(a.connect(t!(b.local_addr())));
+        t!(b.connect(t!(a.local_addr())));
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &a));
+        t!(cp.add_socket(2, &b));
+
+        let mut buf = [0; 10];
+        let mut ra = Overlapped::zero();
+        let mut wa = Overlapped::zero();
+        unsafe {
+            t!(b.recv_overlapped(&mut buf, &mut ra));
+            t!(a.send_overlapped(&[1, 2, 3], &mut wa));
+        }
+        for _ in 0..2 {
+            assert_eq!(t!(cp.get(None)).bytes_transferred(), 3);
+        }
+        assert_eq!(&buf[..3], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn udp_truncated() {
+        let a = t!(UdpSocket::bind("127.0.0.1:0"));
+        let b = t!(UdpSocket::bind("127.0.0.1:0"));
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &a));
+
+        let mut buf = [0; 2];
+        let mut flags = 0;
+        let mut over = Overlapped::zero();
+        let mut addr = SocketAddrBuf::new();
+        unsafe {
+            t!(a.recv_from_overlapped_with_flags(&mut buf, &mut addr,
+                                                 &mut flags, &mut over));
+        }
+        t!(b.send_to(&[1, 2, 3, 4], t!(a.local_addr())));
+        // The completion itself reports `WSAEMSGSIZE`.
+        assert!(cp.get(Some(1000)).is_err());
+        assert_eq!(t!(unsafe { a.recv_result(&over) }), (2, MSG_PARTIAL));
+        assert!(unsafe { a.result(&over) }.is_err());
+        assert_eq!(buf, [1, 2]);
+
+        // A truncated receive which completes immediately still queues its
+        // completion.
+        t!(b.send_to(&[5, 6, 7], t!(a.local_addr())));
+        t!(a.peek_from(&mut [0; 16]));
+        let mut flags = 0;
+        let mut over = Overlapped::zero();
         unsafe {
-            ptrs_to_socket_addr(&self.buf as *const _ as *const _, self.len)
+            assert!(t!(a.recv_from_overlapped_with_flags(&mut buf, &mut addr,
+                                                         &mut flags,
+                                                         &mut over)));
         }
+        assert_eq!(flags & MSG_PARTIAL, MSG_PARTIAL);
+        assert_eq!(buf, [5, 6]);
+        let mut list = [CompletionStatus::zero(); 2];
+        let list = t!(cp.get_many(&mut list, Some(1000)));
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].overlapped(), &mut over as *mut _);
+        assert_eq!(t!(unsafe { a.recv_result(&over) }), (2, MSG_PARTIAL));
     }
-}
 
-static GETACCEPTEXSOCKADDRS: WsaExtension = WsaExtension {
-    guid: GUID {
-        Data1: 0xb5367df2,
-        Data2: 0xcbac,
-        Data3: 0x11cf,
-        Data4: [0x95, 0xca, 0x00, 0x80, 0x5f, 0x48, 0xa1, 0x92],
-    },
-    val: ATOMIC_USIZE_INIT,
-};
-type GetAcceptExSockaddrs = unsafe extern "system" fn(PVOID, DWORD, DWORD, DWORD,
-                                                      *mut LPSOCKADDR, LPINT,
-                                                      *mut LPSOCKADDR, LPINT);
+    #[test]
+    fn udp_connreset() {
+        let a = t!(UdpSocket::bind("127.0.0.1:0"));
+        let b = t!(UdpSocket::bind("127.0.0.1:0"));
+        let closed = t!(t!(UdpSocket::bind("127.0.0.1:0")).local_addr());
+        t!(a.set_connreset(false));
+        t!(a.set_netreset(false));
 
-impl AcceptAddrsBuf {
-    /// Creates a new blank buffer ready to be passed to a call to
-    /// `accept_overlapped`.
-    pub fn new() -> AcceptAddrsBuf {
-        unsafe { mem::zeroed() }
-    }
+        // Without the reset disabled, the "port unreachable" response to
+        // this datagram would fail the receive below.
+        t!(a.send_to(&[1], closed));
+        t!(b.send_to(&[2], t!(a.local_addr())));
 
-    /// Parses the data contained in this address buffer, returning the parsed
-    /// result if successful.
-    ///
-    /// This function can be called after a call to `accept_overlapped` has
-    /// succeeded to parse out the data that was written in.
-    pub fn parse(&self, socket: &TcpListener) -> io::Result<AcceptAddrs> {
-        let mut ret = AcceptAddrs {
-            local: 0 as *mut _, local_len: 0,
-            remote: 0 as *mut _, remote_len: 0,
-            _data: self,
-        };
-        let ptr = try!(GETACCEPTEXSOCKADDRS.get(socket.as_raw_socket()));
-        assert!(ptr != 0);
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &a));
+        let mut buf = [0; 10];
+        let mut over = Overlapped::zero();
+        let mut addr = SocketAddrBuf::new();
         unsafe {
-            let get_sockaddrs = mem::transmute::<_, GetAcceptExSockaddrs>(ptr);
-            let (a, b, c, d) = self.args();
-            get_sockaddrs(a, b, c, d,
-                          &mut ret.local, &mut ret.local_len,
-                          &mut ret.remote, &mut ret.remote_len);
-            Ok(ret)
+            t!(a.recv_from_overlapped(&mut buf, &mut addr, &mut over));
         }
+        let status = t!(cp.get(None));
+        assert_eq!(status.bytes_transferred(), 1);
+        assert_eq!(buf[0], 2);
     }
 
-    fn args(&self) -> (PVOID, DWORD, DWORD, DWORD) {
-        let remote_offset = unsafe {
-            &(*(0 as *const AcceptAddrsBuf)).remote as *const _ as usize
-        };
-        (self as *const _ as *mut _, 0, remote_offset as DWORD,
-         (mem::size_of_val(self) - remote_offset) as DWORD)
+    #[test]
+    fn udp_routing_interface() {
+        let a = t!(UdpSocket::bind("127.0.0.1:0"));
+        let dst = t!("127.0.0.1:1".parse());
+        let local = t!(a.routing_interface(&dst));
+        assert!(local.ip().is_loopback());
+
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &a));
+        let mut over = Overlapped::zero();
+        unsafe {
+            assert!(!t!(a.routing_interface_change_overlapped(&dst,
+                                                              &mut over)));
+        }
+        assert!(cp.get(Some(10)).is_err());
+
+        // Closing the socket aborts the request, wait for that to complete
+        // before `over` goes out of scope.
+        drop(a);
+        assert!(cp.get(None).is_err());
     }
-}
 
-impl<'a> AcceptAddrs<'a> {
-    /// Returns the local socket address contained in this buffer.
-    pub fn local(&self) -> Option<SocketAddr> {
-        unsafe { ptrs_to_socket_addr(self.local, self.local_len) }
+    #[test]
+    fn udp_multicast() {
+        let a = t!(UdpSocket::bind("0.0.0.0:0"));
+        let group = t!("239.255.12.34".parse());
+        let source = t!("127.0.0.1".parse());
+        t!(a.set_multicast_interface_v4(0));
+        t!(a.join_multicast_group(&group, 0));
+        t!(a.leave_multicast_group(&group, 0));
+        t!(a.join_source_group(&group, &source, 0));
+        t!(a.leave_source_group(&group, &source, 0));
+
+        let v6 = t!("::1".parse());
+        assert!(a.join_source_group(&group, &v6, 0).is_err());
     }
 
-    /// Returns the remote socket address contained in this buffer.
-    pub fn remote(&self) -> Option<SocketAddr> {
-        unsafe { ptrs_to_socket_addr(self.remote, self.remote_len) }
+    #[test]
+    fn udp_recv_msg() {
+        let a = t!(UdpSocket::bind("127.0.0.1:0"));
+        let b = t!(UdpSocket::bind("127.0.0.1:0"));
+        t!(a.set_recv_pktinfo_v4(true));
+        t!(a.set_recv_ecn_v4(true));
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &a));
+
+        let mut buf = [0; 10];
+        let mut control = [0; 128];
+        let mut msg = MsgBuf::new();
+        let mut over = Overlapped::zero();
+        unsafe {
+            t!(a.recv_msg_overlapped(&mut buf, &mut control, &mut msg,
+                                     &mut over));
+        }
+        t!(b.send_to(&[1, 2, 3], t!(a.local_addr())));
+        let status = t!(cp.get(None));
+        assert_eq!(status.bytes_transferred(), 3);
+        assert_eq!(&buf[..3], &[1, 2, 3]);
+        assert_eq!(msg.addr(), Some(t!(b.local_addr())));
+        assert!(!msg.is_truncated());
+        assert!(!msg.is_control_truncated());
+        let mut msgs = CMsgIter::new(&control[..msg.control_len()]);
+        assert!(msgs.any(|m| match m {
+            CMsg::PktInfoV4(ip, _) => ip == Ipv4Addr::new(127, 0, 0, 1),
+            _ => false,
+        }));
     }
-}
 
-impl WsaExtension {
-    fn get(&self, socket: SOCKET) -> io::Result<usize> {
-        let prev = self.val.load(Ordering::SeqCst);
-        if prev != 0 && !cfg!(debug_assertions) {
-            return Ok(prev)
+    #[test]
+    fn read_uninit() {
+        use std::mem::MaybeUninit;
+
+        fn init(buf: &[MaybeUninit<u8>]) -> Vec<u8> {
+            buf.iter().map(|b| unsafe { b.assume_init() }).collect()
         }
-        let mut ret = 0 as usize;
-        let mut bytes = 0;
-        let r = unsafe {
-            WSAIoctl(socket, SIO_GET_EXTENSION_FUNCTION_POINTER,
-                     &self.guid as *const _ as *mut _,
-                     mem::size_of_val(&self.guid) as DWORD,
-                     &mut ret as *mut _ as *mut _,
-                     mem::size_of_val(&ret) as DWORD,
-                     &mut bytes,
-                     0 as *mut _, None)
-        };
-        cvt(r).map(|_| {
-            debug_assert_eq!(bytes as usize, mem::size_of_val(&ret));
-            debug_assert!(prev == 0 || prev == ret);
-            self.val.store(ret, Ordering::SeqCst);
-            ret
-        })
 
-    }
-}
+        let (s, mut peer) = ::tcp_pair();
+        let a = t!(UdpSocket::bind("127.0.0.1:0"));
+        let b = t!(UdpSocket::bind("127.0.0.1:0"));
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &s));
+        t!(cp.add_socket(2, &a));
 
-#[cfg(test)]
-mod tests {
-    use std::net::{TcpListener, UdpSocket, TcpStream, SocketAddr};
-    use std::thread;
-    use std::io::prelude::*;
+        let mut buf = [MaybeUninit::<u8>::uninit(); 10];
+        let mut over = Overlapped::zero();
+        unsafe {
+            t!(s.read_overlapped_uninit(&mut buf, &mut over));
+        }
+        t!(peer.write_all(&[1, 2, 3]));
+        assert_eq!(t!(cp.get(None)).bytes_transferred(), 3);
+        assert_eq!(init(&buf[..3]), [1, 2, 3]);
 
-    use Overlapped;
-    use iocp::CompletionPort;
-    use net::{TcpStreamExt, UdpSocketExt, SocketAddrBuf};
-    use net::{TcpBuilderExt, TcpListenerExt, AcceptAddrsBuf};
-    use net2::TcpBuilder;
+        let mut addr = SocketAddrBuf::new();
+        unsafe {
+            t!(a.recv_from_overlapped_uninit(&mut buf, &mut addr, &mut over));
+        }
+        t!(b.send_to(&[4, 5], t!(a.local_addr())));
+        assert_eq!(t!(cp.get(None)).bytes_transferred(), 2);
+        assert_eq!(init(&buf[..2]), [4, 5]);
+        assert_eq!(addr.to_socket_addr(), Some(t!(b.local_addr())));
 
-    fn each_ip(f: &mut FnMut(SocketAddr)) {
-        f(t!("127.0.0.1:0".parse()));
-        f(t!("[::1]:0".parse()));
+        t!(a.connect(t!(b.local_addr())));
+        unsafe {
+            t!(a.recv_overlapped_uninit(&mut buf, &mut over));
+        }
+        t!(b.send_to(&[6], t!(a.local_addr())));
+        assert_eq!(t!(cp.get(None)).bytes_transferred(), 1);
+        assert_eq!(init(&buf[..1]), [6]);
     }
 
     #[test]
-    fn tcp_read() {
-        each_ip(&mut |addr| {
-            let l = t!(TcpListener::bind(addr));
-            let addr = t!(l.local_addr());
-            let t = thread::spawn(move || {
-                let mut a = t!(l.accept()).0;
-                t!(a.write_all(&[1, 2, 3]));
-            });
-
-            let cp = t!(CompletionPort::new(1));
-            let s = t!(TcpStream::connect(addr));
-            t!(cp.add_socket(1, &s));
+    fn udp_send_msg() {
+        let a = t!(UdpSocket::bind("127.0.0.1:0"));
+        let b = t!(UdpSocket::bind("127.0.0.1:0"));
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &a));
 
-            let mut b = [0; 10];
-            let mut a = Overlapped::zero();
-            unsafe {
-                t!(s.read_overlapped(&mut b, &mut a));
-            }
-            let status = t!(cp.get(None));
-            assert_eq!(status.bytes_transferred(), 3);
-            assert_eq!(status.token(), 1);
-            assert_eq!(status.overlapped(), &mut a as *mut _);
-            assert_eq!(&b[0..3], &[1, 2, 3]);
+        let mut msg = MsgBuf::new();
+        let mut over = Overlapped::zero();
+        unsafe {
+            t!(a.send_msg_overlapped(&[1, 2, 3], &t!(b.local_addr()), &[],
+                                     &mut msg, &mut over));
+        }
+        let status = t!(cp.get(None));
+        assert_eq!(status.bytes_transferred(), 3);
 
-            t!(t.join());
-        })
+        let mut buf = [0; 10];
+        let (n, addr) = t!(b.recv_from(&mut buf));
+        assert_eq!(&buf[..n], &[1, 2, 3]);
+        assert_eq!(addr, t!(a.local_addr()));
     }
 
     #[test]
-    fn tcp_write() {
-        each_ip(&mut |addr| {
-            let l = t!(TcpListener::bind(addr));
-            let addr = t!(l.local_addr());
-            let t = thread::spawn(move || {
-                let mut a = t!(l.accept()).0;
-                let mut b = [0; 10];
-                let n = t!(a.read(&mut b));
-                assert_eq!(n, 3);
-                assert_eq!(&b[0..3], &[1, 2, 3]);
-            });
-
-            let cp = t!(CompletionPort::new(1));
-            let s = t!(TcpStream::connect(addr));
-            t!(cp.add_socket(1, &s));
+    fn udp_send_msg_size() {
+        let a = t!(UdpSocket::bind("127.0.0.1:0"));
+        let b = t!(UdpSocket::bind("127.0.0.1:0"));
+        // Segmentation offload isn't available on older versions of Windows.
+        if a.set_send_msg_size(100).is_err() {
+            return
+        }
+        assert_eq!(t!(a.send_msg_size()), 100);
+        t!(a.set_send_msg_size(0));
+        t!(b.set_recv_max_coalesced_size(0));
+        assert_eq!(t!(b.recv_max_coalesced_size()), 0);
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &a));
 
-            let b = [1, 2, 3];
-            let mut a = Overlapped::zero();
-            unsafe {
-                t!(s.write_overlapped(&b, &mut a));
-            }
-            let status = t!(cp.get(None));
-            assert_eq!(status.bytes_transferred(), 3);
-            assert_eq!(status.token(), 1);
-            assert_eq!(status.overlapped(), &mut a as *mut _);
+        let data = [7; 250];
+        let mut control = CMsgBuf::new();
+        control.push_send_msg_size(100);
+        let mut msg = MsgBuf::new();
+        let mut over = Overlapped::zero();
+        unsafe {
+            t!(a.send_msg_overlapped(&data, &t!(b.local_addr()),
+                                     control.as_bytes(), &mut msg,
+                                     &mut over));
+        }
+        assert_eq!(t!(cp.get(None)).bytes_transferred(), 250);
 
-            t!(t.join());
-        })
+        let mut buf = [0; 300];
+        for &len in [100, 100, 50].iter() {
+            let (n, _) = t!(b.recv_from(&mut buf));
+            assert_eq!(n, len);
+        }
     }
 
     #[test]
-    fn tcp_connect() {
-        each_ip(&mut |addr_template| {
-            let l = t!(TcpListener::bind(addr_template));
-            let addr = t!(l.local_addr());
-            let t = thread::spawn(move || {
-                t!(l.accept());
-            });
+    fn udp_timestamping() {
+        use std::io;
 
-            let cp = t!(CompletionPort::new(1));
-            let builder = match addr {
-                SocketAddr::V4(..) => t!(TcpBuilder::new_v4()),
-                SocketAddr::V6(..) => t!(TcpBuilder::new_v6()),
-            };
-            t!(cp.add_socket(1, &builder));
+        use net::{TIMESTAMPING_FLAG_RX, TIMESTAMPING_FLAG_TX};
 
-            let mut a = Overlapped::zero();
-            t!(builder.bind(addr_template));
-            let (_s, _) = unsafe {
-                t!(builder.connect_overlapped(&addr, &mut a))
-            };
-            let status = t!(cp.get(None));
-            assert_eq!(status.bytes_transferred(), 0);
-            assert_eq!(status.token(), 1);
-            assert_eq!(status.overlapped(), &mut a as *mut _);
+        let a = t!(UdpSocket::bind("127.0.0.1:0"));
+        let b = t!(UdpSocket::bind("127.0.0.1:0"));
+        // Timestamping isn't available on older versions of Windows.
+        if a.set_timestamping(TIMESTAMPING_FLAG_RX, 0).is_err() {
+            return
+        }
+        t!(b.set_timestamping(TIMESTAMPING_FLAG_TX, 1));
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &a));
 
-            t!(t.join());
-        })
+        let mut buf = [0; 10];
+        let mut control = [0; 128];
+        let mut msg = MsgBuf::new();
+        let mut over = Overlapped::zero();
+        unsafe {
+            t!(a.recv_msg_overlapped(&mut buf, &mut control, &mut msg,
+                                     &mut over));
+        }
+        let id = t!(b.timestamp_id());
+        t!(b.send_to(&[1, 2, 3], t!(a.local_addr())));
+        assert_eq!(t!(cp.get(None)).bytes_transferred(), 3);
+        let mut msgs = CMsgIter::new(&control[..msg.control_len()]);
+        assert!(msgs.any(|m| match m {
+            CMsg::Timestamp(t) => t > 0,
+            _ => false,
+        }));
+
+        // The send timestamp may not have been recorded yet.
+        match b.tx_timestamp(id) {
+            Ok(t) => assert!(t > 0),
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::WouldBlock),
+        }
     }
 
     #[test]
-    fn udp_recv_from() {
-        each_ip(&mut |addr| {
-            let a = t!(UdpSocket::bind(addr));
-            let b = t!(UdpSocket::bind(addr));
-            let a_addr = t!(a.local_addr());
-            let b_addr = t!(b.local_addr());
-            let t = thread::spawn(move || {
-                t!(a.send_to(&[1, 2, 3], b_addr));
-            });
-
-            let cp = t!(CompletionPort::new(1));
-            t!(cp.add_socket(1, &b));
-
-            let mut buf = [0; 10];
-            let mut a = Overlapped::zero();
-            let mut addr = SocketAddrBuf::new();
-            unsafe {
-                t!(b.recv_from_overlapped(&mut buf, &mut addr, &mut a));
-            }
-            let status = t!(cp.get(None));
-            assert_eq!(status.bytes_transferred(), 3);
-            assert_eq!(status.token(), 1);
-            assert_eq!(status.overlapped(), &mut a as *mut _);
-            assert_eq!(&buf[..3], &[1, 2, 3]);
-            assert_eq!(addr.to_socket_addr(), Some(a_addr));
+    fn cmsg_round_trip() {
+        let mut buf = CMsgBuf::new();
+        buf.push_pktinfo_v4("10.0.0.1".parse().unwrap(), 3)
+           .push_pktinfo_v6("::1".parse().unwrap(), 4)
+           .push_ecn_v4(2)
+           .push(17, 3, &1200u32.to_ne_bytes())
+           .push(0xffff, 0x300a, &42u64.to_ne_bytes())
+           .push(1, 2, &[3, 4, 5]);
+        assert_eq!(buf.as_bytes().len(),
+                   CMsgBuf::space(8) + CMsgBuf::space(20) +
+                   CMsgBuf::space(4) + CMsgBuf::space(4) +
+                   CMsgBuf::space(8) + CMsgBuf::space(3));
+        let msgs = buf.iter().collect::<Vec<_>>();
+        assert_eq!(msgs, [
+            CMsg::PktInfoV4("10.0.0.1".parse().unwrap(), 3),
+            CMsg::PktInfoV6("::1".parse().unwrap(), 4),
+            CMsg::Ecn(2),
+            CMsg::CoalescedSize(1200),
+            CMsg::Timestamp(42),
+            CMsg::Other { level: 1, kind: 2, data: &[3, 4, 5] },
+        ]);
 
-            t!(t.join());
-        })
+        // Truncated trailing data is ignored.
+        let bytes = buf.as_bytes();
+        assert_eq!(CMsgIter::new(&bytes[..bytes.len() - 1]).count(), 5);
+        buf.clear();
+        assert_eq!(buf.iter().count(), 0);
     }
 
     #[test]
@@ -795,7 +6014,7 @@ mod tests {
 
             let mut a = Overlapped::zero();
             let mut addrs = AcceptAddrsBuf::new();
-            let (_s, _) = unsafe {
+            let (s, _) = unsafe {
                 t!(l.accept_overlapped(&builder, &mut addrs, &mut a))
             };
             let status = t!(cp.get(None));
@@ -807,6 +6026,276 @@ mod tests {
             let addrs = addrs.parse(&l).unwrap();
             assert_eq!(addrs.local(), Some(local));
             assert_eq!(addrs.remote(), Some(remote));
+
+            t!(s.accept_complete(&l));
+            assert_eq!(t!(s.peer_addr()), remote);
         })
     }
+
+    #[test]
+    fn tcp_accept_raw() {
+        use std::os::windows::prelude::*;
+
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let addr = t!(l.local_addr());
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &l));
+
+        // The socket accepted into stays owned by the caller throughout.
+        let s = t!(t!(TcpBuilder::new_v4()).to_tcp_stream());
+        let mut a = Overlapped::zero();
+        let mut addrs = AcceptAddrsBuf::new();
+        unsafe {
+            t!(l.accept_overlapped_raw(s.as_raw_socket(), &mut addrs, &mut a));
+        }
+        let c = t!(TcpStream::connect(addr));
+        let status = t!(cp.get(None));
+        assert_eq!(status.overlapped(), &mut a as *mut _);
+
+        t!(s.accept_complete(&l));
+        assert_eq!(t!(s.peer_addr()), t!(c.local_addr()));
+        let addrs = t!(addrs.parse(&l));
+        assert_eq!(addrs.remote(), Some(t!(c.local_addr())));
+    }
+
+    #[test]
+    fn tcp_accept_unmap_v4() {
+        let l = match TcpBuilder::new_v6().and_then(|b| {
+            try!(b.only_v6(false));
+            try!(b.bind("[::]:0"));
+            b.listen(1)
+        }) {
+            Ok(l) => l,
+            // IPv6 isn't available.
+            Err(_) => return,
+        };
+        let port = t!(l.local_addr()).port();
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &l));
+
+        let builder = t!(TcpBuilder::new_v6());
+        let mut a = Overlapped::zero();
+        let mut addrs = AcceptAddrsBuf::new();
+        unsafe {
+            t!(l.accept_overlapped(&builder, &mut addrs, &mut a));
+        }
+        let c = t!(TcpStream::connect(("127.0.0.1", port)));
+        t!(cp.get(None));
+
+        let mut addrs = t!(addrs.parse(&l));
+        match addrs.remote() {
+            Some(SocketAddr::V6(..)) => {}
+            other => panic!("unexpected remote address {:?}", other),
+        }
+        addrs.set_unmap_v4(true);
+        assert_eq!(addrs.remote(), Some(t!(c.local_addr())));
+        assert_eq!(addrs.local(), Some(t!(c.peer_addr())));
+    }
+
+    #[test]
+    fn socket_addr_buf_unmap_v4() {
+        use net::RawSocketAddr;
+
+        let v4 = "10.1.2.3:80".parse::<SocketAddr>().unwrap();
+        let mapped = "[::ffff:10.1.2.3]:80".parse::<SocketAddr>().unwrap();
+        let raw = RawSocketAddr::from(mapped);
+        let mut buf = SocketAddrBuf::new();
+        buf.addr = raw;
+        assert_eq!(buf.to_socket_addr(), Some(mapped));
+        buf.set_unmap_v4(true);
+        assert_eq!(buf.to_socket_addr(), Some(v4));
+
+        let other = "[::1]:80".parse::<SocketAddr>().unwrap();
+        let raw = RawSocketAddr::from(other);
+        buf.reset();
+        buf.addr = raw;
+        assert_eq!(buf.to_socket_addr(), Some(other));
+    }
+
+    #[test]
+    fn listener_builder() {
+        use net::ListenerBuilder;
+
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let l = t!(ListenerBuilder::new(addr).backlog(16)
+                                             .exclusive_address_use(true)
+                                             .listen());
+        let addr = t!(l.local_addr());
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &l));
+
+        let builder = t!(TcpBuilder::new_v4());
+        let mut a = Overlapped::zero();
+        let mut addrs = AcceptAddrsBuf::new();
+        let (s, _) = unsafe {
+            t!(l.accept_overlapped(&builder, &mut addrs, &mut a))
+        };
+        let c = t!(TcpStream::connect(addr));
+        t!(cp.get(None));
+        t!(s.accept_complete(&l));
+        assert_eq!(t!(s.peer_addr()), t!(c.local_addr()));
+
+        // The address is held exclusively.
+        assert!(ListenerBuilder::new(addr).reuse_address(true)
+                                          .listen().is_err());
+    }
+
+    #[test]
+    fn tcp_accept_compact() {
+        use std::mem;
+
+        assert!(mem::size_of::<AcceptAddrsBufV4>() <
+                mem::size_of::<AcceptAddrsBufV6>());
+        assert!(mem::size_of::<AcceptAddrsBufV6>() <
+                mem::size_of::<AcceptAddrsBuf>());
+
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let addr = t!(l.local_addr());
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &l));
+        let t = thread::spawn(move || t!(TcpStream::connect(addr)));
+
+        let builder = t!(TcpBuilder::new_v4());
+        let mut a = Overlapped::zero();
+        let mut addrs = AcceptAddrsBufV4::new();
+        let (accepted, _) = unsafe {
+            t!(l.accept_overlapped(&builder, &mut addrs, &mut a))
+        };
+        t!(cp.get(None));
+        let s = t!(t.join());
+        let addrs = addrs.parse(&accepted).unwrap();
+        assert_eq!(addrs.local(), Some(addr));
+        assert_eq!(addrs.remote(), Some(t!(s.local_addr())));
+
+        let l = t!(TcpListener::bind("[::1]:0"));
+        let addr = t!(l.local_addr());
+        t!(cp.add_socket(2, &l));
+        let t = thread::spawn(move || t!(TcpStream::connect(addr)));
+
+        let builder = t!(TcpBuilder::new_v6());
+        let mut addrs = AcceptAddrsBufV6::new();
+        let (accepted, _) = unsafe {
+            t!(l.accept_overlapped(&builder, &mut addrs, &mut a))
+        };
+        t!(cp.get(None));
+        let s = t!(t.join());
+        drop(l);
+        let addrs = t!(addrs.parse(&accepted));
+        assert_eq!(addrs.local(), Some(addr));
+        assert_eq!(addrs.remote(), Some(t!(s.local_addr())));
+    }
+
+    #[test]
+    fn tcp_accept_error() {
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let mut a = Overlapped::zero();
+        let mut addrs = AcceptAddrsBuf::new();
+
+        // AcceptEx rejects a bound socket, which stays in the builder.
+        let builder = t!(TcpBuilder::new_v4());
+        t!(builder.bind("127.0.0.1:0"));
+        unsafe {
+            assert!(l.accept_overlapped(&builder, &mut addrs, &mut a).is_err());
+        }
+        t!(builder.to_tcp_stream());
+
+        // A consumed builder is an error rather than a panic.
+        unsafe {
+            assert!(l.accept_overlapped(&builder, &mut addrs, &mut a).is_err());
+        }
+    }
+
+    #[test]
+    fn socket_builder() {
+        use net::SocketBuilder;
+
+        let l = t!(SocketBuilder::new_v4());
+        t!(l.bind(&"127.0.0.1:0".parse().unwrap()));
+        let addr = t!(l.local_addr());
+        let l = t!(l.listen(1));
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &l));
+
+        let builder = t!(SocketBuilder::new_v4());
+        let mut a = Overlapped::zero();
+        let mut addrs = AcceptAddrsBuf::new();
+        let (accepted, _) = unsafe {
+            t!(l.accept_overlapped(&builder, &mut addrs, &mut a))
+        };
+        assert!(builder.local_addr().is_err());
+
+        let c = t!(SocketBuilder::new_v4());
+        t!(c.bind(&"127.0.0.1:0".parse().unwrap()));
+        let mut b = Overlapped::zero();
+        t!(cp.add_socket(2, &c));
+        let (s, _) = unsafe { t!(c.connect_overlapped(&addr, &mut b)) };
+        for _ in 0..2 {
+            t!(cp.get(None));
+        }
+        t!(accepted.accept_complete(&l));
+        t!(s.connect_complete());
+        assert_eq!(t!(accepted.peer_addr()), t!(s.local_addr()));
+    }
+
+    #[test]
+    fn extension_per_provider() {
+        use std::os::windows::prelude::*;
+        use net::{single_provider_dll, WsaExtension, WSAID_CONNECTEX};
+
+        static CONNECTEX: WsaExtension = WsaExtension::new(WSAID_CONNECTEX);
+        let v4 = t!(TcpBuilder::new_v4());
+        let v6 = t!(TcpBuilder::new_v6());
+        let a = t!(CONNECTEX.get(v4.as_raw_socket()));
+        let b = t!(CONNECTEX.get(v6.as_raw_socket()));
+        assert_eq!(t!(CONNECTEX.get(v4.as_raw_socket())), a);
+        assert_eq!(t!(CONNECTEX.get(v6.as_raw_socket())), b);
+        let cached = CONNECTEX.cache.read().unwrap().len();
+        if single_provider_dll() {
+            assert_eq!(a, b);
+            assert_eq!(cached, 0);
+        } else {
+            assert_eq!(cached, 2);
+        }
+    }
+
+    #[test]
+    fn wsabuf_layout() {
+        use std::io::IoSlice;
+        use std::mem;
+        use winapi::WSABUF;
+
+        assert_eq!(mem::size_of::<WsaBuf>(), mem::size_of::<WSABUF>());
+        assert_eq!(mem::align_of::<WsaBuf>(), mem::align_of::<WSABUF>());
+        assert_eq!(mem::size_of::<WsaBufMut>(), mem::size_of::<WSABUF>());
+        assert_eq!(mem::align_of::<WsaBufMut>(), mem::align_of::<WSABUF>());
+
+        let a = [1, 2, 3];
+        let mut b = [0; 4];
+        let bufs = [WsaBuf::new(&a), WsaBuf::from(&a[1..])];
+        let raw = WsaBuf::as_raw_slice(&bufs);
+        assert_eq!(raw[0].len, 3);
+        assert_eq!(raw[1].len, 2);
+        assert_eq!(&*bufs[1], &[2, 3]);
+
+        let slices = [IoSlice::new(&a[..1]), IoSlice::new(&a)];
+        let bufs = WsaBuf::from_io_slices(&slices);
+        assert_eq!(&*bufs[0], &[1]);
+        assert_eq!(&*bufs[1], &[1, 2, 3]);
+
+        let mut buf = WsaBufMut::new(&mut b);
+        buf[0] = 4;
+        assert_eq!(buf.as_raw().len, 4);
+        assert_eq!(buf.into_slice(), &[4, 0, 0, 0]);
+    }
+
+    #[test]
+    fn startup_cleanup() {
+        assert_eq!(t!(super::startup((2, 2))), (2, 2));
+        assert_eq!(t!(super::startup((2, 2))), (2, 2));
+        t!(super::cleanup());
+        t!(super::cleanup());
+
+        // Only references acquired through `startup` are released.
+        assert!(super::cleanup().is_err());
+    }
 }
@@ -1,6 +1,15 @@
+use std::fs::File;
+use std::io;
 use std::mem;
+use std::os::windows::io::*;
 
 use winapi::*;
+use handle::{self, Handle};
+
+#[link(name = "ntdll")]
+extern "system" {
+    fn RtlNtStatusToDosError(Status: NTSTATUS) -> ULONG;
+}
 
 /// A wrapper around `OVERLAPPED` to provide "rustic" accessors and
 /// initializers.
@@ -50,4 +59,152 @@ impl Overlapped {
     pub fn event(&self) -> HANDLE {
         self.0.hEvent
     }
+
+    // Returns the error the completed operation tracked by this instance
+    // failed with, decoded from the status code the system stores in it.
+    pub(crate) fn error(&self) -> Option<io::Error> {
+        let status = self.0.Internal as NTSTATUS;
+        if status >= 0 {
+            return None
+        }
+        let code = unsafe { RtlNtStatusToDosError(status) };
+        Some(io::Error::from_raw_os_error(code as i32))
+    }
+}
+
+/// A resource on which overlapped reads and writes can be issued.
+///
+/// This is implemented for sockets, files, and named pipes so that code such
+/// as buffer management and framing can be written once for all of them.
+pub trait OverlappedIo {
+    /// Issues an overlapped read into `buf`.
+    ///
+    /// If the operation succeeds immediately, `Ok(true)` is returned. If an
+    /// asynchronous operation is enqueued, then `Ok(false)` is returned.
+    /// Otherwise if an error occurred it is returned.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that the `buf` and
+    /// `overlapped` pointers are valid until the end of the I/O operation. The
+    /// kernel also requires that `overlapped` is unique for this I/O operation
+    /// and is not in use for any other I/O.
+    ///
+    /// To safely use this function callers must ensure that the pointers are
+    /// valid until the I/O operation is completed, typically via completion
+    /// ports and waiting to receive the completion notification on the port.
+    unsafe fn start_read(&self, buf: &mut [u8],
+                         overlapped: &mut Overlapped) -> io::Result<bool>;
+
+    /// Issues an overlapped write of `buf`.
+    ///
+    /// The return value and unsafety are the same as for `start_read`.
+    unsafe fn start_write(&self, buf: &[u8],
+                          overlapped: &mut Overlapped) -> io::Result<bool>;
+
+    /// Requests cancellation of the outstanding operation tracked by
+    /// `overlapped`.
+    ///
+    /// The operation still completes through the usual mechanism, typically
+    /// with `ERROR_OPERATION_ABORTED`, unless it finished before it could be
+    /// cancelled. An error with `ERROR_NOT_FOUND` is returned if no such
+    /// operation is outstanding.
+    fn cancel(&self, overlapped: &Overlapped) -> io::Result<()>;
+}
+
+impl OverlappedIo for File {
+    unsafe fn start_read(&self, buf: &mut [u8],
+                         overlapped: &mut Overlapped)
+                         -> io::Result<bool> {
+        handle::read_overlapped(self.as_raw_handle(), buf, overlapped.raw())
+    }
+
+    unsafe fn start_write(&self, buf: &[u8],
+                          overlapped: &mut Overlapped)
+                          -> io::Result<bool> {
+        handle::write_overlapped(self.as_raw_handle(), buf, overlapped.raw())
+    }
+
+    fn cancel(&self, overlapped: &Overlapped) -> io::Result<()> {
+        handle::cancel(self.as_raw_handle(), overlapped.raw())
+    }
+}
+
+impl OverlappedIo for Handle {
+    unsafe fn start_read(&self, buf: &mut [u8],
+                         overlapped: &mut Overlapped)
+                         -> io::Result<bool> {
+        self.read_overlapped(buf, overlapped.raw())
+    }
+
+    unsafe fn start_write(&self, buf: &[u8],
+                          overlapped: &mut Overlapped)
+                          -> io::Result<bool> {
+        self.write_overlapped(buf, overlapped.raw())
+    }
+
+    fn cancel(&self, overlapped: &Overlapped) -> io::Result<()> {
+        handle::cancel(self.raw(), overlapped.raw())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::windows::fs::OpenOptionsExt;
+
+    use rand::{thread_rng, Rng};
+    use winapi::*;
+
+    use iocp::CompletionPort;
+    use super::{Overlapped, OverlappedIo};
+
+    unsafe fn round_trip<T: OverlappedIo>(t: &T, cp: &CompletionPort) {
+        let mut over = Overlapped::zero();
+        t!(t.start_write(&[1, 2, 3], &mut over));
+        assert_eq!(t!(cp.get(None)).bytes_transferred(), 3);
+    }
+
+    #[test]
+    fn file() {
+        let name = thread_rng().gen_ascii_chars().take(10).collect::<String>();
+        let path = env::temp_dir().join(format!("miow-{}", name));
+        let f = t!(OpenOptions::new().read(true).write(true).create(true)
+                                     .custom_flags(FILE_FLAG_OVERLAPPED)
+                                     .open(&path));
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_handle(1, &f));
+        unsafe { round_trip(&f, &cp) };
+
+        let mut buf = [0; 3];
+        let mut over = Overlapped::zero();
+        unsafe {
+            t!(f.start_read(&mut buf, &mut over));
+        }
+        assert_eq!(t!(cp.get(None)).bytes_transferred(), 3);
+        assert_eq!(buf, [1, 2, 3]);
+        drop(f);
+        t!(fs::remove_file(&path));
+    }
+
+    #[test]
+    fn cancel() {
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let a = t!(TcpStream::connect(t!(l.local_addr())));
+        let _b = t!(l.accept());
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &a));
+        unsafe { round_trip(&a, &cp) };
+
+        let mut buf = [0; 3];
+        let mut over = Overlapped::zero();
+        unsafe {
+            assert!(!t!(a.start_read(&mut buf, &mut over)));
+        }
+        t!(a.cancel(&over));
+        let err = cp.get(None).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(ERROR_OPERATION_ABORTED as i32));
+    }
 }
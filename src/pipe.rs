@@ -4,13 +4,15 @@ use std::ffi::OsStr;
 use std::fs::{OpenOptions, File};
 use std::io::prelude::*;
 use std::io;
+use std::mem::MaybeUninit;
 use std::os::windows::ffi::*;
 use std::os::windows::io::*;
 
 use winapi::*;
 use kernel32::*;
+use fault::Operation;
 use handle::Handle;
-use Overlapped;
+use {Overlapped, OverlappedIo};
 
 /// Readable half of an anonymous pipe.
 #[derive(Debug)]
@@ -199,13 +201,24 @@ impl NamedPipe {
     /// ports and waiting to receive the completion notification on the port.
     pub unsafe fn connect_overlapped(&self, overlapped: &mut Overlapped)
                                      -> io::Result<bool> {
-        match ::cvt(ConnectNamedPipe(self.0.raw(), overlapped.raw())) {
-            Ok(_) => Ok(true),
+        let handle = self.0.raw();
+        let overlapped = overlapped.raw();
+        // A client which had already connected doesn't post a completion,
+        // so that case is kept as an error until past the fault injection
+        // layer, which mustn't report it as pending.
+        let connect = |_| {
+            match ::cvt(ConnectNamedPipe(handle, overlapped)) {
+                Ok(_) => Ok(true),
+                Err(ref e) if e.raw_os_error() == Some(ERROR_IO_PENDING as i32)
+                    => Ok(false),
+                Err(e) => Err(e),
+            }
+        };
+        match ::fault::submit(Operation::Connect, 0, handle, overlapped,
+                              connect) {
             Err(ref e) if e.raw_os_error() == Some(ERROR_PIPE_CONNECTED as i32)
                 => Ok(true),
-            Err(ref e) if e.raw_os_error() == Some(ERROR_IO_PENDING as i32)
-                => Ok(false),
-            Err(e) => Err(e),
+            res => res,
         }
     }
 
@@ -247,6 +260,64 @@ impl NamedPipe {
         self.0.read_overlapped(buf, overlapped.raw())
     }
 
+    /// Issues an overlapped read operation on this pipe into a buffer which
+    /// may be uninitialized.
+    ///
+    /// This is like `read_overlapped`, but saves zeroing a large buffer
+    /// beforehand. Once the operation has completed, the number of bytes it
+    /// reports at the start of `buf` have been initialized.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as `read_overlapped`.
+    pub unsafe fn read_overlapped_uninit(&self, buf: &mut [MaybeUninit<u8>],
+                                         overlapped: &mut Overlapped)
+                                         -> io::Result<bool> {
+        self.0.read_overlapped_uninit(buf, overlapped.raw())
+    }
+
+    /// Issues an overlapped zero-byte read on this pipe.
+    ///
+    /// The read does not consume any data. Instead it completes, with zero
+    /// bytes transferred, once data is available to be read from the pipe or
+    /// the pipe has been closed by the client. A regular read can then be
+    /// issued, sized with `bytes_available` if desired, which will typically
+    /// complete immediately.
+    ///
+    /// This allows a server to wait for many idle clients without dedicating
+    /// a read buffer to each of them, mirroring the zero-byte receive idiom
+    /// used with sockets.
+    ///
+    /// If the operation succeeds immediately, `Ok(true)` is returned. If an
+    /// asynchronous operation is enqueued, then `Ok(false)` is returned.
+    /// Otherwise if an error occurred it is returned.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that the
+    /// `overlapped` pointer is valid until the end of the I/O operation. The
+    /// kernel also requires that `overlapped` is unique for this I/O operation
+    /// and is not in use for any other I/O.
+    ///
+    /// To safely use this function callers must ensure that this pointer is
+    /// valid until the I/O operation is completed, typically via completion
+    /// ports and waiting to receive the completion notification on the port.
+    pub unsafe fn read_zero_overlapped(&self, overlapped: &mut Overlapped)
+                                       -> io::Result<bool> {
+        self.0.read_overlapped(&mut [], overlapped.raw())
+    }
+
+    /// Returns the number of bytes which can be read from this pipe without
+    /// blocking.
+    pub fn bytes_available(&self) -> io::Result<u32> {
+        let mut avail = 0;
+        try!(::cvt(unsafe {
+            PeekNamedPipe(self.0.raw(), 0 as *mut _, 0, 0 as *mut _,
+                          &mut avail, 0 as *mut _)
+        }));
+        Ok(avail)
+    }
+
     /// Issues an overlapped write operation to occur on this pipe.
     ///
     /// This function will issue an asynchronous write to occur in an overlapped
@@ -279,6 +350,24 @@ impl NamedPipe {
     }
 }
 
+impl OverlappedIo for NamedPipe {
+    unsafe fn start_read(&self, buf: &mut [u8],
+                         overlapped: &mut Overlapped)
+                         -> io::Result<bool> {
+        self.0.start_read(buf, overlapped)
+    }
+
+    unsafe fn start_write(&self, buf: &[u8],
+                          overlapped: &mut Overlapped)
+                          -> io::Result<bool> {
+        self.0.start_write(buf, overlapped)
+    }
+
+    fn cancel(&self, overlapped: &Overlapped) -> io::Result<()> {
+        self.0.cancel(overlapped)
+    }
+}
+
 impl Read for NamedPipe {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.0.read(buf) }
 }
@@ -519,6 +608,35 @@ mod tests {
         t!(t.join());
     }
 
+    #[test]
+    fn named_read_zero_overlapped() {
+        let name = name();
+        let a = t!(NamedPipe::new(&name));
+
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_handle(3, &a));
+        let t = thread::spawn(move || {
+            let mut f = t!(File::create(name));
+            thread::sleep(::std::time::Duration::from_millis(10));
+            t!(f.write_all(&[1, 2, 3]));
+            f
+        });
+        t!(a.connect());
+
+        let mut over = Overlapped::zero();
+        unsafe {
+            t!(a.read_zero_overlapped(&mut over));
+        }
+        let status = t!(cp.get(None));
+        assert_eq!(status.bytes_transferred(), 0);
+        assert_eq!(status.overlapped(), &mut over as *mut _);
+        assert_eq!(t!(a.bytes_available()), 3);
+
+        let mut b = [0; 10];
+        assert_eq!(t!((&a).read(&mut b)), 3);
+        drop(t!(t.join()));
+    }
+
     #[test]
     fn named_read_overlapped() {
         let name = name();
@@ -547,6 +665,35 @@ mod tests {
         t!(t.join());
     }
 
+    #[test]
+    fn named_read_overlapped_uninit() {
+        use std::mem::MaybeUninit;
+
+        let name = name();
+        let a = t!(NamedPipe::new(&name));
+
+        let t = thread::spawn(move || {
+            let mut f = t!(File::create(name));
+            t!(f.write_all(&[1, 2, 3]));
+        });
+
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_handle(3, &a));
+        t!(a.connect());
+
+        let mut b = [MaybeUninit::<u8>::uninit(); 10];
+        let mut over = Overlapped::zero();
+        unsafe {
+            t!(a.read_overlapped_uninit(&mut b, &mut over));
+        }
+        assert_eq!(t!(cp.get(None)).bytes_transferred(), 3);
+        let b = b[..3].iter().map(|b| unsafe { b.assume_init() })
+                      .collect::<Vec<_>>();
+        assert_eq!(b, [1, 2, 3]);
+
+        t!(t.join());
+    }
+
     #[test]
     fn named_write_overlapped() {
         let name = name();
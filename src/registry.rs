@@ -0,0 +1,190 @@
+//! Registry change notifications
+//!
+//! This module allows watching a registry key for changes with the
+//! notifications delivered through an I/O completion port, rather than
+//! dedicating a thread to block on each key being watched.
+
+use std::ffi::OsStr;
+use std::io;
+use std::os::windows::ffi::*;
+
+use advapi32::*;
+use winapi::*;
+use kernel32::*;
+use handle::Handle;
+use iocp::{CompletionPort, CompletionStatus};
+use wait::WaitRegistration;
+
+/// A registry key which is being watched for changes.
+///
+/// Each time the key changes a completion status is posted to the completion
+/// port the watcher was created with. The status carries the token provided
+/// at creation, zero bytes transferred, and a null overlapped pointer.
+///
+/// Like `RegNotifyChangeKeyValue` itself, a watcher only reports the first
+/// change after it has been armed. After receiving a notification, call
+/// `rearm` to be notified of the next change.
+#[derive(Debug)]
+pub struct KeyWatcher {
+    // NB: the order of these fields is significant, the wait must be
+    // unregistered before the key is closed as closing the key signals the
+    // event.
+    _wait: WaitRegistration,
+    key: Key,
+    event: Handle,
+    subtree: bool,
+    filter: DWORD,
+}
+
+/// A predefined registry key under which watched keys are opened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RootKey {
+    /// `HKEY_CLASSES_ROOT`
+    ClassesRoot,
+    /// `HKEY_CURRENT_USER`
+    CurrentUser,
+    /// `HKEY_LOCAL_MACHINE`
+    LocalMachine,
+    /// `HKEY_USERS`
+    Users,
+    /// `HKEY_CURRENT_CONFIG`
+    CurrentConfig,
+}
+
+impl RootKey {
+    fn raw(&self) -> HKEY {
+        match *self {
+            RootKey::ClassesRoot => HKEY_CLASSES_ROOT,
+            RootKey::CurrentUser => HKEY_CURRENT_USER,
+            RootKey::LocalMachine => HKEY_LOCAL_MACHINE,
+            RootKey::Users => HKEY_USERS,
+            RootKey::CurrentConfig => HKEY_CURRENT_CONFIG,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Key(HKEY);
+
+unsafe impl Send for Key {}
+unsafe impl Sync for Key {}
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        unsafe { RegCloseKey(self.0) };
+    }
+}
+
+const REG_NOTIFY_THREAD_AGNOSTIC: DWORD = 0x10000000;
+
+impl KeyWatcher {
+    /// Opens the key `subkey` under `root` and starts watching it for
+    /// changes.
+    ///
+    /// The `filter` is a combination of the `REG_NOTIFY_CHANGE_*` flags
+    /// indicating which changes should be reported, and `subtree` indicates
+    /// whether changes to subkeys of this key should be reported as well.
+    ///
+    /// Notifications will be posted to `port` with the specified `token`.
+    pub fn new<A: AsRef<OsStr>>(root: RootKey,
+                                subkey: A,
+                                subtree: bool,
+                                filter: DWORD,
+                                port: &CompletionPort,
+                                token: usize) -> io::Result<KeyWatcher> {
+        let subkey = subkey.as_ref().encode_wide().chain(Some(0))
+                           .collect::<Vec<_>>();
+        let mut key = 0 as HKEY;
+        let r = unsafe {
+            RegOpenKeyExW(root.raw(), subkey.as_ptr(), 0, KEY_NOTIFY, &mut key)
+        };
+        if r != ERROR_SUCCESS as LONG {
+            return Err(io::Error::from_raw_os_error(r))
+        }
+        let key = Key(key);
+
+        let event = unsafe {
+            CreateEventW(0 as *mut _, FALSE, FALSE, 0 as *const _)
+        };
+        if event.is_null() {
+            return Err(io::Error::last_os_error())
+        }
+        let event = Handle::new(event);
+
+        let status = CompletionStatus::new(0, token, 0 as *mut _);
+        let wait = try!(WaitRegistration::new(port, event.raw(), status,
+                                              false));
+        let watcher = KeyWatcher {
+            _wait: wait,
+            key: key,
+            event: event,
+            subtree: subtree,
+            filter: filter,
+        };
+        try!(watcher.rearm());
+        Ok(watcher)
+    }
+
+    /// Requests notification of the next change to this key.
+    ///
+    /// This must be called after each notification is received in order to
+    /// continue receiving notifications.
+    pub fn rearm(&self) -> io::Result<()> {
+        let r = unsafe {
+            RegNotifyChangeKeyValue(self.key.0, self.subtree as BOOL,
+                                    self.filter | REG_NOTIFY_THREAD_AGNOSTIC,
+                                    self.event.raw(), TRUE)
+        };
+        if r == ERROR_SUCCESS as LONG {
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(r))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use advapi32::*;
+    use winapi::*;
+
+    use iocp::CompletionPort;
+    use super::{KeyWatcher, RootKey};
+
+    #[test]
+    fn notify() {
+        let name = "Software\\miow-registry-test";
+        let wide = name.encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+        let mut key = 0 as HKEY;
+        unsafe {
+            assert_eq!(RegCreateKeyExW(HKEY_CURRENT_USER, wide.as_ptr(), 0,
+                                       ptr::null_mut(), 0, KEY_ALL_ACCESS,
+                                       ptr::null_mut(), &mut key,
+                                       ptr::null_mut()), 0);
+        }
+
+        let cp = t!(CompletionPort::new(1));
+        let watcher = t!(KeyWatcher::new(RootKey::CurrentUser, name, false,
+                                         REG_NOTIFY_CHANGE_LAST_SET, &cp, 5));
+        assert!(cp.get(Some(1)).is_err());
+
+        let value = "foo".encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+        let data = [1u8, 0, 0, 0];
+        unsafe {
+            assert_eq!(RegSetValueExW(key, value.as_ptr(), 0, REG_DWORD,
+                                      data.as_ptr(), 4), 0);
+        }
+        let status = t!(cp.get(Some(1000)));
+        assert_eq!(status.token(), 5);
+        assert_eq!(status.overlapped(), 0 as *mut _);
+        t!(watcher.rearm());
+
+        drop(watcher);
+        unsafe {
+            RegCloseKey(key);
+            RegDeleteKeyW(HKEY_CURRENT_USER, wide.as_ptr());
+        }
+    }
+}
@@ -0,0 +1,385 @@
+//! Relaying data between two streams
+//!
+//! Proxies and tunnels spend most of their time copying the data read from
+//! one connection to another, in both directions at once. A `Relay` drives
+//! that copying with overlapped I/O, keeping reads of the source outstanding
+//! while earlier data is still being written to the destination so neither
+//! side waits on the other.
+
+use std::fmt;
+use std::io;
+
+use iocp::CompletionStatus;
+use {Overlapped, OverlappedIo};
+
+/// Copies data between two streams in both directions with overlapped I/O.
+///
+/// Each direction has a number of buffers, set by the depth given to `new`.
+/// Reads into idle buffers are kept outstanding on the source while the
+/// buffers already filled are written to the destination one at a time, in
+/// the order their data was read.
+///
+/// Both streams must be associated with a completion port. The copying is
+/// started with `start`, after which each completion notification for either
+/// stream is passed to `complete`, which issues the next operations. The
+/// notifications must be dequeued with `get_many`, as `get` returns an error
+/// for a failed operation without its status, which would leave the relay
+/// waiting for it forever. Completions which are skipped when operations
+/// succeed immediately, as set up by
+/// `SocketExt::set_skip_completion_port_on_success`, aren't supported.
+///
+/// A direction finishes once its source reaches the end of its data, at
+/// which point the caller may want to shut down the destination for writing,
+/// or once one of its operations fails.
+pub struct Relay<A, B> {
+    a: A,
+    b: B,
+    a_to_b: Direction,
+    b_to_a: Direction,
+}
+
+struct Direction {
+    // Boxed so the state of each operation stays in place while the relay
+    // is moved.
+    slots: Box<[Slot]>,
+    // The number of reads issued and of buffers fully written so far, which
+    // index the slots to use next modulo their number.
+    read_seq: usize,
+    write_seq: usize,
+    eof: bool,
+    // Set once no further operations are issued, after which those still
+    // outstanding are cancelled and their completions absorbed.
+    done: bool,
+    cancelled: bool,
+    transferred: u64,
+}
+
+struct Slot {
+    overlapped: Overlapped,
+    buf: Box<[u8]>,
+    state: State,
+}
+
+// `Full` and `Writing` hold the range of the buffer which hasn't been written
+// yet, where an empty buffer read marks the end of the source's data.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    Reading,
+    Full(usize, usize),
+    Writing(usize, usize),
+}
+
+impl<A: OverlappedIo, B: OverlappedIo> Relay<A, B> {
+    /// Creates a new relay between `a` and `b`, using `depth` buffers of
+    /// `buf_size` bytes for each direction.
+    ///
+    /// A depth of 2 keeps one read outstanding while the previous data is
+    /// written, and larger depths let reads run further ahead of slow
+    /// writes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `depth` or `buf_size` is 0.
+    pub fn new(a: A, b: B, depth: usize, buf_size: usize) -> Relay<A, B> {
+        assert!(depth > 0, "relay depth must be non-zero");
+        assert!(buf_size > 0, "relay buffer size must be non-zero");
+        Relay {
+            a: a,
+            b: b,
+            a_to_b: Direction::new(depth, buf_size),
+            b_to_a: Direction::new(depth, buf_size),
+        }
+    }
+
+    /// Returns references to the two streams of this relay.
+    pub fn get_ref(&self) -> (&A, &B) {
+        (&self.a, &self.b)
+    }
+
+    /// Consumes this relay, returning its two streams.
+    ///
+    /// This should only be done once no operations are pending.
+    pub fn into_inner(self) -> (A, B) {
+        (self.a, self.b)
+    }
+
+    /// Returns the number of bytes written from `a` to `b`, and from `b` to
+    /// `a`, so far.
+    pub fn transferred(&self) -> (u64, u64) {
+        (self.a_to_b.transferred, self.b_to_a.transferred)
+    }
+
+    /// Returns whether the copying from `a` to `b`, and from `b` to `a`, has
+    /// finished, either because the source reached the end of its data and
+    /// all of it has been written, or because an operation failed.
+    ///
+    /// A direction is only finished once none of its operations is pending
+    /// anymore.
+    pub fn finished(&self) -> (bool, bool) {
+        (self.a_to_b.finished(), self.b_to_a.finished())
+    }
+
+    /// Returns whether the copying in both directions has finished.
+    ///
+    /// Once this returns `true` no operations are pending, so the relay may
+    /// be dropped.
+    pub fn is_finished(&self) -> bool {
+        self.a_to_b.finished() && self.b_to_a.finished()
+    }
+
+    /// Returns the number of operations of this relay which haven't
+    /// completed yet.
+    pub fn pending(&self) -> usize {
+        self.a_to_b.pending() + self.b_to_a.pending()
+    }
+
+    /// Starts copying in both directions.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that this relay
+    /// is valid until the end of the operations it issues, so it must not be
+    /// dropped until the completion of each pending operation has been
+    /// passed to `complete`.
+    pub unsafe fn start(&mut self) -> io::Result<()> {
+        try!(self.a_to_b.advance(Ok(()), &self.a, &self.b));
+        self.b_to_a.advance(Ok(()), &self.b, &self.a)
+    }
+
+    /// Records the completion of an operation issued by this relay and
+    /// issues the operations which can follow it.
+    ///
+    /// `None` is returned if `status` isn't the completion of one of this
+    /// relay's operations. If the operation failed, or issuing the next ones
+    /// did, its direction finishes and the error is returned. The operations
+    /// of a direction which are still pending when it finishes are
+    /// cancelled, and their completions are absorbed.
+    ///
+    /// The status must have been dequeued with `get_many`, as described in
+    /// the documentation of `Relay`.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as `start`.
+    pub unsafe fn complete(&mut self, status: &CompletionStatus)
+                           -> Option<io::Result<()>> {
+        let overlapped = status.overlapped() as *const Overlapped;
+        let bytes = status.bytes_transferred() as usize;
+        if let Some(r) = self.a_to_b.complete(overlapped, bytes) {
+            return Some(self.a_to_b.advance(r, &self.a, &self.b))
+        }
+        if let Some(r) = self.b_to_a.complete(overlapped, bytes) {
+            return Some(self.b_to_a.advance(r, &self.b, &self.a))
+        }
+        None
+    }
+}
+
+impl Direction {
+    fn new(depth: usize, buf_size: usize) -> Direction {
+        let slots = (0..depth).map(|_| {
+            Slot {
+                overlapped: Overlapped::zero(),
+                buf: vec![0; buf_size].into_boxed_slice(),
+                state: State::Idle,
+            }
+        }).collect::<Vec<_>>();
+        Direction {
+            slots: slots.into_boxed_slice(),
+            read_seq: 0,
+            write_seq: 0,
+            eof: false,
+            done: false,
+            cancelled: false,
+            transferred: 0,
+        }
+    }
+
+    fn finished(&self) -> bool {
+        self.done && self.pending() == 0
+    }
+
+    fn pending(&self) -> usize {
+        self.slots.iter().filter(|s| {
+            match s.state {
+                State::Reading | State::Writing(..) => true,
+                State::Idle | State::Full(..) => false,
+            }
+        }).count()
+    }
+
+    fn complete(&mut self, overlapped: *const Overlapped, bytes: usize)
+                -> Option<io::Result<()>> {
+        let slot = match self.slots.iter_mut().find(|s| {
+            &s.overlapped as *const Overlapped == overlapped
+        }) {
+            Some(slot) => slot,
+            None => return None,
+        };
+        let state = slot.state;
+        match state {
+            State::Reading | State::Writing(..) => slot.state = State::Idle,
+            State::Idle | State::Full(..) => return None,
+        }
+        if let Some(e) = slot.overlapped.error() {
+            if self.done {
+                return Some(Ok(()))
+            }
+            self.done = true;
+            return Some(Err(e))
+        }
+        match state {
+            State::Reading => {
+                if bytes == 0 {
+                    self.eof = true;
+                }
+                slot.state = State::Full(0, bytes);
+            }
+            State::Writing(pos, len) => {
+                self.transferred += bytes as u64;
+                if pos + bytes < len {
+                    // The rest is written before any later buffer.
+                    slot.state = State::Full(pos + bytes, len);
+                } else {
+                    self.write_seq += 1;
+                }
+            }
+            State::Idle | State::Full(..) => {}
+        }
+        Some(Ok(()))
+    }
+
+    // Issues the operations which can follow a completion with the result
+    // `r`, and once this direction is done cancels those still pending.
+    unsafe fn advance<R, W>(&mut self, r: io::Result<()>, src: &R, dst: &W)
+                            -> io::Result<()>
+        where R: OverlappedIo, W: OverlappedIo
+    {
+        let r = r.and_then(|()| self.pump(src, dst));
+        if self.done && !self.cancelled {
+            self.cancelled = true;
+            for slot in self.slots.iter() {
+                let _ = match slot.state {
+                    State::Reading => src.cancel(&slot.overlapped),
+                    State::Writing(..) => dst.cancel(&slot.overlapped),
+                    State::Idle | State::Full(..) => continue,
+                };
+            }
+        }
+        r
+    }
+
+    unsafe fn pump<R, W>(&mut self, src: &R, dst: &W) -> io::Result<()>
+        where R: OverlappedIo, W: OverlappedIo
+    {
+        if self.done {
+            return Ok(())
+        }
+        let n = self.slots.len();
+        // Buffers are written one at a time, so that a short write can be
+        // finished before the data after it is written.
+        let slot = &mut self.slots[self.write_seq % n];
+        match slot.state {
+            State::Full(_, 0) => {
+                slot.state = State::Idle;
+                self.done = true;
+                return Ok(())
+            }
+            State::Full(pos, len) => {
+                slot.overlapped = Overlapped::zero();
+                slot.state = State::Writing(pos, len);
+                if let Err(e) = dst.start_write(&slot.buf[pos..len],
+                                                &mut slot.overlapped) {
+                    slot.state = State::Idle;
+                    self.done = true;
+                    return Err(e)
+                }
+            }
+            State::Idle | State::Reading | State::Writing(..) => {}
+        }
+        while !self.eof {
+            let slot = &mut self.slots[self.read_seq % n];
+            if slot.state != State::Idle {
+                break
+            }
+            slot.overlapped = Overlapped::zero();
+            slot.state = State::Reading;
+            self.read_seq += 1;
+            if let Err(e) = src.start_read(&mut slot.buf,
+                                           &mut slot.overlapped) {
+                slot.state = State::Idle;
+                self.done = true;
+                return Err(e)
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<A: fmt::Debug, B: fmt::Debug> fmt::Debug for Relay<A, B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Relay")
+         .field("a", &self.a)
+         .field("b", &self.b)
+         .field("a_to_b", &self.a_to_b.transferred)
+         .field("b_to_a", &self.b_to_a.transferred)
+         .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::net::Shutdown;
+    use std::thread;
+
+    use iocp::{CompletionPort, CompletionStatus};
+    use super::Relay;
+
+    #[test]
+    fn relays() {
+        let (mut client, a) = ::tcp_pair();
+        let (b, mut server) = ::tcp_pair();
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &a));
+        t!(cp.add_socket(2, &b));
+
+        let data = (0..100_000).map(|i| i as u8).collect::<Vec<_>>();
+        let expected = data.clone();
+        let c = thread::spawn(move || {
+            t!(client.write_all(&data));
+            t!(client.shutdown(Shutdown::Write));
+            let mut buf = Vec::new();
+            t!(client.read_to_end(&mut buf));
+            assert_eq!(buf, [1, 2, 3]);
+        });
+        let s = thread::spawn(move || {
+            t!(server.write_all(&[1, 2, 3]));
+            t!(server.shutdown(Shutdown::Write));
+            let mut buf = Vec::new();
+            t!(server.read_to_end(&mut buf));
+            assert!(buf == expected);
+        });
+
+        let mut relay = Relay::new(a, b, 2, 4096);
+        let mut list = [CompletionStatus::zero(); 4];
+        unsafe {
+            t!(relay.start());
+            while !relay.is_finished() {
+                for status in t!(cp.get_many(&mut list, None)).iter() {
+                    t!(relay.complete(status).unwrap());
+                }
+            }
+        }
+        assert_eq!(relay.pending(), 0);
+        assert_eq!(relay.transferred(), (100_000, 3));
+        let other = CompletionStatus::new(0, 1, 0 as *mut _);
+        assert!(unsafe { relay.complete(&other) }.is_none());
+
+        // Closing the streams ends the data seen by both peers.
+        drop(relay.into_inner());
+        t!(c.join());
+        t!(s.join());
+    }
+}
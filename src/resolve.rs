@@ -0,0 +1,214 @@
+//! Asynchronous name resolution
+//!
+//! Resolving a host name through `ToSocketAddrs` blocks the calling thread
+//! for as long as the lookup takes, so servers built on completion ports
+//! usually keep a pool of threads around just to resolve names. A
+//! `Resolution` instead runs the lookup in the background through
+//! `GetAddrInfoExW` and posts a completion status to a port when it finishes.
+
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::os::windows::io::*;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use winapi::*;
+use ws2_32::*;
+
+use handle::Handle;
+use iocp::{CompletionPort, CompletionStatus};
+use net;
+
+const NS_ALL: DWORD = 0;
+
+/// An asynchronous resolution of a host name into socket addresses (via
+/// `GetAddrInfoExW`).
+///
+/// The lookup starts when the resolution is created and posts the given
+/// completion status to a port once it finishes, after which the addresses
+/// are available through `result`. The status is posted however the lookup
+/// ends, even if the resolution has been dropped by then.
+#[derive(Debug)]
+pub struct Resolution {
+    inner: *mut Inner,
+}
+
+unsafe impl Send for Resolution {}
+unsafe impl Sync for Resolution {}
+
+// State shared between a `Resolution` and the completion routine of its
+// lookup, freed by whichever of the two releases it last. The `OVERLAPPED`
+// comes first as it's all the completion routine is given.
+#[repr(C)]
+struct Inner {
+    overlapped: OVERLAPPED,
+    results: PADDRINFOEXW,
+    handle: HANDLE,
+    error: DWORD,
+    done: AtomicBool,
+    refs: AtomicUsize,
+    port: CompletionPort,
+    status: CompletionStatus,
+    service: u16,
+}
+
+impl Resolution {
+    /// Starts resolving `host`, posting `status` to `port` once the lookup
+    /// finishes.
+    ///
+    /// The addresses returned by `result` all have the port number
+    /// `service`.
+    pub fn new(host: &str, service: u16, port: &CompletionPort,
+               status: CompletionStatus) -> io::Result<Resolution> {
+        net::init();
+        // The lookup may outlive the `CompletionPort` it was started with, so
+        // hold on to our own reference to the port.
+        let port = try!(Handle::duplicate(port.as_raw_handle()));
+        let inner = Box::into_raw(Box::new(Inner {
+            overlapped: unsafe { mem::zeroed() },
+            results: 0 as *mut _,
+            handle: 0 as HANDLE,
+            error: 0,
+            done: AtomicBool::new(false),
+            refs: AtomicUsize::new(2),
+            port: unsafe { CompletionPort::from_raw_handle(port.into_raw()) },
+            status: status,
+            service: service,
+        }));
+        let host = host.encode_utf16().chain(Some(0)).collect::<Vec<u16>>();
+        unsafe {
+            // Only stream addresses are asked for, as otherwise each address
+            // is returned once per socket type.
+            let mut hints: ADDRINFOEXW = mem::zeroed();
+            hints.ai_family = AF_UNSPEC;
+            hints.ai_socktype = SOCK_STREAM;
+            let r = GetAddrInfoExW(host.as_ptr(), 0 as *const _, NS_ALL,
+                                   0 as *mut _, &hints, &mut (*inner).results,
+                                   0 as *mut _, &mut (*inner).overlapped,
+                                   Some(callback), &mut (*inner).handle);
+            match r as DWORD {
+                WSA_IO_PENDING => {}
+                // The completion routine isn't called for a lookup which
+                // finished immediately.
+                0 => complete(inner, 0),
+                e => {
+                    drop(Box::from_raw(inner));
+                    return Err(io::Error::from_raw_os_error(e as i32))
+                }
+            }
+        }
+        Ok(Resolution { inner: inner })
+    }
+
+    /// Cancels the lookup if it hasn't finished yet (via
+    /// `GetAddrInfoExCancel`).
+    ///
+    /// The status is still posted once the lookup has been cancelled, after
+    /// which `result` returns an error with the code `WSA_E_CANCELLED`.
+    /// Nothing happens if the lookup has already finished.
+    pub fn cancel(&self) -> io::Result<()> {
+        let inner = unsafe { &*self.inner };
+        if inner.done.load(Ordering::Acquire) {
+            return Ok(())
+        }
+        let handle = &inner.handle as *const HANDLE as *mut HANDLE;
+        match unsafe { GetAddrInfoExCancel(handle) } as DWORD {
+            0 => Ok(()),
+            // The lookup finished while it was being cancelled.
+            WSA_INVALID_HANDLE => Ok(()),
+            e => Err(io::Error::from_raw_os_error(e as i32)),
+        }
+    }
+
+    /// Returns the addresses the host name resolved to.
+    ///
+    /// If the lookup hasn't finished yet an error with the code
+    /// `WSA_IO_INCOMPLETE` is returned, and if it failed its error is
+    /// returned.
+    pub fn result(&self) -> io::Result<Vec<SocketAddr>> {
+        let inner = unsafe { &*self.inner };
+        if !inner.done.load(Ordering::Acquire) {
+            return Err(io::Error::from_raw_os_error(WSA_IO_INCOMPLETE as i32))
+        }
+        if inner.error != 0 {
+            return Err(io::Error::from_raw_os_error(inner.error as i32))
+        }
+        let mut addrs = Vec::new();
+        let mut info = inner.results;
+        while !info.is_null() {
+            let addr = unsafe {
+                let len = (*info).ai_addrlen as c_int;
+                let addr = net::ptrs_to_socket_addr((*info).ai_addr, len);
+                info = (*info).ai_next;
+                addr
+            };
+            if let Some(mut addr) = addr {
+                addr.set_port(inner.service);
+                addrs.push(addr);
+            }
+        }
+        Ok(addrs)
+    }
+}
+
+unsafe extern "system" fn callback(error: DWORD, _bytes: DWORD,
+                                   overlapped: LPWSAOVERLAPPED) {
+    complete(overlapped as *mut Inner, error)
+}
+
+unsafe fn complete(inner: *mut Inner, error: DWORD) {
+    (*inner).error = error;
+    (*inner).done.store(true, Ordering::Release);
+    let _ = (*inner).port.post((*inner).status);
+    release(inner);
+}
+
+unsafe fn release(inner: *mut Inner) {
+    if (*inner).refs.fetch_sub(1, Ordering::AcqRel) != 1 {
+        return
+    }
+    let inner = Box::from_raw(inner);
+    if !inner.results.is_null() {
+        FreeAddrInfoExW(inner.results);
+    }
+}
+
+impl Drop for Resolution {
+    fn drop(&mut self) {
+        unsafe { release(self.inner) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use winapi::*;
+
+    use iocp::{CompletionPort, CompletionStatus};
+    use super::Resolution;
+
+    #[test]
+    fn localhost() {
+        let cp = t!(CompletionPort::new(1));
+        let status = CompletionStatus::new(0, 7, 0 as *mut _);
+        let r = t!(Resolution::new("localhost", 80, &cp, status));
+        assert_eq!(t!(cp.get(Some(10_000))).token(), 7);
+        let addrs = t!(r.result());
+        let v4 = "127.0.0.1:80".parse::<SocketAddr>().unwrap();
+        let v6 = "[::1]:80".parse::<SocketAddr>().unwrap();
+        assert!(addrs.contains(&v4) || addrs.contains(&v6), "{:?}", addrs);
+        t!(r.cancel());
+    }
+
+    #[test]
+    fn cancel() {
+        let cp = t!(CompletionPort::new(1));
+        let status = CompletionStatus::new(0, 1, 0 as *mut _);
+        let r = t!(Resolution::new("cancel.invalid", 80, &cp, status));
+        t!(r.cancel());
+        assert_eq!(t!(cp.get(Some(10_000))).token(), 1);
+        let e = r.result().unwrap_err();
+        assert!(e.raw_os_error() != Some(WSA_IO_INCOMPLETE as i32));
+    }
+}
@@ -0,0 +1,739 @@
+//! Registered I/O
+//!
+//! Registered I/O (RIO) is an extension to Winsock which lowers the overhead
+//! of each network operation. Buffers are registered with the kernel once up
+//! front instead of being locked for each operation, and operations are
+//! posted to request queues and their results dequeued from completion
+//! queues which are shared with the kernel, rather than going through a
+//! system call and a completion port each.
+//!
+//! The functions are loaded into a `Rio` table, which is then used to
+//! register buffers with `RegisteredBuffer`, create a `CompletionQueue`, and
+//! create a `RequestQueue` for each socket. Sockets used with RIO must be
+//! created with the `WSA_FLAG_REGISTERED_IO` flag, as done by `udp_bind`,
+//! `tcp_builder_v4` and `tcp_builder_v6`.
+
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, UdpSocket};
+use std::ops::{Deref, DerefMut};
+use std::os::windows::prelude::*;
+use std::ptr;
+
+use net2::TcpBuilder;
+use winapi::*;
+use ws2_32::*;
+
+use Overlapped;
+use iocp::CompletionPort;
+use net::{self, ptrs_to_socket_addr, RawSocketAddr};
+
+/// Flag for the operations of a `RequestQueue` to not post a completion to
+/// the completion queue when the operation completes.
+pub const RIO_MSG_DONT_NOTIFY: u32 = 0x01;
+/// Flag for the operations of a `RequestQueue` to defer handing the
+/// operation to the network stack until a later operation without this flag
+/// is issued.
+pub const RIO_MSG_DEFER: u32 = 0x02;
+/// Flag for the receive operations of a `RequestQueue` to complete only once
+/// the buffer has been filled.
+pub const RIO_MSG_WAITALL: u32 = 0x04;
+
+/// The size of a buffer which receives a socket address in
+/// `RequestQueue::receive_from`, and the size of the `SOCKADDR_INET`
+/// structure read and written by `RegisteredBuffer::socket_addr` and
+/// `RegisteredBuffer::set_socket_addr`.
+pub const ADDR_LEN: u32 = 28;
+
+const WSA_FLAG_REGISTERED_IO: DWORD = 0x100;
+
+const SIO_GET_MULTIPLE_EXTENSION_FUNCTION_POINTER: DWORD =
+    IOC_IN | IOC_OUT | IOC_WS2 | 36;
+
+const RIO_EVENT_COMPLETION: c_int = 1;
+const RIO_IOCP_COMPLETION: c_int = 2;
+const RIO_INVALID_BUFFERID: RIO_BUFFERID = 0xffffffff as RIO_BUFFERID;
+const RIO_CORRUPT_CQ: ULONG = 0xffffffff;
+
+const WSAID_MULTIPLE_RIO: GUID = GUID {
+    Data1: 0x8509e081,
+    Data2: 0x96dd,
+    Data3: 0x4005,
+    Data4: [0xb1, 0x65, 0x9e, 0x2e, 0xe8, 0xc7, 0x9e, 0x3f],
+};
+
+type RIO_BUFFERID = PVOID;
+type RIO_CQ = PVOID;
+type RIO_RQ = PVOID;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RIO_EXTENSION_FUNCTION_TABLE {
+    cbSize: DWORD,
+    RIOReceive: Option<unsafe extern "system" fn(RIO_RQ, *const RioBuf,
+                                                 ULONG, DWORD, PVOID)
+                                                 -> BOOL>,
+    RIOReceiveEx: Option<unsafe extern "system" fn(RIO_RQ, *const RioBuf,
+                                                   ULONG, *const RioBuf,
+                                                   *const RioBuf,
+                                                   *const RioBuf,
+                                                   *const RioBuf, DWORD,
+                                                   PVOID) -> c_int>,
+    RIOSend: Option<unsafe extern "system" fn(RIO_RQ, *const RioBuf, ULONG,
+                                              DWORD, PVOID) -> BOOL>,
+    RIOSendEx: Option<unsafe extern "system" fn(RIO_RQ, *const RioBuf,
+                                                ULONG, *const RioBuf,
+                                                *const RioBuf,
+                                                *const RioBuf,
+                                                *const RioBuf, DWORD,
+                                                PVOID) -> BOOL>,
+    RIOCloseCompletionQueue: Option<unsafe extern "system" fn(RIO_CQ)>,
+    RIOCreateCompletionQueue: Option<unsafe extern "system" fn(
+        DWORD, *mut RIO_NOTIFICATION_COMPLETION) -> RIO_CQ>,
+    RIOCreateRequestQueue: Option<unsafe extern "system" fn(SOCKET, ULONG,
+                                                            ULONG, ULONG,
+                                                            ULONG, RIO_CQ,
+                                                            RIO_CQ, PVOID)
+                                                            -> RIO_RQ>,
+    RIODequeueCompletion: Option<unsafe extern "system" fn(RIO_CQ,
+                                                           *mut RioResult,
+                                                           ULONG) -> ULONG>,
+    RIODeregisterBuffer: Option<unsafe extern "system" fn(RIO_BUFFERID)>,
+    RIONotify: Option<unsafe extern "system" fn(RIO_CQ) -> c_int>,
+    RIORegisterBuffer: Option<unsafe extern "system" fn(PCHAR, DWORD)
+                                                        -> RIO_BUFFERID>,
+    RIOResizeCompletionQueue: Option<unsafe extern "system" fn(RIO_CQ, DWORD)
+                                                               -> BOOL>,
+    RIOResizeRequestQueue: Option<unsafe extern "system" fn(RIO_RQ, DWORD,
+                                                            DWORD) -> BOOL>,
+}
+
+// The notification is a union of `{ HANDLE EventHandle; BOOL NotifyReset; }`
+// for event completion, which overlays `Handle` and `Key`, and of the three
+// fields below for completion port notification.
+#[repr(C)]
+struct RIO_NOTIFICATION_COMPLETION {
+    Type: c_int,
+    Handle: HANDLE,
+    Key: PVOID,
+    Overlapped: PVOID,
+}
+
+/// The table of Registered I/O extension functions.
+///
+/// This is loaded once with `new` and then shared, by copying it, with the
+/// buffers and queues created from it.
+#[derive(Clone, Copy)]
+pub struct Rio {
+    table: RIO_EXTENSION_FUNCTION_TABLE,
+}
+
+/// A buffer registered for use with Registered I/O.
+///
+/// The memory of the buffer is owned by this type and stays registered until
+/// it's dropped. Regions of it are passed to operations as `RioBuf`s.
+pub struct RegisteredBuffer {
+    rio: Rio,
+    id: RIO_BUFFERID,
+    buf: Vec<u8>,
+}
+
+/// A region of a `RegisteredBuffer`, as passed to the operations of a
+/// `RequestQueue`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct RioBuf {
+    id: RIO_BUFFERID,
+    offset: ULONG,
+    len: ULONG,
+}
+
+/// A queue from which the results of Registered I/O operations are
+/// dequeued.
+///
+/// A completion queue may be shared by the request queues of many sockets.
+/// It isn't safe to dequeue from it on several threads at once, so it can be
+/// sent to another thread but not shared between them.
+pub struct CompletionQueue {
+    rio: Rio,
+    cq: RIO_CQ,
+}
+
+/// The queue through which Registered I/O operations are issued on a
+/// socket.
+///
+/// A request queue is closed along with its socket. It isn't safe to issue
+/// operations through it on several threads at once, so it can be sent to
+/// another thread but not shared between them.
+pub struct RequestQueue {
+    rio: Rio,
+    rq: RIO_RQ,
+}
+
+/// The result of a Registered I/O operation, as dequeued from a
+/// `CompletionQueue`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct RioResult {
+    status: LONG,
+    bytes_transferred: ULONG,
+    socket_context: u64,
+    request_context: u64,
+}
+
+/// Creates a UDP socket suitable for Registered I/O, bound to `addr`.
+pub fn udp_bind(addr: &SocketAddr) -> io::Result<UdpSocket> {
+    let family = match *addr {
+        SocketAddr::V4(..) => AF_INET,
+        SocketAddr::V6(..) => AF_INET6,
+    };
+    let socket = try!(net::socket(family, SOCK_DGRAM, IPPROTO_UDP.0 as c_int,
+                                  WSA_FLAG_REGISTERED_IO));
+    let socket = unsafe { UdpSocket::from_raw_socket(socket) };
+    let addr = RawSocketAddr::from(*addr);
+    let r = unsafe { bind(socket.as_raw_socket(), addr.as_ptr(), addr.len()) };
+    if r == SOCKET_ERROR {
+        return Err(io::Error::from_raw_os_error(unsafe { WSAGetLastError() }))
+    }
+    Ok(socket)
+}
+
+/// Creates an IPv4 TCP socket suitable for Registered I/O.
+pub fn tcp_builder_v4() -> io::Result<TcpBuilder> {
+    let socket = try!(net::socket(AF_INET, SOCK_STREAM, IPPROTO_TCP.0 as c_int,
+                                  WSA_FLAG_REGISTERED_IO));
+    Ok(unsafe { TcpBuilder::from_raw_socket(socket) })
+}
+
+/// Creates an IPv6 TCP socket suitable for Registered I/O.
+pub fn tcp_builder_v6() -> io::Result<TcpBuilder> {
+    let socket = try!(net::socket(AF_INET6, SOCK_STREAM, IPPROTO_TCP.0 as c_int,
+                                  WSA_FLAG_REGISTERED_IO));
+    Ok(unsafe { TcpBuilder::from_raw_socket(socket) })
+}
+
+fn last_err() -> io::Error {
+    io::Error::from_raw_os_error(unsafe { WSAGetLastError() })
+}
+
+fn cvt(r: BOOL) -> io::Result<()> {
+    if r == FALSE {
+        Err(last_err())
+    } else {
+        Ok(())
+    }
+}
+
+impl Rio {
+    /// Loads the Registered I/O extension functions.
+    ///
+    /// An error is returned if the system doesn't support Registered I/O,
+    /// which was introduced in Windows 8 and Windows Server 2012.
+    pub fn new() -> io::Result<Rio> {
+        let socket = try!(udp_bind(&"0.0.0.0:0".parse().unwrap()));
+        let mut table: RIO_EXTENSION_FUNCTION_TABLE = unsafe {
+            mem::zeroed()
+        };
+        table.cbSize = mem::size_of_val(&table) as DWORD;
+        let mut bytes = 0;
+        let r = unsafe {
+            WSAIoctl(socket.as_raw_socket(),
+                     SIO_GET_MULTIPLE_EXTENSION_FUNCTION_POINTER,
+                     &WSAID_MULTIPLE_RIO as *const _ as *mut _,
+                     mem::size_of_val(&WSAID_MULTIPLE_RIO) as DWORD,
+                     &mut table as *mut _ as *mut _,
+                     table.cbSize,
+                     &mut bytes,
+                     0 as *mut _, None)
+        };
+        if r == SOCKET_ERROR {
+            return Err(last_err())
+        }
+        let loaded = table.RIOReceive.is_some() &&
+                     table.RIOReceiveEx.is_some() &&
+                     table.RIOSend.is_some() &&
+                     table.RIOSendEx.is_some() &&
+                     table.RIOCloseCompletionQueue.is_some() &&
+                     table.RIOCreateCompletionQueue.is_some() &&
+                     table.RIOCreateRequestQueue.is_some() &&
+                     table.RIODequeueCompletion.is_some() &&
+                     table.RIODeregisterBuffer.is_some() &&
+                     table.RIONotify.is_some() &&
+                     table.RIORegisterBuffer.is_some() &&
+                     table.RIOResizeCompletionQueue.is_some() &&
+                     table.RIOResizeRequestQueue.is_some();
+        if bytes != table.cbSize || !loaded {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      "failed to load registered I/O \
+                                       extension"))
+        }
+        Ok(Rio { table: table })
+    }
+}
+
+impl fmt::Debug for Rio {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Rio").finish()
+    }
+}
+
+impl RegisteredBuffer {
+    /// Allocates a zeroed buffer of `len` bytes and registers it.
+    pub fn new(rio: &Rio, len: usize) -> io::Result<RegisteredBuffer> {
+        let raw_len = try!(::checked_len(len));
+        let mut buf = vec![0; len];
+        let id = unsafe {
+            (rio.table.RIORegisterBuffer.unwrap())(buf.as_mut_ptr() as PCHAR,
+                                                   raw_len)
+        };
+        if id == RIO_INVALID_BUFFERID {
+            return Err(last_err())
+        }
+        Ok(RegisteredBuffer { rio: *rio, id: id, buf: buf })
+    }
+
+    /// Returns the region of `len` bytes at `offset` in this buffer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the region isn't within this buffer.
+    pub fn slice(&self, offset: usize, len: usize) -> RioBuf {
+        assert!(offset <= self.buf.len() && len <= self.buf.len() - offset,
+                "region out of bounds of registered buffer");
+        // The buffer's length was checked to fit in a `DWORD` when it was
+        // registered, so the region's offset and length do too.
+        RioBuf {
+            id: self.id,
+            offset: offset as ULONG,
+            len: len as ULONG,
+        }
+    }
+
+    /// Returns the socket address stored in the region `addr` of this
+    /// buffer, as filled in by `RequestQueue::receive_from`.
+    ///
+    /// Returns `None` if the region isn't part of this buffer, is shorter
+    /// than `ADDR_LEN`, or doesn't hold an IPv4 or IPv6 address.
+    pub fn socket_addr(&self, addr: &RioBuf) -> Option<SocketAddr> {
+        if addr.id != self.id || addr.len < ADDR_LEN {
+            return None
+        }
+        let bytes = &self.buf[addr.offset as usize..][..ADDR_LEN as usize];
+        let mut storage: SOCKADDR_STORAGE = unsafe { mem::zeroed() };
+        unsafe {
+            ptr::copy_nonoverlapping(bytes.as_ptr(),
+                                     &mut storage as *mut _ as *mut u8,
+                                     bytes.len());
+            ptrs_to_socket_addr(&storage as *const _ as *const _,
+                                ADDR_LEN as c_int)
+        }
+    }
+
+    /// Stores `addr` in the region `buf` of this buffer, for use as the
+    /// destination of `RequestQueue::send_to`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the region isn't part of this buffer or is shorter than
+    /// `ADDR_LEN`.
+    pub fn set_socket_addr(&mut self, buf: &RioBuf, addr: &SocketAddr) {
+        assert!(buf.id == self.id && buf.len >= ADDR_LEN,
+                "region can't hold a socket address");
+        let addr = RawSocketAddr::from(*addr);
+        let dst = &mut self.buf[buf.offset as usize..][..ADDR_LEN as usize];
+        for b in dst.iter_mut() {
+            *b = 0;
+        }
+        unsafe {
+            ptr::copy_nonoverlapping(addr.as_ptr() as *const u8,
+                                     dst.as_mut_ptr(), addr.len() as usize);
+        }
+    }
+}
+
+impl Deref for RegisteredBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for RegisteredBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for RegisteredBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            (self.rio.table.RIODeregisterBuffer.unwrap())(self.id);
+        }
+    }
+}
+
+impl fmt::Debug for RegisteredBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RegisteredBuffer")
+         .field("id", &self.id)
+         .field("len", &self.buf.len())
+         .finish()
+    }
+}
+
+unsafe impl Send for RegisteredBuffer {}
+unsafe impl Sync for RegisteredBuffer {}
+
+impl RioBuf {
+    /// Returns the offset of this region within its buffer.
+    pub fn offset(&self) -> usize {
+        self.offset as usize
+    }
+
+    /// Returns the length of this region.
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// Returns whether this region is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+unsafe impl Send for RioBuf {}
+unsafe impl Sync for RioBuf {}
+
+impl CompletionQueue {
+    /// Creates a completion queue with room for `size` results, which is
+    /// polled with `dequeue`.
+    pub fn new(rio: &Rio, size: u32) -> io::Result<CompletionQueue> {
+        CompletionQueue::create(rio, size, 0 as *mut _)
+    }
+
+    /// Creates a completion queue with room for `size` results, which posts
+    /// a completion status to `port` after `notify` has been called and a
+    /// result is available.
+    ///
+    /// The status is posted with the `token` and `overlapped` pointer given
+    /// here, and `overlapped` isn't accessed by the system.
+    pub fn with_port(rio: &Rio,
+                     size: u32,
+                     port: &CompletionPort,
+                     token: usize,
+                     overlapped: *mut Overlapped)
+                     -> io::Result<CompletionQueue> {
+        let mut notification = RIO_NOTIFICATION_COMPLETION {
+            Type: RIO_IOCP_COMPLETION,
+            Handle: port.as_raw_handle(),
+            Key: token as PVOID,
+            Overlapped: overlapped as PVOID,
+        };
+        CompletionQueue::create(rio, size, &mut notification)
+    }
+
+    /// Creates a completion queue with room for `size` results, which
+    /// signals `event` after `notify` has been called and a result is
+    /// available.
+    ///
+    /// If `reset` is true the event is reset when `notify` is called.
+    pub fn with_event(rio: &Rio, size: u32, event: HANDLE, reset: bool)
+                      -> io::Result<CompletionQueue> {
+        let mut notification = RIO_NOTIFICATION_COMPLETION {
+            Type: RIO_EVENT_COMPLETION,
+            Handle: event,
+            Key: (reset as BOOL) as usize as PVOID,
+            Overlapped: 0 as *mut _,
+        };
+        CompletionQueue::create(rio, size, &mut notification)
+    }
+
+    fn create(rio: &Rio,
+              size: u32,
+              notification: *mut RIO_NOTIFICATION_COMPLETION)
+              -> io::Result<CompletionQueue> {
+        let cq = unsafe {
+            (rio.table.RIOCreateCompletionQueue.unwrap())(size, notification)
+        };
+        if cq.is_null() {
+            Err(last_err())
+        } else {
+            Ok(CompletionQueue { rio: *rio, cq: cq })
+        }
+    }
+
+    /// Dequeues the results of completed operations from this queue.
+    ///
+    /// A buffer of "zero" results is provided and on success the sub-slice of
+    /// results which were dequeued is returned. This doesn't wait for
+    /// operations to complete, so the returned slice may be empty.
+    pub fn dequeue<'a>(&self, list: &'a mut [RioResult])
+                       -> io::Result<&'a mut [RioResult]> {
+        let n = unsafe {
+            (self.rio.table.RIODequeueCompletion.unwrap())(
+                self.cq, list.as_mut_ptr(), ::clamp_len(list.len()))
+        };
+        if n == RIO_CORRUPT_CQ {
+            Err(io::Error::new(io::ErrorKind::Other,
+                               "registered I/O completion queue corrupted"))
+        } else {
+            Ok(&mut list[..n as usize])
+        }
+    }
+
+    /// Requests a notification, through the completion port or event this
+    /// queue was created with, once a result is available to dequeue.
+    ///
+    /// The notification is only delivered once per call, so this must be
+    /// called again after it has been received and the results dequeued.
+    pub fn notify(&self) -> io::Result<()> {
+        let r = unsafe { (self.rio.table.RIONotify.unwrap())(self.cq) };
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(r))
+        }
+    }
+
+    /// Changes the number of results this queue has room for.
+    pub fn resize(&self, size: u32) -> io::Result<()> {
+        cvt(unsafe {
+            (self.rio.table.RIOResizeCompletionQueue.unwrap())(self.cq, size)
+        })
+    }
+}
+
+impl Drop for CompletionQueue {
+    fn drop(&mut self) {
+        unsafe {
+            (self.rio.table.RIOCloseCompletionQueue.unwrap())(self.cq);
+        }
+    }
+}
+
+impl fmt::Debug for CompletionQueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CompletionQueue").field("cq", &self.cq).finish()
+    }
+}
+
+unsafe impl Send for CompletionQueue {}
+
+impl RequestQueue {
+    /// Creates the request queue of `socket`, which must have been created
+    /// for Registered I/O.
+    ///
+    /// Up to `max_receives` receives and `max_sends` sends may be
+    /// outstanding at once, and their results are posted to `receive_cq`
+    /// and `send_cq` respectively, along with the `context` given here.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the request queue refers to the
+    /// socket and completion queues without borrowing them. The completion
+    /// queues must not be dropped while the socket is open, and the request
+    /// queue must not be used once the socket has been closed.
+    pub unsafe fn new<S: AsRawSocket>(rio: &Rio,
+                                      socket: &S,
+                                      max_receives: u32,
+                                      max_sends: u32,
+                                      receive_cq: &CompletionQueue,
+                                      send_cq: &CompletionQueue,
+                                      context: u64)
+                                      -> io::Result<RequestQueue> {
+        let rq = (rio.table.RIOCreateRequestQueue.unwrap())(
+            socket.as_raw_socket(), max_receives, 1, max_sends, 1,
+            receive_cq.cq, send_cq.cq, context as usize as PVOID);
+        if rq.is_null() {
+            Err(last_err())
+        } else {
+            Ok(RequestQueue { rio: *rio, rq: rq })
+        }
+    }
+
+    /// Issues a receive into `buf` on this queue's socket.
+    ///
+    /// The result is posted to the receive completion queue along with
+    /// `context`. The `flags` are a combination of the `RIO_MSG_*`
+    /// constants.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the buffer which `buf` is a region of
+    /// must stay registered, and the region shouldn't be accessed, until the
+    /// result of the operation has been dequeued.
+    pub unsafe fn receive(&self, buf: &RioBuf, flags: u32, context: u64)
+                          -> io::Result<()> {
+        cvt((self.rio.table.RIOReceive.unwrap())(self.rq, buf, 1, flags,
+                                                 context as usize as PVOID))
+    }
+
+    /// Issues a receive into `buf` on this queue's socket, storing the
+    /// address of the sender in `addr`.
+    ///
+    /// The `addr` region must be at least `ADDR_LEN` bytes long, and the
+    /// address can be read from it with `RegisteredBuffer::socket_addr`.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as `receive`, which also
+    /// apply to `addr`.
+    pub unsafe fn receive_from(&self,
+                               buf: &RioBuf,
+                               addr: &RioBuf,
+                               flags: u32,
+                               context: u64)
+                               -> io::Result<()> {
+        let r = (self.rio.table.RIOReceiveEx.unwrap())(
+            self.rq, buf, 1, 0 as *const _, addr, 0 as *const _,
+            0 as *const _, flags, context as usize as PVOID);
+        cvt(r)
+    }
+
+    /// Issues a send of `buf` on this queue's socket.
+    ///
+    /// The result is posted to the send completion queue along with
+    /// `context`. The `flags` are a combination of the `RIO_MSG_*`
+    /// constants.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as `receive`.
+    pub unsafe fn send(&self, buf: &RioBuf, flags: u32, context: u64)
+                       -> io::Result<()> {
+        cvt((self.rio.table.RIOSend.unwrap())(self.rq, buf, 1, flags,
+                                              context as usize as PVOID))
+    }
+
+    /// Issues a send of `buf` on this queue's socket to the address stored
+    /// in `addr`, which is set with `RegisteredBuffer::set_socket_addr`.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe for the same reasons as `receive`, which also
+    /// apply to `addr`.
+    pub unsafe fn send_to(&self,
+                          buf: &RioBuf,
+                          addr: &RioBuf,
+                          flags: u32,
+                          context: u64)
+                          -> io::Result<()> {
+        cvt((self.rio.table.RIOSendEx.unwrap())(
+            self.rq, buf, 1, 0 as *const _, addr, 0 as *const _,
+            0 as *const _, flags, context as usize as PVOID))
+    }
+
+    /// Changes the number of receives and sends which may be outstanding at
+    /// once on this queue.
+    pub fn resize(&self, max_receives: u32, max_sends: u32)
+                  -> io::Result<()> {
+        cvt(unsafe {
+            (self.rio.table.RIOResizeRequestQueue.unwrap())(self.rq,
+                                                            max_receives,
+                                                            max_sends)
+        })
+    }
+}
+
+impl fmt::Debug for RequestQueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RequestQueue").field("rq", &self.rq).finish()
+    }
+}
+
+unsafe impl Send for RequestQueue {}
+
+impl RioResult {
+    /// Returns a zero result, to fill the buffer passed to
+    /// `CompletionQueue::dequeue`.
+    pub fn zero() -> RioResult {
+        RioResult {
+            status: 0,
+            bytes_transferred: 0,
+            socket_context: 0,
+            request_context: 0,
+        }
+    }
+
+    /// Returns the number of bytes transferred by the operation, or the
+    /// error it failed with.
+    pub fn result(&self) -> io::Result<usize> {
+        if self.status == 0 {
+            Ok(self.bytes_transferred as usize)
+        } else {
+            Err(io::Error::from_raw_os_error(self.status))
+        }
+    }
+
+    /// Returns the number of bytes transferred by the operation.
+    pub fn bytes_transferred(&self) -> u32 {
+        self.bytes_transferred
+    }
+
+    /// Returns the context the request queue of the operation was created
+    /// with.
+    pub fn socket_context(&self) -> u64 {
+        self.socket_context
+    }
+
+    /// Returns the context the operation was issued with.
+    pub fn request_context(&self) -> u64 {
+        self.request_context
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn udp() {
+        let rio = t!(Rio::new());
+        let a = t!(udp_bind(&"127.0.0.1:0".parse().unwrap()));
+        let b = t!(udp_bind(&"127.0.0.1:0".parse().unwrap()));
+        let cq = t!(CompletionQueue::new(&rio, 8));
+        let qa = unsafe {
+            t!(RequestQueue::new(&rio, &a, 1, 1, &cq, &cq, 1))
+        };
+        let qb = unsafe {
+            t!(RequestQueue::new(&rio, &b, 1, 1, &cq, &cq, 2))
+        };
+
+        let mut buf = t!(RegisteredBuffer::new(&rio, 256));
+        let send = buf.slice(0, 3);
+        let send_addr = buf.slice(64, ADDR_LEN as usize);
+        let recv = buf.slice(128, 64);
+        let recv_addr = buf.slice(192, ADDR_LEN as usize);
+        buf[..3].copy_from_slice(&[1, 2, 3]);
+        buf.set_socket_addr(&send_addr, &t!(b.local_addr()));
+        unsafe {
+            t!(qb.receive_from(&recv, &recv_addr, 0, 10));
+            t!(qa.send_to(&send, &send_addr, 0, 20));
+        }
+
+        let mut results = [RioResult::zero(); 2];
+        let mut n = 0;
+        for _ in 0..1000 {
+            n += t!(cq.dequeue(&mut results[n..])).len();
+            if n == 2 {
+                break
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(n, 2);
+        for result in results.iter() {
+            assert_eq!(t!(result.result()), 3);
+            match result.socket_context() {
+                1 => assert_eq!(result.request_context(), 20),
+                2 => assert_eq!(result.request_context(), 10),
+                n => panic!("unexpected socket context {}", n),
+            }
+        }
+        assert_eq!(&buf[128..131], &[1, 2, 3]);
+        assert_eq!(buf.socket_addr(&recv_addr), Some(t!(a.local_addr())));
+    }
+}
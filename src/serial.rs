@@ -0,0 +1,187 @@
+//! Serial ports
+
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::mem;
+use std::os::windows::ffi::*;
+
+use advapi32::*;
+use winapi::*;
+
+/// Information about a serial port which is present on the system.
+///
+/// Instances of this type are returned from the `ports` function.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SerialPortInfo {
+    name: OsString,
+    friendly_name: Option<OsString>,
+}
+
+// {4D36E978-E325-11CE-BFC1-08002BE10318}
+const GUID_DEVCLASS_PORTS: GUID = GUID {
+    Data1: 0x4d36e978,
+    Data2: 0xe325,
+    Data3: 0x11ce,
+    Data4: [0xbf, 0xc1, 0x08, 0x00, 0x2b, 0xe1, 0x03, 0x18],
+};
+
+#[link(name = "setupapi")]
+extern "system" {
+    fn SetupDiGetClassDevsW(ClassGuid: *const GUID, Enumerator: PCWSTR,
+                            hwndParent: HWND, Flags: DWORD) -> HDEVINFO;
+    fn SetupDiEnumDeviceInfo(DeviceInfoSet: HDEVINFO, MemberIndex: DWORD,
+                             DeviceInfoData: PSP_DEVINFO_DATA) -> BOOL;
+    fn SetupDiOpenDevRegKey(DeviceInfoSet: HDEVINFO,
+                            DeviceInfoData: PSP_DEVINFO_DATA, Scope: DWORD,
+                            HwProfile: DWORD, KeyType: DWORD,
+                            samDesired: REGSAM) -> HKEY;
+    fn SetupDiGetDeviceRegistryPropertyW(DeviceInfoSet: HDEVINFO,
+                                         DeviceInfoData: PSP_DEVINFO_DATA,
+                                         Property: DWORD,
+                                         PropertyRegDataType: PDWORD,
+                                         PropertyBuffer: PBYTE,
+                                         PropertyBufferSize: DWORD,
+                                         RequiredSize: PDWORD) -> BOOL;
+    fn SetupDiDestroyDeviceInfoList(DeviceInfoSet: HDEVINFO) -> BOOL;
+}
+
+struct DeviceInfoSet(HDEVINFO);
+
+impl Drop for DeviceInfoSet {
+    fn drop(&mut self) {
+        unsafe { SetupDiDestroyDeviceInfoList(self.0) };
+    }
+}
+
+/// Enumerates the serial (COM) ports which are currently present on the
+/// system.
+///
+/// This queries the device installer for all present devices in the "Ports"
+/// setup class, returning the port name (e.g. `COM3`) along with the friendly
+/// name of the device if it has one. Parallel (LPT) ports in the same class
+/// are skipped.
+pub fn ports() -> io::Result<Vec<SerialPortInfo>> {
+    let set = unsafe {
+        SetupDiGetClassDevsW(&GUID_DEVCLASS_PORTS, 0 as *const _,
+                             0 as *mut _, DIGCF_PRESENT)
+    };
+    if set == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error())
+    }
+    let set = DeviceInfoSet(set);
+
+    let mut ret = Vec::new();
+    for index in 0.. {
+        let mut data: SP_DEVINFO_DATA = unsafe { mem::zeroed() };
+        data.cbSize = mem::size_of::<SP_DEVINFO_DATA>() as DWORD;
+        let r = unsafe { SetupDiEnumDeviceInfo(set.0, index, &mut data) };
+        if r == 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(ERROR_NO_MORE_ITEMS as i32) {
+                break
+            }
+            return Err(err)
+        }
+
+        let name = match port_name(&set, &mut data) {
+            Some(name) => name,
+            None => continue,
+        };
+        if !name.to_string_lossy().starts_with("COM") {
+            continue
+        }
+        ret.push(SerialPortInfo {
+            name: name,
+            friendly_name: friendly_name(&set, &mut data),
+        });
+    }
+    Ok(ret)
+}
+
+fn port_name(set: &DeviceInfoSet, data: &mut SP_DEVINFO_DATA)
+             -> Option<OsString> {
+    let key = unsafe {
+        SetupDiOpenDevRegKey(set.0, data, DICS_FLAG_GLOBAL, 0, DIREG_DEV,
+                             KEY_READ)
+    };
+    if key == INVALID_HANDLE_VALUE as HKEY {
+        return None
+    }
+    let value = "PortName".encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+    let mut buf = [0u16; 256];
+    let mut len = mem::size_of_val(&buf) as DWORD;
+    let mut kind = 0;
+    let r = unsafe {
+        let r = RegQueryValueExW(key, value.as_ptr(), 0 as *mut _, &mut kind,
+                                 buf.as_mut_ptr() as *mut _, &mut len);
+        RegCloseKey(key);
+        r
+    };
+    if r != ERROR_SUCCESS as LONG || kind != REG_SZ {
+        return None
+    }
+    Some(from_wide(&buf, len))
+}
+
+fn friendly_name(set: &DeviceInfoSet, data: &mut SP_DEVINFO_DATA)
+                 -> Option<OsString> {
+    let mut buf = [0u16; 256];
+    let mut len = 0;
+    let r = unsafe {
+        SetupDiGetDeviceRegistryPropertyW(set.0, data, SPDRP_FRIENDLYNAME,
+                                          0 as *mut _,
+                                          buf.as_mut_ptr() as *mut _,
+                                          mem::size_of_val(&buf) as DWORD,
+                                          &mut len)
+    };
+    if r == 0 {
+        None
+    } else {
+        Some(from_wide(&buf, len))
+    }
+}
+
+// Converts a registry string of `bytes` length, which may or may not include
+// a terminating nul, to an `OsString`.
+fn from_wide(buf: &[u16], bytes: DWORD) -> OsString {
+    let buf = &buf[..(bytes as usize / 2).min(buf.len())];
+    let end = buf.iter().position(|c| *c == 0).unwrap_or(buf.len());
+    OsString::from_wide(&buf[..end])
+}
+
+impl SerialPortInfo {
+    /// Returns the name of this port, such as `COM3`.
+    pub fn name(&self) -> &OsStr {
+        &self.name
+    }
+
+    /// Returns the device path which can be used to open this port, such as
+    /// `\\.\COM3`.
+    ///
+    /// Unlike the bare name this path also works for ports numbered 10 and
+    /// above.
+    pub fn path(&self) -> OsString {
+        let mut path = OsString::from(r"\\.\");
+        path.push(&self.name);
+        path
+    }
+
+    /// Returns the friendly name of the device backing this port, if one is
+    /// available, such as `USB Serial Device (COM3)`.
+    pub fn friendly_name(&self) -> Option<&OsStr> {
+        self.friendly_name.as_ref().map(|s| &s[..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ports;
+
+    #[test]
+    fn enumerate() {
+        for port in t!(ports()) {
+            assert!(port.name().to_string_lossy().starts_with("COM"));
+            assert!(port.path().to_string_lossy().starts_with(r"\\.\COM"));
+        }
+    }
+}
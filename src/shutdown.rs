@@ -0,0 +1,263 @@
+//! Coordinated shutdown of completion port based services
+//!
+//! Tearing down a service built on completion ports in the right order is
+//! surprisingly subtle: outstanding I/O must be cancelled, the resulting
+//! completions must be drained before buffers and `Overlapped` structures are
+//! freed, and only then can the worker threads be told to exit. The
+//! `Shutdown` type in this module packages up that sequence.
+
+use std::io;
+use std::os::windows::io::*;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use winapi::*;
+use kernel32::*;
+use handle::Handle;
+use iocp::{CompletionPort, CompletionStatus};
+
+/// A coordinator for shutting down the ports, handles, and worker threads of
+/// a service.
+///
+/// Worker threads are expected to cooperate with the coordinator:
+///
+/// * Before issuing an overlapped operation, call `started`, and don't issue
+///   the operation if it returns `false`.
+/// * After dequeuing the completion of such an operation, call `completed`.
+/// * Upon dequeuing a status with the `sentinel` token, call `worker_exited`
+///   and stop dequeuing from the port.
+#[derive(Debug)]
+pub struct Shutdown {
+    sentinel: usize,
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+#[derive(Debug)]
+struct State {
+    ports: Vec<(CompletionPort, usize)>,
+    handles: Vec<HANDLE>,
+    in_flight: usize,
+    workers: usize,
+    shutting_down: bool,
+}
+
+unsafe impl Send for State {}
+
+impl Shutdown {
+    /// Creates a new shutdown coordinator.
+    ///
+    /// The `sentinel` token is posted to each registered port to tell worker
+    /// threads to exit, so it must not be used as a token for any handle.
+    pub fn new(sentinel: usize) -> Shutdown {
+        Shutdown {
+            sentinel: sentinel,
+            state: Mutex::new(State {
+                ports: Vec::new(),
+                handles: Vec::new(),
+                in_flight: 0,
+                workers: 0,
+                shutting_down: false,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Returns the token which is posted to tell worker threads to exit.
+    pub fn sentinel(&self) -> usize {
+        self.sentinel
+    }
+
+    /// Registers a completion port serviced by `workers` worker threads.
+    ///
+    /// On shutdown one sentinel status is posted to the port for each of its
+    /// workers.
+    pub fn add_port(&self, port: &CompletionPort, workers: usize)
+                    -> io::Result<()> {
+        let handle = try!(Handle::duplicate(port.as_raw_handle()));
+        let port = unsafe {
+            CompletionPort::from_raw_handle(handle.into_raw())
+        };
+        let mut state = self.lock();
+        state.workers += workers;
+        state.ports.push((port, workers));
+        Ok(())
+    }
+
+    /// Registers a handle whose outstanding I/O is cancelled on shutdown.
+    ///
+    /// The handle must be unregistered with `remove_handle` before it is
+    /// closed.
+    pub fn add_handle<T: AsRawHandle + ?Sized>(&self, t: &T) {
+        self.lock().handles.push(t.as_raw_handle());
+    }
+
+    /// Registers a socket whose outstanding I/O is cancelled on shutdown.
+    ///
+    /// The socket must be unregistered with `remove_socket` before it is
+    /// closed.
+    pub fn add_socket<T: AsRawSocket + ?Sized>(&self, t: &T) {
+        self.lock().handles.push(t.as_raw_socket() as HANDLE);
+    }
+
+    /// Unregisters a handle previously registered with `add_handle`.
+    pub fn remove_handle<T: AsRawHandle + ?Sized>(&self, t: &T) {
+        self.remove(t.as_raw_handle());
+    }
+
+    /// Unregisters a socket previously registered with `add_socket`.
+    pub fn remove_socket<T: AsRawSocket + ?Sized>(&self, t: &T) {
+        self.remove(t.as_raw_socket() as HANDLE);
+    }
+
+    fn remove(&self, handle: HANDLE) {
+        let mut state = self.lock();
+        if let Some(i) = state.handles.iter().position(|h| *h == handle) {
+            state.handles.swap_remove(i);
+        }
+    }
+
+    /// Returns whether a shutdown has been initiated.
+    pub fn is_shutting_down(&self) -> bool {
+        self.lock().shutting_down
+    }
+
+    /// Records that an overlapped operation is about to be issued.
+    ///
+    /// Returns `false` if a shutdown has been initiated, in which case the
+    /// operation should not be issued.
+    pub fn started(&self) -> bool {
+        let mut state = self.lock();
+        if state.shutting_down {
+            false
+        } else {
+            state.in_flight += 1;
+            true
+        }
+    }
+
+    /// Records that the completion of an operation previously recorded with
+    /// `started` has been dequeued.
+    ///
+    /// This should also be called if the operation failed to be issued.
+    pub fn completed(&self) {
+        let mut state = self.lock();
+        state.in_flight = state.in_flight.saturating_sub(1);
+        self.cond.notify_all();
+    }
+
+    /// Records that a worker thread has received its sentinel and is exiting.
+    pub fn worker_exited(&self) {
+        let mut state = self.lock();
+        state.workers = state.workers.saturating_sub(1);
+        self.cond.notify_all();
+    }
+
+    /// Shuts down all registered ports and handles, waiting up to `timeout`
+    /// for the process to finish.
+    ///
+    /// This will, in order:
+    ///
+    /// 1. Prevent any new operations from being started.
+    /// 2. Cancel all outstanding I/O on the registered handles.
+    /// 3. Wait for the completions of all started operations to be dequeued.
+    /// 4. Post a sentinel status for each worker of each registered port.
+    /// 5. Wait for all workers to exit.
+    ///
+    /// If the timeout elapses before this finishes, an error of kind
+    /// `TimedOut` is returned. In that case operations may still be in
+    /// flight, so the memory they reference must not be freed.
+    pub fn shutdown(&self, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        state.shutting_down = true;
+        for handle in state.handles.iter() {
+            unsafe { CancelIoEx(*handle, 0 as *mut _) };
+        }
+
+        state = try!(self.wait_until(state, deadline, |s| s.in_flight == 0));
+
+        let sentinel = CompletionStatus::new(0, self.sentinel, 0 as *mut _);
+        for &(ref port, workers) in state.ports.iter() {
+            for _ in 0..workers {
+                try!(port.post(sentinel));
+            }
+        }
+
+        self.wait_until(state, deadline, |s| s.workers == 0).map(|_| ())
+    }
+
+    fn wait_until<'a, F>(&self, mut state: MutexGuard<'a, State>,
+                         deadline: Instant, f: F)
+                         -> io::Result<MutexGuard<'a, State>>
+        where F: Fn(&State) -> bool
+    {
+        while !f(&state) {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                          "timed out waiting for shutdown"))
+            }
+            state = self.cond.wait_timeout(state, deadline - now)
+                        .unwrap_or_else(|e| e.into_inner()).0;
+        }
+        Ok(state)
+    }
+
+    fn lock(&self) -> MutexGuard<State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use iocp::CompletionPort;
+    use net::TcpStreamExt;
+    use Overlapped;
+    use super::Shutdown;
+
+    #[test]
+    fn cancels_and_drains() {
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let a = t!(TcpStream::connect(t!(l.local_addr())));
+        let _b = t!(l.accept());
+
+        let cp = Arc::new(t!(CompletionPort::new(1)));
+        let shutdown = Arc::new(Shutdown::new(usize::max_value()));
+        t!(cp.add_socket(1, &a));
+        t!(shutdown.add_port(&cp, 1));
+        shutdown.add_socket(&a);
+
+        let mut buf = [0; 10];
+        let mut over = Overlapped::zero();
+        assert!(shutdown.started());
+        unsafe {
+            assert!(!t!(a.read_overlapped(&mut buf, &mut over)));
+        }
+
+        let (cp2, shutdown2) = (cp.clone(), shutdown.clone());
+        let worker = thread::spawn(move || {
+            loop {
+                let token = match cp2.get(None) {
+                    Ok(status) => status.token(),
+                    Err(_) => 1,
+                };
+                if token == shutdown2.sentinel() {
+                    shutdown2.worker_exited();
+                    break
+                }
+                shutdown2.completed();
+            }
+        });
+
+        t!(shutdown.shutdown(Duration::from_secs(10)));
+        assert!(!shutdown.started());
+        t!(worker.join());
+        shutdown.remove_socket(&a);
+    }
+}
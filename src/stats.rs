@@ -0,0 +1,308 @@
+//! Per-handle I/O statistics
+//!
+//! The `Instrumented` wrapper in this module records the number of
+//! operations issued on a handle, the number of bytes they transferred, and
+//! histograms of the time from the submission of each operation to the
+//! dequeue of its completion.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use iocp::CompletionStatus;
+use {Overlapped, OverlappedIo};
+
+const BUCKETS: usize = 32;
+
+/// A wrapper around an I/O object which records statistics about the
+/// overlapped operations issued through it.
+///
+/// Operations are issued through the `OverlappedIo` implementation of this
+/// type, and the completion of each must be reported with `completed` once it
+/// has been dequeued from the port.
+#[derive(Debug)]
+pub struct Instrumented<T> {
+    inner: T,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    stats: IoStats,
+    pending: HashMap<usize, (Kind, Instant)>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Kind {
+    Read,
+    Write,
+}
+
+/// A snapshot of the statistics recorded by an `Instrumented` object.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IoStats {
+    reads: u64,
+    writes: u64,
+    bytes_read: u64,
+    bytes_written: u64,
+    errors: u64,
+    read_latency: LatencyHistogram,
+    write_latency: LatencyHistogram,
+}
+
+/// A histogram of the latencies of completed operations.
+///
+/// Latencies are counted in buckets of exponentially increasing size: bucket
+/// `i` counts latencies of at least `2^i` and less than `2^(i + 1)`
+/// microseconds, except that the first bucket also counts latencies below
+/// one microsecond and the last also counts all larger latencies.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    buckets: [u64; BUCKETS],
+}
+
+impl<T: OverlappedIo> Instrumented<T> {
+    /// Wraps `inner` to record statistics about its operations.
+    pub fn new(inner: T) -> Instrumented<T> {
+        Instrumented { inner: inner, state: Mutex::new(State::default()) }
+    }
+
+    /// Returns a reference to the wrapped object.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Consumes this wrapper, returning the wrapped object.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Records the completion of an operation issued through this wrapper.
+    ///
+    /// This should be called with every status dequeued for this object.
+    /// Statuses for operations which were not issued through this wrapper,
+    /// or which have already been recorded, are ignored. Failed operations
+    /// are detected through the status code in their `Overlapped`.
+    pub fn completed(&self, status: &CompletionStatus) {
+        let overlapped = status.overlapped();
+        let mut state = self.lock();
+        let (kind, start) = match state.pending.remove(&(overlapped as usize)) {
+            Some(pending) => pending,
+            None => return,
+        };
+        let failed = unsafe { (*(*overlapped).raw()).Internal != 0 };
+        let bytes = status.bytes_transferred() as u64;
+        let latency = start.elapsed();
+        let stats = &mut state.stats;
+        if failed {
+            stats.errors += 1;
+        }
+        match kind {
+            Kind::Read => {
+                stats.bytes_read += bytes;
+                stats.read_latency.record(latency);
+            }
+            Kind::Write => {
+                stats.bytes_written += bytes;
+                stats.write_latency.record(latency);
+            }
+        }
+    }
+
+    /// Returns a snapshot of the statistics recorded so far.
+    pub fn stats(&self) -> IoStats {
+        self.lock().stats.clone()
+    }
+
+    /// Returns a snapshot of the statistics recorded so far and resets them.
+    ///
+    /// Operations which are still outstanding are recorded in the next
+    /// snapshot when they complete.
+    pub fn take_stats(&self) -> IoStats {
+        let mut state = self.lock();
+        let stats = state.stats.clone();
+        state.stats = IoStats::default();
+        stats
+    }
+
+    // The operation is recorded as pending before it is issued, as its
+    // completion may be dequeued by another thread before the call issuing
+    // it returns.
+    fn submitting(&self, kind: Kind, overlapped: &Overlapped) {
+        let mut state = self.lock();
+        match kind {
+            Kind::Read => state.stats.reads += 1,
+            Kind::Write => state.stats.writes += 1,
+        }
+        let key = overlapped as *const _ as usize;
+        state.pending.insert(key, (kind, Instant::now()));
+    }
+
+    fn submitted(&self, overlapped: &Overlapped, res: &io::Result<bool>) {
+        if res.is_err() {
+            let mut state = self.lock();
+            state.pending.remove(&(overlapped as *const _ as usize));
+            state.stats.errors += 1;
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<T: OverlappedIo> OverlappedIo for Instrumented<T> {
+    unsafe fn start_read(&self, buf: &mut [u8],
+                         overlapped: &mut Overlapped)
+                         -> io::Result<bool> {
+        self.submitting(Kind::Read, overlapped);
+        let res = self.inner.start_read(buf, overlapped);
+        self.submitted(overlapped, &res);
+        res
+    }
+
+    unsafe fn start_write(&self, buf: &[u8],
+                          overlapped: &mut Overlapped)
+                          -> io::Result<bool> {
+        self.submitting(Kind::Write, overlapped);
+        let res = self.inner.start_write(buf, overlapped);
+        self.submitted(overlapped, &res);
+        res
+    }
+
+    fn cancel(&self, overlapped: &Overlapped) -> io::Result<()> {
+        self.inner.cancel(overlapped)
+    }
+}
+
+impl IoStats {
+    /// Returns the number of reads issued.
+    pub fn reads(&self) -> u64 {
+        self.reads
+    }
+
+    /// Returns the number of writes issued.
+    pub fn writes(&self) -> u64 {
+        self.writes
+    }
+
+    /// Returns the number of bytes transferred by completed reads.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Returns the number of bytes transferred by completed writes.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Returns the number of operations which failed, either when they were
+    /// issued or when they completed.
+    pub fn errors(&self) -> u64 {
+        self.errors
+    }
+
+    /// Returns the histogram of the latencies of completed reads.
+    pub fn read_latency(&self) -> &LatencyHistogram {
+        &self.read_latency
+    }
+
+    /// Returns the histogram of the latencies of completed writes.
+    pub fn write_latency(&self) -> &LatencyHistogram {
+        &self.write_latency
+    }
+}
+
+impl LatencyHistogram {
+    /// Returns the counts of each bucket of this histogram.
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    /// Returns the total number of latencies recorded.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Returns an upper bound on the latency below which the fraction `p` of
+    /// the recorded latencies fall, or `None` if nothing has been recorded.
+    ///
+    /// For example `percentile(0.99)` returns the bucket boundary at or above
+    /// the 99th percentile latency.
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None
+        }
+        let target = ((count as f64 * p).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += *n;
+            if seen >= target {
+                return Some(Duration::from_micros(1 << (i + 1)))
+            }
+        }
+        Some(Duration::from_micros(1 << BUCKETS))
+    }
+
+    fn record(&mut self, latency: Duration) {
+        let us = latency.as_secs().saturating_mul(1_000_000)
+                        .saturating_add(latency.subsec_micros() as u64);
+        let bucket = if us == 0 {
+            0
+        } else {
+            63 - us.leading_zeros() as usize
+        };
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    use iocp::CompletionPort;
+    use {Overlapped, OverlappedIo};
+    use super::{Instrumented, LatencyHistogram};
+
+    #[test]
+    fn records() {
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let a = Instrumented::new(t!(TcpStream::connect(t!(l.local_addr()))));
+        let _b = t!(l.accept());
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, a.get_ref()));
+
+        let mut over = Overlapped::zero();
+        unsafe {
+            t!(a.start_write(&[1, 2, 3], &mut over));
+        }
+        let status = t!(cp.get(None));
+        a.completed(&status);
+        a.completed(&status);
+
+        let stats = a.take_stats();
+        assert_eq!(stats.writes(), 1);
+        assert_eq!(stats.bytes_written(), 3);
+        assert_eq!(stats.errors(), 0);
+        assert_eq!(stats.write_latency().count(), 1);
+        assert_eq!(stats.read_latency().count(), 0);
+        assert_eq!(a.stats().writes(), 0);
+    }
+
+    #[test]
+    fn histogram() {
+        let mut h = LatencyHistogram::default();
+        assert_eq!(h.percentile(0.5), None);
+        h.record(Duration::from_micros(0));
+        h.record(Duration::from_micros(3));
+        h.record(Duration::from_millis(1));
+        assert_eq!(h.buckets()[0], 1);
+        assert_eq!(h.buckets()[1], 1);
+        assert_eq!(h.buckets()[9], 1);
+        assert_eq!(h.percentile(0.5), Some(Duration::from_micros(4)));
+        assert_eq!(h.percentile(1.0), Some(Duration::from_micros(1024)));
+    }
+}
@@ -0,0 +1,413 @@
+//! Waitable timers
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io;
+use std::os::windows::io::*;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use winapi::*;
+use kernel32::*;
+use handle::Handle;
+use iocp::{CompletionPort, CompletionStatus, Token};
+use wait::{PoolWait, Signaled};
+use Overlapped;
+
+/// A kernel waitable timer.
+///
+/// The timer is an auto-reset timer, so each expiration releases a single
+/// wait on its handle.
+#[derive(Debug)]
+pub struct WaitableTimer(Handle);
+
+impl WaitableTimer {
+    /// Creates a new, inactive, waitable timer.
+    pub fn new() -> io::Result<WaitableTimer> {
+        let h = unsafe {
+            CreateWaitableTimerW(0 as *mut _, FALSE, 0 as *const _)
+        };
+        if h.is_null() {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(WaitableTimer(Handle::new(h)))
+        }
+    }
+
+    /// Activates this timer, which will first expire after `due` has elapsed
+    /// and then every `period` afterwards, if specified.
+    ///
+    /// The expiration is not delayed beyond what the system's timer
+    /// resolution requires.
+    pub fn set(&self, due: Duration, period: Option<Duration>)
+               -> io::Result<()> {
+        self.set_coalescable(due, period, Duration::from_millis(0))
+    }
+
+    /// Activates this timer like `set`, allowing each expiration to be
+    /// delayed by up to `tolerable_delay`.
+    ///
+    /// The system uses the tolerable delay to coalesce the expiration of this
+    /// timer with other timers and activity, reducing the number of times the
+    /// processor has to wake up. This is useful for periodic maintenance work
+    /// which does not need to run at a precise time, and saves a meaningful
+    /// amount of power on idle systems.
+    pub fn set_coalescable(&self,
+                           due: Duration,
+                           period: Option<Duration>,
+                           tolerable_delay: Duration) -> io::Result<()> {
+        // Relative due times are expressed as negative 100ns intervals.
+        let due = due.as_secs().saturating_mul(10_000_000)
+                     .saturating_add(due.subsec_nanos() as u64 / 100);
+        let due = -(cmp::min(due, i64::max_value() as u64) as LARGE_INTEGER);
+        let period = period.map(::dur2ms).unwrap_or(0);
+        let period = cmp::min(period, LONG::max_value() as u64) as LONG;
+        let delay = ::dur2ms(tolerable_delay);
+        let delay = cmp::min(delay, ULONG::max_value() as u64) as ULONG;
+        ::cvt(unsafe {
+            SetWaitableTimerEx(self.0.raw(), &due, period, None, 0 as *mut _,
+                               0 as *mut _, delay)
+        }).map(|_| ())
+    }
+
+    /// Deactivates this timer.
+    pub fn cancel(&self) -> io::Result<()> {
+        ::cvt(unsafe { CancelWaitableTimer(self.0.raw()) }).map(|_| ())
+    }
+}
+
+impl AsRawHandle for WaitableTimer {
+    fn as_raw_handle(&self) -> HANDLE {
+        self.0.raw()
+    }
+}
+
+impl FromRawHandle for WaitableTimer {
+    unsafe fn from_raw_handle(handle: HANDLE) -> WaitableTimer {
+        WaitableTimer(Handle::new(handle))
+    }
+}
+
+impl IntoRawHandle for WaitableTimer {
+    fn into_raw_handle(self) -> HANDLE {
+        self.0.into_raw()
+    }
+}
+
+/// A deadline which cancels an overlapped socket operation once it passes.
+///
+/// The deadline is backed by a `WaitableTimer` and a wait in the system
+/// thread pool, which issues `CancelIoEx` for the operation when the timer
+/// expires. The operation then completes as usual, typically with
+/// `ERROR_OPERATION_ABORTED`. A deadline is reused by calling `schedule`
+/// again for each operation it should guard.
+#[derive(Debug)]
+pub struct Deadline {
+    wait: PoolWait<Mutex<Target>>,
+}
+
+unsafe impl Send for Deadline {}
+unsafe impl Sync for Deadline {}
+
+// The timer of a deadline, the operation cancelled when it expires, if any,
+// and when it was last armed to expire.
+//
+// The wait may have consumed an expiration of an earlier arming just before
+// the timer was armed again for another operation, so `expired` checks that
+// the current deadline has actually passed before cancelling anything.
+#[derive(Debug)]
+struct Target {
+    timer: WaitableTimer,
+    op: Option<(HANDLE, LPOVERLAPPED)>,
+    due: Instant,
+}
+
+unsafe impl Send for Target {}
+
+impl Deadline {
+    /// Creates a new deadline which isn't guarding any operation.
+    pub fn new() -> io::Result<Deadline> {
+        let timer = try!(WaitableTimer::new());
+        let handle = timer.as_raw_handle();
+        let target = Mutex::new(Target {
+            timer: timer,
+            op: None,
+            due: Instant::now(),
+        });
+        let wait = try!(unsafe {
+            PoolWait::new(handle, target, WT_EXECUTEINWAITTHREAD)
+        });
+        Ok(Deadline { wait: wait })
+    }
+
+    /// Schedules cancellation of the operation on `socket` tracked by
+    /// `overlapped` once `after` has elapsed.
+    ///
+    /// This replaces any operation previously scheduled on this deadline. It
+    /// should be called right after the operation has been issued and
+    /// returned that it's pending.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the cancellation refers to the
+    /// operation only through `socket` and `overlapped`. Once the operation
+    /// has completed `clear` must be called before `overlapped` is used for
+    /// another operation or freed, otherwise an unrelated operation may be
+    /// cancelled.
+    pub unsafe fn schedule<S>(&self, socket: &S, overlapped: &Overlapped,
+                              after: Duration) -> io::Result<()>
+        where S: AsRawSocket + ?Sized
+    {
+        let handle = socket.as_raw_socket() as HANDLE;
+        let mut target = self.lock();
+        target.op = Some((handle, overlapped.raw()));
+        target.due = Instant::now() + after;
+        target.timer.set(after, None)
+    }
+
+    /// Stops guarding the scheduled operation, if any, so that it's no longer
+    /// cancelled when the deadline passes.
+    ///
+    /// If the deadline has already passed, the cancellation may have been
+    /// issued, in which case the operation's completion reports it.
+    pub fn clear(&self) -> io::Result<()> {
+        let mut target = self.lock();
+        target.op = None;
+        target.timer.cancel()
+    }
+
+    fn lock(&self) -> MutexGuard<Target> {
+        self.wait.context().lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Signaled for Mutex<Target> {
+    fn signaled(&self) {
+        let mut target = self.lock().unwrap_or_else(|e| e.into_inner());
+        let (handle, overlapped) = match target.op {
+            Some(op) => op,
+            None => return,
+        };
+        let now = Instant::now();
+        if now < target.due {
+            // A stale expiration, so wait for the current deadline instead.
+            drop(target.timer.set(target.due - now, None));
+            return
+        }
+        target.op = None;
+        unsafe { CancelIoEx(handle, overlapped) };
+    }
+}
+
+/// A set of one-shot timers whose expirations are posted to a completion
+/// port.
+///
+/// Each expiration is posted as a completion status carrying the token the
+/// timer was added with, zero bytes transferred, and a null overlapped
+/// pointer, so timeouts can be handled from the same `get` loop as I/O.
+///
+/// All timers of a set share a single `WaitableTimer`, which is armed for the
+/// earliest of them, and a wait in the system thread pool which posts the
+/// expired timers and re-arms it.
+#[derive(Debug)]
+pub struct Timers {
+    wait: PoolWait<Shared>,
+}
+
+unsafe impl Send for Timers {}
+unsafe impl Sync for Timers {}
+
+/// Identifies a timer added to a set of `Timers`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerId(Instant, u64);
+
+#[derive(Debug)]
+struct Shared {
+    timer: WaitableTimer,
+    port: CompletionPort,
+    state: Mutex<Pending>,
+}
+
+// The timers which haven't expired yet, ordered by expiration, along with
+// the sequence number which tells apart timers expiring at the same time.
+#[derive(Debug)]
+struct Pending {
+    timers: BTreeMap<TimerId, usize>,
+    next: u64,
+}
+
+impl Timers {
+    /// Creates a new, empty, set of timers posting to `port`.
+    ///
+    /// The port is duplicated, so the set may outlive the `CompletionPort`
+    /// it was created with.
+    pub fn new<T: Token>(port: &CompletionPort<T>) -> io::Result<Timers> {
+        let timer = try!(WaitableTimer::new());
+        let handle = timer.as_raw_handle();
+        let port = try!(Handle::duplicate(port.untyped().as_raw_handle()));
+        let shared = Shared {
+            timer: timer,
+            port: unsafe { CompletionPort::from_raw_handle(port.into_raw()) },
+            state: Mutex::new(Pending { timers: BTreeMap::new(), next: 0 }),
+        };
+        let wait = try!(unsafe {
+            PoolWait::new(handle, shared, WT_EXECUTEINWAITTHREAD)
+        });
+        Ok(Timers { wait: wait })
+    }
+
+    /// Adds a timer which posts a status with `token` once `after` has
+    /// elapsed.
+    ///
+    /// The returned identifier can be passed to `cancel` to remove the timer
+    /// before it expires.
+    pub fn add(&self, after: Duration, token: usize) -> io::Result<TimerId> {
+        let shared = self.wait.context();
+        let now = Instant::now();
+        let at = try!(now.checked_add(after).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput,
+                           "timer expiration out of range")
+        }));
+        let mut state = self.lock();
+        let id = TimerId(at, state.next);
+        state.next += 1;
+        state.timers.insert(id, token);
+        if state.timers.keys().next() == Some(&id) {
+            if let Err(e) = shared.timer.set(after, None) {
+                state.timers.remove(&id);
+                return Err(e)
+            }
+        }
+        Ok(id)
+    }
+
+    /// Removes a timer which hasn't expired yet.
+    ///
+    /// Returns whether the timer was removed, which is `false` if its status
+    /// has already been posted.
+    pub fn cancel(&self, id: TimerId) -> bool {
+        // The shared timer stays armed, and simply finds no expired timers
+        // if this was the earliest one.
+        self.lock().timers.remove(&id).is_some()
+    }
+
+    /// Returns the number of timers which haven't expired yet.
+    pub fn len(&self) -> usize {
+        self.lock().timers.len()
+    }
+
+    /// Returns whether every timer has expired or been cancelled.
+    pub fn is_empty(&self) -> bool {
+        self.lock().timers.is_empty()
+    }
+
+    fn lock(&self) -> MutexGuard<Pending> {
+        let shared = self.wait.context();
+        shared.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Signaled for Shared {
+    fn signaled(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        loop {
+            let (id, token) = match state.timers.iter().next() {
+                Some((id, token)) => (*id, *token),
+                None => return,
+            };
+            if id.0 > now {
+                // Re-arm for the earliest remaining timer, which may also be
+                // reached here if the timer expired a little early.
+                let _ = self.timer.set(id.0 - now, None);
+                return
+            }
+            state.timers.remove(&id);
+            let status = CompletionStatus::new(0, token, 0 as *mut _);
+            let _ = self.port.post(status);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    use kernel32::*;
+    use winapi::*;
+
+    use iocp::CompletionPort;
+    use net::TcpStreamExt;
+    use Overlapped;
+    use super::{Deadline, Timers, WaitableTimer};
+
+    #[test]
+    fn expires() {
+        let t = t!(WaitableTimer::new());
+        t!(t.set_coalescable(Duration::from_millis(10), None,
+                             Duration::from_millis(10)));
+        unsafe {
+            assert_eq!(WaitForSingleObject(t.0.raw(), 1000), WAIT_OBJECT_0);
+        }
+
+        t!(t.set(Duration::from_secs(10), Some(Duration::from_secs(1))));
+        t!(t.cancel());
+        unsafe {
+            assert_eq!(WaitForSingleObject(t.0.raw(), 10), WAIT_TIMEOUT);
+        }
+    }
+
+    #[test]
+    fn deadline() {
+        let l = t!(TcpListener::bind("127.0.0.1:0"));
+        let a = t!(TcpStream::connect(t!(l.local_addr())));
+        let mut b = t!(l.accept()).0;
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_socket(1, &a));
+        let d = t!(Deadline::new());
+
+        let mut buf = [0; 10];
+        let mut over = Overlapped::zero();
+        unsafe {
+            assert!(!t!(a.read_overlapped(&mut buf, &mut over)));
+            t!(d.schedule(&a, &over, Duration::from_millis(10)));
+        }
+        let err = cp.get(Some(10_000)).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(ERROR_OPERATION_ABORTED as i32));
+        t!(d.clear());
+
+        // An operation which completes in time isn't cancelled.
+        unsafe {
+            assert!(!t!(a.read_overlapped(&mut buf, &mut over)));
+            t!(d.schedule(&a, &over, Duration::from_secs(10)));
+        }
+        t!(b.write_all(&[1]));
+        assert_eq!(t!(cp.get(Some(10_000))).bytes_transferred(), 1);
+        t!(d.clear());
+    }
+
+    #[test]
+    fn timers() {
+        let cp = t!(CompletionPort::new(1));
+        let timers = t!(Timers::new(&cp));
+        t!(timers.add(Duration::from_millis(60), 1));
+        t!(timers.add(Duration::from_millis(20), 2));
+        let late = t!(timers.add(Duration::from_secs(10), 3));
+        assert_eq!(timers.len(), 3);
+        assert!(timers.cancel(late));
+        assert!(!timers.cancel(late));
+
+        let s = t!(cp.get(Some(10_000)));
+        assert_eq!(s.token(), 2);
+        assert_eq!(s.bytes_transferred(), 0);
+        assert!(s.overlapped().is_null());
+        assert_eq!(t!(cp.get(Some(10_000))).token(), 1);
+        assert!(timers.is_empty());
+        assert!(cp.get(Some(50)).is_err());
+
+        assert!(timers.add(Duration::from_secs(u64::max_value()), 4)
+                      .is_err());
+    }
+}
@@ -0,0 +1,358 @@
+//! Recording and replaying sequences of completions
+//!
+//! Bugs in servers built on completion ports frequently depend on the precise
+//! order in which operations were submitted and completed, which makes them
+//! hard to reproduce. The `Recorder` in this module captures that order into a
+//! compact `Trace` which can be saved and later fed back through a completion
+//! port by a `Replayer`.
+
+use std::io::prelude::*;
+use std::io;
+use std::sync::Mutex;
+
+use winapi::*;
+use iocp::{CompletionPort, CompletionStatus};
+use Overlapped;
+
+/// A single event captured in a trace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// An overlapped operation was submitted.
+    Submit {
+        /// The token of the handle the operation was submitted on.
+        token: usize,
+        /// The address of the `Overlapped` tracking the operation, which
+        /// identifies the operation within the trace.
+        overlapped: usize,
+        /// The length of the buffer submitted with the operation.
+        len: u32,
+        /// The result of the submission: `Ok(true)` if it completed
+        /// immediately, `Ok(false)` if it is pending, or the OS error code it
+        /// failed with.
+        result: Result<bool, i32>,
+    },
+    /// A completion status was dequeued from the port.
+    Complete {
+        /// The token the status was dequeued with.
+        token: usize,
+        /// The address of the `Overlapped` of the status, which identifies
+        /// the operation within the trace.
+        overlapped: usize,
+        /// The number of bytes transferred.
+        bytes: u32,
+        /// The `NTSTATUS` code the operation completed with, zero on success.
+        status: u32,
+    },
+}
+
+/// A sequence of submission and completion events.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Trace {
+    events: Vec<Event>,
+}
+
+/// A wrapper around a completion port which records every completion
+/// dequeued through it along with the submissions reported to it.
+#[derive(Debug)]
+pub struct Recorder<'a> {
+    port: &'a CompletionPort,
+    trace: Mutex<Trace>,
+}
+
+/// Feeds the completions of a recorded trace back through a completion port.
+#[derive(Debug)]
+pub struct Replayer {
+    trace: Trace,
+}
+
+const TAG_SUBMIT: u8 = 1;
+const TAG_COMPLETE: u8 = 2;
+
+impl Trace {
+    /// Creates a new empty trace.
+    pub fn new() -> Trace {
+        Trace { events: Vec::new() }
+    }
+
+    /// Returns the events captured in this trace, in order.
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Appends an event to this trace.
+    pub fn push(&mut self, event: Event) {
+        self.events.push(event);
+    }
+
+    /// Encodes this trace into a compact binary representation.
+    ///
+    /// Each event is written as a fixed size little-endian record. The trace
+    /// can be read back with `Trace::read`.
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for event in self.events.iter() {
+            let mut rec = [0u8; 26];
+            let (tag, token, overlapped, a, b, kind) = match *event {
+                Event::Submit { token, overlapped, len, result } => {
+                    let (kind, code) = match result {
+                        Ok(true) => (0, 0),
+                        Ok(false) => (1, 0),
+                        Err(code) => (2, code as u32),
+                    };
+                    (TAG_SUBMIT, token, overlapped, len, code, kind)
+                }
+                Event::Complete { token, overlapped, bytes, status } => {
+                    (TAG_COMPLETE, token, overlapped, bytes, status, 0)
+                }
+            };
+            rec[0] = tag;
+            rec[1..9].copy_from_slice(&(token as u64).to_le_bytes());
+            rec[9..17].copy_from_slice(&(overlapped as u64).to_le_bytes());
+            rec[17..21].copy_from_slice(&a.to_le_bytes());
+            rec[21..25].copy_from_slice(&b.to_le_bytes());
+            rec[25] = kind;
+            try!(w.write_all(&rec));
+        }
+        Ok(())
+    }
+
+    /// Decodes a trace previously encoded with `write`.
+    pub fn read<R: Read>(r: &mut R) -> io::Result<Trace> {
+        let mut trace = Trace::new();
+        let mut rec = [0u8; 26];
+        loop {
+            match r.read(&mut rec[..1]) {
+                Ok(0) => return Ok(trace),
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                    continue
+                }
+                Err(e) => return Err(e),
+            }
+            try!(r.read_exact(&mut rec[1..]));
+            let u64_at = |i: usize| {
+                let mut b = [0; 8];
+                b.copy_from_slice(&rec[i..i + 8]);
+                u64::from_le_bytes(b) as usize
+            };
+            let u32_at = |i: usize| {
+                let mut b = [0; 4];
+                b.copy_from_slice(&rec[i..i + 4]);
+                u32::from_le_bytes(b)
+            };
+            let (token, overlapped) = (u64_at(1), u64_at(9));
+            let (a, b) = (u32_at(17), u32_at(21));
+            let event = match rec[0] {
+                TAG_SUBMIT => {
+                    let result = match rec[25] {
+                        0 => Ok(true),
+                        1 => Ok(false),
+                        _ => Err(b as i32),
+                    };
+                    Event::Submit {
+                        token: token,
+                        overlapped: overlapped,
+                        len: a,
+                        result: result,
+                    }
+                }
+                TAG_COMPLETE => Event::Complete {
+                    token: token,
+                    overlapped: overlapped,
+                    bytes: a,
+                    status: b,
+                },
+                _ => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              "unknown trace record"))
+                }
+            };
+            trace.push(event);
+        }
+    }
+}
+
+impl<'a> Recorder<'a> {
+    /// Creates a new recorder for completions dequeued from `port`.
+    pub fn new(port: &'a CompletionPort) -> Recorder<'a> {
+        Recorder { port: port, trace: Mutex::new(Trace::new()) }
+    }
+
+    /// Records the submission of an overlapped operation.
+    ///
+    /// This should be called with the result of each overlapped operation
+    /// issued on a handle associated with the port, such as the result of
+    /// `read_overlapped`.
+    pub fn submitted(&self,
+                     token: usize,
+                     overlapped: *mut Overlapped,
+                     len: usize,
+                     result: &io::Result<bool>) {
+        let result = match *result {
+            Ok(b) => Ok(b),
+            Err(ref e) => Err(e.raw_os_error().unwrap_or(-1)),
+        };
+        self.push(Event::Submit {
+            token: token,
+            overlapped: overlapped as usize,
+            len: len as u32,
+            result: result,
+        });
+    }
+
+    /// Dequeues a completion status from the port, recording it.
+    ///
+    /// Unlike `CompletionPort::get`, statuses for operations which failed are
+    /// returned successfully here and are recorded along with their failure.
+    pub fn get(&self, timeout_ms: Option<u32>)
+               -> io::Result<CompletionStatus> {
+        let mut list = [CompletionStatus::zero()];
+        try!(self.get_many(&mut list, timeout_ms));
+        Ok(list[0])
+    }
+
+    /// Dequeues a number of completion statuses from the port, recording
+    /// them.
+    ///
+    /// See `CompletionPort::get_many` for more information.
+    pub fn get_many<'b>(&self,
+                        list: &'b mut [CompletionStatus],
+                        timeout_ms: Option<u32>)
+                        -> io::Result<&'b mut [CompletionStatus]> {
+        let list = try!(self.port.get_many(list, timeout_ms));
+        for status in list.iter() {
+            let overlapped = status.overlapped();
+            let code = if overlapped.is_null() {
+                0
+            } else {
+                unsafe { (*(*overlapped).raw()).Internal as u32 }
+            };
+            self.push(Event::Complete {
+                token: status.token(),
+                overlapped: overlapped as usize,
+                bytes: status.bytes_transferred(),
+                status: code,
+            });
+        }
+        Ok(list)
+    }
+
+    /// Returns a copy of the trace recorded so far.
+    pub fn trace(&self) -> Trace {
+        self.trace.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Consumes this recorder, returning the trace it recorded.
+    pub fn into_trace(self) -> Trace {
+        self.trace.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn push(&self, event: Event) {
+        self.trace.lock().unwrap_or_else(|e| e.into_inner()).push(event);
+    }
+}
+
+impl Replayer {
+    /// Creates a new replayer for the specified trace.
+    pub fn new(trace: Trace) -> Replayer {
+        Replayer { trace: trace }
+    }
+
+    /// Posts every completion in the trace to `port`, in order.
+    ///
+    /// The addresses of the `Overlapped` structures recorded in the trace are
+    /// meaningless in the current process, so `map` is called with each
+    /// recorded address to translate it to the `Overlapped` which should be
+    /// posted in its place. Recorded null addresses are posted as null.
+    ///
+    /// Before each status is posted, the translated `Overlapped` is updated
+    /// with the recorded status code and byte count, so code inspecting it
+    /// observes the same outcome as in the recording. Submission events are
+    /// skipped; they are available through `Trace::events` for comparison
+    /// against the submissions made while replaying.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the pointers returned from `map` are
+    /// written to and must be valid for that.
+    pub unsafe fn replay<F>(&self, port: &CompletionPort, mut map: F)
+                            -> io::Result<()>
+        where F: FnMut(usize) -> *mut Overlapped
+    {
+        for event in self.trace.events() {
+            let (token, overlapped, bytes, status) = match *event {
+                Event::Complete { token, overlapped, bytes, status } => {
+                    (token, overlapped, bytes, status)
+                }
+                Event::Submit { .. } => continue,
+            };
+            let overlapped = if overlapped == 0 {
+                0 as *mut Overlapped
+            } else {
+                map(overlapped)
+            };
+            if !overlapped.is_null() {
+                let raw = (*overlapped).raw();
+                (*raw).Internal = status as ULONG_PTR;
+                (*raw).InternalHigh = bytes as ULONG_PTR;
+            }
+            try!(port.post(CompletionStatus::new(bytes, token, overlapped)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iocp::{CompletionPort, CompletionStatus};
+    use Overlapped;
+    use super::{Event, Recorder, Replayer, Trace};
+
+    #[test]
+    fn round_trip() {
+        let mut trace = Trace::new();
+        trace.push(Event::Submit {
+            token: 1, overlapped: 0x1000, len: 10, result: Ok(false),
+        });
+        trace.push(Event::Submit {
+            token: 1, overlapped: 0x2000, len: 10, result: Err(10054),
+        });
+        trace.push(Event::Submit {
+            token: 2, overlapped: 0x3000, len: 0, result: Err(-1),
+        });
+        trace.push(Event::Submit {
+            token: 2, overlapped: 0x4000, len: 0, result: Err(1 << 30),
+        });
+        trace.push(Event::Complete {
+            token: 1, overlapped: 0x1000, bytes: 3, status: 0xc0000120,
+        });
+        let mut buf = Vec::new();
+        t!(trace.write(&mut buf));
+        assert_eq!(t!(Trace::read(&mut &buf[..])), trace);
+    }
+
+    #[test]
+    fn record_replay() {
+        let cp = t!(CompletionPort::new(1));
+        let mut a = Overlapped::zero();
+        let recorder = Recorder::new(&cp);
+        recorder.submitted(2, &mut a, 5, &Ok(false));
+        t!(cp.post(CompletionStatus::new(5, 2, &mut a)));
+        let status = t!(recorder.get(None));
+        assert_eq!(status.token(), 2);
+        let trace = recorder.into_trace();
+        assert_eq!(trace.events().len(), 2);
+
+        let mut b = Overlapped::zero();
+        let bp = &mut b as *mut _;
+        unsafe {
+            t!(Replayer::new(trace).replay(&cp, |id| {
+                assert_eq!(id, &mut a as *mut _ as usize);
+                bp
+            }));
+        }
+        let status = t!(cp.get(None));
+        assert_eq!(status.bytes_transferred(), 5);
+        assert_eq!(status.token(), 2);
+        assert_eq!(status.overlapped(), bp);
+    }
+}
@@ -0,0 +1,183 @@
+//! Delivering signaled kernel objects through a completion port
+//!
+//! Events, processes, semaphores and waitable timers are signaled rather than
+//! completing I/O, so they can't be associated with a completion port. A
+//! `WaitRegistration` bridges them in by waiting for the object on the system
+//! thread pool and posting a completion status to the port when it's
+//! signaled, so they can be handled from the same `get` loop as sockets and
+//! files.
+
+use std::io;
+use std::os::windows::io::*;
+
+use winapi::*;
+use kernel32::*;
+use handle::Handle;
+use iocp::{CompletionPort, CompletionStatus, Token};
+
+/// A registration with the system thread pool which posts a completion status
+/// to a port whenever a kernel object is signaled.
+///
+/// The wait is registered with `RegisterWaitForSingleObject`, and dropping
+/// the registration unregisters it, blocking until any status being posted
+/// has been posted.
+#[derive(Debug)]
+pub struct WaitRegistration {
+    // Declared before the object so that the wait is unregistered before the
+    // object is closed.
+    _wait: PoolWait<Context>,
+    // Our own reference to the object, which keeps it open for as long as
+    // it's waited on.
+    _object: Handle,
+}
+
+unsafe impl Send for WaitRegistration {}
+unsafe impl Sync for WaitRegistration {}
+
+#[derive(Debug)]
+struct Context {
+    port: CompletionPort,
+    status: CompletionStatus,
+}
+
+impl WaitRegistration {
+    /// Registers a wait for `object` which posts `status` to `port` each time
+    /// the object is signaled, or only the first time if `once` is set.
+    ///
+    /// The status is posted as is, so its token is typically used to tell
+    /// which object was signaled. Objects which stay signaled, such as
+    /// manual-reset events and exited processes, post a status repeatedly
+    /// unless `once` is set.
+    ///
+    /// The object and the port are duplicated, so either may be closed by the
+    /// caller while the wait is registered.
+    pub fn new<T: Token>(port: &CompletionPort<T>, object: HANDLE,
+                         status: CompletionStatus, once: bool)
+                         -> io::Result<WaitRegistration> {
+        let object = try!(Handle::duplicate(object));
+        // The registration may outlive the `CompletionPort` it was created
+        // with, so hold on to our own reference to the port.
+        let port = try!(Handle::duplicate(port.untyped().as_raw_handle()));
+        let context = Context {
+            port: unsafe { CompletionPort::from_raw_handle(port.into_raw()) },
+            status: status,
+        };
+        let mut flags = WT_EXECUTEINWAITTHREAD;
+        if once {
+            flags |= WT_EXECUTEONLYONCE;
+        }
+        let wait = try!(unsafe { PoolWait::new(object.raw(), context, flags) });
+        Ok(WaitRegistration { _wait: wait, _object: object })
+    }
+}
+
+impl Signaled for Context {
+    fn signaled(&self) {
+        let _ = self.port.post(self.status);
+    }
+}
+
+// Context of a `PoolWait`, notified on a thread pool thread each time the
+// waited object is signaled.
+pub(crate) trait Signaled {
+    fn signaled(&self);
+}
+
+// A wait for an object in the system thread pool, which owns the context its
+// callback is run with.
+//
+// Dropping the wait unregisters it, blocking until any running callback has
+// finished, and only then frees the context.
+#[derive(Debug)]
+pub(crate) struct PoolWait<C> {
+    wait: HANDLE,
+    context: *mut C,
+}
+
+impl<C: Signaled> PoolWait<C> {
+    // Registers a wait for `object` with the `WT_*` `flags`, calling
+    // `signaled` on `context` each time it's signaled.
+    //
+    // This is unsafe because `object` must stay open for as long as the wait
+    // is registered, either by living in `context` or by outliving the
+    // returned wait.
+    pub(crate) unsafe fn new(object: HANDLE, context: C, flags: ULONG)
+                             -> io::Result<PoolWait<C>> {
+        let context = Box::into_raw(Box::new(context));
+        let mut wait = 0 as HANDLE;
+        let r = RegisterWaitForSingleObject(&mut wait, object,
+                                            Some(callback::<C>),
+                                            context as PVOID, INFINITE,
+                                            flags);
+        if r == 0 {
+            let err = io::Error::last_os_error();
+            drop(Box::from_raw(context));
+            Err(err)
+        } else {
+            Ok(PoolWait { wait: wait, context: context })
+        }
+    }
+
+    pub(crate) fn context(&self) -> &C {
+        unsafe { &*self.context }
+    }
+}
+
+unsafe extern "system" fn callback<C: Signaled>(context: PVOID,
+                                                _timed_out: BOOLEAN) {
+    (*(context as *const C)).signaled();
+}
+
+impl<C> Drop for PoolWait<C> {
+    fn drop(&mut self) {
+        unsafe {
+            // Block until any running callback has finished so the context
+            // can be freed.
+            UnregisterWaitEx(self.wait, INVALID_HANDLE_VALUE);
+            drop(Box::from_raw(self.context));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+    use std::os::windows::prelude::*;
+
+    use winapi::*;
+    use kernel32::*;
+    use handle::Handle;
+    use iocp::{CompletionPort, CompletionStatus};
+    use super::WaitRegistration;
+
+    #[test]
+    fn event() {
+        let cp = t!(CompletionPort::new(1));
+        let event = unsafe {
+            CreateEventW(0 as *mut _, FALSE, FALSE, 0 as *const _)
+        };
+        assert!(!event.is_null());
+        let event = Handle::new(event);
+        let signal = t!(Handle::duplicate(event.raw()));
+        let status = CompletionStatus::new(0, 1, 0 as *mut _);
+        let _wait = t!(WaitRegistration::new(&cp, event.raw(), status, false));
+        drop(event);
+        assert!(cp.get(Some(50)).is_err());
+
+        unsafe { SetEvent(signal.raw()) };
+        assert_eq!(t!(cp.get(None)).token(), 1);
+        unsafe { SetEvent(signal.raw()) };
+        assert_eq!(t!(cp.get(None)).token(), 1);
+    }
+
+    #[test]
+    fn process_once() {
+        let cp = t!(CompletionPort::new(1));
+        let child = t!(Command::new("cmd").args(&["/C", "exit"]).spawn());
+        let status = CompletionStatus::new(0, 2, 0 as *mut _);
+        let _wait = t!(WaitRegistration::new(&cp, child.as_raw_handle(),
+                                             status, true));
+        assert_eq!(t!(cp.get(None)).token(), 2);
+        assert!(cp.get(Some(50)).is_err());
+    }
+}
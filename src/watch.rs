@@ -0,0 +1,330 @@
+//! Directory change notifications
+//!
+//! This module provides a `DirectoryWatcher` which issues overlapped
+//! `ReadDirectoryChangesW` requests, allowing changes to a directory to be
+//! received through an I/O completion port.
+
+use std::cmp;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::windows::ffi::*;
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::*;
+use std::path::{Path, PathBuf};
+use std::slice;
+
+use winapi::*;
+use kernel32::*;
+use handle::Handle;
+use Overlapped;
+
+/// A directory which is being watched for changes.
+#[derive(Debug)]
+pub struct DirectoryWatcher {
+    handle: Handle,
+    path: PathBuf,
+    buf: Vec<u32>,
+    // The buffer size requested through `buffer_size`, which only takes
+    // effect at the next read as the kernel may still be writing to `buf`.
+    buf_len: usize,
+    subtree: bool,
+    filter: DWORD,
+    rescan: bool,
+}
+
+/// A change reported by a `DirectoryWatcher`.
+///
+/// All paths are relative to the directory being watched.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DirectoryEvent {
+    /// A file or directory was added.
+    Added(PathBuf),
+    /// A file or directory was removed.
+    Removed(PathBuf),
+    /// A file or directory was modified.
+    Modified(PathBuf),
+    /// A file or directory was renamed, and this is its old name.
+    RenamedFrom(PathBuf),
+    /// A file or directory was renamed, and this is its new name.
+    RenamedTo(PathBuf),
+    /// More changes happened than could be reported, so an unknown set of
+    /// changes was lost and the caller must re-examine the directory.
+    ///
+    /// If the watcher was configured to rescan on overflow this contains the
+    /// current contents of the directory, otherwise it is `None`.
+    Rescan(Option<Vec<PathBuf>>),
+}
+
+impl DirectoryWatcher {
+    /// Opens the directory at `path` to be watched for changes.
+    ///
+    /// The `filter` is a combination of the `FILE_NOTIFY_CHANGE_*` flags
+    /// indicating which changes should be reported, and `subtree` indicates
+    /// whether changes in subdirectories should be reported as well.
+    ///
+    /// The directory is opened for overlapped I/O, so it can be associated
+    /// with a completion port before any reads are issued.
+    pub fn new<P: AsRef<Path>>(path: P, subtree: bool, filter: DWORD)
+                               -> io::Result<DirectoryWatcher> {
+        let path = path.as_ref();
+        let file = try!(OpenOptions::new()
+                            .access_mode(FILE_LIST_DIRECTORY)
+                            .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE |
+                                        FILE_SHARE_DELETE)
+                            .custom_flags(FILE_FLAG_BACKUP_SEMANTICS |
+                                          FILE_FLAG_OVERLAPPED)
+                            .open(path));
+        Ok(DirectoryWatcher {
+            handle: Handle::new(file.into_raw_handle()),
+            path: path.to_path_buf(),
+            buf: vec![0; 64 * 1024 / 4],
+            buf_len: 64 * 1024 / 4,
+            subtree: subtree,
+            filter: filter,
+            rescan: false,
+        })
+    }
+
+    /// Sets the size of the buffer the system fills in with changes.
+    ///
+    /// If more changes happen between two reads than fit in this buffer, they
+    /// are lost and a `Rescan` event is reported instead. The default is 64
+    /// KiB, which is also the largest size supported for directories on
+    /// network shares.
+    ///
+    /// The new size takes effect at the next call to `read_overlapped`, so
+    /// this may be called while a read is outstanding.
+    pub fn buffer_size(&mut self, size: usize) -> &mut Self {
+        self.buf_len = (size + 3) / 4;
+        self
+    }
+
+    /// Indicates whether this watcher should enumerate the contents of the
+    /// directory itself when changes are lost.
+    ///
+    /// If enabled, `Rescan` events will contain the current contents of the
+    /// directory (recursively if this watcher watches a subtree).
+    pub fn rescan(&mut self, rescan: bool) -> &mut Self {
+        self.rescan = rescan;
+        self
+    }
+
+    /// Issues an overlapped read for changes to the watched directory.
+    ///
+    /// If the operation succeeds immediately, `Ok(true)` is returned. If an
+    /// asynchronous operation is enqueued, then `Ok(false)` is returned.
+    /// Otherwise if an error occurred it is returned.
+    ///
+    /// Once the operation completes, pass its result to `events` to decode
+    /// the changes which were reported.
+    ///
+    /// # Unsafety
+    ///
+    /// This function is unsafe because the kernel requires that this watcher
+    /// and the `overlapped` pointer are valid until the end of the I/O
+    /// operation. The kernel also requires that `overlapped` is unique for
+    /// this I/O operation and is not in use for any other I/O.
+    ///
+    /// To safely use this function callers must ensure that the pointers are
+    /// valid until the I/O operation is completed, typically via completion
+    /// ports and waiting to receive the completion notification on the port.
+    pub unsafe fn read_overlapped(&mut self, overlapped: &mut Overlapped)
+                                  -> io::Result<bool> {
+        if self.buf.len() != self.buf_len {
+            self.buf = vec![0; self.buf_len];
+        }
+        let len = mem::size_of_val(&self.buf[..]) as DWORD;
+        let r = ReadDirectoryChangesW(self.handle.raw(),
+                                      self.buf.as_mut_ptr() as LPVOID, len,
+                                      self.subtree as BOOL, self.filter,
+                                      0 as *mut _, overlapped.raw(), None);
+        match ::cvt(r) {
+            Ok(_) => Ok(true),
+            Err(ref e) if e.raw_os_error() == Some(ERROR_IO_PENDING as i32)
+                => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Decodes the changes reported by a completed `read_overlapped`.
+    ///
+    /// The `result` is the outcome of the completed operation, either the
+    /// number of bytes transferred or the error it failed with.
+    ///
+    /// If the system reported that changes were lost, either through
+    /// `ERROR_NOTIFY_ENUM_DIR` or a completion with no bytes transferred, a
+    /// single `Rescan` event is returned rather than an error.
+    pub fn events(&self, result: io::Result<u32>)
+                  -> io::Result<Vec<DirectoryEvent>> {
+        let bytes = match result {
+            Ok(0) => return self.overflow(),
+            Ok(n) => n as usize,
+            Err(ref e) if e.raw_os_error() == Some(ERROR_NOTIFY_ENUM_DIR as i32)
+                => return self.overflow(),
+            Err(e) => return Err(e),
+        };
+
+        let mut events = Vec::new();
+        let base = self.buf.as_ptr() as *const u8;
+        let len = cmp::min(bytes, mem::size_of_val(&self.buf[..]));
+        let header = mem::size_of::<FILE_NOTIFY_INFORMATION>();
+        let mut offset = 0;
+        while offset + header <= len {
+            let info = unsafe {
+                &*(base.offset(offset as isize) as
+                   *const FILE_NOTIFY_INFORMATION)
+            };
+            let name_len = cmp::min(info.FileNameLength as usize,
+                                    len - offset - header) / 2;
+            let name = unsafe {
+                let ptr = base.offset((offset + header) as isize);
+                slice::from_raw_parts(ptr as *const u16, name_len)
+            };
+            let path = PathBuf::from(OsString::from_wide(name));
+            events.push(match info.Action {
+                FILE_ACTION_ADDED => DirectoryEvent::Added(path),
+                FILE_ACTION_REMOVED => DirectoryEvent::Removed(path),
+                FILE_ACTION_RENAMED_OLD_NAME => {
+                    DirectoryEvent::RenamedFrom(path)
+                }
+                FILE_ACTION_RENAMED_NEW_NAME => DirectoryEvent::RenamedTo(path),
+                _ => DirectoryEvent::Modified(path),
+            });
+            if info.NextEntryOffset == 0 {
+                break
+            }
+            offset += info.NextEntryOffset as usize;
+        }
+        Ok(events)
+    }
+
+    /// Enumerates the current contents of the watched directory, recursively
+    /// if this watcher watches a subtree.
+    ///
+    /// The returned paths are relative to the watched directory.
+    pub fn enumerate(&self) -> io::Result<Vec<PathBuf>> {
+        let mut ret = Vec::new();
+        try!(enumerate(&self.path, Path::new(""), self.subtree, &mut ret));
+        Ok(ret)
+    }
+
+    fn overflow(&self) -> io::Result<Vec<DirectoryEvent>> {
+        let entries = if self.rescan {
+            Some(try!(self.enumerate()))
+        } else {
+            None
+        };
+        Ok(vec![DirectoryEvent::Rescan(entries)])
+    }
+}
+
+fn enumerate(root: &Path, rel: &Path, recurse: bool,
+             ret: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in try!(fs::read_dir(root.join(rel))) {
+        let entry = try!(entry);
+        let path = rel.join(entry.file_name());
+        if recurse && try!(entry.file_type()).is_dir() {
+            try!(enumerate(root, &path, recurse, ret));
+        }
+        ret.push(path);
+    }
+    Ok(())
+}
+
+impl AsRawHandle for DirectoryWatcher {
+    fn as_raw_handle(&self) -> HANDLE {
+        self.handle.raw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::path::PathBuf;
+
+    use rand::{thread_rng, Rng};
+    use winapi::*;
+
+    use iocp::CompletionPort;
+    use Overlapped;
+    use super::{DirectoryWatcher, DirectoryEvent};
+
+    fn tmpdir() -> PathBuf {
+        let name = thread_rng().gen_ascii_chars().take(10).collect::<String>();
+        let dir = env::temp_dir().join(format!("miow-{}", name));
+        t!(fs::create_dir(&dir));
+        dir
+    }
+
+    #[test]
+    fn added() {
+        let dir = tmpdir();
+        let mut w = t!(DirectoryWatcher::new(&dir, false,
+                                             FILE_NOTIFY_CHANGE_FILE_NAME));
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_handle(1, &w));
+
+        let mut over = Overlapped::zero();
+        unsafe {
+            t!(w.read_overlapped(&mut over));
+        }
+        t!(File::create(dir.join("foo")));
+        let status = t!(cp.get(None));
+        let events = t!(w.events(Ok(status.bytes_transferred())));
+        assert_eq!(events[0], DirectoryEvent::Added(PathBuf::from("foo")));
+        t!(fs::remove_dir_all(&dir));
+    }
+
+    #[test]
+    fn buffer_size_while_reading() {
+        let dir = tmpdir();
+        let mut w = t!(DirectoryWatcher::new(&dir, false,
+                                             FILE_NOTIFY_CHANGE_FILE_NAME));
+        let cp = t!(CompletionPort::new(1));
+        t!(cp.add_handle(1, &w));
+
+        let mut over = Overlapped::zero();
+        unsafe {
+            t!(w.read_overlapped(&mut over));
+        }
+        w.buffer_size(1024);
+        t!(File::create(dir.join("foo")));
+        let status = t!(cp.get(None));
+        let events = t!(w.events(Ok(status.bytes_transferred())));
+        assert_eq!(events[0], DirectoryEvent::Added(PathBuf::from("foo")));
+
+        unsafe {
+            t!(w.read_overlapped(&mut over));
+        }
+        t!(File::create(dir.join("bar")));
+        let status = t!(cp.get(None));
+        let events = t!(w.events(Ok(status.bytes_transferred())));
+        assert_eq!(events[0], DirectoryEvent::Added(PathBuf::from("bar")));
+        drop(w);
+        t!(fs::remove_dir_all(&dir));
+    }
+
+    #[test]
+    fn overflow() {
+        let dir = tmpdir();
+        t!(File::create(dir.join("foo")));
+        let mut w = t!(DirectoryWatcher::new(&dir, false,
+                                             FILE_NOTIFY_CHANGE_FILE_NAME));
+        let events = t!(w.events(Ok(0)));
+        assert_eq!(events, vec![DirectoryEvent::Rescan(None)]);
+
+        w.rescan(true);
+        let err = ::std::io::Error::from_raw_os_error(
+            ERROR_NOTIFY_ENUM_DIR as i32);
+        let events = t!(w.events(Err(err)));
+        assert_eq!(events,
+                   vec![DirectoryEvent::Rescan(Some(vec![
+                       PathBuf::from("foo"),
+                   ]))]);
+        drop(w);
+        t!(fs::remove_dir_all(&dir));
+    }
+}